@@ -0,0 +1,704 @@
+//! A deposit watcher crediting incoming ETH and ERC-20 transfers to an internal ledger.
+//!
+//! A principal opts in by calling `deposit_address`, which starts watching the Ethereum
+//! address derived for it. A timer then periodically
+//! * reads the ETH balance of every watched address at the latest confirmed block and credits
+//!   any increase since the previous poll, and
+//! * scans the newly confirmed blocks for ERC-20 `Transfer` events to a watched address,
+//!   emitted by one of the configured token contracts.
+//!
+//! An address that hasn't received any deposit within `UNUSED_ADDRESS_EXPIRY` of being
+//! watched is no longer watched, so that addresses requested but never used don't take up
+//! the bounded number of watched addresses for good.
+//!
+//! Plain ETH transfers don't emit logs, hence the balance polling. A consequence is that an
+//! incoming and an outgoing transfer of the same address within one poll interval net out,
+//! and that ETH already held by an address when it is first watched is credited as a deposit.
+//!
+//! The funds of a watched address can only leave it through a sweep, which records the amount
+//! it debits together with the nonce of its transaction. Once a poll sees that nonce used at
+//! the confirmed block, the debit is deducted from the baseline before the balances are
+//! compared, so that a deposit landing in the same poll interval as a sweep is still credited.
+//!
+//! The watched addresses with their baseline balances, the scan position and the ledger are
+//! kept in stable memory, so that an upgrade neither loses credits nor credits a balance twice.
+
+use crate::rpc::{consistent, eth_balance, evm_rpc_client, latest_block_number, transaction_count};
+use crate::state::{memory, read_state, Memory};
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use evm_rpc_types::{BlockTag, GetLogsArgs, Hex20, Hex32, LogEntry, Nat256};
+use ic_ethereum_types::Address;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use num::BigUint;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Ethereum produces a block every 12 seconds, so polling more often brings little.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A block counts as confirmed once this many blocks, itself included, have been mined.
+pub const DEFAULT_MIN_CONFIRMATIONS: u64 = 12;
+
+/// Providers limit the block range of `eth_getLogs`, so long backlogs are scanned in chunks.
+const MAX_BLOCKS_PER_SCAN: u64 = 500;
+
+/// Every watched address costs an `eth_getBalance` outcall per poll, so their number is
+/// bounded. A principal has a single deposit address, derived from the principal, so each
+/// caller can add at most one.
+const MAX_WATCHED_ADDRESSES: u64 = 100;
+
+/// How long an address that hasn't received any deposit stays watched, measured from the
+/// last call to `watch`.
+const UNUSED_ADDRESS_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `keccak256("Transfer(address,address,uint256)")`, the first topic of every ERC-20 transfer log.
+const TRANSFER_EVENT_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+const WATCHED_MEMORY_ID: MemoryId = MemoryId::new(0);
+const NEXT_BLOCK_TO_SCAN_MEMORY_ID: MemoryId = MemoryId::new(1);
+const DEPOSITS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+/// The asset key of ETH in `BALANCES`, no token contract lives at the zero address.
+const ETH_ASSET_KEY: [u8; 20] = [0; 20];
+
+// (owner, token contract or `ETH_ASSET_KEY`) -> credited amount, big-endian.
+type Balances = StableBTreeMap<(Principal, [u8; 20]), Vec<u8>, Memory>;
+
+thread_local! {
+    static WATCHED: RefCell<StableBTreeMap<[u8; 20], WatchedAddress, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory(WATCHED_MEMORY_ID))
+    );
+
+    // The first block not yet scanned for ERC-20 transfers, 0 before the first scan. Each
+    // block is scanned once, so that no transfer is credited twice.
+    static NEXT_BLOCK_TO_SCAN: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory(NEXT_BLOCK_TO_SCAN_MEMORY_ID), 0)
+    );
+
+    // (owner, sequence number) -> deposit, in the order in which they were credited.
+    static DEPOSITS: RefCell<StableBTreeMap<(Principal, u64), Deposit, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory(DEPOSITS_MEMORY_ID))
+    );
+
+    static BALANCES: RefCell<Balances> = RefCell::new(
+        StableBTreeMap::init(memory(BALANCES_MEMORY_ID))
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DepositAsset {
+    Eth,
+    /// The address of the token contract.
+    Erc20(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Deposit {
+    pub asset: DepositAsset,
+    /// In wei for ETH, in the smallest unit of the token for ERC-20 deposits.
+    pub amount: Nat,
+    /// The deposit address of the owner that received the funds.
+    pub address: String,
+    /// For ETH deposits, the confirmed block at which the increased balance was observed.
+    pub block_number: u64,
+    /// Only known for ERC-20 deposits, which are detected from their `Transfer` event.
+    pub transaction_hash: Option<String>,
+    pub log_index: Option<u64>,
+    /// When the deposit was credited, in nanoseconds since the UNIX epoch.
+    pub credited_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct WatchedAddress {
+    owner: Principal,
+    /// The ETH balance at `balance_block`, the baseline for detecting ETH deposits.
+    balance: Nat,
+    balance_block: u64,
+    /// When `watch` was last called for the address, in nanoseconds since the UNIX epoch.
+    watched_at: u64,
+    /// A sweep from the address whose transaction was not yet seen at a confirmed block.
+    pending_sweep: Option<PendingSweep>,
+}
+
+/// A sweep transaction sent from a watched address.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingSweep {
+    pub nonce: u64,
+    /// The amount sent plus the transaction fee.
+    pub debit: Nat,
+}
+
+macro_rules! impl_candid_storable {
+    ($t:ty) => {
+        impl Storable for $t {
+            const BOUND: Bound = Bound::Unbounded;
+
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).expect("failed to serialize"))
+            }
+
+            fn into_bytes(self) -> Vec<u8> {
+                Encode!(&self).expect("failed to serialize")
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                Decode!(bytes.as_ref(), $t).expect("failed to deserialize")
+            }
+        }
+    };
+}
+
+impl_candid_storable!(Deposit);
+impl_candid_storable!(WatchedAddress);
+
+/// The configuration of the watcher, set from the init arguments.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DepositWatcher {
+    min_confirmations: u64,
+    erc20_tokens: Vec<Address>,
+}
+
+impl Default for DepositWatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_CONFIRMATIONS, vec![])
+    }
+}
+
+impl DepositWatcher {
+    pub fn new(min_confirmations: u64, erc20_tokens: Vec<Address>) -> Self {
+        assert!(min_confirmations > 0, "min_confirmations must be positive");
+        Self {
+            min_confirmations,
+            erc20_tokens,
+        }
+    }
+
+    /// The highest block with at least `min_confirmations` confirmations.
+    fn confirmed_block(&self, latest_block: u64) -> Option<u64> {
+        (latest_block + 1).checked_sub(self.min_confirmations)
+    }
+
+    fn decode_deposit(&self, log: &LogEntry, now: u64) -> Option<(Address, Deposit)> {
+        // ERC-721 transfers share the event signature but have a fourth topic.
+        let [signature, _from, to] = log.topics.as_slice() else {
+            return None;
+        };
+        if log.removed || signature.to_string() != TRANSFER_EVENT_TOPIC {
+            return None;
+        }
+        let token = Address::new(log.address.clone().into());
+        if !self.erc20_tokens.contains(&token) {
+            return None;
+        }
+        let to = Address::try_from(&<[u8; 32]>::from(to.clone())).ok()?;
+        let value: &[u8; 32] = log.data.as_ref().try_into().ok()?;
+        let deposit = Deposit {
+            asset: DepositAsset::Erc20(token.to_string()),
+            amount: Nat(BigUint::from_bytes_be(value)),
+            address: to.to_string(),
+            block_number: u64::try_from(log.block_number.clone()?).ok()?,
+            transaction_hash: log.transaction_hash.as_ref().map(ToString::to_string),
+            log_index: log
+                .log_index
+                .clone()
+                .and_then(|index| u64::try_from(index).ok()),
+            credited_at: now,
+        };
+        Some((to, deposit))
+    }
+}
+
+/// Starts watching `address`, the deposit address of `owner`. Watching an address again
+/// keeps its baseline balance, so that its funds are not credited a second time, and
+/// postpones its expiry.
+pub fn watch(address: Address, owner: Principal, now: u64) -> Result<(), String> {
+    WATCHED.with_borrow_mut(|watched| {
+        let key = address.into_bytes();
+        if let Some(mut existing) = watched.get(&key) {
+            existing.watched_at = now;
+            watched.insert(key, existing);
+            return Ok(());
+        }
+        if watched.len() >= MAX_WATCHED_ADDRESSES {
+            return Err("the maximum number of deposit addresses has been reached".to_string());
+        }
+        watched.insert(
+            key,
+            WatchedAddress {
+                owner,
+                balance: Nat::from(0_u8),
+                balance_block: 0,
+                watched_at: now,
+                pending_sweep: None,
+            },
+        );
+        Ok(())
+    })
+}
+
+/// Stops watching the addresses whose owner hasn't received any deposit within
+/// `UNUSED_ADDRESS_EXPIRY` of the address being watched. An address with deposits stays
+/// watched, since its funds back the credits of its owner.
+fn unwatch_unused_addresses(now: u64) {
+    let expiry = UNUSED_ADDRESS_EXPIRY.as_nanos() as u64;
+    let unused: Vec<[u8; 20]> = WATCHED.with_borrow(|watched| {
+        watched
+            .iter()
+            .filter(|entry| {
+                let watched = entry.value();
+                now.saturating_sub(watched.watched_at) >= expiry && !has_deposits(&watched.owner)
+            })
+            .map(|entry| *entry.key())
+            .collect()
+    });
+    WATCHED.with_borrow_mut(|watched| {
+        for key in unused {
+            watched.remove(&key);
+        }
+    });
+}
+
+pub fn is_watched(address: &Address) -> bool {
+    WATCHED.with_borrow(|watched| watched.contains_key(&address.into_bytes()))
+}
+
+/// The ETH credited for a watched address, i.e. its baseline balance, together with the
+/// sweep from the address that is still pending, if any. `None` if the address isn't watched.
+pub fn credited_eth(address: &Address) -> Option<(Nat, Option<PendingSweep>)> {
+    WATCHED
+        .with_borrow(|watched| watched.get(&address.into_bytes()))
+        .map(|watched| (watched.balance, watched.pending_sweep))
+}
+
+/// Records a sweep sent from a watched address, to be debited from its baseline once its
+/// transaction is seen at a confirmed block.
+pub fn record_sweep(address: &Address, sweep: PendingSweep) {
+    let key = address.into_bytes();
+    WATCHED.with_borrow_mut(|watched| {
+        if let Some(mut existing) = watched.get(&key) {
+            existing.pending_sweep = Some(sweep);
+            watched.insert(key, existing);
+        }
+    });
+}
+
+fn has_pending_sweep(address: &Address) -> bool {
+    WATCHED.with_borrow(|watched| {
+        watched
+            .get(&address.into_bytes())
+            .is_some_and(|watched| watched.pending_sweep.is_some())
+    })
+}
+
+fn watched_addresses() -> Vec<Address> {
+    WATCHED.with_borrow(|watched| {
+        watched
+            .iter()
+            .map(|entry| Address::new(*entry.key()))
+            .collect()
+    })
+}
+
+/// The inclusive block range to scan next for ERC-20 transfers. Watching starts at the
+/// first confirmed block seen, earlier transfers are not picked up.
+fn next_scan_range(confirmed_block: u64) -> Option<(u64, u64)> {
+    let from = match NEXT_BLOCK_TO_SCAN.with_borrow(|next| *next.get()) {
+        0 => confirmed_block,
+        next => next,
+    };
+    (from <= confirmed_block).then(|| (from, confirmed_block.min(from + MAX_BLOCKS_PER_SCAN - 1)))
+}
+
+/// Credits the increase of the ETH balance of `address` over its saved baseline, and makes
+/// `balance` the new baseline. A decrease only lowers the baseline. `transaction_count` is the
+/// number of transactions sent from the address as of `block_number`, and only needed if a
+/// sweep is pending: the sweep is debited from the baseline once its nonce has been used.
+fn observe_eth_balance(
+    address: Address,
+    balance: Nat,
+    transaction_count: Option<u64>,
+    block_number: u64,
+    now: u64,
+) {
+    let key = address.into_bytes();
+    let Some(mut watched) = WATCHED.with_borrow(|w| w.get(&key)) else {
+        return;
+    };
+    // A lagging provider may report an older latest block than in a previous poll.
+    if block_number < watched.balance_block {
+        return;
+    }
+    if let Some(sweep) = &watched.pending_sweep {
+        if transaction_count.is_some_and(|count| count > sweep.nonce) {
+            watched.balance = if sweep.debit < watched.balance {
+                watched.balance.clone() - sweep.debit.clone()
+            } else {
+                Nat::from(0_u8)
+            };
+            watched.pending_sweep = None;
+        }
+    }
+    let increase = (balance > watched.balance).then(|| balance.clone() - watched.balance.clone());
+    watched.balance = balance;
+    watched.balance_block = block_number;
+    let owner = watched.owner;
+    WATCHED.with_borrow_mut(|w| w.insert(key, watched));
+    if let Some(amount) = increase {
+        credit(
+            owner,
+            Deposit {
+                asset: DepositAsset::Eth,
+                amount,
+                address: address.to_string(),
+                block_number,
+                transaction_hash: None,
+                log_index: None,
+                credited_at: now,
+            },
+        );
+    }
+}
+
+/// Credits the ERC-20 transfers to watched addresses among `logs`, which cover the blocks
+/// up to and including `to_block`.
+fn observe_logs(watcher: &DepositWatcher, logs: &[LogEntry], to_block: u64, now: u64) {
+    for log in logs {
+        let Some((to, deposit)) = watcher.decode_deposit(log, now) else {
+            continue;
+        };
+        if let Some(watched) = WATCHED.with_borrow(|w| w.get(&to.into_bytes())) {
+            credit(watched.owner, deposit);
+        }
+    }
+    NEXT_BLOCK_TO_SCAN.with_borrow_mut(|next| next.set(to_block + 1));
+}
+
+fn credit(owner: Principal, deposit: Deposit) {
+    let key = (owner, asset_key(&deposit.asset));
+    BALANCES.with_borrow_mut(|balances| {
+        let credited = balances
+            .get(&key)
+            .map_or_else(BigUint::default, |bytes| BigUint::from_bytes_be(&bytes));
+        balances.insert(key, (credited + &deposit.amount.0).to_bytes_be());
+    });
+    DEPOSITS.with_borrow_mut(|deposits| {
+        let sequence_number = deposits
+            .range((owner, 0)..=(owner, u64::MAX))
+            .next_back()
+            .map_or(0, |entry| entry.key().1 + 1);
+        deposits.insert((owner, sequence_number), deposit);
+    });
+}
+
+fn asset_key(asset: &DepositAsset) -> [u8; 20] {
+    match asset {
+        DepositAsset::Eth => ETH_ASSET_KEY,
+        DepositAsset::Erc20(token) => Address::from_str(token)
+            .expect("BUG: invalid token address in deposit")
+            .into_bytes(),
+    }
+}
+
+fn has_deposits(owner: &Principal) -> bool {
+    DEPOSITS.with_borrow(|deposits| {
+        deposits
+            .range((*owner, 0)..=(*owner, u64::MAX))
+            .next()
+            .is_some()
+    })
+}
+
+pub fn deposits(owner: &Principal) -> Vec<Deposit> {
+    DEPOSITS.with_borrow(|deposits| {
+        deposits
+            .range((*owner, 0)..=(*owner, u64::MAX))
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+pub fn balances(owner: &Principal) -> Vec<(DepositAsset, Nat)> {
+    BALANCES.with_borrow(|balances| {
+        balances
+            .range((*owner, [0; 20])..=(*owner, [u8::MAX; 20]))
+            .map(|entry| {
+                let asset = match entry.key().1 {
+                    ETH_ASSET_KEY => DepositAsset::Eth,
+                    token => DepositAsset::Erc20(Address::new(token).to_string()),
+                };
+                (asset, Nat(BigUint::from_bytes_be(&entry.value())))
+            })
+            .collect()
+    })
+}
+
+pub fn start_polling() {
+    ic_cdk_timers::set_timer_interval_serial(POLL_INTERVAL, async || poll_deposits().await);
+}
+
+async fn poll_deposits() {
+    unwatch_unused_addresses(ic_cdk::api::time());
+    let addresses = watched_addresses();
+    if addresses.is_empty() {
+        return;
+    }
+    let client = evm_rpc_client();
+    let latest_block = match latest_block_number(&client).await {
+        Ok(block_number) => block_number,
+        Err(e) => {
+            ic_cdk::println!("Failed to poll deposits: {}", e);
+            return;
+        }
+    };
+    let Some(confirmed_block) = read_state(|s| s.deposit_watcher().confirmed_block(latest_block))
+    else {
+        return;
+    };
+
+    for address in &addresses {
+        let block = BlockTag::Number(Nat256::from(confirmed_block));
+        let count = if has_pending_sweep(address) {
+            match transaction_count(&client, address, block.clone()).await {
+                Ok(count) => Some(count),
+                Err(e) => {
+                    ic_cdk::println!("Failed to get the nonce of {}: {}", address, e);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        match eth_balance(&client, address, block).await {
+            Ok(balance) => observe_eth_balance(
+                *address,
+                balance,
+                count,
+                confirmed_block,
+                ic_cdk::api::time(),
+            ),
+            Err(e) => ic_cdk::println!("Failed to get the balance of {}: {}", address, e),
+        }
+    }
+
+    let tokens = read_state(|s| s.deposit_watcher().erc20_tokens.clone());
+    let scan_range = next_scan_range(confirmed_block);
+    let Some((from_block, to_block)) = scan_range.filter(|_| !tokens.is_empty()) else {
+        return;
+    };
+    let args = GetLogsArgs {
+        from_block: Some(BlockTag::Number(Nat256::from(from_block))),
+        to_block: Some(BlockTag::Number(Nat256::from(to_block))),
+        addresses: tokens
+            .iter()
+            .map(|token| Hex20::from(token.into_bytes()))
+            .collect(),
+        topics: Some(vec![
+            vec![Hex32::from_str(TRANSFER_EVENT_TOPIC).expect("BUG: invalid topic")],
+            // Any sender.
+            vec![],
+            addresses
+                .iter()
+                .map(|address| Hex32::from(<[u8; 32]>::from(address)))
+                .collect(),
+        ]),
+    };
+    match consistent(client.get_logs(args).send().await) {
+        Ok(logs) => {
+            let now = ic_cdk::api::time();
+            read_state(|s| observe_logs(s.deposit_watcher(), &logs, to_block, now))
+        }
+        Err(e) => ic_cdk::println!(
+            "Failed to get the logs of blocks {} to {}: {}",
+            from_block,
+            to_block,
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evm_rpc_types::Hex;
+
+    const DEPOSIT_ADDRESS: &str = "0x378a452B20d1f06008C06c581b1656BdC5313c0C";
+    const TOKEN: &str = "0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238";
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    // Each test runs in its own thread and thus starts with empty stable memory.
+    fn watcher() -> DepositWatcher {
+        watch(Address::from_str(DEPOSIT_ADDRESS).unwrap(), owner(), 0).unwrap();
+        DepositWatcher::new(12, vec![Address::from_str(TOKEN).unwrap()])
+    }
+
+    fn transfer_log(token: &str, to: &str, value: u64, block_number: u64) -> LogEntry {
+        let to = Address::from_str(to).unwrap();
+        let mut data = [0_u8; 32];
+        data[24..].copy_from_slice(&value.to_be_bytes());
+        LogEntry {
+            address: Hex20::from_str(token).unwrap(),
+            topics: vec![
+                Hex32::from_str(TRANSFER_EVENT_TOPIC).unwrap(),
+                Hex32::from([0_u8; 32]),
+                Hex32::from(<[u8; 32]>::from(&to)),
+            ],
+            data: Hex::from(data.to_vec()),
+            block_number: Some(Nat256::from(block_number)),
+            transaction_hash: Some(Hex32::from([7_u8; 32])),
+            transaction_index: Some(Nat256::from(0_u64)),
+            block_hash: Some(Hex32::from([8_u8; 32])),
+            log_index: Some(Nat256::from(3_u64)),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn only_counts_blocks_with_enough_confirmations() {
+        let watcher = watcher();
+        assert_eq!(watcher.confirmed_block(100), Some(89));
+        assert_eq!(watcher.confirmed_block(10), None);
+
+        assert_eq!(next_scan_range(89), Some((89, 89)));
+        observe_logs(&watcher, &[], 49, 0);
+        assert_eq!(next_scan_range(49), None);
+        assert_eq!(next_scan_range(10_000), Some((50, 549)));
+    }
+
+    #[test]
+    fn credits_eth_balance_increases() {
+        watcher();
+        let address = Address::from_str(DEPOSIT_ADDRESS).unwrap();
+
+        observe_eth_balance(address, Nat::from(1_000_u32), None, 10, 0);
+        // Outgoing transfers only lower the baseline.
+        observe_eth_balance(address, Nat::from(400_u32), None, 11, 0);
+        observe_eth_balance(address, Nat::from(900_u32), None, 12, 0);
+        // A stale observation is ignored.
+        observe_eth_balance(address, Nat::from(5_000_u32), None, 11, 0);
+
+        let amounts: Vec<Nat> = deposits(&owner())
+            .into_iter()
+            .map(|deposit| deposit.amount)
+            .collect();
+        assert_eq!(amounts, vec![Nat::from(1_000_u32), Nat::from(500_u32)]);
+        assert_eq!(
+            balances(&owner()),
+            vec![(DepositAsset::Eth, Nat::from(1_500_u32))]
+        );
+    }
+
+    #[test]
+    fn does_not_credit_a_balance_twice_when_watched_again() {
+        watcher();
+        let address = Address::from_str(DEPOSIT_ADDRESS).unwrap();
+        observe_eth_balance(address, Nat::from(1_000_u32), None, 10, 0);
+
+        watch(address, owner(), 0).unwrap();
+        observe_eth_balance(address, Nat::from(1_000_u32), None, 11, 0);
+
+        assert_eq!(
+            balances(&owner()),
+            vec![(DepositAsset::Eth, Nat::from(1_000_u32))]
+        );
+    }
+
+    #[test]
+    fn credits_deposits_landing_in_the_same_poll_interval_as_a_sweep() {
+        watcher();
+        let address = Address::from_str(DEPOSIT_ADDRESS).unwrap();
+        observe_eth_balance(address, Nat::from(1_000_u32), None, 10, 0);
+        record_sweep(
+            &address,
+            PendingSweep {
+                nonce: 0,
+                debit: Nat::from(1_000_u32),
+            },
+        );
+
+        // A deposit confirmed before the sweep.
+        observe_eth_balance(address, Nat::from(1_300_u32), Some(0), 11, 0);
+        // Another deposit confirmed in the same block as the sweep.
+        observe_eth_balance(address, Nat::from(500_u32), Some(1), 12, 0);
+
+        let amounts: Vec<Nat> = deposits(&owner())
+            .into_iter()
+            .map(|deposit| deposit.amount)
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![Nat::from(1_000_u32), Nat::from(300_u32), Nat::from(200_u32)]
+        );
+        assert_eq!(credited_eth(&address), Some((Nat::from(500_u32), None)));
+    }
+
+    #[test]
+    fn limits_the_number_of_watched_addresses() {
+        for i in 0..MAX_WATCHED_ADDRESSES {
+            let mut address = [0_u8; 20];
+            address[12..].copy_from_slice(&i.to_be_bytes());
+            let address = Address::new(address);
+            watch(address, Principal::from_slice(&i.to_be_bytes()), 0).unwrap();
+        }
+        assert!(watch(Address::from_str(DEPOSIT_ADDRESS).unwrap(), owner(), 0).is_err());
+    }
+
+    #[test]
+    fn unwatches_addresses_without_deposits_after_the_expiry() {
+        let expiry = UNUSED_ADDRESS_EXPIRY.as_nanos() as u64;
+        let used = Address::from_str(DEPOSIT_ADDRESS).unwrap();
+        let unused = Address::new([1; 20]);
+        let renewed = Address::new([2; 20]);
+        watch(used, owner(), 0).unwrap();
+        watch(unused, Principal::from_slice(&[2]), 0).unwrap();
+        watch(renewed, Principal::from_slice(&[3]), 0).unwrap();
+        observe_eth_balance(used, Nat::from(1_000_u32), None, 10, 0);
+
+        watch(renewed, Principal::from_slice(&[3]), 1).unwrap();
+        unwatch_unused_addresses(expiry);
+
+        assert!(is_watched(&used));
+        assert!(!is_watched(&unused));
+        assert!(is_watched(&renewed));
+    }
+
+    #[test]
+    fn credits_erc20_transfers_to_watched_addresses() {
+        let watcher = watcher();
+        let other_address = "0x0000000000000000000000000000000000000001";
+        let logs = vec![
+            transfer_log(TOKEN, DEPOSIT_ADDRESS, 250, 89),
+            transfer_log(TOKEN, other_address, 1, 89),
+            transfer_log(other_address, DEPOSIT_ADDRESS, 1, 89),
+        ];
+
+        observe_logs(&watcher, &logs, 89, 42);
+
+        let token = Address::from_str(TOKEN).unwrap().to_string();
+        assert_eq!(
+            deposits(&owner()),
+            vec![Deposit {
+                asset: DepositAsset::Erc20(token.clone()),
+                amount: Nat::from(250_u32),
+                address: Address::from_str(DEPOSIT_ADDRESS).unwrap().to_string(),
+                block_number: 89,
+                transaction_hash: Some(Hex32::from([7_u8; 32]).to_string()),
+                log_index: Some(3),
+                credited_at: 42,
+            }]
+        );
+        assert_eq!(
+            balances(&owner()),
+            vec![(DepositAsset::Erc20(token), Nat::from(250_u32))]
+        );
+        assert_eq!(next_scan_range(90), Some((90, 90)));
+    }
+}
@@ -1,17 +1,22 @@
+mod deposits;
 mod ecdsa;
 mod ethereum_wallet;
+mod rpc;
 mod state;
+mod sweep;
 
+use crate::deposits::{Deposit, DepositAsset};
 use crate::ethereum_wallet::EthereumWallet;
-use crate::state::{init_state, read_state};
+use crate::state::{init_state, read_state, saved_init_arg};
+use crate::sweep::{SweepResult, MAX_PRINCIPALS_PER_SWEEP};
 use alloy_consensus::{SignableTransaction, TxEip1559, TxEnvelope};
 use alloy_primitives::{hex, Signature, TxKind, U256};
 use candid::{CandidType, Deserialize, Nat, Principal};
 use evm_rpc_types::{
     BlockTag, EthMainnetService, EthSepoliaService, GetTransactionCountArgs, Hex20,
-    MultiRpcResult, Nat256, RpcService,
+    MultiRpcResult, Nat256, RpcService, SendRawTransactionStatus,
 };
-use ic_cdk::{init, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_ethereum_types::Address;
 use num::{BigUint, Num};
 use std::str::FromStr;
@@ -31,6 +36,15 @@ pub fn init(maybe_init: Option<InitArg>) {
     if let Some(init_arg) = maybe_init {
         init_state(init_arg)
     }
+    deposits::start_polling();
+}
+
+// The state lives on the heap, so it is re-initialized on upgrade, from the arguments saved
+// in stable memory unless new ones are passed. The watched addresses and the deposit ledger
+// are kept in stable memory.
+#[post_upgrade]
+pub fn post_upgrade(maybe_init: Option<InitArg>) {
+    init(Some(maybe_init.unwrap_or_else(saved_init_arg)))
 }
 
 #[update]
@@ -41,6 +55,29 @@ pub async fn ethereum_address(owner: Option<Principal>) -> String {
     wallet.ethereum_address().to_string()
 }
 
+/// Returns the caller's deposit address and starts watching it for incoming ETH and
+/// ERC-20 deposits, which are then credited to the caller.
+#[update]
+pub async fn deposit_address() -> String {
+    let caller = validate_caller_not_anonymous();
+    let address = EthereumWallet::new(caller).await.ethereum_address();
+    deposits::watch(address, caller, ic_cdk::api::time()).unwrap_or_else(|e| ic_cdk::trap(e));
+    address.to_string()
+}
+
+#[query]
+pub fn get_deposits(owner: Option<Principal>) -> Vec<Deposit> {
+    let owner = owner.unwrap_or_else(validate_caller_not_anonymous);
+    deposits::deposits(&owner)
+}
+
+/// Returns the total amount credited to `owner` per asset.
+#[query]
+pub fn get_deposit_balances(owner: Option<Principal>) -> Vec<(DepositAsset, Nat)> {
+    let owner = owner.unwrap_or_else(validate_caller_not_anonymous);
+    deposits::balances(&owner)
+}
+
 #[update]
 pub async fn get_balance(address: Option<String>) -> Nat {
     let address = address.unwrap_or(ethereum_address(None).await);
@@ -120,11 +157,11 @@ pub async fn transaction_count(owner: Option<Principal>, block: Option<BlockTag>
         MultiRpcResult::Consistent(consistent_result) => match consistent_result {
             Ok(count) => Nat(count.as_ref().0.clone()),
             Err(error) => {
-                ic_cdk::trap(&format!("failed to get transaction count for {:?}, error: {:?}", args, error))
+                ic_cdk::trap(format!("failed to get transaction count for {:?}, error: {:?}", args, error))
             }
         },
         MultiRpcResult::Inconsistent(inconsistent_results) => {
-            ic_cdk::trap(&format!("inconsistent results when retrieving transaction count for {:?}. Received results: {:?}", args, inconsistent_results))
+            ic_cdk::trap(format!("inconsistent results when retrieving transaction count for {:?}. Received results: {:?}", args, inconsistent_results))
         }
     }
 }
@@ -157,10 +194,10 @@ pub async fn transaction_count_with_client(address: Option<String>, block: Optio
     match result {
         MultiRpcResult::Consistent(Ok(count)) => Nat(count.as_ref().0.clone()),
         MultiRpcResult::Consistent(Err(error)) => {
-            ic_cdk::trap(&format!("failed to get transaction count, error: {:?}", error))
+            ic_cdk::trap(format!("failed to get transaction count, error: {:?}", error))
         }
         MultiRpcResult::Inconsistent(inconsistent_results) => {
-            ic_cdk::trap(&format!(
+            ic_cdk::trap(format!(
                 "inconsistent results when retrieving transaction count. Received results: {:?}",
                 inconsistent_results
             ))
@@ -170,12 +207,15 @@ pub async fn transaction_count_with_client(address: Option<String>, block: Optio
 
 #[update]
 pub async fn send_eth(to: String, amount: Nat) -> String {
-    use alloy_eips::eip2718::Encodable2718;
-
     let caller = validate_caller_not_anonymous();
     let _to_address = Address::from_str(&to).unwrap_or_else(|e| {
-        ic_cdk::trap(&format!("failed to parse the recipient address: {:?}", e))
+        ic_cdk::trap(format!("failed to parse the recipient address: {:?}", e))
     });
+    let wallet = EthereumWallet::new(caller).await;
+    // The funds of a deposit address back the credits of its owner.
+    if deposits::is_watched(&wallet.ethereum_address()) {
+        ic_cdk::trap("cannot send ETH from a deposit address");
+    }
     let chain_id = read_state(|s| s.ethereum_network().chain_id());
     let nonce = nat_to_u64(transaction_count(Some(caller), Some(BlockTag::Latest)).await);
     let (gas_limit, max_fee_per_gas, max_priority_fee_per_gas) = estimate_transaction_fees();
@@ -192,7 +232,21 @@ pub async fn send_eth(to: String, amount: Nat) -> String {
         input: Default::default(),
     };
 
-    let wallet = EthereumWallet::new(caller).await;
+    let (transaction_hash, _result) = sign_and_send_transaction(&wallet, transaction)
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
+    transaction_hash
+}
+
+/// Signs `transaction` with the key of `wallet` and sends it. Returns the transaction hash
+/// together with the response of the EVM RPC canister, or an error if the call to the
+/// EVM RPC canister failed.
+async fn sign_and_send_transaction(
+    wallet: &EthereumWallet,
+    transaction: TxEip1559,
+) -> Result<(String, MultiRpcResult<SendRawTransactionStatus>), String> {
+    use alloy_eips::eip2718::Encodable2718;
+
     let tx_hash = transaction.signature_hash().0;
     let (raw_signature, recovery_id) = wallet.sign_with_ecdsa(tx_hash).await;
     let signature = Signature::from_bytes_and_parity(&raw_signature, recovery_id.is_y_odd())
@@ -212,19 +266,17 @@ pub async fn send_eth(to: String, amount: Nat) -> String {
     // For demonstration purposes, the canister uses a single provider to send the signed transaction,
     // but in production multiple providers (e.g., using a round-robin strategy) should be used to avoid a single point of failure.
     let single_rpc_service = read_state(|s| s.single_evm_rpc_service());
-
-    use evm_rpc_types::{MultiRpcResult as SendResult, SendRawTransactionStatus};
-    let (result,): (SendResult<SendRawTransactionStatus>,) =
+    let (result,): (MultiRpcResult<SendRawTransactionStatus>,) =
         ic_cdk::call::Call::bounded_wait(evm_rpc_id(), "eth_sendRawTransaction")
             .with_args(&(single_rpc_service, Option::<evm_rpc_types::RpcConfig>::None, raw_transaction_hex.clone()))
             .with_cycles(2_000_000_000_u128)
             .await
-            .unwrap_or_else(|e| {
-                panic!(
+            .map_err(|e| {
+                format!(
                     "failed to send raw transaction {}, error: {:?}",
                     raw_transaction_hex, e
                 )
-            })
+            })?
             .candid_tuple()
             .expect("failed to decode response");
 
@@ -238,7 +290,31 @@ pub async fn send_eth(to: String, amount: Nat) -> String {
         result
     );
 
-    raw_transaction_hash.to_string()
+    Ok((raw_transaction_hash.to_string(), result))
+}
+
+/// Sends the ETH held by the addresses of `principals` to `treasury`, minus the transaction
+/// fee, and reports the outcome per address. With `dry_run`, nothing is sent.
+/// Only controllers of the canister may sweep funds.
+#[update]
+pub async fn sweep(
+    principals: Vec<Principal>,
+    treasury: String,
+    dry_run: bool,
+) -> Result<Vec<SweepResult>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("only controllers can sweep funds".to_string());
+    }
+    if principals.len() > MAX_PRINCIPALS_PER_SWEEP {
+        return Err(format!(
+            "at most {} principals can be swept at once",
+            MAX_PRINCIPALS_PER_SWEEP
+        ));
+    }
+    let treasury = Address::from_str(&treasury)
+        .map_err(|e| format!("failed to parse the treasury address: {:?}", e))?;
+    Ok(sweep::sweep(principals, treasury, dry_run).await)
 }
 
 fn estimate_transaction_fees() -> (u128, u128, u128) {
@@ -253,13 +329,17 @@ fn estimate_transaction_fees() -> (u128, u128, u128) {
     (GAS_LIMIT, MAX_FEE_PER_GAS, MAX_PRIORITY_FEE_PER_GAS)
 }
 
-#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
 pub struct InitArg {
     pub ethereum_network: Option<EthereumNetwork>,
     /// ECDSA key name as used by the IC management canister: "dfx_test_key" (local dfx),
     /// "test_key_1" (ICP mainnet testing), or "key_1" (ICP mainnet production).
     /// Defaults to "test_key_1".
     pub ecdsa_key_name: Option<String>,
+    /// Number of confirmations after which a deposit is credited. Defaults to 12.
+    pub deposit_min_confirmations: Option<u64>,
+    /// ERC-20 token contracts whose transfers to deposit addresses are credited.
+    pub deposit_erc20_tokens: Option<Vec<String>>,
 }
 
 #[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
@@ -290,7 +370,7 @@ fn nat_to_u64(nat: Nat) -> u64 {
     use num_traits::cast::ToPrimitive;
     nat.0
         .to_u64()
-        .unwrap_or_else(|| ic_cdk::trap(&format!("Nat {} doesn't fit into a u64", nat)))
+        .unwrap_or_else(|| ic_cdk::trap(format!("Nat {} doesn't fit into a u64", nat)))
 }
 
 fn nat_to_u256(value: Nat) -> U256 {
@@ -0,0 +1,202 @@
+//! Fallible wrappers around the `EvmRpcClient`, for callers that must not trap on the
+//! first failing RPC call, such as timers and batch operations.
+
+use crate::evm_rpc_id;
+use crate::state::read_state;
+use candid::Nat;
+use evm_rpc_client::{CandidResponseConverter, EvmRpcClient, NoRetry};
+use evm_rpc_types::{Block, BlockTag, FeeHistory, FeeHistoryArgs, Hex20, MultiRpcResult, Nat256};
+use ic_canister_runtime::IcRuntime;
+use ic_ethereum_types::Address;
+use num::{BigUint, Num};
+use num_traits::ToPrimitive;
+use std::fmt::Debug;
+
+/// The number of recent blocks whose priority fees are considered.
+const FEE_HISTORY_BLOCK_COUNT: u64 = 5;
+/// The percentile of the priority fees paid in each block that is used as the tip.
+const PRIORITY_FEE_PERCENTILE: u8 = 50;
+/// A lower tip risks the transaction not being picked up at all.
+const MIN_PRIORITY_FEE_PER_GAS: u128 = 100_000_000;
+
+pub type Client = EvmRpcClient<IcRuntime, CandidResponseConverter, NoRetry>;
+
+pub fn evm_rpc_client() -> Client {
+    EvmRpcClient::builder(IcRuntime::new(), evm_rpc_id())
+        .with_rpc_sources(read_state(|s| s.evm_rpc_services()))
+        .build()
+}
+
+pub fn consistent<T: Debug>(result: MultiRpcResult<T>) -> Result<T, String> {
+    match result {
+        MultiRpcResult::Consistent(Ok(value)) => Ok(value),
+        MultiRpcResult::Consistent(Err(error)) => Err(format!("{:?}", error)),
+        MultiRpcResult::Inconsistent(results) => {
+            Err(format!("inconsistent results: {:?}", results))
+        }
+    }
+}
+
+pub async fn latest_block_number(client: &Client) -> Result<u64, String> {
+    let result: MultiRpcResult<Block> = client.get_block_by_number(BlockTag::Latest).send().await;
+    let block = consistent(result)?;
+    u64::try_from(block.number).map_err(|e| format!("invalid block number: {}", e))
+}
+
+pub async fn transaction_count(
+    client: &Client,
+    address: &Address,
+    block: BlockTag,
+) -> Result<u64, String> {
+    let address = Hex20::from(address.into_bytes());
+    let count = consistent(client.get_transaction_count((address, block)).send().await)?;
+    u64::try_from(count).map_err(|e| format!("invalid transaction count: {}", e))
+}
+
+pub async fn eth_balance(
+    client: &Client,
+    address: &Address,
+    block: BlockTag,
+) -> Result<Nat, String> {
+    // The EVM RPC canister has no typed endpoint for `eth_getBalance`.
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getBalance",
+        "params": [address.to_string(), block_tag_param(&block)],
+    });
+    let response = consistent(client.multi_request(request).send().await)?;
+    parse_balance_response(&response)
+}
+
+/// EIP-1559 fees for a transaction to be included in one of the next blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeeEstimate {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+pub async fn estimate_fees(client: &Client) -> Result<FeeEstimate, String> {
+    let args = FeeHistoryArgs {
+        block_count: Nat256::from(FEE_HISTORY_BLOCK_COUNT),
+        newest_block: BlockTag::Latest,
+        reward_percentiles: Some(vec![PRIORITY_FEE_PERCENTILE]),
+    };
+    let history = consistent(client.fee_history(args).send().await)?;
+    fee_estimate(&history)
+}
+
+/// Uses the median of the recent priority fees as the tip, and allows the base fee to double
+/// before the transaction is included, which takes at least six consecutive full blocks.
+fn fee_estimate(history: &FeeHistory) -> Result<FeeEstimate, String> {
+    // The last base fee is the one of the block following the newest block.
+    let base_fee_per_gas = history
+        .base_fee_per_gas
+        .last()
+        .ok_or("empty fee history")
+        .and_then(|fee| fee.as_ref().0.to_u128().ok_or("base fee too large"))?;
+    let mut priority_fees = history
+        .reward
+        .iter()
+        .filter_map(|rewards| rewards.first())
+        .map(|fee| fee.as_ref().0.to_u128().ok_or("priority fee too large"))
+        .collect::<Result<Vec<_>, _>>()?;
+    priority_fees.sort_unstable();
+    let median_priority_fee = priority_fees
+        .get(priority_fees.len() / 2)
+        .copied()
+        .unwrap_or_default();
+    let max_priority_fee_per_gas = median_priority_fee.max(MIN_PRIORITY_FEE_PER_GAS);
+    Ok(FeeEstimate {
+        max_fee_per_gas: base_fee_per_gas
+            .checked_mul(2)
+            .and_then(|fee| fee.checked_add(max_priority_fee_per_gas))
+            .ok_or("base fee too large")?,
+        max_priority_fee_per_gas,
+    })
+}
+
+fn block_tag_param(block: &BlockTag) -> String {
+    match block {
+        BlockTag::Latest => "latest".to_string(),
+        BlockTag::Finalized => "finalized".to_string(),
+        BlockTag::Safe => "safe".to_string(),
+        BlockTag::Earliest => "earliest".to_string(),
+        BlockTag::Pending => "pending".to_string(),
+        BlockTag::Number(number) => format!("0x{}", number.as_ref().0.to_str_radix(16)),
+    }
+}
+
+fn parse_balance_response(response: &str) -> Result<Nat, String> {
+    let response: serde_json::Value =
+        serde_json::from_str(response).map_err(|e| format!("invalid JSON-RPC response: {}", e))?;
+    let hex_balance = response
+        .get("result")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("unexpected JSON-RPC response: {}", response))?;
+    let digits = hex_balance
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("invalid balance: {}", hex_balance))?;
+    BigUint::from_str_radix(digits, 16)
+        .map(Nat)
+        .map_err(|e| format!("invalid balance {}: {}", hex_balance, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evm_rpc_types::Nat256;
+
+    #[test]
+    fn formats_block_tags() {
+        assert_eq!(block_tag_param(&BlockTag::Latest), "latest");
+        assert_eq!(
+            block_tag_param(&BlockTag::Number(Nat256::from(46_147_u64))),
+            "0xb443"
+        );
+    }
+
+    #[test]
+    fn estimates_fees_from_the_fee_history() {
+        let gwei = |fee: u64| Nat256::from(fee * 1_000_000_000);
+        let history = FeeHistory {
+            oldest_block: Nat256::from(100_u64),
+            base_fee_per_gas: vec![gwei(8), gwei(9), gwei(10)],
+            gas_used_ratio: vec![0.5, 0.6],
+            reward: vec![vec![gwei(3)], vec![gwei(1)], vec![gwei(2)]],
+        };
+        assert_eq!(
+            fee_estimate(&history),
+            Ok(FeeEstimate {
+                max_fee_per_gas: 22_000_000_000,
+                max_priority_fee_per_gas: 2_000_000_000,
+            })
+        );
+
+        let without_tips = FeeHistory {
+            reward: vec![],
+            ..history
+        };
+        assert_eq!(
+            fee_estimate(&without_tips).map(|fees| fees.max_priority_fee_per_gas),
+            Ok(MIN_PRIORITY_FEE_PER_GAS)
+        );
+        assert!(fee_estimate(&FeeHistory {
+            base_fee_per_gas: vec![],
+            ..without_tips
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn parses_balance_responses() {
+        assert_eq!(
+            parse_balance_response(r#"{"jsonrpc":"2.0","id":1,"result":"0x1bc16d674ec80000"}"#),
+            Ok(Nat::from(2_000_000_000_000_000_000_u128))
+        );
+        assert!(parse_balance_response(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"missing trie node"}}"#
+        )
+        .is_err());
+    }
+}
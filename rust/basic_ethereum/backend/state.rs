@@ -1,16 +1,65 @@
+use crate::deposits::{DepositWatcher, DEFAULT_MIN_CONFIRMATIONS};
 use crate::ecdsa::EcdsaPublicKey;
 use crate::{EthereumNetwork, InitArg};
+use candid::{Decode, Encode};
 use evm_rpc_types::{EthMainnetService, EthSepoliaService, RpcServices};
+use ic_ethereum_types::Address;
 use ic_cdk_management_canister::{EcdsaCurve, EcdsaKeyId};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// The memories 0 to 3 are used by the deposit watcher.
+const INIT_ARG_MEMORY_ID: MemoryId = MemoryId::new(4);
 
 thread_local! {
     pub static STATE: RefCell<State> = RefCell::default();
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // The arguments the state was last initialized from, so that an upgrade without
+    // arguments keeps the configuration.
+    static INIT_ARG: RefCell<StableCell<InitArg, Memory>> = RefCell::new(
+        StableCell::init(memory(INIT_ARG_MEMORY_ID), InitArg::default())
+    );
+}
+
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with_borrow(|m| m.get(id))
+}
+
+impl Storable for InitArg {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to serialize"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        Encode!(&self).expect("failed to serialize")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), InitArg).expect("failed to deserialize")
+    }
 }
 
 pub fn init_state(init_arg: InitArg) {
-    STATE.with(|s| *s.borrow_mut() = State::from(init_arg));
+    let state = State::from(init_arg.clone());
+    INIT_ARG.with_borrow_mut(|cell| cell.set(init_arg));
+    STATE.with(|s| *s.borrow_mut() = state);
+}
+
+/// The arguments the state was last initialized from, the default ones if none were passed.
+pub fn saved_init_arg() -> InitArg {
+    INIT_ARG.with_borrow(|cell| cell.get().clone())
 }
 
 pub fn read_state<R>(f: impl FnOnce(&State) -> R) -> R {
@@ -29,6 +78,7 @@ pub struct State {
     ethereum_network: EthereumNetwork,
     ecdsa_key_name: String,
     ecdsa_public_key: Option<EcdsaPublicKey>,
+    deposit_watcher: DepositWatcher,
 }
 
 impl Default for State {
@@ -37,6 +87,7 @@ impl Default for State {
             ethereum_network: EthereumNetwork::default(),
             ecdsa_key_name: "test_key_1".to_string(),
             ecdsa_public_key: None,
+            deposit_watcher: DepositWatcher::default(),
         }
     }
 }
@@ -53,6 +104,10 @@ impl State {
         self.ethereum_network
     }
 
+    pub fn deposit_watcher(&self) -> &DepositWatcher {
+        &self.deposit_watcher
+    }
+
     // Returns the RPC services to use for multi-provider calls.
     // Uses PublicNode by default (no API key required) so the example works
     // out of the box locally and without credentials.
@@ -90,6 +145,21 @@ impl From<InitArg> for State {
             ethereum_network: init_arg.ethereum_network.unwrap_or_default(),
             ecdsa_key_name: init_arg.ecdsa_key_name.unwrap_or_else(|| "test_key_1".to_string()),
             ecdsa_public_key: None,
+            deposit_watcher: DepositWatcher::new(
+                init_arg
+                    .deposit_min_confirmations
+                    .unwrap_or(DEFAULT_MIN_CONFIRMATIONS),
+                init_arg
+                    .deposit_erc20_tokens
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|token| {
+                        Address::from_str(token).unwrap_or_else(|e| {
+                            ic_cdk::trap(format!("invalid ERC-20 token address {}: {}", token, e))
+                        })
+                    })
+                    .collect(),
+            ),
         }
     }
 }
@@ -109,7 +179,7 @@ pub async fn lazy_call_ecdsa_public_key() -> EcdsaPublicKey {
     })
     .await
     .unwrap_or_else(|e| {
-        ic_cdk::trap(&format!(
+        ic_cdk::trap(format!(
             "failed to get ECDSA public key for key '{}': {:?}",
             key_name, e,
         ))
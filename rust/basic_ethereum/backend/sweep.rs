@@ -0,0 +1,200 @@
+//! Consolidation of the funds held by the addresses derived for IC principals into a single
+//! treasury address.
+//!
+//! A deposit address may hold ETH that arrived after the last poll of the deposit watcher and
+//! is not credited yet. Its sweep therefore only sends the credited balance, and is recorded
+//! with the watcher, which debits it from the baseline once the transaction is confirmed.
+
+use crate::deposits::{self, PendingSweep};
+use crate::ethereum_wallet::EthereumWallet;
+use crate::rpc::{estimate_fees, eth_balance, evm_rpc_client, transaction_count, Client};
+use crate::state::read_state;
+use crate::{estimate_transaction_fees, nat_to_u256, sign_and_send_transaction};
+use alloy_consensus::TxEip1559;
+use alloy_primitives::TxKind;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use evm_rpc_types::{BlockTag, MultiRpcResult, SendRawTransactionStatus};
+use ic_ethereum_types::Address;
+
+/// Each swept address requires several HTTPS outcalls and a threshold signature, so the
+/// number of addresses swept in a single call is bounded.
+pub const MAX_PRINCIPALS_PER_SWEEP: usize = 20;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SweepResult {
+    pub owner: Principal,
+    pub address: String,
+    pub outcome: SweepOutcome,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SweepOutcome {
+    /// `amount` was sent to the treasury, paying `fee` for the transaction. `balance` is the
+    /// whole balance of the address, or only the credited one for a deposit address.
+    /// `transaction_hash` is `None` in dry-run mode.
+    Swept {
+        balance: Nat,
+        fee: Nat,
+        amount: Nat,
+        transaction_hash: Option<String>,
+    },
+    /// The balance doesn't exceed the transaction fee, so there is nothing to sweep.
+    InsufficientBalance {
+        balance: Nat,
+        fee: Nat,
+    },
+    Failed(String),
+}
+
+/// Sweeps the address of every principal in `owners` into `treasury`, or only reports what
+/// would be swept if `dry_run` is set. A failure for one address doesn't affect the others.
+pub async fn sweep(owners: Vec<Principal>, treasury: Address, dry_run: bool) -> Vec<SweepResult> {
+    let client = evm_rpc_client();
+    let mut results = Vec::with_capacity(owners.len());
+    for owner in owners {
+        let wallet = EthereumWallet::new(owner).await;
+        let address = wallet.ethereum_address();
+        let outcome = if address == treasury {
+            SweepOutcome::Failed("the address is the treasury".to_string())
+        } else {
+            sweep_address(&client, &wallet, treasury, dry_run).await
+        };
+        results.push(SweepResult {
+            owner,
+            address: address.to_string(),
+            outcome,
+        });
+    }
+    results
+}
+
+async fn sweep_address(
+    client: &Client,
+    wallet: &EthereumWallet,
+    treasury: Address,
+    dry_run: bool,
+) -> SweepOutcome {
+    let address = wallet.ethereum_address();
+    let latest_nonce = match transaction_count(client, &address, BlockTag::Latest).await {
+        Ok(nonce) => nonce,
+        Err(e) => return SweepOutcome::Failed(format!("failed to get the nonce: {}", e)),
+    };
+    let (balance, nonce) = match deposits::credited_eth(&address) {
+        Some((_, Some(pending))) if latest_nonce > pending.nonce => {
+            return SweepOutcome::Failed(
+                "the previous sweep of the address is not confirmed yet".to_string(),
+            )
+        }
+        // The previous sweep was not mined. It is replaced by a transaction with the same
+        // nonce and debit, so that the recorded debit is right whichever of them is mined.
+        Some((_, Some(pending))) => (pending.debit, pending.nonce),
+        Some((credited, None)) => (credited, latest_nonce),
+        None => match eth_balance(client, &address, BlockTag::Latest).await {
+            Ok(balance) => (balance, latest_nonce),
+            Err(e) => return SweepOutcome::Failed(format!("failed to get the balance: {}", e)),
+        },
+    };
+    let (gas_limit, _, _) = estimate_transaction_fees();
+    let fees = match estimate_fees(client).await {
+        Ok(fees) => fees,
+        Err(e) => return SweepOutcome::Failed(format!("failed to estimate the fees: {}", e)),
+    };
+    let (amount, fee) = match split_balance(balance.clone(), gas_limit, fees.max_fee_per_gas) {
+        Ok(split) => split,
+        Err(outcome) => return outcome,
+    };
+    if dry_run {
+        return SweepOutcome::Swept {
+            balance,
+            fee,
+            amount,
+            transaction_hash: None,
+        };
+    }
+
+    // The whole max fee is paid as priority fee, so that the fee charged is exactly the
+    // deducted one and no dust is left in the address.
+    let transaction = TxEip1559 {
+        chain_id: read_state(|s| s.ethereum_network().chain_id()),
+        nonce,
+        gas_limit,
+        max_fee_per_gas: fees.max_fee_per_gas,
+        max_priority_fee_per_gas: fees.max_fee_per_gas,
+        to: TxKind::Call(treasury.into_bytes().into()),
+        value: nat_to_u256(amount.clone()),
+        access_list: Default::default(),
+        input: Default::default(),
+    };
+    // Recorded before sending, since the transaction may be sent even if sending reports an
+    // error. A sweep that is never mined is replaced by the next one.
+    if deposits::is_watched(&address) {
+        deposits::record_sweep(
+            &address,
+            PendingSweep {
+                nonce,
+                debit: balance.clone(),
+            },
+        );
+    }
+    match sign_and_send_transaction(wallet, transaction).await {
+        // A `NonceTooLow` may be reported even though the transaction was sent,
+        // see `sign_and_send_transaction`.
+        Ok((
+            transaction_hash,
+            MultiRpcResult::Consistent(Ok(
+                SendRawTransactionStatus::Ok(_) | SendRawTransactionStatus::NonceTooLow,
+            )),
+        )) => SweepOutcome::Swept {
+            balance,
+            fee,
+            amount,
+            transaction_hash: Some(transaction_hash),
+        },
+        Ok((transaction_hash, result)) => SweepOutcome::Failed(format!(
+            "failed to send transaction {}: {:?}",
+            transaction_hash, result
+        )),
+        Err(e) => SweepOutcome::Failed(e),
+    }
+}
+
+/// Splits `balance` into the amount to send and the transaction fee. A transfer to an EOA uses
+/// exactly `gas_limit` gas, so the treasury must be an EOA: a transfer to a contract requires
+/// more gas and would fail.
+fn split_balance(
+    balance: Nat,
+    gas_limit: u128,
+    gas_price: u128,
+) -> Result<(Nat, Nat), SweepOutcome> {
+    let fee = Nat::from(gas_limit) * Nat::from(gas_price);
+    if balance <= fee {
+        return Err(SweepOutcome::InsufficientBalance { balance, fee });
+    }
+    Ok((balance - fee.clone(), fee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_balance_minus_the_fee() {
+        assert_eq!(
+            split_balance(Nat::from(1_000_000_u32), 21_000, 10),
+            Ok((Nat::from(790_000_u32), Nat::from(210_000_u32)))
+        );
+    }
+
+    #[test]
+    fn does_not_sweep_balances_not_exceeding_the_fee() {
+        for balance in [0_u32, 209_999, 210_000] {
+            assert_eq!(
+                split_balance(Nat::from(balance), 21_000, 10),
+                Err(SweepOutcome::InsufficientBalance {
+                    balance: Nat::from(balance),
+                    fee: Nat::from(210_000_u32),
+                })
+            );
+        }
+    }
+}
@@ -46,3 +46,21 @@ result=$(icp canister call backend transaction_count_with_client "(opt \"$KNOWN_
 echo "$result"
 count=$(echo "$result" | grep -oE '[0-9][0-9_]*' | head -1 | tr -d '_')
 [ "$count" -ge 2 ] && echo "PASS" || (echo "FAIL: expected nonce >= 2 for $KNOWN_ADDRESS, got $count" && exit 1)
+
+echo "=== Test 6: deposit_address returns the caller's derived Ethereum address ==="
+result=$(icp canister call backend deposit_address '()')
+echo "$result"
+echo "$result" | grep -q "$my_address" && echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 7: get_deposits returns the (initially empty) list of credited deposits ==="
+result=$(icp canister call backend get_deposits '(null)')
+echo "$result"
+echo "$result" | grep -q "vec" && echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 8: sweep is restricted to controllers ==="
+icp identity new not-a-controller --storage plaintext 2>/dev/null || true
+if icp canister call backend sweep "(vec {}, \"$KNOWN_ADDRESS\", true)" --identity not-a-controller 2>&1 | grep -q "only controllers"; then
+  echo "PASS"
+else
+  echo "FAIL" && exit 1
+fi
@@ -0,0 +1,115 @@
+use candid::{CandidType, Deserialize};
+use evm_rpc_types::{Hex20, Hex32, LogEntry, Nat256};
+
+/// `keccak256("Transfer(address,address,uint256)")`, the first topic of every ERC-20 transfer log.
+pub const TRANSFER_EVENT_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// An ERC-20 `Transfer(from, to, value)` event decoded from a receipt log.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Erc20Transfer {
+    /// The token contract that emitted the event.
+    pub token: Hex20,
+    pub from: Hex20,
+    pub to: Hex20,
+    /// The amount transferred, in the token's smallest unit.
+    pub value: Nat256,
+    pub log_index: Option<Nat256>,
+}
+
+/// Decodes `log` as an ERC-20 `Transfer` event.
+///
+/// Returns `None` for any other event. ERC-721 uses the same event signature but
+/// indexes the token id as a fourth topic, so those logs are skipped as well.
+pub fn decode_transfer(log: &LogEntry) -> Option<Erc20Transfer> {
+    let [signature, from, to] = log.topics.as_slice() else {
+        return None;
+    };
+    if signature.to_string() != TRANSFER_EVENT_TOPIC {
+        return None;
+    }
+    let value: [u8; 32] = log.data.as_ref().try_into().ok()?;
+    Some(Erc20Transfer {
+        token: log.address.clone(),
+        from: address_from_topic(from),
+        to: address_from_topic(to),
+        value: Nat256::from_be_bytes(value),
+        log_index: log.log_index.clone(),
+    })
+}
+
+/// Indexed `address` arguments are left-padded to 32 bytes in the topic.
+pub fn address_from_topic(topic: &Hex32) -> Hex20 {
+    let bytes: [u8; 20] = topic.as_ref()[12..]
+        .try_into()
+        .expect("BUG: topics are 32 bytes long");
+    Hex20::from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evm_rpc_types::Hex;
+    use std::str::FromStr;
+
+    // A USDC transfer from Ethereum mainnet block 0x161bd70.
+    fn usdc_transfer_log() -> LogEntry {
+        LogEntry {
+            address: Hex20::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap(),
+            topics: vec![
+                Hex32::from_str(TRANSFER_EVENT_TOPIC).unwrap(),
+                Hex32::from_str(
+                    "0x000000000000000000000000000000000004444c5dc75cb358380d2e3de08a90",
+                )
+                .unwrap(),
+                Hex32::from_str(
+                    "0x0000000000000000000000000000000aa232009084bd71a5797d089aa4edfad4",
+                )
+                .unwrap(),
+            ],
+            data: Hex::from_str(
+                "0x00000000000000000000000000000000000000000000000000000000cd566ae8",
+            )
+            .unwrap(),
+            block_number: Some(0x161bd70_u64.into()),
+            transaction_hash: None,
+            transaction_index: Some(0_u64.into()),
+            block_hash: None,
+            log_index: Some(3_u64.into()),
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn decodes_erc20_transfer() {
+        let transfer = decode_transfer(&usdc_transfer_log()).unwrap();
+        assert_eq!(
+            transfer,
+            Erc20Transfer {
+                token: Hex20::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap(),
+                from: Hex20::from_str("0x000000000004444c5dc75cb358380d2e3de08a90").unwrap(),
+                to: Hex20::from_str("0x0000000aa232009084bd71a5797d089aa4edfad4").unwrap(),
+                value: Nat256::from(0xcd566ae8_u64),
+                log_index: Some(3_u64.into()),
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_other_events() {
+        let mut log = usdc_transfer_log();
+        // Approval(address,address,uint256)
+        log.topics[0] =
+            Hex32::from_str("0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925")
+                .unwrap();
+        assert_eq!(decode_transfer(&log), None);
+    }
+
+    #[test]
+    fn ignores_erc721_transfers() {
+        let mut log = usdc_transfer_log();
+        log.topics.push(Hex32::from([0; 32]));
+        log.data = Hex::from(vec![]);
+        assert_eq!(decode_transfer(&log), None);
+    }
+}
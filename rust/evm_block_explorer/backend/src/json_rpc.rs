@@ -0,0 +1,202 @@
+//! Support for RPC methods that the EVM RPC canister has no typed endpoint for,
+//! such as `eth_getTransactionByHash` and `eth_getBalance`. These are sent through
+//! `multi_request`, which returns each provider's raw JSON-RPC response body.
+
+use candid::{CandidType, Deserialize};
+use evm_rpc_types::{BlockTag, Hex, Hex20, Hex32, Nat256};
+use serde::Deserializer;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+/// A transaction as returned by `eth_getTransactionByHash`.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Transaction {
+    pub hash: Hex32,
+    #[serde(deserialize_with = "quantity")]
+    pub nonce: Nat256,
+    /// `None` while the transaction is pending.
+    #[serde(rename = "blockHash")]
+    pub block_hash: Option<Hex32>,
+    #[serde(
+        rename = "blockNumber",
+        default,
+        deserialize_with = "optional_quantity"
+    )]
+    pub block_number: Option<Nat256>,
+    #[serde(
+        rename = "transactionIndex",
+        default,
+        deserialize_with = "optional_quantity"
+    )]
+    pub transaction_index: Option<Nat256>,
+    pub from: Hex20,
+    /// `None` for contract creation transactions.
+    pub to: Option<Hex20>,
+    /// Value transferred, in wei.
+    #[serde(deserialize_with = "quantity")]
+    pub value: Nat256,
+    #[serde(deserialize_with = "quantity")]
+    pub gas: Nat256,
+    #[serde(rename = "gasPrice", default, deserialize_with = "optional_quantity")]
+    pub gas_price: Option<Nat256>,
+    #[serde(
+        rename = "maxFeePerGas",
+        default,
+        deserialize_with = "optional_quantity"
+    )]
+    pub max_fee_per_gas: Option<Nat256>,
+    #[serde(
+        rename = "maxPriorityFeePerGas",
+        default,
+        deserialize_with = "optional_quantity"
+    )]
+    pub max_priority_fee_per_gas: Option<Nat256>,
+    pub input: Hex,
+    #[serde(rename = "type", default, deserialize_with = "optional_quantity")]
+    pub tx_type: Option<Nat256>,
+    #[serde(rename = "chainId", default, deserialize_with = "optional_quantity")]
+    pub chain_id: Option<Nat256>,
+}
+
+pub fn get_transaction_by_hash(hash: &Hex32) -> Value {
+    request("eth_getTransactionByHash", json!([hash.to_string()]))
+}
+
+pub fn get_balance(address: &Hex20, block: &BlockTag) -> Value {
+    request(
+        "eth_getBalance",
+        json!([address.to_string(), block_tag_param(block)]),
+    )
+}
+
+fn request(method: &str, params: Value) -> Value {
+    // The `id` is overwritten by the EVM RPC canister.
+    json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct Response<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+/// Extracts the `result` of a raw JSON-RPC response. A `null` result
+/// (e.g. an unknown transaction hash) is returned as `None`.
+pub fn parse_response<T: DeserializeOwned>(body: &str) -> Result<Option<T>, String> {
+    let response: Response<T> =
+        serde_json::from_str(body).map_err(|e| format!("Invalid JSON-RPC response: {e}"))?;
+    match response.error {
+        Some(error) => Err(format!("JSON-RPC error: {error}")),
+        None => Ok(response.result),
+    }
+}
+
+/// Parses a hex-encoded JSON-RPC quantity such as `"0x1bc16d674ec80000"`.
+pub fn parse_quantity(value: &str) -> Result<Nat256, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("Quantity {value} doesn't start with 0x"))?;
+    if digits.is_empty() || digits.len() > 64 {
+        return Err(format!("Invalid quantity {value}"));
+    }
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(format!("{digits:0>64}"), &mut bytes)
+        .map_err(|e| format!("Invalid quantity {value}: {e}"))?;
+    Ok(Nat256::from_be_bytes(bytes))
+}
+
+fn block_tag_param(block: &BlockTag) -> String {
+    match block {
+        BlockTag::Latest => "latest".to_string(),
+        BlockTag::Finalized => "finalized".to_string(),
+        BlockTag::Safe => "safe".to_string(),
+        BlockTag::Earliest => "earliest".to_string(),
+        BlockTag::Pending => "pending".to_string(),
+        BlockTag::Number(number) => format!("0x{}", number.as_ref().0.to_str_radix(16)),
+    }
+}
+
+fn quantity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Nat256, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_quantity(&value).map_err(serde::de::Error::custom)
+}
+
+fn optional_quantity<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Nat256>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_quantity(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parses_quantities() {
+        assert_eq!(parse_quantity("0x0"), Ok(Nat256::ZERO));
+        assert_eq!(
+            parse_quantity("0x1bc16d674ec80000"),
+            Ok(Nat256::from(2_000_000_000_000_000_000_u128))
+        );
+        assert!(parse_quantity("1234").is_err());
+        assert!(parse_quantity("0x").is_err());
+        assert!(parse_quantity("0xzz").is_err());
+        assert!(parse_quantity(&format!("0x1{}", "0".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn formats_block_tags() {
+        assert_eq!(block_tag_param(&BlockTag::Latest), "latest");
+        assert_eq!(
+            block_tag_param(&BlockTag::Number(Nat256::from(46_147_u64))),
+            "0xb443"
+        );
+    }
+
+    #[test]
+    fn parses_transaction_response() {
+        // The first ever Ethereum transaction, in block 46147.
+        let body = r#"{"jsonrpc":"2.0","id":7,"result":{
+            "blockHash":"0x4e3a3754410177e6937ef1f84bba68ea139e8d1a2258c5f85db9f1cd715a1bdd",
+            "blockNumber":"0xb443",
+            "from":"0xa1e4380a3b1f749673e270229993ee55f35663b4",
+            "gas":"0x5208",
+            "gasPrice":"0x2d79883d2000",
+            "hash":"0x5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060",
+            "input":"0x",
+            "nonce":"0x0",
+            "to":"0x5df9b87991262f6ba471f09758cde1c0fc1de734",
+            "transactionIndex":"0x0",
+            "value":"0x7a69",
+            "type":"0x0"
+        }}"#;
+        let tx: Transaction = parse_response(body).unwrap().unwrap();
+        assert_eq!(tx.block_number, Some(Nat256::from(46_147_u64)));
+        assert_eq!(
+            tx.from,
+            Hex20::from_str("0xa1e4380a3b1f749673e270229993ee55f35663b4").unwrap()
+        );
+        assert_eq!(tx.value, Nat256::from(31_337_u64));
+        assert_eq!(tx.gas_price, Some(Nat256::from(50_000_000_000_000_u64)));
+        assert_eq!(tx.max_fee_per_gas, None);
+        assert_eq!(tx.input, Hex::from(vec![]));
+    }
+
+    #[test]
+    fn parses_null_and_error_responses() {
+        let not_found = r#"{"jsonrpc":"2.0","id":7,"result":null}"#;
+        assert_eq!(parse_response::<Transaction>(not_found), Ok(None));
+
+        let error =
+            r#"{"jsonrpc":"2.0","id":7,"error":{"code":-32602,"message":"invalid argument"}}"#;
+        assert!(parse_response::<String>(error).is_err());
+    }
+}
@@ -1,5 +1,23 @@
-use candid::Principal;
-use evm_rpc_types::{Block, BlockTag, EthMainnetService, MultiRpcResult, Nat256, RpcServices};
+mod erc20;
+mod json_rpc;
+
+use candid::{CandidType, Deserialize, Principal};
+use erc20::Erc20Transfer;
+use evm_rpc_client::{CandidResponseConverter, EvmRpcClient, NoRetry};
+use evm_rpc_types::{
+    Block, BlockTag, EthMainnetService, Hex20, Hex32, MultiRpcResult, Nat256, RpcServices,
+    TransactionReceipt,
+};
+use ic_canister_runtime::IcRuntime;
+use json_rpc::Transaction;
+use std::str::FromStr;
+
+/// A transaction receipt together with the ERC-20 transfers decoded from its logs.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReceiptWithTransfers {
+    pub receipt: TransactionReceipt,
+    pub erc20_transfers: Vec<Erc20Transfer>,
+}
 
 /// Resolve the EVM RPC canister ID at runtime. icp-cli sets the
 /// `PUBLIC_CANISTER_ID:evm_rpc` env var for local deployments; on production
@@ -9,8 +27,7 @@ fn evm_rpc_id() -> Principal {
     Principal::from_text(&id).expect("Invalid PUBLIC_CANISTER_ID:evm_rpc")
 }
 
-#[ic_cdk::update]
-async fn get_evm_block(height: u128) -> Result<Block, String> {
+fn evm_rpc_client() -> EvmRpcClient<IcRuntime, CandidResponseConverter, NoRetry> {
     // Uses PublicNode by default — no API key required, works locally and on mainnet.
     // For production deployments requiring premium providers (Alchemy, Ankr, BlockPi),
     // configure API keys via the EVM RPC canister, then pass None to use all configured
@@ -27,24 +44,77 @@ async fn get_evm_block(height: u128) -> Result<Block, String> {
     //     }],
     // };
 
-    let client = evm_rpc_client::EvmRpcClient::builder(
-        ic_canister_runtime::IcRuntime::new(),
-        evm_rpc_id(),
-    )
-    .with_rpc_sources(rpc_services)
-    .build();
+    EvmRpcClient::builder(IcRuntime::new(), evm_rpc_id())
+        .with_rpc_sources(rpc_services)
+        .build()
+}
+
+/// Collapse the per-provider results into a single one, failing if the providers disagree.
+fn consistent<T: std::fmt::Debug>(result: MultiRpcResult<T>) -> Result<T, String> {
+    match result {
+        MultiRpcResult::Consistent(Ok(value)) => Ok(value),
+        MultiRpcResult::Consistent(Err(err)) => Err(format!("{err:?}")),
+        MultiRpcResult::Inconsistent(v) => Err(format!("RPC providers gave inconsistent results: {v:?}")),
+    }
+}
 
+#[ic_cdk::update]
+async fn get_evm_block(height: u128) -> Result<Block, String> {
     // Call `eth_getBlockByNumber` RPC method (unused cycles will be refunded)
-    let result: MultiRpcResult<Block> = client
+    let result: MultiRpcResult<Block> = evm_rpc_client()
         .get_block_by_number(BlockTag::Number(Nat256::from(height)))
         .send()
         .await;
 
-    match result {
-        MultiRpcResult::Consistent(Ok(block)) => Ok(block),
-        MultiRpcResult::Consistent(Err(err)) => Err(format!("{err:?}")),
-        MultiRpcResult::Inconsistent(v) => Err(format!("RPC providers gave inconsistent results: {v:?}")),
-    }
+    consistent(result)
+}
+
+/// Returns the transaction with the given hash, or `None` if no provider knows it.
+#[ic_cdk::update]
+async fn get_transaction(hash: String) -> Result<Option<Transaction>, String> {
+    let hash = Hex32::from_str(&hash)?;
+
+    // The EVM RPC canister has no typed endpoint for `eth_getTransactionByHash`,
+    // so the request goes through `multi_request`. Providers are still compared on
+    // their raw response, hence the same consensus handling as for typed calls.
+    let result: MultiRpcResult<String> = evm_rpc_client()
+        .multi_request(json_rpc::get_transaction_by_hash(&hash))
+        .send()
+        .await;
+
+    json_rpc::parse_response(&consistent(result)?)
+}
+
+/// Returns the receipt of a mined transaction, with ERC-20 `Transfer` events decoded.
+#[ic_cdk::update]
+async fn get_transaction_receipt(hash: String) -> Result<Option<ReceiptWithTransfers>, String> {
+    let hash = Hex32::from_str(&hash)?;
+
+    // Call `eth_getTransactionReceipt` RPC method (unused cycles will be refunded)
+    let result: MultiRpcResult<Option<TransactionReceipt>> = evm_rpc_client()
+        .get_transaction_receipt(hash)
+        .send()
+        .await;
+
+    Ok(consistent(result)?.map(|receipt| ReceiptWithTransfers {
+        erc20_transfers: receipt.logs.iter().filter_map(erc20::decode_transfer).collect(),
+        receipt,
+    }))
+}
+
+/// Returns the ETH balance of `address` in wei at the given block.
+#[ic_cdk::update]
+async fn get_balance(address: String, block: BlockTag) -> Result<Nat256, String> {
+    let address = Hex20::from_str(&address)?;
+
+    let result: MultiRpcResult<String> = evm_rpc_client()
+        .multi_request(json_rpc::get_balance(&address, &block))
+        .send()
+        .await;
+
+    let balance: String = json_rpc::parse_response(&consistent(result)?)?
+        .ok_or("eth_getBalance returned no result")?;
+    json_rpc::parse_quantity(&balance)
 }
 
 ic_cdk::export_candid!();
@@ -0,0 +1,90 @@
+import React, { useState } from 'react';
+import { backend } from './actor';
+import { JsonView, allExpanded, defaultStyles } from 'react-json-view-lite';
+import 'react-json-view-lite/dist/index.css';
+
+function Transaction() {
+  const [loading, setLoading] = useState(false);
+  const [details, setDetails] = useState();
+  const [error, setError] = useState();
+  const [hash, setHash] = useState('');
+
+  const isValid = /^0x[0-9a-fA-F]{64}$/.test(hash);
+
+  const fetchTransaction = async () => {
+    try {
+      setLoading(true);
+      setError(undefined);
+      setDetails(undefined);
+      const [transaction, receipt] = await Promise.all([
+        backend.get_transaction(hash),
+        backend.get_transaction_receipt(hash)
+      ]);
+      if ('Err' in transaction) {
+        setError(transaction.Err);
+      } else if ('Err' in receipt) {
+        setError(receipt.Err);
+      } else if (transaction.Ok.length === 0) {
+        setError('Transaction not found');
+      } else {
+        // Candid `opt` values are decoded as empty or one-element arrays.
+        const decoded = receipt.Ok[0];
+        setDetails({
+          transaction: transaction.Ok[0],
+          erc20Transfers: decoded?.erc20_transfers ?? [],
+          receipt: decoded?.receipt ?? 'pending'
+        });
+      }
+    } catch (err) {
+      console.error(err);
+      setError(String(err));
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  return (
+    <div>
+      <h2 className="mb-6 text-center text-xl font-bold text-gray-800">Transaction Details</h2>
+
+      <div className="space-y-4">
+        <div className="flex items-center space-x-4">
+          <input
+            type="text"
+            value={hash}
+            onChange={(e) => setHash(e.target.value.trim())}
+            placeholder="Enter transaction hash (0x...)"
+            className="w-full rounded-lg border border-gray-300 p-3 text-sm focus:border-blue-500 focus:ring-blue-500 md:w-1/2"
+          />
+          <button
+            onClick={fetchTransaction}
+            disabled={loading || !isValid}
+            className={`w-full rounded-lg px-6 py-3 text-sm font-medium md:w-auto ${loading || !isValid ? 'cursor-not-allowed bg-gray-300 text-gray-700' : 'bg-blue-500 text-white hover:bg-blue-600'}`}
+          >
+            {loading ? 'Loading...' : 'Fetch transaction'}
+          </button>
+        </div>
+
+        {error && <p className="text-sm text-red-500">{error}</p>}
+
+        {!!details && (
+          <div className="mt-4 rounded-lg border border-gray-200 bg-white p-4 shadow-sm">
+            <JsonView
+              data={details}
+              shouldExpandNode={allExpanded}
+              style={{
+                ...defaultStyles,
+                container: 'bg-gray-50 overflow-x-auto p-4 rounded-lg',
+                label: 'font-semibold text-sm',
+                value: 'text-xs text-gray-700',
+                item: 'mb-2'
+              }}
+            />
+          </div>
+        )}
+      </div>
+    </div>
+  );
+}
+
+export default Transaction;
@@ -2,6 +2,7 @@ import React from 'react';
 import ReactDOM from 'react-dom/client';
 import '../index.css';
 import Block from './Block';
+import Transaction from './Transaction';
 
 ReactDOM.createRoot(document.getElementById('root')).render(
   <React.StrictMode>
@@ -9,6 +10,9 @@ ReactDOM.createRoot(document.getElementById('root')).render(
       <div className="space-y-6 rounded-lg bg-gray-50 p-6">
         <Block />
       </div>
+      <div className="mt-6 space-y-6 rounded-lg bg-gray-50 p-6">
+        <Transaction />
+      </div>
     </div>
   </React.StrictMode>
 );
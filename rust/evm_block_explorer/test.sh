@@ -7,3 +7,21 @@ echo "$result"
 echo "$result" | grep -q "Ok" && echo "PASS (Ok variant)" || (echo "FAIL (expected Ok)" && exit 1)
 echo "$result" | grep -q "0x88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6" && echo "PASS (hash)" || (echo "FAIL (wrong hash)" && exit 1)
 echo "$result" | grep -q "0x05a56e2d52c817161883f50c441c3228cfe54d9f" && echo "PASS (miner)" || (echo "FAIL (wrong miner)" && exit 1)
+
+echo "=== Test 2: get_transaction returns the first Ethereum mainnet transaction ==="
+result=$(icp canister call backend get_transaction '("0x5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060")')
+echo "$result"
+echo "$result" | grep -q "Ok" && echo "PASS (Ok variant)" || (echo "FAIL (expected Ok)" && exit 1)
+echo "$result" | grep -q "0xa1e4380a3b1f749673e270229993ee55f35663b4" && echo "PASS (from)" || (echo "FAIL (wrong sender)" && exit 1)
+echo "$result" | grep -q "31_337" && echo "PASS (value)" || (echo "FAIL (wrong value)" && exit 1)
+
+echo "=== Test 3: get_transaction_receipt decodes ERC-20 transfers ==="
+result=$(icp canister call backend get_transaction_receipt '("0xfe5bc88d0818b66a67b0619b1b4d81bfe38029e3799c7f0eb86b33ca7dc4c811")')
+echo "$result"
+echo "$result" | grep -q "Ok" && echo "PASS (Ok variant)" || (echo "FAIL (expected Ok)" && exit 1)
+echo "$result" | grep -q "erc20_transfers = vec { record" && echo "PASS (transfers)" || (echo "FAIL (no decoded transfers)" && exit 1)
+
+echo "=== Test 4: get_balance returns the latest balance of an address ==="
+result=$(icp canister call backend get_balance '("0x5df9b87991262f6ba471f09758cde1c0fc1de734", variant { Latest })')
+echo "$result"
+echo "$result" | grep -q "Ok" && echo "PASS (Ok variant)" || (echo "FAIL (expected Ok)" && exit 1)
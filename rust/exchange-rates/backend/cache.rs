@@ -0,0 +1,201 @@
+use ic_xrc_types::{Asset, AssetClass, ExchangeRate, GetExchangeRateRequest};
+use std::collections::BTreeMap;
+
+// How long a fetched rate is served from the cache before the XRC is queried again.
+const CACHE_TTL_SECONDS: u64 = 60;
+
+// Bounds the heap used by the cache. Expired entries are evicted first, then the oldest ones.
+const MAX_CACHE_ENTRIES: usize = 1_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AssetKey {
+    symbol: String,
+    is_crypto: bool,
+}
+
+impl From<&Asset> for AssetKey {
+    fn from(asset: &Asset) -> Self {
+        AssetKey {
+            symbol: asset.symbol.clone(),
+            is_crypto: matches!(asset.class, AssetClass::Cryptocurrency),
+        }
+    }
+}
+
+/// Rates are cached per asset pair and minute, which is the granularity of the XRC.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CacheKey {
+    base: AssetKey,
+    quote: AssetKey,
+    minute: u64,
+}
+
+impl CacheKey {
+    /// Requests without a timestamp ask for the current rate, so they are keyed by `now`.
+    pub fn new(request: &GetExchangeRateRequest, now_secs: u64) -> Self {
+        let timestamp = request.timestamp.unwrap_or(now_secs);
+        CacheKey {
+            base: AssetKey::from(&request.base_asset),
+            quote: AssetKey::from(&request.quote_asset),
+            minute: timestamp - timestamp % 60,
+        }
+    }
+}
+
+/// Groups `requests` by their cache key, so that identical pairs in a batch are only fetched once. Returns the key
+/// of each request, in order, and one request per key.
+pub fn group_by_key(
+    requests: Vec<GetExchangeRateRequest>,
+    now_secs: u64,
+) -> (Vec<CacheKey>, BTreeMap<CacheKey, GetExchangeRateRequest>) {
+    let mut unique_requests = BTreeMap::new();
+    let keys = requests
+        .into_iter()
+        .map(|request| {
+            let key = CacheKey::new(&request, now_secs);
+            unique_requests.entry(key.clone()).or_insert(request);
+            key
+        })
+        .collect();
+    (keys, unique_requests)
+}
+
+struct CacheEntry {
+    rate: ExchangeRate,
+    inserted_at: u64,
+}
+
+#[derive(Default)]
+pub struct RateCache {
+    entries: BTreeMap<CacheKey, CacheEntry>,
+}
+
+impl RateCache {
+    pub fn get(&self, key: &CacheKey, now_secs: u64) -> Option<ExchangeRate> {
+        self.entries
+            .get(key)
+            .filter(|entry| now_secs < entry.inserted_at + CACHE_TTL_SECONDS)
+            .map(|entry| entry.rate.clone())
+    }
+
+    pub fn insert(&mut self, key: CacheKey, rate: ExchangeRate, now_secs: u64) {
+        self.entries
+            .retain(|_, entry| now_secs < entry.inserted_at + CACHE_TTL_SECONDS);
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                rate,
+                inserted_at: now_secs,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_xrc_types::ExchangeRateMetadata;
+
+    fn asset(symbol: &str, class: AssetClass) -> Asset {
+        Asset {
+            symbol: symbol.to_string(),
+            class,
+        }
+    }
+
+    fn request(timestamp: Option<u64>) -> GetExchangeRateRequest {
+        GetExchangeRateRequest {
+            base_asset: asset("ICP", AssetClass::Cryptocurrency),
+            quote_asset: asset("USD", AssetClass::FiatCurrency),
+            timestamp,
+        }
+    }
+
+    fn rate(rate: u64) -> ExchangeRate {
+        ExchangeRate {
+            base_asset: asset("ICP", AssetClass::Cryptocurrency),
+            quote_asset: asset("USD", AssetClass::FiatCurrency),
+            timestamp: 0,
+            rate,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 0,
+                base_asset_num_received_rates: 0,
+                quote_asset_num_queried_sources: 0,
+                quote_asset_num_received_rates: 0,
+                standard_deviation: 0,
+                forex_timestamp: None,
+            },
+        }
+    }
+
+    #[test]
+    fn keys_are_truncated_to_the_minute() {
+        assert_eq!(
+            CacheKey::new(&request(Some(1_700_000_040)), 0),
+            CacheKey::new(&request(Some(1_700_000_099)), 0)
+        );
+        assert_ne!(
+            CacheKey::new(&request(Some(1_700_000_040)), 0),
+            CacheKey::new(&request(Some(1_700_000_100)), 0)
+        );
+        assert_eq!(
+            CacheKey::new(&request(None), 1_700_000_085),
+            CacheKey::new(&request(Some(1_700_000_040)), 0)
+        );
+    }
+
+    #[test]
+    fn identical_pairs_are_grouped() {
+        let (keys, unique_requests) = group_by_key(
+            vec![
+                request(Some(1_700_000_040)),
+                request(None),
+                request(Some(1_700_000_099)),
+                request(Some(1_700_000_100)),
+            ],
+            1_700_000_085,
+        );
+
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[0], keys[1]);
+        assert_eq!(keys[0], keys[2]);
+        assert_ne!(keys[0], keys[3]);
+        assert_eq!(unique_requests.len(), 2);
+        assert_eq!(unique_requests[&keys[0]].timestamp, Some(1_700_000_040));
+    }
+
+    #[test]
+    fn entries_expire_after_ttl() {
+        let mut cache = RateCache::default();
+        let key = CacheKey::new(&request(Some(1_700_000_040)), 0);
+        cache.insert(key.clone(), rate(42), 100);
+
+        assert_eq!(cache.get(&key, 100 + CACHE_TTL_SECONDS - 1), Some(rate(42)));
+        assert_eq!(cache.get(&key, 100 + CACHE_TTL_SECONDS), None);
+    }
+
+    #[test]
+    fn evicts_oldest_entry_when_full() {
+        let mut cache = RateCache::default();
+        for i in 0..MAX_CACHE_ENTRIES as u64 {
+            cache.insert(CacheKey::new(&request(Some(i * 60)), 0), rate(i), i / 100);
+        }
+        let newest = CacheKey::new(&request(Some(u64::MAX / 2)), 0);
+        cache.insert(newest.clone(), rate(7), 10);
+
+        assert_eq!(cache.entries.len(), MAX_CACHE_ENTRIES);
+        assert_eq!(cache.get(&CacheKey::new(&request(Some(0)), 0), 10), None);
+        assert_eq!(cache.get(&newest, 10), Some(rate(7)));
+    }
+}
@@ -1,10 +1,35 @@
-use candid::Principal;
+mod cache;
+mod price_feed;
+
+use cache::{CacheKey, RateCache};
+use candid::{CandidType, Principal};
 use ic_cdk::call::{Call, Response};
 use ic_xrc_types::{Asset, ExchangeRate, ExchangeRateError, GetExchangeRateRequest};
+use price_feed::{PriceFeed, PriceFeedSettings, PricePair, PriceSample, PriceStats, Subscription};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 // 1B cycles per request as required by the XRC canister.
 const CYCLES_PER_REQUEST: u128 = 1_000_000_000;
 
+// Every cache miss in a batch costs CYCLES_PER_REQUEST, so batches are kept small.
+const MAX_BATCH_SIZE: usize = 10;
+
+thread_local! {
+    // Rates already paid for, so that repeated queries for the same pair and minute are free.
+    static CACHE: RefCell<RateCache> = RefCell::new(RateCache::default());
+}
+
+#[derive(CandidType, Clone, Debug)]
+enum GetExchangeRateError {
+    /// The XRC canister could not provide the rate.
+    Xrc(ExchangeRateError),
+    /// The call to the XRC canister failed or its response could not be decoded.
+    CallFailed(String),
+    /// More pairs were requested at once than `get_exchange_rates` accepts.
+    TooManyRequests { max_batch_size: u64 },
+}
+
 // The XRC canister ID is injected as PUBLIC_CANISTER_ID:xrc at deploy time:
 //   local:  auto-injected by icp-cli after deploying the pre-built xrc_mock canister
 //   ic:     set in icp.yaml to uf6dk-hyaaa-aaaaq-qaaaq-cai (production XRC on mainnet)
@@ -15,24 +40,83 @@ fn xrc_principal() -> Principal {
     Principal::from_text(&id).expect("invalid PUBLIC_CANISTER_ID:xrc")
 }
 
+#[ic_cdk::init]
+fn init(price_feed_settings: Option<PriceFeedSettings>) {
+    price_feed::init(price_feed_settings);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    price_feed::start_sampling();
+}
+
+fn now_secs() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+/// Returns the rate of `base` in `quote` together with its metadata. The rate is a
+/// fixed-point number with `metadata.decimals` decimal places.
+///
+/// `timestamp` (in seconds since the UNIX epoch) selects a historical rate; if omitted,
+/// the current rate is returned.
 #[ic_cdk::update]
-async fn get_exchange_rate(base: Asset, quote: Asset) -> u128 {
+async fn get_exchange_rate(
+    base: Asset,
+    quote: Asset,
+    timestamp: Option<u64>,
+) -> Result<ExchangeRate, GetExchangeRateError> {
+    fetch_rate(GetExchangeRateRequest {
+        base_asset: base,
+        quote_asset: quote,
+        timestamp,
+    })
+    .await
+}
+
+/// Returns the rates for several pairs at once, in the order of `requests`.
+/// Rates missing from the cache are fetched from the XRC concurrently, and
+/// identical pairs are fetched only once.
+#[ic_cdk::update]
+async fn get_exchange_rates(
+    requests: Vec<GetExchangeRateRequest>,
+) -> Result<Vec<Result<ExchangeRate, GetExchangeRateError>>, GetExchangeRateError> {
+    if requests.len() > MAX_BATCH_SIZE {
+        return Err(GetExchangeRateError::TooManyRequests {
+            max_batch_size: MAX_BATCH_SIZE as u64,
+        });
+    }
+    let (keys, unique_requests) = cache::group_by_key(requests, now_secs());
+    let (unique_keys, unique_requests): (Vec<CacheKey>, Vec<GetExchangeRateRequest>) =
+        unique_requests.into_iter().unzip();
+    let rates: BTreeMap<CacheKey, Result<ExchangeRate, GetExchangeRateError>> = unique_keys
+        .into_iter()
+        .zip(futures::future::join_all(unique_requests.into_iter().map(fetch_rate)).await)
+        .collect();
+    Ok(keys.iter().map(|key| rates[key].clone()).collect())
+}
+
+async fn fetch_rate(request: GetExchangeRateRequest) -> Result<ExchangeRate, GetExchangeRateError> {
+    let key = CacheKey::new(&request, now_secs());
+    if let Some(rate) = CACHE.with_borrow(|cache| cache.get(&key, now_secs())) {
+        return Ok(rate);
+    }
+
     let response: Response = Call::bounded_wait(xrc_principal(), "get_exchange_rate")
         .with_cycles(CYCLES_PER_REQUEST)
-        .with_arg(&GetExchangeRateRequest {
-            base_asset: base,
-            quote_asset: quote,
-            timestamp: None,
-        })
+        .with_arg(&request)
         .await
-        .expect("Call to XRC canister failed.");
+        .map_err(|e| GetExchangeRateError::CallFailed(e.to_string()))?;
 
-    let exchange_rate_result: Result<ExchangeRate, ExchangeRateError> =
-        response.candid().expect("Decoding result failed.");
+    let exchange_rate_result: Result<ExchangeRate, ExchangeRateError> = response
+        .candid()
+        .map_err(|e| GetExchangeRateError::CallFailed(e.to_string()))?;
 
     ic_cdk::println!("result: {:?}", exchange_rate_result);
 
-    exchange_rate_result.unwrap().rate as u128
+    // Errors are not cached: some of them, like `Pending` or `RateLimited`, are transient.
+    let rate = exchange_rate_result.map_err(GetExchangeRateError::Xrc)?;
+    CACHE.with_borrow_mut(|cache| cache.insert(key, rate.clone(), now_secs()));
+    Ok(rate)
 }
 
 ic_cdk::export_candid!();
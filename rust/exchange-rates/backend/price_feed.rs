@@ -0,0 +1,554 @@
+//! A price oracle built on top of the XRC.
+//!
+//! A timer periodically samples every configured asset pair, keeping a rolling window of
+//! samples in stable memory. From this window the canister derives the time-weighted average
+//! price (TWAP) and the min/max price, and notifies subscriber canisters whenever the latest
+//! price deviates from the TWAP by more than their configured threshold.
+
+use crate::{fetch_rate, now_secs};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::call::Call;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use ic_xrc_types::{Asset, GetExchangeRateRequest};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// All prices are stored and returned with this many decimal places, the XRC's default.
+const PRICE_DECIMALS: u32 = 9;
+
+/// The XRC has a granularity of one minute, so sampling more often is pointless.
+const MIN_SAMPLING_INTERVAL_SECONDS: u64 = 60;
+
+/// The method called on subscriber canisters, with a `PriceDeviationAlert` as argument.
+const ALERT_METHOD: &str = "on_price_deviation";
+
+/// Every subscription may cost a call on each sample, so their number is bounded.
+const MAX_SUBSCRIPTIONS_PER_PRINCIPAL: usize = 10;
+const MAX_SUBSCRIPTIONS: u64 = 1_000;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const FEEDS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const SAMPLES_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SUBSCRIPTIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static SETTINGS: RefCell<StableCell<PriceFeedSettings, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SETTINGS_MEMORY_ID)),
+            PriceFeedSettings::default(),
+        )
+    );
+
+    static FEEDS: RefCell<StableBTreeMap<u32, PricePair, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(FEEDS_MEMORY_ID)))
+    );
+
+    // (feed id, XRC timestamp in seconds) -> price with PRICE_DECIMALS decimal places.
+    static SAMPLES: RefCell<StableBTreeMap<(u32, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(SAMPLES_MEMORY_ID)))
+    );
+
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, Subscription, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(SUBSCRIPTIONS_MEMORY_ID)))
+    );
+
+    // Timers do not survive upgrades, so this is re-created in `post_upgrade`.
+    static SAMPLING_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceFeedSettings {
+    pub sampling_interval_seconds: u64,
+    /// Samples older than this are dropped and no longer count towards the statistics.
+    pub window_seconds: u64,
+}
+
+impl Default for PriceFeedSettings {
+    fn default() -> Self {
+        // Every sample costs 1B cycles per pair, so the default is deliberately conservative.
+        PriceFeedSettings {
+            sampling_interval_seconds: 300,
+            window_seconds: 3_600,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PricePair {
+    pub base: Asset,
+    pub quote: Asset,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PriceFeed {
+    pub id: u32,
+    pub pair: PricePair,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceSample {
+    pub timestamp: u64,
+    pub price: u64,
+}
+
+/// Statistics over the samples in the current window. Prices have `decimals` decimal places.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceStats {
+    pub latest: u64,
+    pub latest_timestamp: u64,
+    pub twap: u64,
+    pub min: u64,
+    pub max: u64,
+    pub num_samples: u64,
+    pub decimals: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    pub subscriber: Principal,
+    pub feed_id: u32,
+    /// Alert when the latest price is more than this many basis points (1% = 100) away from the TWAP.
+    pub deviation_threshold_bps: u32,
+    /// Whether the last sample was already past the threshold. Subscribers are alerted
+    /// once when the price crosses the threshold, not on every sample while it stays there.
+    pub alerting: bool,
+}
+
+/// The argument of `on_price_deviation` calls to subscriber canisters.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PriceDeviationAlert {
+    pub subscription_id: u64,
+    pub feed_id: u32,
+    pub pair: PricePair,
+    pub price: u64,
+    pub twap: u64,
+    pub deviation_bps: u64,
+    pub decimals: u32,
+    pub timestamp: u64,
+}
+
+// Uses Candid for serialization - this is not efficient, but simple.
+macro_rules! impl_candid_storable {
+    ($t:ty) => {
+        impl Storable for $t {
+            const BOUND: Bound = Bound::Unbounded;
+
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).expect("failed to serialize"))
+            }
+
+            fn into_bytes(self) -> Vec<u8> {
+                Encode!(&self).expect("failed to serialize")
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                Decode!(bytes.as_ref(), $t).expect("failed to deserialize")
+            }
+        }
+    };
+}
+
+impl_candid_storable!(PriceFeedSettings);
+impl_candid_storable!(PricePair);
+impl_candid_storable!(Subscription);
+
+fn ensure_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        Ok(())
+    } else {
+        Err("only controllers can manage price feeds".to_string())
+    }
+}
+
+fn validate_settings(settings: &PriceFeedSettings) -> Result<(), String> {
+    if settings.sampling_interval_seconds < MIN_SAMPLING_INTERVAL_SECONDS {
+        return Err(format!(
+            "sampling interval must be at least {MIN_SAMPLING_INTERVAL_SECONDS} seconds"
+        ));
+    }
+    if settings.window_seconds < settings.sampling_interval_seconds {
+        return Err("window must be at least as long as the sampling interval".to_string());
+    }
+    Ok(())
+}
+
+/// Applies the init arguments of the canister.
+pub fn init(settings: Option<PriceFeedSettings>) {
+    if let Some(settings) = settings {
+        validate_settings(&settings).expect("invalid price feed settings");
+        SETTINGS.with_borrow_mut(|cell| cell.set(settings));
+    }
+    start_sampling();
+}
+
+/// (Re)starts the sampling timer with the current settings.
+pub fn start_sampling() {
+    let interval = SETTINGS.with_borrow(|cell| cell.get().sampling_interval_seconds);
+    let timer_id =
+        ic_cdk_timers::set_timer_interval_serial(Duration::from_secs(interval), async || {
+            sample_price_feeds().await
+        });
+    if let Some(previous) = SAMPLING_TIMER.replace(Some(timer_id)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn same_asset(a: &Asset, b: &Asset) -> bool {
+    a.symbol == b.symbol && a.class == b.class
+}
+
+fn samples_of(feed_id: u32) -> Vec<PriceSample> {
+    SAMPLES.with_borrow(|samples| {
+        samples
+            .range((feed_id, 0)..=(feed_id, u64::MAX))
+            .map(|entry| PriceSample {
+                timestamp: entry.key().1,
+                price: entry.value(),
+            })
+            .collect()
+    })
+}
+
+/// Rescales an XRC rate with `decimals` decimal places to `PRICE_DECIMALS`.
+fn normalize_rate(rate: u64, decimals: u32) -> Option<u64> {
+    if decimals <= PRICE_DECIMALS {
+        rate.checked_mul(10_u64.checked_pow(PRICE_DECIMALS - decimals)?)
+    } else {
+        Some(rate / 10_u64.checked_pow(decimals - PRICE_DECIMALS)?)
+    }
+}
+
+/// Computes the statistics of `samples`, which must be sorted by timestamp.
+///
+/// Each sample's price is weighted by how long it was the latest one, i.e. until the next
+/// sample or, for the last sample, until `now`.
+fn compute_stats(samples: &[PriceSample], now: u64) -> Option<PriceStats> {
+    let last = samples.last()?;
+    let mut weighted_sum: u128 = 0;
+    let mut total_duration: u128 = 0;
+    for (i, sample) in samples.iter().enumerate() {
+        let end = samples
+            .get(i + 1)
+            .map_or(now.max(sample.timestamp), |next| next.timestamp);
+        let duration = (end - sample.timestamp) as u128;
+        weighted_sum += sample.price as u128 * duration;
+        total_duration += duration;
+    }
+    let twap = weighted_sum
+        .checked_div(total_duration)
+        .map_or(last.price, |twap| twap as u64);
+    Some(PriceStats {
+        latest: last.price,
+        latest_timestamp: last.timestamp,
+        twap,
+        min: samples.iter().map(|s| s.price).min()?,
+        max: samples.iter().map(|s| s.price).max()?,
+        num_samples: samples.len() as u64,
+        decimals: PRICE_DECIMALS,
+    })
+}
+
+/// The distance between the latest price and the TWAP, in basis points of the TWAP.
+fn deviation_bps(stats: &PriceStats) -> Option<u64> {
+    if stats.twap == 0 {
+        return None;
+    }
+    let diff = stats.latest.abs_diff(stats.twap) as u128;
+    Some((diff * 10_000 / stats.twap as u128) as u64)
+}
+
+async fn sample_price_feeds() {
+    let feeds: Vec<(u32, PricePair)> =
+        FEEDS.with_borrow(|feeds| feeds.iter().map(|entry| entry.into_pair()).collect());
+    let requests = feeds.iter().map(|(_, pair)| {
+        fetch_rate(GetExchangeRateRequest {
+            base_asset: pair.base.clone(),
+            quote_asset: pair.quote.clone(),
+            timestamp: None,
+        })
+    });
+    let results = futures::future::join_all(requests).await;
+
+    let now = now_secs();
+    let window_start = now.saturating_sub(SETTINGS.with_borrow(|cell| cell.get().window_seconds));
+    for ((feed_id, pair), result) in feeds.into_iter().zip(results) {
+        // The feed may have been removed while the XRC calls were in flight.
+        if !FEEDS.with_borrow(|feeds| feeds.contains_key(&feed_id)) {
+            continue;
+        }
+        match result.map(|rate| {
+            (
+                rate.timestamp,
+                normalize_rate(rate.rate, rate.metadata.decimals),
+            )
+        }) {
+            Ok((timestamp, Some(price))) => {
+                SAMPLES.with_borrow_mut(|samples| samples.insert((feed_id, timestamp), price));
+            }
+            Ok((_, None)) => ic_cdk::println!("feed {feed_id}: rate does not fit in a u64"),
+            Err(err) => ic_cdk::println!("feed {feed_id}: failed to fetch rate: {err:?}"),
+        }
+        remove_samples(feed_id, window_start);
+        if let Some(stats) = compute_stats(&samples_of(feed_id), now) {
+            notify_subscribers(feed_id, &pair, &stats);
+        }
+    }
+}
+
+/// Removes the samples of `feed_id` taken before `before`.
+fn remove_samples(feed_id: u32, before: u64) {
+    SAMPLES.with_borrow_mut(|samples| {
+        let expired: Vec<(u32, u64)> = samples
+            .keys_range((feed_id, 0)..(feed_id, before))
+            .collect();
+        for key in expired {
+            samples.remove(&key);
+        }
+    });
+}
+
+fn notify_subscribers(feed_id: u32, pair: &PricePair, stats: &PriceStats) {
+    let Some(deviation_bps) = deviation_bps(stats) else {
+        return;
+    };
+    let subscriptions: Vec<(u64, Subscription)> = SUBSCRIPTIONS.with_borrow(|subscriptions| {
+        subscriptions
+            .iter()
+            .map(|entry| entry.into_pair())
+            .filter(|(_, subscription)| subscription.feed_id == feed_id)
+            .collect()
+    });
+    for (subscription_id, mut subscription) in subscriptions {
+        let deviated = deviation_bps > subscription.deviation_threshold_bps as u64;
+        if deviated && !subscription.alerting {
+            let alert = PriceDeviationAlert {
+                subscription_id,
+                feed_id,
+                pair: pair.clone(),
+                price: stats.latest,
+                twap: stats.twap,
+                deviation_bps,
+                decimals: stats.decimals,
+                timestamp: stats.latest_timestamp,
+            };
+            // Best effort: a failing or misbehaving subscriber must not stall the price feed.
+            if let Err(err) = Call::bounded_wait(subscription.subscriber, ALERT_METHOD)
+                .with_arg(&alert)
+                .oneway()
+            {
+                ic_cdk::println!("failed to notify {}: {err:?}", subscription.subscriber);
+            }
+        }
+        if deviated != subscription.alerting {
+            subscription.alerting = deviated;
+            SUBSCRIPTIONS.with_borrow_mut(|s| s.insert(subscription_id, subscription));
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_price_feed_settings() -> PriceFeedSettings {
+    SETTINGS.with_borrow(|cell| cell.get().clone())
+}
+
+/// Changes the sampling interval and window. Controllers only.
+#[ic_cdk::update]
+fn set_price_feed_settings(settings: PriceFeedSettings) -> Result<(), String> {
+    ensure_controller()?;
+    validate_settings(&settings)?;
+    SETTINGS.with_borrow_mut(|cell| cell.set(settings));
+    start_sampling();
+    Ok(())
+}
+
+/// Starts sampling a new asset pair and returns the id of its feed. Controllers only.
+#[ic_cdk::update]
+fn add_price_feed(pair: PricePair) -> Result<u32, String> {
+    ensure_controller()?;
+    FEEDS.with_borrow_mut(|feeds| {
+        if feeds.iter().any(|entry| {
+            let existing = entry.value();
+            same_asset(&existing.base, &pair.base) && same_asset(&existing.quote, &pair.quote)
+        }) {
+            return Err("a feed for this pair already exists".to_string());
+        }
+        let id = feeds.last_key_value().map_or(0, |(id, _)| id + 1);
+        feeds.insert(id, pair);
+        Ok(id)
+    })
+}
+
+/// Stops sampling a pair, dropping its samples and subscriptions. Controllers only.
+#[ic_cdk::update]
+fn remove_price_feed(feed_id: u32) -> Result<(), String> {
+    ensure_controller()?;
+    FEEDS
+        .with_borrow_mut(|feeds| feeds.remove(&feed_id))
+        .ok_or("no such price feed")?;
+    remove_samples(feed_id, u64::MAX);
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| {
+        let ids: Vec<u64> = subscriptions
+            .iter()
+            .filter(|entry| entry.value().feed_id == feed_id)
+            .map(|entry| *entry.key())
+            .collect();
+        for id in ids {
+            subscriptions.remove(&id);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_price_feeds() -> Vec<PriceFeed> {
+    FEEDS.with_borrow(|feeds| {
+        feeds
+            .iter()
+            .map(|entry| {
+                let (id, pair) = entry.into_pair();
+                PriceFeed { id, pair }
+            })
+            .collect()
+    })
+}
+
+/// Returns the samples of a feed in the current window, oldest first.
+#[ic_cdk::query]
+fn get_price_samples(feed_id: u32) -> Vec<PriceSample> {
+    samples_of(feed_id)
+}
+
+/// Returns the TWAP, min and max price of a feed over the current window.
+#[ic_cdk::query]
+fn get_price_stats(feed_id: u32) -> Result<PriceStats, String> {
+    if !FEEDS.with_borrow(|feeds| feeds.contains_key(&feed_id)) {
+        return Err("no such price feed".to_string());
+    }
+    compute_stats(&samples_of(feed_id), now_secs()).ok_or("no samples yet".to_string())
+}
+
+/// Subscribes the calling canister to deviation alerts of a feed. Alerts are delivered by
+/// calling its `on_price_deviation` method with a `PriceDeviationAlert`.
+#[ic_cdk::update]
+fn subscribe(feed_id: u32, deviation_threshold_bps: u32) -> Result<u64, String> {
+    let subscriber = ic_cdk::api::msg_caller();
+    if subscriber == Principal::anonymous() {
+        return Err("anonymous callers cannot subscribe".to_string());
+    }
+    if !FEEDS.with_borrow(|feeds| feeds.contains_key(&feed_id)) {
+        return Err("no such price feed".to_string());
+    }
+    if deviation_threshold_bps == 0 {
+        return Err("deviation threshold must be positive".to_string());
+    }
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| {
+        if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err("the maximum number of subscriptions has been reached".to_string());
+        }
+        let subscriptions_of_caller = subscriptions
+            .iter()
+            .filter(|entry| entry.value().subscriber == subscriber)
+            .count();
+        if subscriptions_of_caller >= MAX_SUBSCRIPTIONS_PER_PRINCIPAL {
+            return Err(format!(
+                "a principal can have at most {MAX_SUBSCRIPTIONS_PER_PRINCIPAL} subscriptions"
+            ));
+        }
+        let id = subscriptions.last_key_value().map_or(0, |(id, _)| id + 1);
+        subscriptions.insert(
+            id,
+            Subscription {
+                subscriber,
+                feed_id,
+                deviation_threshold_bps,
+                alerting: false,
+            },
+        );
+        Ok(id)
+    })
+}
+
+/// Cancels a subscription. Allowed for the subscriber and for controllers.
+#[ic_cdk::update]
+fn unsubscribe(subscription_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| {
+        let subscription = subscriptions
+            .get(&subscription_id)
+            .ok_or("no such subscription")?;
+        if subscription.subscriber != caller && !ic_cdk::api::is_controller(&caller) {
+            return Err("only the subscriber can unsubscribe".to_string());
+        }
+        subscriptions.remove(&subscription_id);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_subscriptions() -> Vec<(u64, Subscription)> {
+    let caller = ic_cdk::api::msg_caller();
+    SUBSCRIPTIONS.with_borrow(|subscriptions| {
+        subscriptions
+            .iter()
+            .map(|entry| entry.into_pair())
+            .filter(|(_, subscription)| subscription.subscriber == caller)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: u64, price: u64) -> PriceSample {
+        PriceSample { timestamp, price }
+    }
+
+    #[test]
+    fn twap_weights_prices_by_duration() {
+        // 100 for 60s, 200 for 180s, 400 for 60s until now.
+        let samples = [sample(0, 100), sample(60, 200), sample(240, 400)];
+        let stats = compute_stats(&samples, 300).unwrap();
+        assert_eq!(stats.twap, (100 * 60 + 200 * 180 + 400 * 60) / 300);
+        assert_eq!(stats.latest, 400);
+        assert_eq!(stats.latest_timestamp, 240);
+        assert_eq!(stats.min, 100);
+        assert_eq!(stats.max, 400);
+        assert_eq!(stats.num_samples, 3);
+    }
+
+    #[test]
+    fn twap_of_single_fresh_sample_is_its_price() {
+        let stats = compute_stats(&[sample(600, 123)], 600).unwrap();
+        assert_eq!(stats.twap, 123);
+        assert_eq!(compute_stats(&[], 600), None);
+    }
+
+    #[test]
+    fn computes_deviation_in_basis_points() {
+        let mut stats = compute_stats(&[sample(0, 1_000)], 0).unwrap();
+        stats.latest = 1_025;
+        assert_eq!(deviation_bps(&stats), Some(250));
+        stats.latest = 900;
+        assert_eq!(deviation_bps(&stats), Some(1_000));
+        stats.twap = 0;
+        assert_eq!(deviation_bps(&stats), None);
+    }
+
+    #[test]
+    fn normalizes_rates_to_price_decimals() {
+        assert_eq!(normalize_rate(1_234, 9), Some(1_234));
+        assert_eq!(normalize_rate(1_234, 6), Some(1_234_000));
+        assert_eq!(normalize_rate(1_234_000, 12), Some(1_234));
+        assert_eq!(normalize_rate(u64::MAX, 8), None);
+    }
+}
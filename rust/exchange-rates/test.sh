@@ -1,9 +1,45 @@
 #!/usr/bin/env bash
 set -e
 
-echo "=== Test 1: get_exchange_rate returns a numeric rate ==="
-result=$(icp canister call backend get_exchange_rate \
-  '(record { symbol = "ICP"; class = variant { Cryptocurrency } }, record { symbol = "USD"; class = variant { FiatCurrency } })')
+ICP='record { symbol = "ICP"; class = variant { Cryptocurrency } }'
+BTC='record { symbol = "BTC"; class = variant { Cryptocurrency } }'
+USD='record { symbol = "USD"; class = variant { FiatCurrency } }'
+
+echo "=== Test 1: get_exchange_rate returns the rate with its metadata ==="
+result=$(icp canister call backend get_exchange_rate "($ICP, $USD, null)")
+echo "$result"
+echo "$result" | grep -q "rate = 10_000_000_000" && \
+  echo "PASS (rate)" || (echo "FAIL (rate)" && exit 1)
+echo "$result" | grep -q "decimals = 9" && \
+  echo "PASS (decimals)" || (echo "FAIL (decimals)" && exit 1)
+
+echo "=== Test 2: get_exchange_rate accepts a historical timestamp ==="
+result=$(icp canister call backend get_exchange_rate "($ICP, $USD, opt 1_700_000_000)")
+echo "$result"
+echo "$result" | grep -q "Ok" && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 3: get_exchange_rates returns one result per pair ==="
+result=$(icp canister call backend get_exchange_rates \
+  "(vec { record { base_asset = $ICP; quote_asset = $USD; timestamp = null }; record { base_asset = $BTC; quote_asset = $USD; timestamp = null } })")
+echo "$result"
+[ "$(echo "$result" | grep -c "Ok = record")" -eq 2 ] && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 4: controllers can add a price feed ==="
+result=$(icp canister call backend add_price_feed "(record { base = $ICP; quote = $USD })")
+echo "$result"
+echo "$result" | grep -q "Ok" && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 5: the new price feed is listed ==="
+result=$(icp canister call backend list_price_feeds '()')
+echo "$result"
+echo "$result" | grep -q '"ICP"' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 6: the sampling interval cannot be shorter than a minute ==="
+result=$(icp canister call backend set_price_feed_settings '(record { sampling_interval_seconds = 10; window_seconds = 3_600 })')
 echo "$result"
-echo "$result" | grep -qE '^\([0-9]' && \
+echo "$result" | grep -q "Err" && \
   echo "PASS" || (echo "FAIL" && exit 1)
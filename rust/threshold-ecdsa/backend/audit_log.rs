@@ -0,0 +1,148 @@
+//! A log of the most recent signature requests, kept in stable memory.
+
+use crate::policy::NANOS_PER_DAY;
+use crate::{AUDIT_LOG_MEMORY_ID, DENIED_REQUESTS_MEMORY_ID, Memory, memory};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, storable::Bound};
+use serde::Deserialize;
+use std::{borrow::Cow, cell::RefCell};
+
+/// The maximum number of entries in the log. Once it is full, the oldest entry
+/// is removed for every new one.
+const MAX_AUDIT_LOG_ENTRIES: u64 = 10_000;
+/// The maximum number of denied requests recorded per UTC day. Any principal
+/// can make requests that are denied, so without a limit they could push all
+/// other entries out of the log. Further denied requests are only counted.
+const MAX_DENIED_ENTRIES_PER_DAY: u32 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum SignatureOutcome {
+    Signed {
+        signature_hex: String,
+    },
+    /// The request was rejected by the signing policy.
+    Denied(String),
+    /// The management canister failed to compute the signature.
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditLogEntry {
+    pub timestamp_nanos: u64,
+    pub caller: Principal,
+    /// The derivation path requested by the caller, without the caller's
+    /// principal that the canister prepends.
+    pub derivation_path: Vec<Vec<u8>>,
+    pub message_hash_hex: String,
+    /// Whether the caller passed the message hash (`sign_prehashed`) rather
+    /// than the message (`sign`).
+    pub prehashed: bool,
+    pub outcome: SignatureOutcome,
+}
+
+impl Storable for AuditLogEntry {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, AuditLogEntry).expect("failed to deserialize AuditLogEntry")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self).expect("failed to serialize AuditLogEntry").into()
+    }
+}
+
+/// The denied requests on `day`, and those that were not recorded in the log
+/// because there were too many.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct DeniedRequests {
+    day: u64,
+    recorded_on_day: u32,
+    not_recorded: u64,
+}
+
+impl Storable for DeniedRequests {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, DeniedRequests).expect("failed to deserialize DeniedRequests")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self).expect("failed to serialize DeniedRequests").into()
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AuditLogPage {
+    /// The total number of entries recorded in the log, which is also the
+    /// position of the next entry.
+    pub total: u64,
+    /// The position of the oldest entry still in the log. Older entries were
+    /// removed to make room for newer ones.
+    pub first: u64,
+    /// The number of denied requests that were not recorded because the daily
+    /// maximum of denied entries was reached.
+    pub denied_not_recorded: u64,
+    pub entries: Vec<AuditLogEntry>,
+}
+
+thread_local! {
+    // Entries are keyed by their position in the log.
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditLogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(AUDIT_LOG_MEMORY_ID)));
+
+    static DENIED_REQUESTS: RefCell<StableCell<DeniedRequests, Memory>> = RefCell::new(
+        StableCell::init(memory(DENIED_REQUESTS_MEMORY_ID), DeniedRequests::default())
+            .expect("failed to initialize the denied requests"),
+    );
+}
+
+pub fn record(entry: AuditLogEntry) {
+    if matches!(entry.outcome, SignatureOutcome::Denied(_))
+        && !count_denied_request(entry.timestamp_nanos / NANOS_PER_DAY)
+    {
+        return;
+    }
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let position = next_position(&log);
+        log.insert(position, entry);
+        if log.len() > MAX_AUDIT_LOG_ENTRIES {
+            log.pop_first();
+        }
+    });
+}
+
+/// Counts a denied request on `day` and returns whether it is recorded.
+fn count_denied_request(day: u64) -> bool {
+    DENIED_REQUESTS.with(|cell| {
+        let mut denied = cell.borrow().get().clone();
+        if denied.day != day {
+            denied.day = day;
+            denied.recorded_on_day = 0;
+        }
+        let is_recorded = denied.recorded_on_day < MAX_DENIED_ENTRIES_PER_DAY;
+        if is_recorded {
+            denied.recorded_on_day += 1;
+        } else {
+            denied.not_recorded += 1;
+        }
+        cell.borrow_mut().set(denied).expect("failed to persist the denied requests");
+        is_recorded
+    })
+}
+
+/// Returns up to `limit` entries, starting at position `start`.
+pub fn page(start: u64, limit: u64) -> AuditLogPage {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        AuditLogPage {
+            total: next_position(&log),
+            first: log.first_key_value().map_or(0, |(position, _)| position),
+            denied_not_recorded: DENIED_REQUESTS.with(|cell| cell.borrow().get().not_recorded),
+            entries: log.range(start..).take(limit as usize).map(|(_, entry)| entry).collect(),
+        }
+    })
+}
+
+fn next_position(log: &StableBTreeMap<u64, AuditLogEntry, Memory>) -> u64 {
+    log.last_key_value().map_or(0, |(position, _)| position + 1)
+}
@@ -1,7 +1,17 @@
-use candid::CandidType;
-use ic_cdk::{query, update};
+mod audit_log;
+mod policy;
+
+use audit_log::{AuditLogEntry, AuditLogPage, SignatureOutcome};
+use candid::{CandidType, Principal};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_cdk_management_canister::{EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs, SignWithEcdsaArgs};
+use ic_stable_structures::{
+    DefaultMemoryImpl,
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+};
+use policy::SigningPolicy;
 use serde::Serialize;
+use std::cell::RefCell;
 
 #[derive(CandidType, Serialize, Debug)]
 struct PublicKeyReply {
@@ -22,11 +32,52 @@ struct SignatureVerificationReply {
 // Use "key_1" for the production key on mainnet.
 const KEY_NAME: &str = "test_key_1";
 
+// The management canister accepts at most 255 derivation path elements, and
+// the canister uses the first one for the caller's principal.
+const MAX_DERIVATION_PATH_LEN: usize = 254;
+// The derivation path is recorded in the audit log, so its size is limited to
+// keep the size of the log bounded.
+const MAX_DERIVATION_PATH_BYTES: usize = 1024;
+const MAX_AUDIT_LOG_PAGE_SIZE: u64 = 100;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+const POLICY_MEMORY_ID: MemoryId = MemoryId::new(0);
+const AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(1);
+const DAILY_COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const DENIED_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+#[init]
+fn init(policy: Option<SigningPolicy>) {
+    if let Some(policy) = policy {
+        policy::set_policy(policy);
+    }
+}
+
+/// The signing policy, the daily counters and the audit log are kept in stable
+/// memory. The policy is replaced if one is given.
+#[post_upgrade]
+fn post_upgrade(policy: Option<SigningPolicy>) {
+    init(policy);
+}
+
+/// Returns the public key of the caller, optionally derived further along
+/// `derivation_path`.
 #[update]
-async fn public_key() -> Result<PublicKeyReply, String> {
+async fn public_key(derivation_path: Option<Vec<Vec<u8>>>) -> Result<PublicKeyReply, String> {
+    let derivation_path =
+        caller_derivation_path(ic_cdk::api::msg_caller(), derivation_path.unwrap_or_default())?;
     let response = ic_cdk_management_canister::ecdsa_public_key(&EcdsaPublicKeyArgs {
         canister_id: None,
-        derivation_path: vec![ic_cdk::api::msg_caller().as_slice().to_vec()],
+        derivation_path,
         key_id: EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: KEY_NAME.to_string() },
     })
     .await
@@ -37,19 +88,48 @@ async fn public_key() -> Result<PublicKeyReply, String> {
     })
 }
 
+/// Signs the SHA-256 hash of `message` with the caller's key.
 #[update]
-async fn sign(message: String) -> Result<SignatureReply, String> {
-    let response = ic_cdk_management_canister::sign_with_ecdsa(&SignWithEcdsaArgs {
-        message_hash: sha256(&message).to_vec(),
-        derivation_path: vec![ic_cdk::api::msg_caller().as_slice().to_vec()],
-        key_id: EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: KEY_NAME.to_string() },
-    })
-    .await
-    .map_err(|e| format!("sign_with_ecdsa failed: {:?}", e))?;
+async fn sign(
+    message: String,
+    derivation_path: Option<Vec<Vec<u8>>>,
+) -> Result<SignatureReply, String> {
+    sign_message_hash(sha256(&message), derivation_path.unwrap_or_default(), false).await
+}
 
-    Ok(SignatureReply {
-        signature_hex: hex::encode(response.signature),
-    })
+/// Signs a 32-byte message hash computed by the caller, e.g., the Keccak-256
+/// hash of an Ethereum transaction, with the caller's key.
+#[update]
+async fn sign_prehashed(
+    message_hash_hex: String,
+    derivation_path: Option<Vec<Vec<u8>>>,
+) -> Result<SignatureReply, String> {
+    let message_hash = decode_message_hash(&message_hash_hex)?;
+    sign_message_hash(message_hash, derivation_path.unwrap_or_default(), true).await
+}
+
+/// Replaces the signing policy. Only controllers may call this endpoint.
+#[update]
+fn set_signing_policy(policy: SigningPolicy) -> Result<(), String> {
+    ensure_controller()?;
+    policy::set_policy(policy);
+    Ok(())
+}
+
+#[query]
+fn signing_policy() -> SigningPolicy {
+    policy::policy()
+}
+
+/// Returns up to `limit` audit log entries, starting at position `start`.
+/// Only controllers may call this endpoint.
+#[query]
+fn audit_log(start: u64, limit: u64) -> Result<AuditLogPage, String> {
+    ensure_controller()?;
+    if limit > MAX_AUDIT_LOG_PAGE_SIZE {
+        return Err(format!("at most {MAX_AUDIT_LOG_PAGE_SIZE} entries can be requested at once"));
+    }
+    Ok(audit_log::page(start, limit))
 }
 
 #[query]
@@ -75,6 +155,114 @@ fn verify(
     Ok(SignatureVerificationReply { is_signature_valid })
 }
 
+#[query]
+fn verify_prehashed(
+    signature_hex: String,
+    message_hash_hex: String,
+    public_key_hex: String,
+) -> Result<SignatureVerificationReply, String> {
+    let signature_bytes =
+        hex::decode(&signature_hex).map_err(|e| format!("failed to hex-decode signature: {e}"))?;
+    let pubkey_bytes = hex::decode(&public_key_hex)
+        .map_err(|e| format!("failed to hex-decode public key: {e}"))?;
+    let message_hash = decode_message_hash(&message_hash_hex)?;
+
+    use k256::ecdsa::signature::hazmat::PrehashVerifier;
+    let signature = k256::ecdsa::Signature::try_from(signature_bytes.as_slice())
+        .map_err(|e| format!("failed to deserialize signature: {e}"))?;
+    let is_signature_valid = k256::ecdsa::VerifyingKey::from_sec1_bytes(&pubkey_bytes)
+        .map_err(|e| format!("failed to deserialize public key: {e}"))?
+        .verify_prehash(&message_hash, &signature)
+        .is_ok();
+
+    Ok(SignatureVerificationReply { is_signature_valid })
+}
+
+/// Checks the signing policy, signs `message_hash` with the caller's key and
+/// records the request in the audit log, whatever its outcome.
+async fn sign_message_hash(
+    message_hash: [u8; 32],
+    derivation_path: Vec<Vec<u8>>,
+    prehashed: bool,
+) -> Result<SignatureReply, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let full_derivation_path = caller_derivation_path(caller, derivation_path.clone())?;
+    let record = |outcome| {
+        audit_log::record(AuditLogEntry {
+            timestamp_nanos: ic_cdk::api::time(),
+            caller,
+            derivation_path: derivation_path.clone(),
+            message_hash_hex: hex::encode(message_hash),
+            prehashed,
+            outcome,
+        })
+    };
+
+    if let Err(e) = policy::authorize(caller, ic_cdk::api::time()) {
+        record(SignatureOutcome::Denied(e.clone()));
+        return Err(e);
+    }
+
+    let result = ic_cdk_management_canister::sign_with_ecdsa(&SignWithEcdsaArgs {
+        message_hash: message_hash.to_vec(),
+        derivation_path: full_derivation_path,
+        key_id: EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: KEY_NAME.to_string() },
+    })
+    .await
+    .map_err(|e| format!("sign_with_ecdsa failed: {:?}", e));
+
+    match result {
+        Ok(response) => {
+            let signature_hex = hex::encode(response.signature);
+            record(SignatureOutcome::Signed { signature_hex: signature_hex.clone() });
+            Ok(SignatureReply { signature_hex })
+        }
+        Err(e) => {
+            record(SignatureOutcome::Failed(e.clone()));
+            Err(e)
+        }
+    }
+}
+
+/// Prepends the caller's principal to `derivation_path`, so that every
+/// caller has its own keys.
+fn caller_derivation_path(
+    caller: Principal,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<u8>>, String> {
+    if derivation_path.len() > MAX_DERIVATION_PATH_LEN {
+        return Err(format!(
+            "the derivation path must have at most {MAX_DERIVATION_PATH_LEN} elements"
+        ));
+    }
+    if derivation_path.iter().map(Vec::len).sum::<usize>() > MAX_DERIVATION_PATH_BYTES {
+        return Err(format!(
+            "the derivation path must have at most {MAX_DERIVATION_PATH_BYTES} bytes in total"
+        ));
+    }
+    let mut full_derivation_path = vec![caller.as_slice().to_vec()];
+    full_derivation_path.extend(derivation_path);
+    Ok(full_derivation_path)
+}
+
+fn decode_message_hash(message_hash_hex: &str) -> Result<[u8; 32], String> {
+    hex::decode(message_hash_hex)
+        .map_err(|e| format!("failed to hex-decode message hash: {e}"))?
+        .try_into()
+        .map_err(|hash: Vec<u8>| {
+            format!("the message hash must be 32 bytes long, but it is {} bytes", hash.len())
+        })
+}
+
+fn ensure_controller() -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(format!("principal {caller} is not a controller of this canister"))
+    }
+}
+
 fn sha256(input: &str) -> [u8; 32] {
     use sha2::Digest;
     let mut hasher = sha2::Sha256::new();
@@ -0,0 +1,145 @@
+//! Who may request signatures, and how many per day.
+
+use crate::{DAILY_COUNTERS_MEMORY_ID, Memory, POLICY_MEMORY_ID, memory};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, storable::Bound};
+use serde::Deserialize;
+use std::{borrow::Cow, cell::RefCell};
+
+pub const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// The number of counters of past days removed per counted request. Removing
+/// more than one lets the counters of past days disappear faster than those of
+/// the current day are added.
+const STALE_COUNTERS_REMOVED_PER_REQUEST: usize = 2;
+
+/// The policy applied to `sign` and `sign_prehashed`. The default policy
+/// allows every principal to request an unlimited number of signatures.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SigningPolicy {
+    /// If set, only these principals may request signatures.
+    pub allowed_principals: Option<Vec<Principal>>,
+    /// If set, the maximum number of signatures a principal may request per
+    /// UTC day.
+    pub daily_limit: Option<u32>,
+}
+
+impl Storable for SigningPolicy {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, SigningPolicy).expect("failed to deserialize SigningPolicy")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self).expect("failed to serialize SigningPolicy").into()
+    }
+}
+
+thread_local! {
+    static POLICY: RefCell<StableCell<SigningPolicy, Memory>> = RefCell::new(
+        StableCell::init(memory(POLICY_MEMORY_ID), SigningPolicy::default())
+            .expect("failed to initialize the signing policy"),
+    );
+
+    // The number of signatures each principal requested, keyed by the UTC day
+    // and the principal, so that the counters of past days come first.
+    static DAILY_COUNTERS: RefCell<StableBTreeMap<(u64, Principal), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DAILY_COUNTERS_MEMORY_ID)));
+}
+
+pub fn policy() -> SigningPolicy {
+    POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_policy(policy: SigningPolicy) {
+    POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .expect("failed to persist the signing policy");
+}
+
+/// Checks whether `caller` may request a signature at time `now_nanos` and,
+/// if so, counts the request towards the caller's daily limit. Requests are
+/// only counted while a daily limit is set.
+pub fn authorize(caller: Principal, now_nanos: u64) -> Result<(), String> {
+    let policy = policy();
+    if let Some(allowed_principals) = &policy.allowed_principals
+        && !allowed_principals.contains(&caller)
+    {
+        return Err(format!("principal {caller} is not allowed to request signatures"));
+    }
+    let Some(daily_limit) = policy.daily_limit else {
+        return Ok(());
+    };
+
+    let day = now_nanos / NANOS_PER_DAY;
+    DAILY_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        for _ in 0..STALE_COUNTERS_REMOVED_PER_REQUEST {
+            match counters.first_key_value() {
+                Some(((counter_day, _), _)) if counter_day < day => counters.pop_first(),
+                _ => break,
+            };
+        }
+        let count = counters.get(&(day, caller)).unwrap_or_default();
+        if count >= daily_limit {
+            return Err(format!(
+                "principal {caller} has reached the daily limit of {daily_limit} signatures"
+            ));
+        }
+        counters.insert((day, caller), count + 1);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: Principal = Principal::from_slice(&[1; 29]);
+    const BOB: Principal = Principal::from_slice(&[2; 29]);
+    const NOW_NANOS: u64 = 20_000 * NANOS_PER_DAY + 12 * 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn should_allow_every_principal_by_default() {
+        for _ in 0..10 {
+            assert_eq!(authorize(ALICE, NOW_NANOS), Ok(()));
+            assert_eq!(authorize(BOB, NOW_NANOS), Ok(()));
+        }
+    }
+
+    #[test]
+    fn should_only_allow_the_allowed_principals() {
+        set_policy(SigningPolicy { allowed_principals: Some(vec![ALICE]), daily_limit: None });
+
+        assert_eq!(authorize(ALICE, NOW_NANOS), Ok(()));
+        assert_eq!(
+            authorize(BOB, NOW_NANOS),
+            Err(format!("principal {BOB} is not allowed to request signatures"))
+        );
+    }
+
+    #[test]
+    fn should_allow_up_to_the_daily_limit_per_principal_and_day() {
+        set_policy(SigningPolicy { allowed_principals: None, daily_limit: Some(2) });
+
+        assert_eq!(authorize(ALICE, NOW_NANOS), Ok(()));
+        assert_eq!(authorize(ALICE, NOW_NANOS), Ok(()));
+        assert_eq!(
+            authorize(ALICE, NOW_NANOS),
+            Err(format!("principal {ALICE} has reached the daily limit of 2 signatures"))
+        );
+        // the limit applies to each principal on its own
+        assert_eq!(authorize(BOB, NOW_NANOS), Ok(()));
+
+        // the counters start over on the next UTC day
+        let next_day_nanos = (NOW_NANOS / NANOS_PER_DAY + 1) * NANOS_PER_DAY;
+        assert!(authorize(ALICE, next_day_nanos - 1).is_err());
+        assert_eq!(authorize(ALICE, next_day_nanos), Ok(()));
+        assert_eq!(authorize(ALICE, next_day_nanos), Ok(()));
+        assert!(authorize(ALICE, next_day_nanos).is_err());
+        assert_eq!(
+            DAILY_COUNTERS.with(|counters| counters.borrow().len()),
+            1,
+            "the counters of the previous day should be removed"
+        );
+    }
+}
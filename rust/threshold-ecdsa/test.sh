@@ -1,19 +1,19 @@
 #!/usr/bin/env bash
 set -e
 
-echo "=== Test 1/3: public_key() returns a hex-encoded public key ==="
+echo "=== Test 1/7: public_key() returns a hex-encoded public key ==="
 result=$(icp canister call backend public_key '()') && \
   echo "$result" && \
   echo "$result" | grep -q 'public_key_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 2/3: sign() returns a hex-encoded signature ==="
+echo "=== Test 2/7: sign() returns a hex-encoded signature ==="
 result=$(icp canister call backend sign '("hello world")') && \
   echo "$result" && \
   echo "$result" | grep -q 'signature_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 3/3: signature verifies cryptographically (secp256k1) ==="
+echo "=== Test 3/7: signature verifies cryptographically (secp256k1) ==="
 npm install --silent
 
 export LC_ALL=C
@@ -44,3 +44,48 @@ console.log("verified =", verified);
 if (!verified) process.exit(1);
 END
 echo "PASS"
+
+echo "=== Test 4/7: sign_prehashed() signs a 32-byte hash as is ==="
+# keccak256("hello world"), as an Ethereum wallet would pass it
+message_hash_hex="47173285a8d7341e5e972fc677286384f802f8ef42a5ec5f03bbfa254cb01fad"
+
+signature_hex=$(get_text_in_double_quotes "$(icp canister call backend sign_prehashed "(\"$message_hash_hex\")" | grep signature_hex)")
+echo "signature_hex=$signature_hex"
+
+node <<END
+const secp256k1 = require("secp256k1");
+
+const signature = new Uint8Array(Buffer.from("${signature_hex}", "hex"));
+const public_key = new Uint8Array(Buffer.from("${public_key_hex}", "hex"));
+const message_hash = new Uint8Array(Buffer.from("${message_hash_hex}", "hex"));
+
+const verified = secp256k1.ecdsaVerify(signature, message_hash, public_key);
+console.log("verified =", verified);
+if (!verified) process.exit(1);
+END
+
+result=$(icp canister call backend verify_prehashed "(\"$signature_hex\", \"$message_hash_hex\", \"$public_key_hex\")") && \
+  echo "$result" && \
+  echo "$result" | grep -q 'is_signature_valid = true' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 5/7: a derivation path yields a different key of the caller ==="
+derived_public_key_hex=$(get_text_in_double_quotes "$(icp canister call backend public_key '(opt vec { blob "account-1" })' | grep public_key_hex)")
+echo "derived_public_key_hex=$derived_public_key_hex"
+[ -n "$derived_public_key_hex" ] && [ "$derived_public_key_hex" != "$public_key_hex" ] && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 6/7: the audit log records the signature requests ==="
+result=$(icp canister call backend audit_log '(0, 100)') && \
+  echo "$result" && \
+  echo "$result" | grep -q "$message_hash_hex" && \
+  echo "$result" | grep -q 'prehashed = true' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 7/7: the signing policy enforces the daily limit ==="
+icp canister call backend set_signing_policy '(record { allowed_principals = null; daily_limit = opt (0 : nat32) })'
+result=$(icp canister call backend sign '("hello world")') && \
+  echo "$result" && \
+  echo "$result" | grep -q 'daily limit' && \
+  icp canister call backend set_signing_policy '(record { allowed_principals = null; daily_limit = null })' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
@@ -1,7 +1,9 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-#[derive(CandidType, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[derive(
+    CandidType, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub enum SchnorrAlgorithm {
     #[serde(rename = "bip340secp256k1")]
     Bip340Secp256k1,
@@ -9,9 +11,46 @@ pub enum SchnorrAlgorithm {
     Ed25519,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    #[serde(rename = "mainnet")]
+    Mainnet,
+    #[serde(rename = "testnet")]
+    Testnet,
+    #[serde(rename = "regtest")]
+    Regtest,
+}
+
+/// Requests the BIP-341 output key of a BIP-340 public key.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct TaprootOutputRequest {
+    /// The root of the script tree, or an empty string if the output can only
+    /// be spent with the key, like `opt_merkle_tree_root_hex` of `sign`.
+    pub merkle_tree_root_hex: String,
+    pub network: BitcoinNetwork,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaprootOutput {
+    /// The tweaked x-only public key that BIP-341 signatures verify against.
+    pub output_key_hex: String,
+    /// The P2TR address paying to the output key.
+    pub address: String,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 pub struct PublicKeyReply {
     pub public_key_hex: String,
+    /// Only set if `public_key` was called with a [`TaprootOutputRequest`].
+    pub taproot_output: Option<TaprootOutput>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct NostrPublicKeyReply {
+    /// The x-only public key as used in the `pubkey` field of events.
+    pub public_key_hex: String,
+    /// The public key encoded as specified in NIP-19.
+    pub npub: String,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
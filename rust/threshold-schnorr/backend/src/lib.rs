@@ -1,5 +1,6 @@
 mod all_architectures;
 pub use all_architectures::*;
+pub mod nostr_event;
 
 // We need to compile this crate for integration testing. However, it fails to
 // compile with linking errors on x86 targets, but we only need a few types
@@ -0,0 +1,47 @@
+//! The parts of [Nostr](https://github.com/nostr-protocol/nips/blob/master/01.md)
+//! event signing that don't need the Internet Computer, so that they can be
+//! unit tested.
+
+use sha2::{Digest, Sha256};
+
+/// Computes the id of an event as specified in NIP-01: the SHA-256 hash of the
+/// compact JSON serialization of `[0, pubkey, created_at, kind, tags, content]`.
+pub fn event_id(
+    pubkey: &str,
+    created_at: u64,
+    kind: u16,
+    tags: &[Vec<String>],
+    content: &str,
+) -> [u8; 32] {
+    let serialized = serde_json::to_string(&(0, pubkey, created_at, kind, tags, content))
+        .expect("failed to serialize event");
+    Sha256::digest(serialized.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_id_should_hash_the_nip01_serialization() {
+        let pubkey = "f7234bd4c1394dda46d09f35bd384dd30cc552ad5541990f98844fb06676e9ca";
+        let tags = vec![
+            vec![
+                "e".to_string(),
+                "5c83da77af1dec6d7289834998ad7aafbd9e2191396d75ec3cc27f5a77226f36".to_string(),
+                "wss://nostr.example.com".to_string(),
+            ],
+            vec!["p".to_string(), pubkey.to_string()],
+        ];
+        // NIP-01 escapes quotes, line breaks, backslashes and tabs, but not
+        // other characters
+        let content = "Hello \"Nostr\"\nback\\slash\ttab é \u{1F680}";
+
+        // The SHA-256 hash of
+        // [0,"f7234bd4…e9ca",1673347337,1,[["e","5c83da77…6f36","wss://nostr.example.com"],["p","f7234bd4…e9ca"]],"Hello \"Nostr\"\nback\\slash\ttab é 🚀"]
+        assert_eq!(
+            hex::encode(event_id(pubkey, 1_673_347_337, 1, &tags, content)),
+            "2595200fc1ad3eb5fdf297642df236a96e745d50196aae445ce863cf3c772b00"
+        );
+    }
+}
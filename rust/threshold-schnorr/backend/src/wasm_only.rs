@@ -1,7 +1,10 @@
-use super::{PublicKeyReply, SchnorrAlgorithm, SignatureReply, SignatureVerificationReply};
+use super::{
+    BitcoinNetwork, PublicKeyReply, SchnorrAlgorithm, SignatureReply, SignatureVerificationReply,
+    TaprootOutput, TaprootOutputRequest,
+};
 use bitcoin::{
-    key::{Secp256k1, TapTweak},
-    XOnlyPublicKey,
+    key::{Secp256k1, TapTweak, TweakedPublicKey},
+    Address, XOnlyPublicKey,
 };
 use candid::{CandidType, Principal};
 use ic_cdk::call::Call;
@@ -9,10 +12,28 @@ use ic_cdk::{query, update};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 
+mod nostr;
+
 type CanisterId = Principal;
 
+/// The maximum number of messages signed by one `sign_batch` call.
+const MAX_BATCH_SIZE: usize = 16;
+/// The management canister accepts derivation paths with up to 255 elements,
+/// and the first one is always the caller's principal.
+const MAX_DERIVATION_PATH_LEN: usize = 254;
+const SIGN_WITH_SCHNORR_CYCLES: u128 = 26_153_846_153;
+
+/// The public key of the canister for an algorithm, i.e., for the empty
+/// derivation path, from which the keys for all derivation paths are derived.
+#[derive(Clone, Debug)]
+struct CanisterPublicKey {
+    public_key: Vec<u8>,
+    chain_code: [u8; 32],
+}
+
 #[derive(CandidType, Serialize, Debug)]
 struct ManagementCanisterSchnorrPublicKeyRequest {
     pub canister_id: Option<CanisterId>,
@@ -59,6 +80,11 @@ struct ManagementCanisterSignatureReply {
 thread_local! {
     static MGMT_CANISTER_ID: RefCell<String> = RefCell::new("aaaaa-aa".to_string());
     static SCHNORR_KEY_NAME: RefCell<String> = RefCell::new("test_key_1".to_string());
+    // The canister's public keys never change, so they are only fetched once.
+    // This also lets `public_key_for` derive keys in a query, which cannot call
+    // the management canister.
+    static CANISTER_PUBLIC_KEYS: RefCell<BTreeMap<SchnorrAlgorithm, CanisterPublicKey>> =
+        const { RefCell::new(BTreeMap::new()) };
 }
 
 /// Accepts an optional key name at deploy time.
@@ -70,6 +96,24 @@ fn init(key_name: Option<String>) {
     if let Some(name) = key_name {
         SCHNORR_KEY_NAME.with_borrow_mut(|n| *n = name);
     }
+    fetch_canister_public_keys();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(key_name: Option<String>) {
+    init(key_name);
+}
+
+/// Fetches the canister's public keys in the background, so that
+/// `public_key_for` can answer right after the canister was installed.
+fn fetch_canister_public_keys() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, async {
+        for algorithm in [SchnorrAlgorithm::Bip340Secp256k1, SchnorrAlgorithm::Ed25519] {
+            if let Err(e) = canister_public_key(algorithm).await {
+                println!("Failed to fetch the canister's {algorithm:?} public key: {e}");
+            }
+        }
+    });
 }
 
 #[update]
@@ -79,15 +123,56 @@ async fn for_test_only_change_management_canister_id(id: String) -> Result<(), S
         println!("Changing management canister id from {current} to {id}");
         *current = id;
     });
+    CANISTER_PUBLIC_KEYS.with_borrow_mut(|keys| keys.clear());
     Ok(())
 }
 
+/// Returns the public key of the caller, optionally below the given
+/// derivation path, see [`derivation_path_for`]. For BIP-340 keys, it also
+/// returns the BIP-341 output key and P2TR address if `taproot_output` is set.
 #[update]
-async fn public_key(algorithm: SchnorrAlgorithm) -> Result<PublicKeyReply, String> {
+async fn public_key(
+    algorithm: SchnorrAlgorithm,
+    derivation_path: Option<Vec<serde_bytes::ByteBuf>>,
+    taproot_output: Option<TaprootOutputRequest>,
+) -> Result<PublicKeyReply, String> {
+    if taproot_output.is_some() && algorithm != SchnorrAlgorithm::Bip340Secp256k1 {
+        return Err("taproot outputs require bip340secp256k1 keys".to_string());
+    }
+    let derivation_path = derivation_path_for(
+        ic_cdk::api::msg_caller(),
+        &derivation_path.unwrap_or_default(),
+    )?;
+    let public_key = schnorr_public_key(algorithm, derivation_path).await?;
+    let taproot_output = taproot_output
+        .map(|request| self::taproot_output(&public_key, &request))
+        .transpose()?;
+    Ok(PublicKeyReply {
+        public_key_hex: hex::encode(&public_key),
+        taproot_output,
+    })
+}
+
+/// Returns the public key for `algorithm` and `derivation_path`. It is
+/// derived from the canister's public key, which is only fetched from the
+/// management canister once.
+async fn schnorr_public_key(
+    algorithm: SchnorrAlgorithm,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let canister_public_key = canister_public_key(algorithm).await?;
+    derive_public_key(algorithm, &canister_public_key, derivation_path)
+}
+
+async fn canister_public_key(algorithm: SchnorrAlgorithm) -> Result<CanisterPublicKey, String> {
+    if let Some(key) = CANISTER_PUBLIC_KEYS.with_borrow(|keys| keys.get(&algorithm).cloned()) {
+        return Ok(key);
+    }
+
     let key_name = SCHNORR_KEY_NAME.with_borrow(|n| n.clone());
     let request = ManagementCanisterSchnorrPublicKeyRequest {
         canister_id: None,
-        derivation_path: vec![ic_cdk::api::msg_caller().as_slice().to_vec()],
+        derivation_path: vec![],
         key_id: SchnorrKeyId {
             algorithm,
             name: key_name,
@@ -102,8 +187,108 @@ async fn public_key(algorithm: SchnorrAlgorithm) -> Result<PublicKeyReply, Strin
             .candid_tuple()
             .map_err(|e| format!("failed to decode schnorr_public_key reply {e:?}"))?;
 
+    let key = CanisterPublicKey {
+        public_key: res.public_key,
+        chain_code: res.chain_code.try_into().map_err(|chain_code: Vec<u8>| {
+            format!("chain codes must be 32 bytes long but got {}", chain_code.len())
+        })?,
+    };
+    CANISTER_PUBLIC_KEYS.with_borrow_mut(|keys| keys.insert(algorithm, key.clone()));
+    Ok(key)
+}
+
+/// Derives the public key for `derivation_path` from the canister's public
+/// key in the same way as the management canister does, so that keys can be
+/// computed without calling it.
+fn derive_public_key(
+    algorithm: SchnorrAlgorithm,
+    canister_public_key: &CanisterPublicKey,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    match algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => {
+            use ic_secp256k1::{DerivationIndex, DerivationPath, PublicKey};
+
+            let public_key = PublicKey::deserialize_sec1(&canister_public_key.public_key)
+                .map_err(|e| format!("failed to deserialize canister public key: {e:?}"))?;
+            let derivation_path =
+                DerivationPath::new(derivation_path.into_iter().map(DerivationIndex).collect());
+            let (derived_public_key, _chain_code) = public_key
+                .derive_subkey_with_chain_code(&derivation_path, &canister_public_key.chain_code);
+            Ok(derived_public_key.serialize_sec1(true))
+        }
+        SchnorrAlgorithm::Ed25519 => {
+            derive_ed25519_public_key(canister_public_key, derivation_path)
+        }
+    }
+}
+
+/// Derives an Ed25519 public key, see the `ic-ed25519` crate: for each element
+/// of the derivation path, HKDF-SHA-512 keyed with the chain code yields an
+/// offset that is added to the key, and the next chain code.
+fn derive_ed25519_public_key(
+    canister_public_key: &CanisterPublicKey,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    use curve25519_dalek::{edwards::CompressedEdwardsY, EdwardsPoint, Scalar};
+
+    let mut point = CompressedEdwardsY::from_slice(&canister_public_key.public_key)
+        .ok()
+        .and_then(|compressed| compressed.decompress())
+        .ok_or("failed to deserialize canister public key")?;
+    let mut chain_code = canister_public_key.chain_code;
+    for element in derivation_path {
+        let ikm = [point.compress().as_bytes().as_slice(), &element].concat();
+        let mut okm = [0u8; 96];
+        hkdf::Hkdf::<sha2::Sha512>::new(Some(&chain_code), &ikm)
+            .expand(b"Ed25519", &mut okm)
+            .expect("96 bytes is a valid HKDF-SHA-512 output length");
+        // the offset is big endian, but dalek expects little endian scalars
+        let mut offset = [0u8; 64];
+        offset.copy_from_slice(&okm[..64]);
+        offset.reverse();
+        point += EdwardsPoint::mul_base(&Scalar::from_bytes_mod_order_wide(&offset));
+        chain_code.copy_from_slice(&okm[64..]);
+    }
+    Ok(point.compress().to_bytes().to_vec())
+}
+
+fn taproot_output(
+    secp1_pk_bytes: &[u8],
+    request: &TaprootOutputRequest,
+) -> Result<TaprootOutput, String> {
+    let merkle_tree_root_bytes = hex::decode(&request.merkle_tree_root_hex)
+        .map_err(|e| format!("failed to decode hex: {e:?}"))?;
+    let output_key = bip341_output_key(secp1_pk_bytes, &merkle_tree_root_bytes)?;
+    let network = match request.network {
+        BitcoinNetwork::Mainnet => bitcoin::Network::Bitcoin,
+        BitcoinNetwork::Testnet => bitcoin::Network::Testnet,
+        BitcoinNetwork::Regtest => bitcoin::Network::Regtest,
+    };
+    Ok(TaprootOutput {
+        output_key_hex: hex::encode(output_key.to_x_only_public_key().serialize()),
+        address: Address::p2tr_tweaked(output_key, network).to_string(),
+    })
+}
+
+/// Returns the public key of `principal`, optionally below the given
+/// derivation path. Queries cannot call the management canister, so the key is
+/// derived from the canister's public key, which is fetched when the canister
+/// is installed.
+#[query]
+fn public_key_for(
+    principal: candid::Principal,
+    algorithm: SchnorrAlgorithm,
+    derivation_path: Option<Vec<serde_bytes::ByteBuf>>,
+) -> Result<PublicKeyReply, String> {
+    let derivation_path = derivation_path_for(principal, &derivation_path.unwrap_or_default())?;
+    let canister_public_key = CANISTER_PUBLIC_KEYS
+        .with_borrow(|keys| keys.get(&algorithm).cloned())
+        .ok_or("the canister's public key is not fetched yet, try again later")?;
+    let public_key = derive_public_key(algorithm, &canister_public_key, derivation_path)?;
     Ok(PublicKeyReply {
-        public_key_hex: hex::encode(&res.public_key),
+        public_key_hex: hex::encode(public_key),
+        taproot_output: None,
     })
 }
 
@@ -132,10 +317,54 @@ async fn sign(
         })
         .transpose()?;
 
+    sign_with_schnorr(
+        message.into_bytes(),
+        algorithm,
+        derivation_path_for(ic_cdk::api::msg_caller(), &[])?,
+        aux,
+    )
+    .await
+}
+
+/// Signs each of `messages` with the caller's key below `derivation_path`.
+/// The signatures are requested concurrently, and the result of each request
+/// is returned at the position of its message, so that some signatures can
+/// succeed while others fail.
+#[update]
+async fn sign_batch(
+    messages: Vec<String>,
+    algorithm: SchnorrAlgorithm,
+    derivation_path: Vec<serde_bytes::ByteBuf>,
+) -> Result<Vec<Result<SignatureReply, String>>, String> {
+    if messages.is_empty() || messages.len() > MAX_BATCH_SIZE {
+        return Err(format!(
+            "a batch must contain between 1 and {MAX_BATCH_SIZE} messages but got {}",
+            messages.len()
+        ));
+    }
+    let derivation_path = derivation_path_for(ic_cdk::api::msg_caller(), &derivation_path)?;
+
+    let signatures = messages.into_iter().map(|message| {
+        sign_with_schnorr(
+            message.into_bytes(),
+            algorithm,
+            derivation_path.clone(),
+            None,
+        )
+    });
+    Ok(futures::future::join_all(signatures).await)
+}
+
+async fn sign_with_schnorr(
+    message: Vec<u8>,
+    algorithm: SchnorrAlgorithm,
+    derivation_path: Vec<Vec<u8>>,
+    aux: Option<SignWithSchnorrAux>,
+) -> Result<SignatureReply, String> {
     let key_name = SCHNORR_KEY_NAME.with_borrow(|n| n.clone());
     let internal_request = ManagementCanisterSignatureRequest {
-        message: message.as_bytes().to_vec(),
-        derivation_path: vec![ic_cdk::api::msg_caller().as_slice().to_vec()],
+        message,
+        derivation_path,
         key_id: SchnorrKeyId {
             algorithm,
             name: key_name,
@@ -146,7 +375,7 @@ async fn sign(
     let (internal_reply,): (ManagementCanisterSignatureReply,) =
         Call::bounded_wait(mgmt_canister_id(), "sign_with_schnorr")
             .with_arg(internal_request)
-            .with_cycles(26_153_846_153u128)
+            .with_cycles(SIGN_WITH_SCHNORR_CYCLES)
             .await
             .map_err(|e| format!("sign_with_schnorr failed {e:?}"))?
             .candid_tuple()
@@ -157,6 +386,24 @@ async fn sign(
     })
 }
 
+/// Scopes `derivation_path` to `principal`: the principal's bytes are always
+/// the first element, so that each principal gets its own keys, and callers
+/// cannot use the keys of other principals.
+fn derivation_path_for(
+    principal: Principal,
+    derivation_path: &[ByteBuf],
+) -> Result<Vec<Vec<u8>>, String> {
+    if derivation_path.len() > MAX_DERIVATION_PATH_LEN {
+        return Err(format!(
+            "derivation paths can have at most {MAX_DERIVATION_PATH_LEN} elements but got {}",
+            derivation_path.len()
+        ));
+    }
+    Ok(std::iter::once(principal.as_slice().to_vec())
+        .chain(derivation_path.iter().map(|element| element.to_vec()))
+        .collect())
+}
+
 #[query]
 async fn verify(
     signature_hex: String,
@@ -214,32 +461,11 @@ fn verify_bip341_secp256k1(
     secp1_pk_bytes: &[u8],
     merkle_tree_root_bytes: &[u8],
 ) -> Result<SignatureVerificationReply, String> {
-    assert_eq!(secp1_pk_bytes.len(), 33);
-
-    let pk = XOnlyPublicKey::from_slice(&secp1_pk_bytes[1..]).unwrap();
-    let tweaked_pk_bytes = {
-        let secp256k1_engine = Secp256k1::new();
-        let merkle_root = if merkle_tree_root_bytes.len() == 0 {
-            None
-        } else {
-            Some(
-                bitcoin::hashes::Hash::from_slice(&merkle_tree_root_bytes)
-                    .expect("failed to create TapBranchHash"),
-            )
-        };
-
-        pk.tap_tweak(&secp256k1_engine, merkle_root)
-            .0
-            .to_x_only_public_key()
-            .serialize()
-    };
+    let pk = bip341_output_key(secp1_pk_bytes, merkle_tree_root_bytes)?.to_x_only_public_key();
 
     let sig = bitcoin::secp256k1::schnorr::Signature::from_slice(sig_bytes)
         .expect("failed to deserialize signature");
 
-    let pk = bitcoin::secp256k1::XOnlyPublicKey::from_slice(&tweaked_pk_bytes)
-        .expect("failed to deserialize tweaked BIP340 encoding into public key");
-
     let secp256k1_engine = Secp256k1::new();
     let msg =
         bitcoin::secp256k1::Message::from_digest_slice(msg_bytes).expect("failed to parse message");
@@ -248,6 +474,38 @@ fn verify_bip341_secp256k1(
     Ok(SignatureVerificationReply { is_signature_valid })
 }
 
+/// Tweaks a BIP-340 public key, given in its 33-byte compressed SEC1
+/// encoding, with the root of a script tree as specified in BIP-341. An empty
+/// root means that the output can only be spent with the key.
+fn bip341_output_key(
+    secp1_pk_bytes: &[u8],
+    merkle_tree_root_bytes: &[u8],
+) -> Result<TweakedPublicKey, String> {
+    if secp1_pk_bytes.len() != 33 {
+        return Err(format!(
+            "BIP-340 public keys must be 33 bytes long but got {}",
+            secp1_pk_bytes.len()
+        ));
+    }
+    let pk = XOnlyPublicKey::from_slice(&secp1_pk_bytes[1..])
+        .map_err(|e| format!("failed to deserialize BIP340 public key: {e:?}"))?;
+    let merkle_root = match merkle_tree_root_bytes.len() {
+        0 => None,
+        32 => Some(
+            bitcoin::hashes::Hash::from_slice(merkle_tree_root_bytes)
+                .map_err(|e| format!("failed to create TapNodeHash: {e:?}"))?,
+        ),
+        len => {
+            return Err(format!(
+                "merkle tree root bytes must be 0 or 32 bytes long but got {len}"
+            ))
+        }
+    };
+
+    let secp256k1_engine = Secp256k1::new();
+    Ok(pk.tap_tweak(&secp256k1_engine, merkle_root).0)
+}
+
 fn verify_ed25519(
     sig_bytes: &[u8],
     msg_bytes: &[u8],
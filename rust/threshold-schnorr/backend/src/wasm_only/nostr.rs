@@ -0,0 +1,108 @@
+//! Signing of [Nostr](https://github.com/nostr-protocol/nips/blob/master/01.md)
+//! events with a BIP-340 key per caller.
+
+use super::{derivation_path_for, schnorr_public_key, sign_with_schnorr};
+use crate::nostr_event::event_id;
+use crate::{NostrPublicKeyReply, SchnorrAlgorithm};
+use bitcoin::bech32::{self, Bech32, Hrp};
+use candid::Principal;
+use ic_cdk::update;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+/// Appended to the caller's principal in the derivation path, so that the
+/// Nostr key of a caller differs from the key it uses with `sign`.
+const NOSTR_DERIVATION_PATH: &[u8] = b"nostr";
+const NPUB_HRP: Hrp = Hrp::parse_unchecked("npub");
+
+/// An event as created by a client, without `id` and `sig`. If `pubkey` is
+/// given, it must be the caller's Nostr public key. If `created_at` is not
+/// given, the current time is used.
+#[derive(Deserialize)]
+struct UnsignedEvent {
+    pubkey: Option<String>,
+    created_at: Option<u64>,
+    kind: u16,
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct Event {
+    id: String,
+    pubkey: String,
+    created_at: u64,
+    kind: u16,
+    tags: Vec<Vec<String>>,
+    content: String,
+    sig: String,
+}
+
+/// Returns the caller's Nostr public key, in hex and as npub.
+#[update]
+async fn nostr_public_key() -> Result<NostrPublicKeyReply, String> {
+    let public_key_hex = nostr_public_key_hex(ic_cdk::api::msg_caller()).await?;
+    let public_key = hex::decode(&public_key_hex).expect("public key is valid hex");
+    let npub = bech32::encode::<Bech32>(NPUB_HRP, &public_key)
+        .map_err(|e| format!("failed to encode npub: {e:?}"))?;
+    Ok(NostrPublicKeyReply {
+        public_key_hex,
+        npub,
+    })
+}
+
+/// Signs an unsigned event, given as JSON, with the caller's Nostr key and
+/// returns the signed event as JSON, ready to be published to relays.
+#[update]
+async fn sign_nostr_event(unsigned_event_json: String) -> Result<String, String> {
+    let event: UnsignedEvent = serde_json::from_str(&unsigned_event_json)
+        .map_err(|e| format!("malformed unsigned event: {e}"))?;
+    let caller = ic_cdk::api::msg_caller();
+    let pubkey = nostr_public_key_hex(caller).await?;
+    if let Some(given) = &event.pubkey {
+        if !given.eq_ignore_ascii_case(&pubkey) {
+            return Err(format!(
+                "the event's pubkey {given} is not the caller's Nostr public key {pubkey}"
+            ));
+        }
+    }
+    let created_at = event
+        .created_at
+        .unwrap_or_else(|| ic_cdk::api::time() / 1_000_000_000);
+
+    let id = event_id(&pubkey, created_at, event.kind, &event.tags, &event.content);
+    let signature = sign_with_schnorr(
+        id.to_vec(),
+        SchnorrAlgorithm::Bip340Secp256k1,
+        nostr_derivation_path(caller)?,
+        None,
+    )
+    .await?;
+
+    serde_json::to_string(&Event {
+        id: hex::encode(id),
+        pubkey,
+        created_at,
+        kind: event.kind,
+        tags: event.tags,
+        content: event.content,
+        sig: signature.signature_hex,
+    })
+    .map_err(|e| format!("failed to serialize event: {e}"))
+}
+
+/// The x-only public key of `principal`, i.e., without the first byte of the
+/// compressed SEC1 encoding returned by `schnorr_public_key`.
+async fn nostr_public_key_hex(principal: Principal) -> Result<String, String> {
+    let public_key = schnorr_public_key(
+        SchnorrAlgorithm::Bip340Secp256k1,
+        nostr_derivation_path(principal)?,
+    )
+    .await?;
+    Ok(hex::encode(&public_key[1..]))
+}
+
+fn nostr_derivation_path(principal: Principal) -> Result<Vec<Vec<u8>>, String> {
+    derivation_path_for(principal, &[ByteBuf::from(NOSTR_DERIVATION_PATH)])
+}
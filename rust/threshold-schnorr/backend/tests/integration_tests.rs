@@ -1,8 +1,12 @@
-use backend::{PublicKeyReply, SchnorrAlgorithm, SignatureReply, SignatureVerificationReply};
+use backend::{
+    BitcoinNetwork, NostrPublicKeyReply, PublicKeyReply, SchnorrAlgorithm, SignatureReply,
+    SignatureVerificationReply, TaprootOutputRequest,
+};
 use candid::{decode_one, encode_args, encode_one};
 use candid::{CandidType, Principal};
 use pocket_ic::{PocketIc, PocketIcBuilder};
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 
 /// Tests every combination of algorithm (BIP340-secp256k1, Ed25519) and merkle root hash
 /// (absent, empty, invalid length, valid 32 bytes) for sign+verify, plus negative cases.
@@ -176,6 +180,354 @@ fn test_impl(pic: &PocketIc, algorithm: SchnorrAlgorithm, merkle_tree_root_bytes
     );
 }
 
+/// Tests that `sign_batch` signs every message with the caller's key below the
+/// given derivation path, that different callers get different keys, and that
+/// `public_key_for` returns the key of any principal, also before they called
+/// `public_key`.
+#[test]
+fn sign_batch_should_sign_with_caller_scoped_keys() {
+    const ALGORITHMS: [SchnorrAlgorithm; 2] =
+        [SchnorrAlgorithm::Bip340Secp256k1, SchnorrAlgorithm::Ed25519];
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let derivation_path = vec![
+        ByteBuf::from(b"wallet".to_vec()),
+        ByteBuf::from(vec![0, 0, 0, 1]),
+    ];
+    // 32-byte messages, so that they are valid BIP340 messages
+    let messages: Vec<String> = ["a", "b", "c"].iter().map(|c| c.repeat(32)).collect();
+
+    let pic = PocketIcBuilder::new()
+        .with_application_subnet()
+        .with_test_threshold_keys_subnet()
+        .build();
+    let canister_id = install_backend(&pic);
+
+    for algorithm in ALGORITHMS {
+        // derived before alice ever called `public_key`
+        let alice_public_key_for =
+            public_key_for(&pic, canister_id, alice, algorithm, &derivation_path)
+                .map(|reply| reply.public_key_hex);
+        let alice_public_key_hex =
+            public_key(&pic, canister_id, alice, algorithm, &derivation_path);
+        let bob_public_key_hex = public_key(&pic, canister_id, bob, algorithm, &derivation_path);
+        assert_ne!(alice_public_key_hex, bob_public_key_hex);
+        assert_ne!(
+            alice_public_key_hex,
+            public_key(&pic, canister_id, alice, algorithm, &[]),
+            "the derivation path should be part of the key"
+        );
+        assert_eq!(alice_public_key_for, Ok(alice_public_key_hex.clone()));
+
+        let signatures = update::<Result<Vec<Result<SignatureReply, String>>, String>>(
+            &pic,
+            alice,
+            canister_id,
+            "sign_batch",
+            encode_args((messages.clone(), algorithm, derivation_path.clone())).unwrap(),
+        )
+        .expect("update call failed")
+        .expect("sign_batch failed");
+        assert_eq!(signatures.len(), messages.len());
+
+        for (message, signature) in messages.iter().zip(signatures) {
+            let signature_hex = signature.expect("signing failed").signature_hex;
+            for (public_key_hex, is_signature_valid) in
+                [(&alice_public_key_hex, true), (&bob_public_key_hex, false)]
+            {
+                let reply = update::<Result<SignatureVerificationReply, String>>(
+                    &pic,
+                    alice,
+                    canister_id,
+                    "verify",
+                    encode_args((
+                        signature_hex.clone(),
+                        message.clone(),
+                        public_key_hex.clone(),
+                        None::<String>,
+                        algorithm,
+                    ))
+                    .unwrap(),
+                )
+                .expect("verify update call failed");
+                assert_eq!(reply, Ok(SignatureVerificationReply { is_signature_valid }));
+            }
+        }
+    }
+}
+
+/// Tests that `sign_batch` rejects empty and oversized batches, and reports
+/// failed signature requests per message.
+#[test]
+fn sign_batch_should_report_failures_per_message() {
+    let pic = PocketIcBuilder::new()
+        .with_application_subnet()
+        .with_test_threshold_keys_subnet()
+        .build();
+    let canister_id = install_backend(&pic);
+    let sign_batch = |messages: Vec<String>| {
+        update::<Result<Vec<Result<SignatureReply, String>>, String>>(
+            &pic,
+            Principal::anonymous(),
+            canister_id,
+            "sign_batch",
+            encode_args((messages, SchnorrAlgorithm::Ed25519, Vec::<ByteBuf>::new())).unwrap(),
+        )
+        .expect("update call failed")
+    };
+
+    assert!(sign_batch(vec![]).is_err());
+    assert!(sign_batch(vec!["message".to_string(); 17]).is_err());
+
+    // The canister itself does not implement `sign_with_schnorr`, so every
+    // signature request fails.
+    update::<Result<(), String>>(
+        &pic,
+        Principal::anonymous(),
+        canister_id,
+        "for_test_only_change_management_canister_id",
+        encode_one(canister_id.to_text()).unwrap(),
+    )
+    .expect("update call failed")
+    .expect("failed to change the management canister id");
+    let signatures = sign_batch(vec!["message".to_string(); 2]).expect("sign_batch failed");
+    assert_eq!(signatures.len(), 2);
+    assert!(signatures.iter().all(|signature| signature.is_err()));
+}
+
+/// Tests that the taproot output returned by `public_key` is the key that BIP-341
+/// signatures created with the same merkle root verify against, and that its
+/// address pays to that key.
+#[test]
+fn public_key_should_return_the_taproot_output() {
+    use bitcoin::secp256k1::{schnorr::Signature, Message, Secp256k1, XOnlyPublicKey};
+    use std::str::FromStr;
+
+    let pic = PocketIcBuilder::new()
+        .with_application_subnet()
+        .with_test_threshold_keys_subnet()
+        .build();
+    let canister_id = install_backend(&pic);
+    let message = "a".repeat(32);
+
+    for (merkle_tree_root_hex, network, hrp) in [
+        (String::new(), BitcoinNetwork::Mainnet, "bc1p"),
+        (hex::encode([1; 32]), BitcoinNetwork::Testnet, "tb1p"),
+        (hex::encode([2; 32]), BitcoinNetwork::Regtest, "bcrt1p"),
+    ] {
+        let reply = update::<Result<PublicKeyReply, String>>(
+            &pic,
+            Principal::anonymous(),
+            canister_id,
+            "public_key",
+            encode_args((
+                SchnorrAlgorithm::Bip340Secp256k1,
+                None::<Vec<ByteBuf>>,
+                Some(TaprootOutputRequest {
+                    merkle_tree_root_hex: merkle_tree_root_hex.clone(),
+                    network,
+                }),
+            ))
+            .unwrap(),
+        )
+        .expect("update call failed")
+        .expect("public_key failed");
+        let taproot_output = reply.taproot_output.expect("missing taproot output");
+        let output_key =
+            XOnlyPublicKey::from_slice(&hex::decode(&taproot_output.output_key_hex).unwrap())
+                .expect("invalid output key");
+        assert_ne!(
+            output_key.serialize()[..],
+            hex::decode(&reply.public_key_hex).unwrap()[1..],
+            "the output key should be tweaked"
+        );
+
+        let address = bitcoin::Address::from_str(&taproot_output.address)
+            .expect("invalid address")
+            .assume_checked();
+        assert!(taproot_output.address.starts_with(hrp));
+        assert_eq!(
+            address.script_pubkey(),
+            bitcoin::ScriptBuf::new_p2tr_tweaked(
+                bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(output_key)
+            )
+        );
+
+        let signature_hex = update::<Result<SignatureReply, String>>(
+            &pic,
+            Principal::anonymous(),
+            canister_id,
+            "sign",
+            encode_args((
+                message.clone(),
+                SchnorrAlgorithm::Bip340Secp256k1,
+                Some(merkle_tree_root_hex),
+            ))
+            .unwrap(),
+        )
+        .expect("update call failed")
+        .expect("sign failed")
+        .signature_hex;
+        let signature = Signature::from_slice(&hex::decode(signature_hex).unwrap()).unwrap();
+        Secp256k1::verification_only()
+            .verify_schnorr(
+                &signature,
+                &Message::from_digest_slice(message.as_bytes()).unwrap(),
+                &output_key,
+            )
+            .expect("the signature should verify against the output key");
+    }
+
+    // Ed25519 keys cannot be used for taproot outputs.
+    let reply = update::<Result<PublicKeyReply, String>>(
+        &pic,
+        Principal::anonymous(),
+        canister_id,
+        "public_key",
+        encode_args((
+            SchnorrAlgorithm::Ed25519,
+            None::<Vec<ByteBuf>>,
+            Some(TaprootOutputRequest {
+                merkle_tree_root_hex: String::new(),
+                network: BitcoinNetwork::Mainnet,
+            }),
+        ))
+        .unwrap(),
+    )
+    .expect("update call failed");
+    assert!(reply.is_err());
+}
+
+/// Tests that `sign_nostr_event` returns events with a NIP-01 id and a valid
+/// BIP-340 signature by the caller's Nostr key, as returned by `nostr_public_key`.
+#[test]
+fn nostr_events_should_be_signed_with_the_callers_key() {
+    use bitcoin::secp256k1::{schnorr::Signature, Message, Secp256k1, XOnlyPublicKey};
+    use sha2::Digest;
+
+    let pic = PocketIcBuilder::new()
+        .with_application_subnet()
+        .with_test_threshold_keys_subnet()
+        .build();
+    let canister_id = install_backend(&pic);
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+
+    let nostr_public_key = |sender| {
+        update::<Result<NostrPublicKeyReply, String>>(
+            &pic,
+            sender,
+            canister_id,
+            "nostr_public_key",
+            encode_args(()).unwrap(),
+        )
+        .expect("update call failed")
+        .expect("nostr_public_key failed")
+    };
+    let sign_nostr_event = |sender, unsigned_event: serde_json::Value| {
+        update::<Result<String, String>>(
+            &pic,
+            sender,
+            canister_id,
+            "sign_nostr_event",
+            encode_one(unsigned_event.to_string()).unwrap(),
+        )
+        .expect("update call failed")
+    };
+
+    let alice_key = nostr_public_key(alice);
+    assert_ne!(
+        alice_key.public_key_hex,
+        nostr_public_key(bob).public_key_hex
+    );
+    let (hrp, npub_data) = bitcoin::bech32::decode(&alice_key.npub).expect("invalid npub");
+    assert_eq!(hrp.as_str(), "npub");
+    assert_eq!(hex::encode(npub_data), alice_key.public_key_hex);
+    // the Nostr key is the caller's key below the derivation path "nostr"
+    assert_eq!(
+        public_key_for(
+            &pic,
+            canister_id,
+            alice,
+            SchnorrAlgorithm::Bip340Secp256k1,
+            &[ByteBuf::from(b"nostr".to_vec())],
+        )
+        .map(|reply| reply.public_key_hex[2..].to_string()),
+        Ok(alice_key.public_key_hex.clone())
+    );
+
+    for unsigned_event in [
+        serde_json::json!({
+            "created_at": 1_700_000_000,
+            "kind": 1,
+            "tags": [["t", "icp"], ["p", alice_key.public_key_hex]],
+            "content": "Hello \"Nostr\"\nfrom a canister \u{1F680}",
+        }),
+        serde_json::json!({
+            "pubkey": alice_key.public_key_hex,
+            "kind": 0,
+            "content": "{\"name\":\"alice\"}",
+        }),
+    ] {
+        let event: serde_json::Value = serde_json::from_str(
+            &sign_nostr_event(alice, unsigned_event.clone()).expect("sign_nostr_event failed"),
+        )
+        .expect("the signed event should be JSON");
+
+        assert_eq!(event["pubkey"], alice_key.public_key_hex.as_str());
+        assert_eq!(event["kind"], unsigned_event["kind"]);
+        assert_eq!(event["content"], unsigned_event["content"]);
+        assert_eq!(
+            event["tags"],
+            unsigned_event
+                .get("tags")
+                .cloned()
+                .unwrap_or(serde_json::json!([]))
+        );
+        if let Some(created_at) = unsigned_event.get("created_at") {
+            assert_eq!(&event["created_at"], created_at);
+        } else {
+            assert!(event["created_at"].as_u64().unwrap() > 1_700_000_000);
+        }
+
+        let serialized = serde_json::json!([
+            0,
+            event["pubkey"],
+            event["created_at"],
+            event["kind"],
+            event["tags"],
+            event["content"],
+        ])
+        .to_string();
+        let id = sha2::Sha256::digest(serialized.as_bytes());
+        assert_eq!(event["id"], hex::encode(id));
+
+        let signature =
+            Signature::from_slice(&hex::decode(event["sig"].as_str().unwrap()).unwrap()).unwrap();
+        let public_key =
+            XOnlyPublicKey::from_slice(&hex::decode(&alice_key.public_key_hex).unwrap()).unwrap();
+        Secp256k1::verification_only()
+            .verify_schnorr(
+                &signature,
+                &Message::from_digest_slice(&id).unwrap(),
+                &public_key,
+            )
+            .expect("the event signature should verify");
+    }
+
+    // Events can only be signed with the caller's own key.
+    assert!(sign_nostr_event(
+        bob,
+        serde_json::json!({
+            "pubkey": alice_key.public_key_hex,
+            "kind": 1,
+            "content": "impersonating alice",
+        })
+    )
+    .is_err());
+    assert!(sign_nostr_event(bob, serde_json::json!({ "kind": 1 })).is_err());
+}
+
 // ── Helpers ─────────────────────────────────────────────────────────────────
 
 fn load_backend_wasm() -> Vec<u8> {
@@ -213,6 +565,51 @@ fn update<T: CandidType + for<'de> Deserialize<'de>>(
     }
 }
 
+fn install_backend(pic: &PocketIc) -> Principal {
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    pic.install_canister(canister_id, load_backend_wasm(), vec![], None);
+    fast_forward(pic, 5);
+    canister_id
+}
+
+fn public_key(
+    pic: &PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+    algorithm: SchnorrAlgorithm,
+    derivation_path: &[ByteBuf],
+) -> String {
+    update::<Result<PublicKeyReply, String>>(
+        pic,
+        sender,
+        canister_id,
+        "public_key",
+        encode_args((algorithm, Some(derivation_path.to_vec()))).unwrap(),
+    )
+    .expect("update call failed")
+    .expect("public_key failed")
+    .public_key_hex
+}
+
+fn public_key_for(
+    pic: &PocketIc,
+    canister_id: Principal,
+    principal: Principal,
+    algorithm: SchnorrAlgorithm,
+    derivation_path: &[ByteBuf],
+) -> Result<PublicKeyReply, String> {
+    let reply = pic
+        .query_call(
+            canister_id,
+            Principal::anonymous(),
+            "public_key_for",
+            encode_args((principal, algorithm, Some(derivation_path.to_vec()))).unwrap(),
+        )
+        .expect("query call failed");
+    decode_one(&reply).expect("failed to decode reply")
+}
+
 fn fast_forward(ic: &PocketIc, ticks: u64) {
     for _ in 0..ticks {
         ic.tick();
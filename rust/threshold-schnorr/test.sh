@@ -1,31 +1,62 @@
 #!/usr/bin/env bash
 set -e
 
-echo "=== Test 1/5: public_key returns hex for bip340secp256k1 ==="
+echo "=== Test 1/9: public_key returns hex for bip340secp256k1 ==="
 result=$(icp canister call backend public_key '(variant { bip340secp256k1 })') && \
   echo "$result" && \
   echo "$result" | grep -q 'public_key_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 2/5: public_key returns hex for ed25519 ==="
+echo "=== Test 2/9: public_key returns hex for ed25519 ==="
 result=$(icp canister call backend public_key '(variant { ed25519 })') && \
   echo "$result" && \
   echo "$result" | grep -q 'public_key_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 3/5: sign returns signature_hex for bip340secp256k1 ==="
+echo "=== Test 3/9: sign returns signature_hex for bip340secp256k1 ==="
 result=$(icp canister call backend sign '("hello world of BIP340-secp256k1!", variant { bip340secp256k1 }, null)') && \
   echo "$result" && \
   echo "$result" | grep -q 'signature_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 4/5: sign returns signature_hex for ed25519 ==="
+echo "=== Test 4/9: sign returns signature_hex for ed25519 ==="
 result=$(icp canister call backend sign '("hello world", variant { ed25519 }, null)') && \
   echo "$result" && \
   echo "$result" | grep -q 'signature_hex' && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
-echo "=== Test 5/5: all three signature types verify cryptographically ==="
+echo "=== Test 5/9: sign_batch returns a signature per message ==="
+result=$(icp canister call backend sign_batch '(vec { "first message"; "second message" }, variant { ed25519 }, vec { blob "wallet" })') && \
+  echo "$result" && \
+  [ "$(echo "$result" | grep -c 'signature_hex')" -eq 2 ] && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 6/9: public_key_for returns the same key as public_key ==="
+caller=$(icp identity principal)
+public_key=$(icp canister call backend public_key '(variant { ed25519 }, opt vec { blob "wallet" })' | grep public_key_hex) && \
+  result=$(icp canister call backend public_key_for "(principal \"${caller}\", variant { ed25519 }, opt vec { blob \"wallet\" })") && \
+  echo "$result" && \
+  echo "$result" | grep -qF "$public_key" && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 7/9: public_key returns the taproot output for a merkle root ==="
+result=$(icp canister call backend public_key '(variant { bip340secp256k1 }, null, opt record { merkle_tree_root_hex = "012345678901234567890123456789012345678901234567890123456789abcd"; network = variant { regtest } })') && \
+  echo "$result" && \
+  echo "$result" | grep -q 'output_key_hex' && \
+  echo "$result" | grep -q 'address = "bcrt1p' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 8/9: sign_nostr_event returns a signed Nostr event ==="
+result=$(icp canister call backend nostr_public_key '()') && \
+  echo "$result" && \
+  echo "$result" | grep -q 'npub = "npub1' && \
+  result=$(icp canister call backend sign_nostr_event '("{\"kind\":1,\"tags\":[],\"content\":\"hello from a canister\"}")') && \
+  echo "$result" && \
+  echo "$result" | grep -q '\\"id\\":\\"[0-9a-f]\{64\}\\"' && \
+  echo "$result" | grep -q '\\"sig\\":\\"[0-9a-f]\{128\}\\"' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 9/9: all three signature types verify cryptographically ==="
 npm install --silent
 
 set -euo pipefail
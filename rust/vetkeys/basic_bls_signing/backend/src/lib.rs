@@ -1,14 +1,21 @@
+pub mod multisig;
 pub mod types;
 use candid::Principal;
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId, VetKDPublicKeyArgs};
-use ic_cdk::{init, query, update};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     Cell as StableCell, DefaultMemoryImpl, StableBTreeMap,
 };
+use ic_vetkeys::DerivedPublicKey;
+use multisig::{Attestation, AttestationId};
 use serde_bytes::ByteBuf;
 use std::cell::RefCell;
-use types::Signature;
+use std::time::Duration;
+use types::{
+    IndexBackfill, MessageHash, Signature, SignaturesPage, SignerSignature, SignerSignaturesPage,
+    INDEX_BACKFILL_BATCH_SIZE, MAX_SIGNATURES_PER_PAGE,
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -31,6 +38,26 @@ thread_local! {
             String::new(),
         )
         .expect("failed to initialize key name"));
+
+    static ATTESTATIONS: RefCell<StableBTreeMap<AttestationId, Attestation, Memory>> = RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4))),
+        ));
+
+    /// An index of SIGNATURES by the hash of the signed message.
+    static SIGNATURES_BY_MESSAGE_HASH: RefCell<StableBTreeMap<(MessageHash, Principal, Timestamp), (), Memory>> = RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(5))),
+        ));
+
+    /// How far the signatures stored before SIGNATURES_BY_MESSAGE_HASH existed are indexed.
+    static INDEX_BACKFILL: RefCell<StableCell<IndexBackfill, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+            IndexBackfill::default(),
+        )
+        .expect("failed to initialize the index backfill"));
+
+    /// The public key of the canister, from which the verification keys of all signers are derived offline.
+    static CANISTER_PUBLIC_KEY: RefCell<Option<DerivedPublicKey>> = const { RefCell::new(None) };
 }
 
 #[init]
@@ -40,6 +67,54 @@ fn init(key_name_string: String) {
             .set(key_name_string)
             .expect("failed to set key name");
     });
+    set_index_backfill(IndexBackfill::Done);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Index the signatures stored before the index existed. This is done in batches on a timer, so that neither the
+    // upgrade nor a single batch runs out of instructions.
+    if INDEX_BACKFILL.with_borrow(|backfill| *backfill.get() != IndexBackfill::Done) {
+        ic_cdk_timers::set_timer(Duration::ZERO, async { backfill_index_batch() });
+    }
+}
+
+/// Indexes the next batch of signatures stored before the index existed and schedules the following batch, if any.
+/// Signatures indexed by `sign_message` in the meantime are indexed again, which doesn't change the index.
+fn backfill_index_batch() {
+    let start = match INDEX_BACKFILL.with_borrow(|backfill| backfill.get().clone()) {
+        IndexBackfill::NotStarted => (Principal::management_canister(), 0),
+        IndexBackfill::InProgress { next } => next,
+        IndexBackfill::Done => return,
+    };
+    let mut batch: Vec<((Principal, Timestamp), Signature)> = SIGNATURES.with_borrow(|sigs| {
+        sigs.range(start..)
+            .take(INDEX_BACKFILL_BATCH_SIZE + 1)
+            .collect()
+    });
+    let next = if batch.len() > INDEX_BACKFILL_BATCH_SIZE {
+        batch.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+    for ((signer, timestamp), sig) in batch {
+        index_signature(signer, timestamp, &sig);
+    }
+
+    match next {
+        Some(next) => {
+            set_index_backfill(IndexBackfill::InProgress { next });
+            ic_cdk_timers::set_timer(Duration::ZERO, async { backfill_index_batch() });
+        }
+        None => set_index_backfill(IndexBackfill::Done),
+    }
+}
+
+fn set_index_backfill(backfill: IndexBackfill) {
+    INDEX_BACKFILL.with_borrow_mut(|cell| {
+        cell.set(backfill)
+            .expect("failed to set the index backfill");
+    });
 }
 
 #[update]
@@ -71,6 +146,7 @@ async fn sign_message(message: RawMessage) -> RawSignature {
             timestamp_for_mapkey += 1;
         }
 
+        index_signature(signer, timestamp_for_mapkey, &sig);
         assert!(sigs.insert((signer, timestamp_for_mapkey), sig).is_none());
     });
 
@@ -89,6 +165,64 @@ fn get_my_signatures() -> Vec<Signature> {
     })
 }
 
+/// Returns the signatures of `signer`, starting at `page`, which is the `next_page` of the previous page, or `None`
+/// for the first page.
+#[query]
+fn get_signatures_by_signer(signer: Principal, page: Option<Timestamp>) -> SignaturesPage {
+    SIGNATURES.with_borrow(|sigs| {
+        SignaturesPage::from_entries(
+            sigs.range((signer, page.unwrap_or(0))..)
+                .take_while(|((s, _ts), _sig)| s == &signer)
+                .map(|((_, ts), sig)| (ts, sig)),
+        )
+    })
+}
+
+/// Returns up to `MAX_SIGNATURES_PER_PAGE` stored signatures of the message with the given SHA-256 hash, starting at
+/// `start`, which is the `next` cursor of the previous page, or `None` for the first page. Right after an upgrade,
+/// signatures stored before the index existed may be missing until they are indexed.
+#[query]
+fn get_signatures_by_message_hash(
+    message_hash: ByteBuf,
+    start: Option<(Principal, Timestamp)>,
+) -> SignerSignaturesPage {
+    let Ok(message_hash) = MessageHash::try_from(message_hash.as_slice()) else {
+        return SignerSignaturesPage::default();
+    };
+    let (start_signer, start_timestamp) = start.unwrap_or((Principal::management_canister(), 0));
+    let mut keys: Vec<(Principal, Timestamp)> = SIGNATURES_BY_MESSAGE_HASH.with_borrow(|index| {
+        index
+            .range((message_hash, start_signer, start_timestamp)..)
+            .take_while(|((hash, _, _), ())| hash == &message_hash)
+            .take(MAX_SIGNATURES_PER_PAGE + 1)
+            .map(|((_, signer, ts), ())| (signer, ts))
+            .collect()
+    });
+    let next = if keys.len() > MAX_SIGNATURES_PER_PAGE {
+        keys.pop()
+    } else {
+        None
+    };
+    let signatures = SIGNATURES.with_borrow(|sigs| {
+        keys.into_iter()
+            .filter_map(|key| {
+                sigs.get(&key).map(|signature| SignerSignature {
+                    signer: key.0,
+                    signature,
+                })
+            })
+            .collect()
+    });
+    SignerSignaturesPage { signatures, next }
+}
+
+/// Verifies a signature of `message` by `signer` with the verification key of the signer.
+#[update]
+async fn verify_signature(signer: Principal, message: RawMessage, signature: RawSignature) -> bool {
+    let verification_key = verification_key(&signer).await;
+    ic_vetkeys::verify_bls_signature(&verification_key, message.as_bytes(), &signature)
+}
+
 #[update]
 async fn get_my_verification_key() -> VetKeyPublicKey {
     let request = VetKDPublicKeyArgs {
@@ -103,6 +237,166 @@ async fn get_my_verification_key() -> VetKeyPublicKey {
     VetKeyPublicKey::from(result.public_key)
 }
 
+/// Creates an attestation of `message`, which is complete once all `signers` have signed it.
+#[update]
+fn create_attestation(
+    message: RawMessage,
+    signers: Vec<Principal>,
+) -> Result<AttestationId, String> {
+    ATTESTATIONS.with_borrow_mut(|attestations| {
+        let id = attestations
+            .last_key_value()
+            .map_or(0, |(last_id, _)| last_id + 1);
+        let attestation = Attestation::new(
+            id,
+            message,
+            ic_cdk::api::msg_caller(),
+            signers,
+            ic_cdk::api::time(),
+        )?;
+        attestations.insert(id, attestation);
+        Ok(id)
+    })
+}
+
+/// Signs the message of an attestation for the caller, who must be one of its signers.
+#[update]
+async fn sign_attestation(attestation_id: AttestationId) -> Result<RawSignature, String> {
+    let signer = ic_cdk::api::msg_caller();
+    let attestation = get_attestation(attestation_id)
+        .ok_or_else(|| format!("attestation {attestation_id} not found"))?;
+    attestation.check_can_sign(&signer)?;
+
+    let signature_bytes = ic_vetkeys::management_canister::sign_with_bls(
+        attestation.message.into_bytes(),
+        context(&signer),
+        key_id(),
+    )
+    .await
+    .map_err(|e| format!("failed to sign: {e:?}"))?;
+    let signature = RawSignature::from(signature_bytes);
+
+    // The signer may have signed concurrently while we were waiting for the signature.
+    update_attestation(attestation_id, |attestation| {
+        attestation.check_can_sign(&signer)?;
+        attestation.signatures.insert(signer, signature.clone());
+        Ok(())
+    })?;
+    Ok(signature)
+}
+
+/// Aggregates the signatures of a complete attestation and stores the aggregate, after checking it against the
+/// verification keys of all signers.
+#[update]
+async fn aggregate_attestation(attestation_id: AttestationId) -> Result<RawSignature, String> {
+    let attestation = get_attestation(attestation_id)
+        .ok_or_else(|| format!("attestation {attestation_id} not found"))?;
+    if let Some(aggregate_signature) = attestation.aggregate_signature {
+        return Ok(aggregate_signature);
+    }
+    if !attestation.is_complete() {
+        return Err(format!(
+            "attestation {attestation_id} is missing signatures"
+        ));
+    }
+
+    let aggregate_signature = multisig::aggregate_signatures(
+        attestation
+            .signatures
+            .values()
+            .map(|signature| signature.as_slice()),
+    )?;
+    let public_keys = verification_keys(&attestation.signers).await;
+    if !multisig::verify_aggregate_signature(
+        &public_keys,
+        attestation.message.as_bytes(),
+        &aggregate_signature,
+    ) {
+        return Err(format!(
+            "the aggregate signature of attestation {attestation_id} is invalid"
+        ));
+    }
+
+    let aggregate_signature = RawSignature::from(aggregate_signature);
+    update_attestation(attestation_id, |attestation| {
+        attestation.aggregate_signature = Some(aggregate_signature.clone());
+        Ok(())
+    })?;
+    Ok(aggregate_signature)
+}
+
+/// Verifies an aggregate signature of `message` by all `signers`.
+#[update]
+async fn verify_aggregate_signature(
+    message: RawMessage,
+    signers: Vec<Principal>,
+    aggregate_signature: RawSignature,
+) -> bool {
+    let public_keys = verification_keys(&signers).await;
+    multisig::verify_aggregate_signature(&public_keys, message.as_bytes(), &aggregate_signature)
+}
+
+#[query]
+fn get_attestation(attestation_id: AttestationId) -> Option<Attestation> {
+    ATTESTATIONS.with_borrow(|attestations| attestations.get(&attestation_id))
+}
+
+fn update_attestation(
+    attestation_id: AttestationId,
+    f: impl FnOnce(&mut Attestation) -> Result<(), String>,
+) -> Result<(), String> {
+    ATTESTATIONS.with_borrow_mut(|attestations| {
+        let mut attestation = attestations
+            .get(&attestation_id)
+            .ok_or_else(|| format!("attestation {attestation_id} not found"))?;
+        f(&mut attestation)?;
+        attestations.insert(attestation_id, attestation);
+        Ok(())
+    })
+}
+
+/// Returns the verification keys of the signers, i.e., the keys returned by `get_my_verification_key` to each of
+/// them, which are derived from the public key of the canister.
+async fn verification_keys(signers: &[Principal]) -> Vec<Vec<u8>> {
+    let canister_public_key = canister_public_key().await;
+    signers
+        .iter()
+        .map(|signer| {
+            canister_public_key
+                .derive_sub_key(&context(signer))
+                .serialize()
+        })
+        .collect()
+}
+
+async fn verification_key(signer: &Principal) -> DerivedPublicKey {
+    canister_public_key().await.derive_sub_key(&context(signer))
+}
+
+fn index_signature(signer: Principal, timestamp: Timestamp, sig: &Signature) {
+    SIGNATURES_BY_MESSAGE_HASH.with_borrow_mut(|index| {
+        index.insert((types::message_hash(&sig.message), signer, timestamp), ());
+    });
+}
+
+async fn canister_public_key() -> DerivedPublicKey {
+    if let Some(public_key) = CANISTER_PUBLIC_KEY.with_borrow(|public_key| public_key.clone()) {
+        return public_key;
+    }
+    let request = VetKDPublicKeyArgs {
+        canister_id: None,
+        context: vec![],
+        key_id: key_id(),
+    };
+    let result = ic_cdk_management_canister::vetkd_public_key(&request)
+        .await
+        .expect("call to vetkd_public_key failed");
+    let public_key = DerivedPublicKey::deserialize(&result.public_key)
+        .expect("failed to deserialize the canister public key");
+    CANISTER_PUBLIC_KEY.with_borrow_mut(|cached| *cached = Some(public_key.clone()));
+    public_key
+}
+
 fn context(signer: &Principal) -> Vec<u8> {
     // A domain separator is not strictly necessary in this app, but having one is considered a good practice.
     const DOMAIN_SEPARATOR: [u8; 21] = *b"basic_bls_signing_app";
@@ -0,0 +1,299 @@
+//! Multi-party attestations. Several principals sign the same message, each with the vetKey derived for their
+//! principal, and their signatures are aggregated into a single BLS signature of the same size. Since the signatures
+//! use message augmentation, i.e., every signer effectively signs their public key together with the message, an
+//! aggregate can be verified against the set of the signers' public keys without any proofs of possession.
+
+use candid::{CandidType, Principal};
+use ic_bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
+use ic_bls12_381::{multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, Gt};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Neg;
+
+pub type AttestationId = u64;
+
+pub const MAX_SIGNERS_PER_ATTESTATION: usize = 100;
+
+/// The domain separator of the augmented BLS signatures produced by vetKD, see
+/// <https://datatracker.ietf.org/doc/html/draft-irtf-cfrg-bls-signature#name-message-augmentation>.
+const AUGMENTED_SIGNATURE_DOMAIN_SEPARATOR: &[u8] = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_AUG_";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attestation {
+    pub id: AttestationId,
+    pub message: String,
+    pub creator: Principal,
+    /// The principals who need to sign the message for the attestation to be complete
+    pub signers: Vec<Principal>,
+    pub signatures: BTreeMap<Principal, ByteBuf>,
+    /// The aggregate of the signatures of all signers, once the attestation is complete and aggregated
+    pub aggregate_signature: Option<ByteBuf>,
+    pub created_at: u64,
+}
+
+impl Attestation {
+    pub fn new(
+        id: AttestationId,
+        message: String,
+        creator: Principal,
+        signers: Vec<Principal>,
+        created_at: u64,
+    ) -> Result<Self, String> {
+        if signers.is_empty() {
+            return Err("an attestation needs at least one signer".to_string());
+        }
+        if signers.len() > MAX_SIGNERS_PER_ATTESTATION {
+            return Err(format!(
+                "an attestation can have at most {MAX_SIGNERS_PER_ATTESTATION} signers"
+            ));
+        }
+        if signers.contains(&Principal::anonymous()) {
+            return Err("the anonymous principal cannot be a signer".to_string());
+        }
+        let mut sorted_signers = signers.clone();
+        sorted_signers.sort();
+        sorted_signers.dedup();
+        if sorted_signers.len() != signers.len() {
+            return Err("the signers must be distinct".to_string());
+        }
+        Ok(Self {
+            id,
+            message,
+            creator,
+            signers,
+            signatures: BTreeMap::new(),
+            aggregate_signature: None,
+            created_at,
+        })
+    }
+
+    /// Checks that `signer` may still add their signature.
+    pub fn check_can_sign(&self, signer: &Principal) -> Result<(), String> {
+        if !self.signers.contains(signer) {
+            return Err(format!(
+                "{signer} is not a signer of attestation {}",
+                self.id
+            ));
+        }
+        if self.signatures.contains_key(signer) {
+            return Err(format!("{signer} already signed attestation {}", self.id));
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signers
+            .iter()
+            .all(|signer| self.signatures.contains_key(signer))
+    }
+}
+
+impl Storable for Attestation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Aggregates BLS signatures by adding them up.
+pub fn aggregate_signatures<'a>(
+    signatures: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Vec<u8>, String> {
+    let mut aggregate = G1Projective::identity();
+    for signature in signatures {
+        aggregate += deserialize_signature(signature)?;
+    }
+    Ok(G1Affine::from(aggregate).to_compressed().to_vec())
+}
+
+/// Verifies an aggregate signature of `message` against the public keys of all signers, i.e., checks that
+/// `e(aggregate, G2) == e(H(pk_1 || message), pk_1) * ... * e(H(pk_n || message), pk_n)`.
+pub fn verify_aggregate_signature(
+    public_keys: &[Vec<u8>],
+    message: &[u8],
+    aggregate_signature: &[u8],
+) -> bool {
+    if public_keys.is_empty() {
+        return false;
+    }
+    let Ok(aggregate_signature) = deserialize_signature(aggregate_signature) else {
+        return false;
+    };
+    let Ok(public_keys) = public_keys
+        .iter()
+        .map(|public_key| deserialize_public_key(public_key))
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return false;
+    };
+
+    let hashes: Vec<G1Affine> = public_keys
+        .iter()
+        .map(|public_key| augmented_hash_to_g1(public_key, message))
+        .collect();
+    let neg_generator = G2Prepared::from(G2Affine::generator().neg());
+    let prepared_public_keys: Vec<G2Prepared> = public_keys
+        .iter()
+        .map(|public_key| G2Prepared::from(*public_key))
+        .collect();
+
+    let mut terms = vec![(&aggregate_signature, &neg_generator)];
+    terms.extend(hashes.iter().zip(prepared_public_keys.iter()));
+    multi_miller_loop(&terms).final_exponentiation() == Gt::identity()
+}
+
+fn augmented_hash_to_g1(public_key: &G2Affine, message: &[u8]) -> G1Affine {
+    let input = [public_key.to_compressed().as_slice(), message].concat();
+    G1Affine::from(
+        <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(
+            input,
+            AUGMENTED_SIGNATURE_DOMAIN_SEPARATOR,
+        ),
+    )
+}
+
+fn deserialize_signature(bytes: &[u8]) -> Result<G1Affine, String> {
+    let bytes: &[u8; 48] = bytes
+        .try_into()
+        .map_err(|_| format!("invalid signature length {}", bytes.len()))?;
+    G1Affine::from_compressed(bytes)
+        .into_option()
+        .ok_or_else(|| "invalid signature".to_string())
+}
+
+fn deserialize_public_key(bytes: &[u8]) -> Result<G2Affine, String> {
+    let bytes: &[u8; 96] = bytes
+        .try_into()
+        .map_err(|_| format!("invalid public key length {}", bytes.len()))?;
+    G2Affine::from_compressed(bytes)
+        .into_option()
+        .filter(|public_key| !bool::from(public_key.is_identity()))
+        .ok_or_else(|| "invalid public key".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_bls12_381::Scalar;
+    use ic_vetkeys::{verify_bls_signature, DerivedPublicKey};
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    /// Returns the public key and the signature of `message` for the secret key `secret_key`.
+    fn sign(secret_key: u64, message: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let secret_key = Scalar::from(secret_key);
+        let public_key = G2Affine::from(G2Affine::generator() * secret_key);
+        let signature = G1Affine::from(augmented_hash_to_g1(&public_key, message) * secret_key);
+        (
+            public_key.to_compressed().to_vec(),
+            signature.to_compressed().to_vec(),
+        )
+    }
+
+    #[test]
+    fn signatures_are_compatible_with_vetkeys() {
+        let (public_key, signature) = sign(42, b"message");
+        let public_key = DerivedPublicKey::deserialize(&public_key).unwrap();
+        assert!(verify_bls_signature(&public_key, b"message", &signature));
+        assert!(!verify_bls_signature(
+            &public_key,
+            b"other message",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn aggregate_verifies_only_against_all_signers() {
+        let signed: Vec<_> = [3, 5, 7]
+            .into_iter()
+            .map(|sk| sign(sk, b"message"))
+            .collect();
+        let public_keys: Vec<Vec<u8>> = signed.iter().map(|(pk, _)| pk.clone()).collect();
+        let aggregate = aggregate_signatures(signed.iter().map(|(_, sig)| sig.as_slice())).unwrap();
+
+        assert!(verify_aggregate_signature(
+            &public_keys,
+            b"message",
+            &aggregate
+        ));
+        assert!(!verify_aggregate_signature(
+            &public_keys,
+            b"other message",
+            &aggregate
+        ));
+        assert!(!verify_aggregate_signature(
+            &public_keys[..2],
+            b"message",
+            &aggregate
+        ));
+        assert!(!verify_aggregate_signature(&[], b"message", &aggregate));
+
+        let partial =
+            aggregate_signatures(signed[..2].iter().map(|(_, sig)| sig.as_slice())).unwrap();
+        assert!(!verify_aggregate_signature(
+            &public_keys,
+            b"message",
+            &partial
+        ));
+        assert!(verify_aggregate_signature(
+            &public_keys[..2],
+            b"message",
+            &partial
+        ));
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        assert!(aggregate_signatures([[0u8; 47].as_slice()]).is_err());
+        assert!(aggregate_signatures([[0xffu8; 48].as_slice()]).is_err());
+        let (public_key, _) = sign(3, b"message");
+        assert!(!verify_aggregate_signature(
+            &[public_key],
+            b"message",
+            &[0; 48]
+        ));
+    }
+
+    #[test]
+    fn signers_must_be_distinct_and_sign_once() {
+        assert!(Attestation::new(0, "m".to_string(), principal(1), vec![], 0).is_err());
+        assert!(Attestation::new(
+            0,
+            "m".to_string(),
+            principal(1),
+            vec![principal(2), principal(2)],
+            0
+        )
+        .is_err());
+
+        let mut attestation = Attestation::new(
+            0,
+            "m".to_string(),
+            principal(1),
+            vec![principal(2), principal(3)],
+            0,
+        )
+        .unwrap();
+        assert!(attestation.check_can_sign(&principal(1)).is_err());
+        assert!(attestation.check_can_sign(&principal(2)).is_ok());
+        attestation
+            .signatures
+            .insert(principal(2), ByteBuf::from(vec![0; 48]));
+        assert!(attestation.check_can_sign(&principal(2)).is_err());
+        assert!(!attestation.is_complete());
+        attestation
+            .signatures
+            .insert(principal(3), ByteBuf::from(vec![0; 48]));
+        assert!(attestation.is_complete());
+    }
+}
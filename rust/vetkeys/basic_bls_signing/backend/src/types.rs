@@ -5,8 +5,14 @@ use ic_stable_structures::{storable::Bound, Storable};
 use serde::{Deserialize, Serialize};
 
 pub type CanisterId = Principal;
+/// The SHA-256 hash of a signed message, by which signatures can be looked up
+pub type MessageHash = [u8; 32];
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub const MAX_SIGNATURES_PER_PAGE: usize = 50;
+/// The number of signatures indexed by a single timer run when backfilling the index by message hash after an upgrade
+pub const INDEX_BACKFILL_BATCH_SIZE: usize = 1_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 
 pub struct Signature {
     pub message: String,
@@ -16,7 +22,7 @@ pub struct Signature {
 }
 
 impl Storable for Signature {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -26,3 +32,98 @@ impl Storable for Signature {
 
     const BOUND: Bound = Bound::Unbounded;
 }
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignaturesPage {
+    pub signatures: Vec<Signature>,
+    /// The page to request next, if there are more signatures
+    pub next_page: Option<u64>,
+}
+
+impl SignaturesPage {
+    /// Builds a page from the `(map key timestamp, signature)` entries of a signer, starting at the requested page.
+    pub fn from_entries(entries: impl IntoIterator<Item = (u64, Signature)>) -> Self {
+        let mut entries = entries.into_iter();
+        let signatures = entries
+            .by_ref()
+            .take(MAX_SIGNATURES_PER_PAGE)
+            .map(|(_, signature)| signature)
+            .collect();
+        Self {
+            signatures,
+            next_page: entries.next().map(|(timestamp, _)| timestamp),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SignerSignature {
+    pub signer: Principal,
+    pub signature: Signature,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SignerSignaturesPage {
+    pub signatures: Vec<SignerSignature>,
+    /// The `(signer, timestamp)` cursor to request next, if there are more signatures
+    pub next: Option<(Principal, u64)>,
+}
+
+/// The progress of indexing the signatures that were stored before the index by message hash existed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IndexBackfill {
+    #[default]
+    NotStarted,
+    /// The signatures before the `(signer, timestamp)` key are indexed
+    InProgress {
+        next: (Principal, u64),
+    },
+    Done,
+}
+
+impl Storable for IndexBackfill {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn message_hash(message: &str) -> MessageHash {
+    use sha2::Digest;
+    sha2::Sha256::digest(message.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(timestamp: u64) -> Signature {
+        Signature {
+            message: "message".to_string(),
+            signature: vec![],
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn pages_point_to_the_next_entry() {
+        let entries = (0..MAX_SIGNATURES_PER_PAGE as u64 + 1).map(|ts| (ts * 10, signature(ts)));
+        let page = SignaturesPage::from_entries(entries);
+        assert_eq!(page.signatures.len(), MAX_SIGNATURES_PER_PAGE);
+        assert_eq!(page.next_page, Some(MAX_SIGNATURES_PER_PAGE as u64 * 10));
+
+        let page = SignaturesPage::from_entries((0..3).map(|ts| (ts, signature(ts))));
+        assert_eq!(page.signatures.len(), 3);
+        assert_eq!(page.next_page, None);
+    }
+
+    #[test]
+    fn message_hash_is_sha256() {
+        assert_eq!(message_hash("abc")[..4], [0xba, 0x78, 0x16, 0xbf],);
+    }
+}
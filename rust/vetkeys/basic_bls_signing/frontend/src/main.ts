@@ -2,7 +2,13 @@ import "./style.css";
 import { Principal } from "@icp-sdk/core/principal";
 import { AuthClient, LocalStorage } from "@icp-sdk/auth/client";
 import { HttpAgent } from "@icp-sdk/core/agent";
-import { createActor, type Backend, type Signature } from "./bindings/backend";
+import {
+  createActor,
+  type Attestation,
+  type Backend,
+  type Signature,
+  type SignerSignature,
+} from "./bindings/backend";
 import { DerivedPublicKey, verifyBlsSignature } from "@icp-sdk/vetkeys";
 import { safeGetCanisterEnv } from "@icp-sdk/core/agent/canister-env";
 
@@ -84,15 +90,19 @@ function updateUI(isAuthenticated: boolean) {
   const principalDisplay = document.getElementById("principalDisplay")!;
   const logoutButton = document.getElementById("logoutButton")!;
   const signingActions = document.getElementById("signingActions")!;
+  const attestationActions = document.getElementById("attestationActions")!;
   const customSignatureForm = document.getElementById("customSignatureForm")!;
   const signaturesList = document.getElementById("signaturesList")!;
+  const attestationDetails = document.getElementById("attestationDetails")!;
 
   loginButton.classList.toggle("hidden", isAuthenticated);
   principalDisplay.classList.toggle("hidden", !isAuthenticated);
   logoutButton.classList.toggle("hidden", !isAuthenticated);
   signingActions.classList.toggle("hidden", !isAuthenticated);
+  attestationActions.classList.toggle("hidden", !isAuthenticated);
   customSignatureForm.classList.toggle("hidden", true);
   signaturesList.classList.toggle("hidden", true);
+  attestationDetails.classList.toggle("hidden", true);
 
   if (isAuthenticated && myPrincipal) {
     principalDisplay.textContent = `Principal: ${myPrincipal.toString()}`;
@@ -121,6 +131,13 @@ document.querySelector<HTMLDivElement>("#app")!.innerHTML = `
       <button id="signMessageButton">Sign Message</button>
       <button id="listSignaturesButton">List Signatures</button>
       <button id="customSignatureButton">Verify Custom Signature</button>
+      <button id="lookUpBySignerButton">Look Up by Signer</button>
+      <button id="lookUpByMessageButton">Look Up by Message</button>
+    </div>
+    <div id="attestationActions" class="buttons">
+      <button id="createAttestationButton">Create Attestation</button>
+      <button id="signAttestationButton">Sign Attestation</button>
+      <button id="showAttestationButton">Show Attestation</button>
     </div>
     <div id="customSignatureForm">
       <h3>Verify Custom Signature</h3>
@@ -141,9 +158,13 @@ document.querySelector<HTMLDivElement>("#app")!.innerHTML = `
       </form>
     </div>
     <div id="signaturesList">
-      <h3>My Signatures</h3>
+      <h3 id="signaturesTitle">My Signatures</h3>
       <div id="signatures"></div>
     </div>
+    <div id="attestationDetails">
+      <h3>Attestation</h3>
+      <div id="attestation"></div>
+    </div>
   </div>
 `;
 
@@ -171,6 +192,9 @@ document
       .getElementById("customSignatureForm")!
       .classList.toggle("hidden", false);
     document.getElementById("signaturesList")!.classList.toggle("hidden", true);
+    document
+      .getElementById("attestationDetails")!
+      .classList.toggle("hidden", true);
   });
 
 document
@@ -213,9 +237,219 @@ document
     }
   });
 
+document
+  .getElementById("createAttestationButton")!
+  .addEventListener("click", () => {
+    void (async () => {
+      const message = prompt("Enter message to attest:");
+      if (!message) return;
+      const signersText = prompt(
+        "Enter the principals of the signers (comma-separated):",
+        myPrincipal?.toString() ?? "",
+      );
+      if (!signersText) return;
+      try {
+        const signers = signersText
+          .split(",")
+          .map((signer) => Principal.fromText(signer.trim()));
+        const result = await (
+          await getBasicBlsSigningActor()
+        ).create_attestation(message, signers);
+        if ("Err" in result) throw Error(result.Err);
+        alert(`Created attestation ${result.Ok}.`);
+      } catch (error) {
+        alert(`Error: ${error as Error}`);
+      }
+    })();
+  });
+
+document
+  .getElementById("signAttestationButton")!
+  .addEventListener("click", () => {
+    void (async () => {
+      const id = prompt("Enter the ID of the attestation to sign:");
+      if (!id) return;
+      try {
+        const result = await (
+          await getBasicBlsSigningActor()
+        ).sign_attestation(BigInt(id));
+        if ("Err" in result) throw Error(result.Err);
+        alert("Signed the attestation successfully.");
+      } catch (error) {
+        alert(`Error: ${error as Error}`);
+      }
+    })();
+  });
+
+document
+  .getElementById("showAttestationButton")!
+  .addEventListener("click", () => {
+    void (async () => {
+      const id = prompt("Enter the ID of the attestation to show:");
+      if (!id) return;
+      try {
+        await showAttestation(BigInt(id));
+      } catch (error) {
+        alert(`Error: ${error as Error}`);
+      }
+    })();
+  });
+
+document
+  .getElementById("lookUpBySignerButton")!
+  .addEventListener("click", () => {
+    void (async () => {
+      const signerText = prompt("Enter the principal of the signer:");
+      if (!signerText) return;
+      try {
+        const signer = Principal.fromText(signerText.trim());
+        const page = await (
+          await getBasicBlsSigningActor()
+        ).get_signatures_by_signer(signer, []);
+        await showLookedUpSignatures(
+          `Signatures of ${signer.toString()}`,
+          page.signatures.map((signature) => ({ signer, signature })),
+        );
+      } catch (error) {
+        alert(`Error: ${error as Error}`);
+      }
+    })();
+  });
+
+document
+  .getElementById("lookUpByMessageButton")!
+  .addEventListener("click", () => {
+    void (async () => {
+      const message = prompt("Enter the signed message:");
+      if (!message) return;
+      try {
+        const messageHash = new Uint8Array(
+          await crypto.subtle.digest(
+            "SHA-256",
+            new TextEncoder().encode(message),
+          ),
+        );
+        const page = await (
+          await getBasicBlsSigningActor()
+        ).get_signatures_by_message_hash(messageHash, []);
+        await showLookedUpSignatures(
+          `Signatures of "${message}"`,
+          page.signatures,
+        );
+      } catch (error) {
+        alert(`Error: ${error as Error}`);
+      }
+    })();
+  });
+
+async function showLookedUpSignatures(
+  title: string,
+  signatures: Array<SignerSignature>,
+) {
+  const actor = await getBasicBlsSigningActor();
+  document.getElementById("signaturesTitle")!.textContent = title;
+  const signaturesDiv = document.getElementById("signatures")!;
+  signaturesDiv.innerHTML = "";
+
+  if (signatures.length === 0) {
+    signaturesDiv.innerHTML = `
+        <div class="no-signatures">
+          <p>No signatures found.</p>
+        </div>
+      `;
+  }
+  for (const { signer, signature: signatureData } of signatures) {
+    // The canister verifies the signature with the verification key it derives for the signer.
+    const isValid = await actor.verify_signature(
+      signer,
+      signatureData.message,
+      signatureData.signature,
+    );
+    const timestamp = new Date(Number(signatureData.timestamp) / 1_000_000);
+
+    const signatureElement = document.createElement("div");
+    signatureElement.className = "signature";
+    signatureElement.innerHTML = `
+        <h5>Signed message: ${signatureData.message}</h5>
+        <p class="signature-hex">Signer: ${signer.toString()}</p>
+        <p class="signature-hex">Signature: ${toHex(signatureData.signature)}</p>
+        <p class="verification-status ${isValid ? "valid" : "invalid"}">Verification: ${isValid ? "Valid" : "Invalid"}</p>
+        <p class="timestamp">Added: ${timestamp.toLocaleString()}</p>
+          `;
+    signaturesDiv.appendChild(signatureElement);
+  }
+
+  document.getElementById("signaturesList")!.classList.toggle("hidden", false);
+  document
+    .getElementById("attestationDetails")!
+    .classList.toggle("hidden", true);
+  document
+    .getElementById("customSignatureForm")!
+    .classList.toggle("hidden", true);
+}
+
+function toHex(bytes: Uint8Array | number[]): string {
+  return Array.from(bytes)
+    .map((b) => b.toString(16).padStart(2, "0"))
+    .join("");
+}
+
+async function showAttestation(id: bigint) {
+  const actor = await getBasicBlsSigningActor();
+  const found = await actor.get_attestation(id);
+  if (found.length === 0) throw Error(`Attestation ${id} not found`);
+  let attestation: Attestation = found[0];
+
+  const signed = new Set(
+    attestation.signatures.map(([signer]) => signer.toString()),
+  );
+  const isComplete = attestation.signers.every((signer) =>
+    signed.has(signer.toString()),
+  );
+  if (isComplete && attestation.aggregate_signature.length === 0) {
+    const result = await actor.aggregate_attestation(id);
+    if ("Err" in result) throw Error(result.Err);
+    attestation = { ...attestation, aggregate_signature: [result.Ok] };
+  }
+
+  let aggregateStatus = "Waiting for signatures";
+  if (attestation.aggregate_signature.length > 0) {
+    // The canister verifies the aggregate against the verification keys of all signers.
+    const isValid = await actor.verify_aggregate_signature(
+      attestation.message,
+      attestation.signers,
+      attestation.aggregate_signature[0],
+    );
+    aggregateStatus = `${toHex(attestation.aggregate_signature[0])} (${isValid ? "Valid" : "Invalid"})`;
+  }
+
+  const signersHtml = attestation.signers
+    .map(
+      (signer) =>
+        `<li>${signer.toString()}: ${signed.has(signer.toString()) ? "signed" : "pending"}</li>`,
+    )
+    .join("");
+  document.getElementById("attestation")!.innerHTML = `
+    <div class="signature">
+      <h5>Attested message: ${attestation.message}</h5>
+      <ul>${signersHtml}</ul>
+      <p class="signature-hex">Aggregate signature: ${aggregateStatus}</p>
+    </div>
+  `;
+
+  document
+    .getElementById("attestationDetails")!
+    .classList.toggle("hidden", false);
+  document.getElementById("signaturesList")!.classList.toggle("hidden", true);
+  document
+    .getElementById("customSignatureForm")!
+    .classList.toggle("hidden", true);
+}
+
 async function listSignatures() {
   const actor = await getBasicBlsSigningActor();
   const signatures: Array<Signature> = await actor.get_my_signatures();
+  document.getElementById("signaturesTitle")!.textContent = "My Signatures";
   const signaturesDiv = document.getElementById("signatures")!;
   signaturesDiv.innerHTML = "";
 
@@ -267,6 +501,9 @@ async function listSignatures() {
   }
 
   document.getElementById("signaturesList")!.classList.toggle("hidden", false);
+  document
+    .getElementById("attestationDetails")!
+    .classList.toggle("hidden", true);
   document
     .getElementById("customSignatureForm")!
     .classList.toggle("hidden", true);
@@ -0,0 +1,217 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+pub type GroupId = u64;
+pub type GroupMessageId = u64;
+/// Every removal of a member starts a new key epoch, so that the removed member cannot obtain the key used for later
+/// messages.
+pub type KeyEpoch = u64;
+
+pub const MAX_MEMBERS_PER_GROUP: usize = 100;
+pub const MAX_GROUPS_PER_OWNER: u64 = 100;
+pub const MAX_GROUPS_PER_PAGE: usize = 50;
+pub const MAX_GROUP_MESSAGE_SIZE: usize = 10_000;
+pub const MAX_MESSAGES_PER_GROUP: u64 = 10_000;
+pub const MAX_GROUP_MESSAGES_PER_PAGE: usize = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub id: GroupId,
+    pub name: String,
+    pub owner: Principal,
+    /// The members, each with the first key epoch whose key they may obtain
+    pub members: BTreeMap<Principal, KeyEpoch>,
+    pub key_epoch: KeyEpoch,
+    pub next_message_id: GroupMessageId,
+    /// The ID of the oldest message that was not evicted
+    #[serde(default)]
+    pub first_message_id: GroupMessageId,
+}
+
+impl Group {
+    pub fn new(id: GroupId, name: String, owner: Principal) -> Self {
+        Self {
+            id,
+            name,
+            owner,
+            members: BTreeMap::from([(owner, 0)]),
+            key_epoch: 0,
+            next_message_id: 0,
+            first_message_id: 0,
+        }
+    }
+
+    pub fn is_member(&self, principal: &Principal) -> bool {
+        self.members.contains_key(principal)
+    }
+
+    /// Adds a member, who can obtain the keys of the current and all later key epochs.
+    pub fn add_member(&mut self, member: Principal) -> Result<(), String> {
+        if member == Principal::anonymous() {
+            return Err("the anonymous principal cannot be a member".to_string());
+        }
+        if self.is_member(&member) {
+            return Err(format!("{member} is already a member"));
+        }
+        if self.members.len() >= MAX_MEMBERS_PER_GROUP {
+            return Err(format!("group {} is full", self.id));
+        }
+        self.members.insert(member, self.key_epoch);
+        Ok(())
+    }
+
+    /// Removes a member and rotates the group key by starting a new key epoch.
+    pub fn remove_member(&mut self, member: &Principal) -> Result<KeyEpoch, String> {
+        if *member == self.owner {
+            return Err("the owner cannot be removed".to_string());
+        }
+        if self.members.remove(member).is_none() {
+            return Err(format!("{member} is not a member"));
+        }
+        self.key_epoch += 1;
+        Ok(self.key_epoch)
+    }
+
+    /// A member may obtain the keys from the epoch they joined in up to the current one.
+    pub fn may_get_key(&self, principal: &Principal, key_epoch: KeyEpoch) -> bool {
+        self.members
+            .get(principal)
+            .is_some_and(|joined_in| *joined_in <= key_epoch && key_epoch <= self.key_epoch)
+    }
+
+    pub fn take_message_id(&mut self) -> GroupMessageId {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        id
+    }
+
+    /// Makes room for a new message if the group holds `MAX_MESSAGES_PER_GROUP` messages, and returns the ID of the
+    /// oldest message, which is to be removed. Only members can send messages, so an owner can stop a member flooding
+    /// the group by removing them.
+    pub fn evict_oldest_message(&mut self) -> Option<GroupMessageId> {
+        if self.next_message_id - self.first_message_id < MAX_MESSAGES_PER_GROUP {
+            return None;
+        }
+        let id = self.first_message_id;
+        self.first_message_id += 1;
+        Some(id)
+    }
+}
+
+/// The vetKD input from which the key of a group in a key epoch is derived.
+pub fn group_key_input(group_id: GroupId, key_epoch: KeyEpoch) -> Vec<u8> {
+    [group_id.to_be_bytes(), key_epoch.to_be_bytes()].concat()
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GroupMessage {
+    pub id: GroupMessageId,
+    pub sender: Principal,
+    /// The key epoch of the group key the message is encrypted with
+    pub key_epoch: KeyEpoch,
+    #[serde(with = "serde_bytes")]
+    pub encrypted_message: Vec<u8>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GroupsPage {
+    pub groups: Vec<Group>,
+    /// The cursor to pass to get the next page, if there are more groups
+    pub next_cursor: Option<GroupId>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GroupMessagesPage {
+    pub messages: Vec<GroupMessage>,
+    /// Whether there are more messages after the last one in this page
+    pub has_more: bool,
+}
+
+impl Storable for Group {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for GroupMessage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn removing_a_member_rotates_the_key() {
+        let mut group = Group::new(0, "group".to_string(), principal(1));
+        group.add_member(principal(2)).unwrap();
+        group.add_member(principal(3)).unwrap();
+        assert!(group.may_get_key(&principal(2), 0));
+
+        assert_eq!(group.remove_member(&principal(2)), Ok(1));
+        assert!(!group.may_get_key(&principal(2), 0));
+        assert!(!group.may_get_key(&principal(2), 1));
+        assert!(group.may_get_key(&principal(3), 0));
+        assert!(group.may_get_key(&principal(3), 1));
+        assert!(!group.may_get_key(&principal(3), 2));
+    }
+
+    #[test]
+    fn new_members_only_get_keys_from_their_epoch_on() {
+        let mut group = Group::new(0, "group".to_string(), principal(1));
+        group.add_member(principal(2)).unwrap();
+        group.remove_member(&principal(2)).unwrap();
+        group.add_member(principal(3)).unwrap();
+        assert!(!group.may_get_key(&principal(3), 0));
+        assert!(group.may_get_key(&principal(3), 1));
+        assert!(group.may_get_key(&principal(1), 0));
+    }
+
+    #[test]
+    fn owner_cannot_be_removed() {
+        let mut group = Group::new(0, "group".to_string(), principal(1));
+        assert!(group.remove_member(&principal(1)).is_err());
+        assert!(group.remove_member(&principal(2)).is_err());
+        assert_eq!(group.key_epoch, 0);
+    }
+
+    #[test]
+    fn full_groups_evict_the_oldest_message() {
+        let mut group = Group::new(0, "group".to_string(), principal(1));
+        for _ in 0..MAX_MESSAGES_PER_GROUP {
+            assert_eq!(group.evict_oldest_message(), None);
+            group.take_message_id();
+        }
+        assert_eq!(group.evict_oldest_message(), Some(0));
+        assert_eq!(group.take_message_id(), MAX_MESSAGES_PER_GROUP);
+        assert_eq!(group.evict_oldest_message(), Some(1));
+    }
+
+    #[test]
+    fn key_inputs_differ_per_group_and_epoch() {
+        assert_ne!(group_key_input(1, 0), group_key_input(0, 1));
+        assert_eq!(group_key_input(1, 2).len(), 16);
+    }
+}
@@ -1,12 +1,14 @@
 use candid::Principal;
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_cdk_management_canister::{VetKDCurve, VetKDDeriveKeyArgs, VetKDKeyId, VetKDPublicKeyArgs};
-use ic_cdk::{init, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BTreeMap as StableBTreeMap, Cell as StableCell, DefaultMemoryImpl};
 use serde_bytes::ByteBuf;
 use std::cell::RefCell;
 
+mod group;
 mod types;
+use group::*;
 use types::*;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -26,9 +28,39 @@ thread_local! {
             String::new(),
         )
         .expect("failed to initialize key name"));
+    static GROUPS: RefCell<StableBTreeMap<GroupId, Group, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+    ));
+    static GROUP_MESSAGES: RefCell<StableBTreeMap<(GroupId, GroupMessageId), GroupMessage, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+    ));
+    /// The senders each user blocked, keyed by (user, blocked sender)
+    static BLOCKED_SENDERS: RefCell<StableBTreeMap<(Principal, Principal), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+    ));
+    static RETENTION_POLICY: RefCell<StableCell<RetentionPolicy, Memory>> =
+        RefCell::new(StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+            RetentionPolicy::default(),
+        )
+        .expect("failed to initialize retention policy"));
+    /// The groups each principal is a member of, keyed by (member, group)
+    static MEMBER_GROUPS: RefCell<StableBTreeMap<(Principal, GroupId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+    ));
+    /// The number of groups each principal created
+    static OWNED_GROUP_COUNTS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+    ));
+    /// The last inbox the retention policy was applied to, from which the next run continues
+    static RETENTION_CURSOR: RefCell<Option<Principal>> = const { RefCell::new(None) };
 }
 
 static DOMAIN_SEPARATOR: &str = "basic_ibe_example_dapp";
+/// The group keys are derived in a separate domain, so that they cannot collide with the users' IBE keys.
+static GROUP_DOMAIN_SEPARATOR: &str = "basic_ibe_example_dapp_group_chat";
+const RETENTION_INTERVAL_SECS: u64 = 60;
+const INBOXES_PER_RETENTION_RUN: usize = 100;
 
 #[init]
 fn init(key_name_string: String) {
@@ -37,10 +69,18 @@ fn init(key_name_string: String) {
             .set(key_name_string)
             .expect("failed to set key name");
     });
+    start_retention_timer();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    start_retention_timer();
 }
 
+/// Sends a message and returns its ID in the receiver's inbox. If the inbox is full, the receiver's oldest read message
+/// is evicted, and the message is rejected if the receiver didn't read any.
 #[update]
-fn send_message(request: SendMessageRequest) -> Result<(), String> {
+fn send_message(request: SendMessageRequest) -> Result<MessageId, String> {
     let sender = ic_cdk::api::msg_caller();
     let SendMessageRequest {
         receiver,
@@ -48,22 +88,16 @@ fn send_message(request: SendMessageRequest) -> Result<(), String> {
     } = request;
     let timestamp = ic_cdk::api::time();
 
-    let message = Message {
-        sender,
-        encrypted_message,
-        timestamp,
-    };
+    if BLOCKED_SENDERS.with_borrow(|blocked| blocked.contains_key(&(receiver, sender))) {
+        return Err(format!("{} does not accept messages from you", receiver));
+    }
 
+    let policy = RETENTION_POLICY.with_borrow(|policy| policy.get().clone());
     INBOXES.with_borrow_mut(|inboxes| {
         let mut inbox = inboxes.get(&receiver).unwrap_or_default();
-
-        if inbox.messages.len() >= MAX_MESSAGES_PER_INBOX {
-            Err(format!("Inbox for {} is full", receiver))
-        } else {
-            inbox.messages.push(message);
-            inboxes.insert(receiver, inbox);
-            Ok(())
-        }
+        let id = inbox.push(sender, encrypted_message, timestamp, &policy)?;
+        inboxes.insert(receiver, inbox);
+        Ok(id)
     })
 }
 
@@ -100,24 +134,391 @@ async fn get_my_encrypted_ibe_key(transport_key: TransportPublicKey) -> Encrypte
     EncryptedVetKey::from(result.encrypted_key)
 }
 
+/// Returns up to `MAX_MESSAGES_PER_PAGE` of the caller's messages, oldest first, starting at `cursor`, which is the
+/// `next_cursor` of the previous page.
+#[query]
+fn get_my_messages(cursor: Option<MessageId>) -> MessagesPage {
+    let caller = ic_cdk::api::msg_caller();
+    INBOXES.with_borrow(|inboxes| {
+        inboxes
+            .get(&caller)
+            .unwrap_or_default()
+            .page(cursor, MAX_MESSAGES_PER_PAGE)
+    })
+}
+
+#[update]
+fn remove_my_message(message_id: MessageId) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    update_inbox(caller, |inbox| {
+        inbox
+            .remove(message_id)
+            .map(|_| ())
+            .ok_or_else(|| format!("Message {} not found", message_id))
+    })
+}
+
+#[update]
+fn mark_my_messages_as_read(message_ids: Vec<MessageId>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now = ic_cdk::api::time();
+    let not_found = update_inbox(caller, |inbox| Ok(inbox.mark_as_read(&message_ids, now)))?;
+    if not_found.is_empty() {
+        Ok(())
+    } else {
+        // The messages that were found are still marked as read.
+        Err(format!("Messages {:?} not found", not_found))
+    }
+}
+
+/// Returns when the receiver read a message sent by the caller, or `None` if it is unread. Fails if the message was
+/// removed from the receiver's inbox.
 #[query]
-fn get_my_messages() -> Inbox {
+fn get_sent_message_read_at(
+    receiver: Principal,
+    message_id: MessageId,
+) -> Result<Option<u64>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    INBOXES.with_borrow(|inboxes| {
+        inboxes
+            .get(&receiver)
+            .unwrap_or_default()
+            .get(message_id)
+            .filter(|message| message.sender == caller)
+            .map(|message| message.read_at)
+            .ok_or_else(|| format!("Message {} not found", message_id))
+    })
+}
+
+#[update]
+fn block_sender(sender: Principal) -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
-    INBOXES.with_borrow(|inboxes| inboxes.get(&caller).unwrap_or_default())
+    BLOCKED_SENDERS.with_borrow_mut(|blocked| {
+        if get_blocked_senders(blocked, caller).len() >= MAX_BLOCKED_SENDERS {
+            return Err("Too many blocked senders".to_string());
+        }
+        blocked.insert((caller, sender), ());
+        Ok(())
+    })
 }
 
 #[update]
-fn remove_my_message_by_index(message_index: usize) -> Result<(), String> {
+fn unblock_sender(sender: Principal) {
     let caller = ic_cdk::api::msg_caller();
+    BLOCKED_SENDERS.with_borrow_mut(|blocked| blocked.remove(&(caller, sender)));
+}
+
+#[query]
+fn get_my_blocked_senders() -> Vec<Principal> {
+    let caller = ic_cdk::api::msg_caller();
+    BLOCKED_SENDERS.with_borrow(|blocked| get_blocked_senders(blocked, caller))
+}
+
+fn get_blocked_senders(
+    blocked: &StableBTreeMap<(Principal, Principal), (), Memory>,
+    user: Principal,
+) -> Vec<Principal> {
+    blocked
+        .range((user, Principal::management_canister())..)
+        .take_while(|((this_user, _), _)| *this_user == user)
+        .map(|((_, sender), _)| sender)
+        .collect()
+}
+
+#[query]
+fn get_retention_policy() -> RetentionPolicy {
+    RETENTION_POLICY.with_borrow(|policy| policy.get().clone())
+}
+
+/// Sets the retention policy applied to all inboxes. Only controllers can set it.
+#[update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        return Err("Only controllers can set the retention policy".to_string());
+    }
+    RETENTION_POLICY.with_borrow_mut(|cell| {
+        cell.set(policy)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to set the retention policy: {:?}", e))
+    })
+}
+
+fn start_retention_timer() {
+    ic_cdk_timers::set_timer_interval(
+        std::time::Duration::from_secs(RETENTION_INTERVAL_SECS),
+        || async { apply_retention_policy() },
+    );
+}
+
+/// Applies the retention policy to the next `INBOXES_PER_RETENTION_RUN` inboxes, continuing where the previous run
+/// stopped, so that the work per run is bounded.
+fn apply_retention_policy() {
+    let policy = RETENTION_POLICY.with_borrow(|policy| policy.get().clone());
+    let now = ic_cdk::api::time();
+    let cursor = RETENTION_CURSOR.with_borrow(|cursor| *cursor);
+
+    INBOXES.with_borrow_mut(|inboxes| {
+        let owners: Vec<Principal> = match cursor {
+            Some(cursor) => inboxes
+                .range(cursor..)
+                .skip_while(|(owner, _)| *owner == cursor)
+                .map(|(owner, _)| owner)
+                .take(INBOXES_PER_RETENTION_RUN)
+                .collect(),
+            None => inboxes
+                .iter()
+                .map(|(owner, _)| owner)
+                .take(INBOXES_PER_RETENTION_RUN)
+                .collect(),
+        };
+        for owner in &owners {
+            let mut inbox = inboxes.get(owner).expect("bug: inbox disappeared");
+            if inbox.apply_retention_policy(&policy, now) > 0 {
+                inboxes.insert(*owner, inbox);
+            }
+        }
+        // Start over once all inboxes were visited.
+        RETENTION_CURSOR.with_borrow_mut(|cursor| *cursor = owners.last().copied());
+    });
+}
+
+fn update_inbox<T>(
+    owner: Principal,
+    f: impl FnOnce(&mut Inbox) -> Result<T, String>,
+) -> Result<T, String> {
     INBOXES.with_borrow_mut(|inboxes| {
-        let mut inbox = inboxes.get(&caller).unwrap_or_default();
-        if message_index >= inbox.messages.len() {
-            Err("Message index out of bounds".to_string())
-        } else {
-            inbox.messages.remove(message_index);
-            inboxes.insert(caller, inbox);
-            Ok(())
+        let mut inbox = inboxes.get(&owner).unwrap_or_default();
+        let result = f(&mut inbox)?;
+        inboxes.insert(owner, inbox);
+        Ok(result)
+    })
+}
+
+/// Creates a group owned by the caller. A principal can create at most `MAX_GROUPS_PER_OWNER` groups.
+#[update]
+fn create_group(name: String, members: Vec<Principal>) -> Result<GroupId, String> {
+    let owner = ic_cdk::api::msg_caller();
+    if owner == Principal::anonymous() {
+        return Err("the anonymous principal cannot create groups".to_string());
+    }
+    let owned = OWNED_GROUP_COUNTS.with_borrow(|counts| counts.get(&owner).unwrap_or_default());
+    if owned >= MAX_GROUPS_PER_OWNER {
+        return Err(format!(
+            "cannot create more than {MAX_GROUPS_PER_OWNER} groups"
+        ));
+    }
+
+    let group = GROUPS.with_borrow_mut(|groups| {
+        let group_id = groups.len();
+        let mut group = Group::new(group_id, name, owner);
+        for member in members {
+            if member != owner {
+                group.add_member(member)?;
+            }
+        }
+        groups.insert(group_id, group.clone());
+        Ok::<_, String>(group)
+    })?;
+    MEMBER_GROUPS.with_borrow_mut(|member_groups| {
+        for member in group.members.keys() {
+            member_groups.insert((*member, group.id), ());
         }
+    });
+    OWNED_GROUP_COUNTS.with_borrow_mut(|counts| counts.insert(owner, owned + 1));
+    Ok(group.id)
+}
+
+/// Adds a member to a group. Only the owner of the group can add members.
+#[update]
+fn add_group_member(group_id: GroupId, member: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    update_group(group_id, |group| {
+        if group.owner != caller {
+            return Err("only the group owner can add members".to_string());
+        }
+        group.add_member(member)
+    })?;
+    MEMBER_GROUPS.with_borrow_mut(|member_groups| member_groups.insert((member, group_id), ()));
+    Ok(())
+}
+
+/// Removes a member from a group and rotates the group key, returning the new key epoch. The owner can remove any
+/// other member, and members can leave by removing themselves.
+#[update]
+fn remove_group_member(group_id: GroupId, member: Principal) -> Result<KeyEpoch, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let key_epoch = update_group(group_id, |group| {
+        if group.owner != caller && member != caller {
+            return Err("only the group owner can remove other members".to_string());
+        }
+        group.remove_member(&member)
+    })?;
+    MEMBER_GROUPS.with_borrow_mut(|member_groups| member_groups.remove(&(member, group_id)));
+    Ok(key_epoch)
+}
+
+/// Returns up to `MAX_GROUPS_PER_PAGE` of the groups the caller is a member of, ordered by ID, starting at `cursor`,
+/// which is the `next_cursor` of the previous page.
+#[query]
+fn get_my_groups(cursor: Option<GroupId>) -> GroupsPage {
+    let caller = ic_cdk::api::msg_caller();
+    let mut group_ids: Vec<GroupId> = MEMBER_GROUPS.with_borrow(|member_groups| {
+        member_groups
+            .range((caller, cursor.unwrap_or(0))..=(caller, GroupId::MAX))
+            .map(|((_, group_id), _)| group_id)
+            .take(MAX_GROUPS_PER_PAGE + 1)
+            .collect()
+    });
+    let next_cursor = group_ids.get(MAX_GROUPS_PER_PAGE).copied();
+    group_ids.truncate(MAX_GROUPS_PER_PAGE);
+    let groups = GROUPS.with_borrow(|groups| {
+        group_ids
+            .iter()
+            .map(|group_id| groups.get(group_id).expect("bug: indexed group not found"))
+            .collect()
+    });
+    GroupsPage {
+        groups,
+        next_cursor,
+    }
+}
+
+#[update]
+/// Retrieves the public key against which the group keys can be verified.
+async fn get_group_public_key() -> VetKeyPublicKey {
+    let request = VetKDPublicKeyArgs {
+        canister_id: None,
+        context: GROUP_DOMAIN_SEPARATOR.as_bytes().to_vec(),
+        key_id: key_id(),
+    };
+
+    let result = ic_cdk_management_canister::vetkd_public_key(&request)
+        .await
+        .expect("call to vetkd_public_key failed");
+
+    VetKeyPublicKey::from(result.public_key)
+}
+
+#[update]
+/// Retrieves the encrypted key of a group for a key epoch, from which the members derive the symmetric key the group
+/// messages of that epoch are encrypted with.
+async fn get_my_encrypted_group_key(
+    group_id: GroupId,
+    key_epoch: KeyEpoch,
+    transport_key: TransportPublicKey,
+) -> Result<EncryptedVetKey, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let group = GROUPS
+        .with_borrow(|groups| groups.get(&group_id))
+        .ok_or_else(|| format!("group {group_id} not found"))?;
+    if !group.may_get_key(&caller, key_epoch) {
+        return Err(format!(
+            "not allowed to get the key of group {group_id} for epoch {key_epoch}"
+        ));
+    }
+
+    let request = VetKDDeriveKeyArgs {
+        input: group_key_input(group_id, key_epoch),
+        context: GROUP_DOMAIN_SEPARATOR.as_bytes().to_vec(),
+        key_id: key_id(),
+        transport_public_key: transport_key.into_vec(),
+    };
+
+    let result = ic_cdk_management_canister::vetkd_derive_key(&request)
+        .await
+        .expect("call to vetkd_derive_key failed");
+
+    Ok(EncryptedVetKey::from(result.encrypted_key))
+}
+
+/// Stores a message encrypted with the group key of `key_epoch`, which must be the current key epoch of the group, so
+/// that removed members cannot read new messages. A group keeps up to `MAX_MESSAGES_PER_GROUP` messages, after which
+/// a new message evicts the oldest one.
+#[update]
+fn send_group_message(
+    group_id: GroupId,
+    key_epoch: KeyEpoch,
+    encrypted_message: ByteBuf,
+) -> Result<GroupMessageId, String> {
+    let sender = ic_cdk::api::msg_caller();
+    if encrypted_message.len() > MAX_GROUP_MESSAGE_SIZE {
+        return Err("message is too large".to_string());
+    }
+
+    let (message_id, evicted) = update_group(group_id, |group| {
+        if !group.is_member(&sender) {
+            return Err(format!("not a member of group {group_id}"));
+        }
+        if key_epoch != group.key_epoch {
+            return Err(format!(
+                "the group key was rotated, the current key epoch is {}",
+                group.key_epoch
+            ));
+        }
+        let evicted = group.evict_oldest_message();
+        Ok((group.take_message_id(), evicted))
+    })?;
+
+    GROUP_MESSAGES.with_borrow_mut(|messages| {
+        if let Some(evicted) = evicted {
+            messages.remove(&(group_id, evicted));
+        }
+        messages.insert(
+            (group_id, message_id),
+            GroupMessage {
+                id: message_id,
+                sender,
+                key_epoch,
+                encrypted_message: encrypted_message.into_vec(),
+                timestamp: ic_cdk::api::time(),
+            },
+        );
+    });
+
+    Ok(message_id)
+}
+
+/// Returns up to `MAX_GROUP_MESSAGES_PER_PAGE` messages of a group with an ID greater than `since`, or from the first
+/// message if `since` is not set. Message IDs never change, so the ID of the last message in a page can be passed as
+/// `since` to get the next page.
+#[query]
+fn get_group_messages(
+    group_id: GroupId,
+    since: Option<GroupMessageId>,
+) -> Result<GroupMessagesPage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let group = GROUPS
+        .with_borrow(|groups| groups.get(&group_id))
+        .ok_or_else(|| format!("group {group_id} not found"))?;
+    if !group.is_member(&caller) {
+        return Err(format!("not a member of group {group_id}"));
+    }
+
+    let first_id = since.map_or(0, |since| since.saturating_add(1));
+    let mut messages: Vec<GroupMessage> = GROUP_MESSAGES.with_borrow(|messages| {
+        messages
+            .range((group_id, first_id)..=(group_id, GroupMessageId::MAX))
+            .map(|(_, message)| message)
+            .take(MAX_GROUP_MESSAGES_PER_PAGE + 1)
+            .collect()
+    });
+    let has_more = messages.len() > MAX_GROUP_MESSAGES_PER_PAGE;
+    messages.truncate(MAX_GROUP_MESSAGES_PER_PAGE);
+
+    Ok(GroupMessagesPage { messages, has_more })
+}
+
+fn update_group<T>(
+    group_id: GroupId,
+    f: impl FnOnce(&mut Group) -> Result<T, String>,
+) -> Result<T, String> {
+    GROUPS.with_borrow_mut(|groups| {
+        let mut group = groups
+            .get(&group_id)
+            .ok_or_else(|| format!("group {group_id} not found"))?;
+        let result = f(&mut group)?;
+        groups.insert(group_id, group);
+        Ok(result)
     })
 }
 
@@ -4,22 +4,188 @@ use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 pub const MAX_MESSAGES_PER_INBOX: usize = 1000;
+pub const MAX_MESSAGES_PER_PAGE: usize = 50;
+pub const MAX_BLOCKED_SENDERS: usize = 1000;
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+const NANOS_IN_SEC: u64 = 1_000_000_000;
+
+pub type MessageId = u64;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Message {
+    /// Unique within the inbox and never reused, also after the message is removed
+    #[serde(default)]
+    pub id: MessageId,
     pub sender: Principal,
     #[serde(with = "serde_bytes")]
     pub encrypted_message: Vec<u8>,
     pub timestamp: u64,
+    /// When the receiver marked the message as read
+    #[serde(default)]
+    pub read_at: Option<u64>,
 }
 
+/// The messages of a user, ordered by ID, which is also the order in which they were received.
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Inbox {
     pub messages: Vec<Message>,
+    #[serde(default)]
+    pub next_message_id: MessageId,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessagesPage {
+    pub messages: Vec<Message>,
+    /// The cursor to pass to get the next page, if there are more messages
+    pub next_cursor: Option<MessageId>,
+}
+
+/// Limits on how long and how many messages are kept in every inbox. When an inbox holds the maximum number of messages,
+/// a new message evicts the oldest read one, and is rejected if no message was read.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age_seconds: Option<u64>,
+    /// At most `MAX_MESSAGES_PER_INBOX`, which also applies if this is not set
+    pub max_messages: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn max_messages(&self) -> usize {
+        self.max_messages
+            .map_or(MAX_MESSAGES_PER_INBOX, |max_messages| {
+                (max_messages as usize).min(MAX_MESSAGES_PER_INBOX)
+            })
+    }
+}
+
+impl Inbox {
+    /// Assigns IDs to messages stored before messages had IDs.
+    pub fn assign_missing_ids(&mut self) {
+        if self.next_message_id == 0 && !self.messages.is_empty() {
+            for (id, message) in self.messages.iter_mut().enumerate() {
+                message.id = id as MessageId;
+            }
+            self.next_message_id = self.messages.len() as MessageId;
+        }
+    }
+
+    /// Adds a message and returns its ID. If the inbox is full, the oldest messages the owner already read are evicted,
+    /// so that senders cannot flush an inbox, and the message is rejected if there are not enough of them.
+    pub fn push(
+        &mut self,
+        sender: Principal,
+        encrypted_message: Vec<u8>,
+        timestamp: u64,
+        policy: &RetentionPolicy,
+    ) -> Result<MessageId, String> {
+        let excess = (self.messages.len() + 1).saturating_sub(policy.max_messages());
+        let read = self
+            .messages
+            .iter()
+            .filter(|message| message.read_at.is_some())
+            .count();
+        if read < excess {
+            return Err("the inbox of the receiver is full".to_string());
+        }
+        let mut evicted = 0;
+        self.messages.retain(|message| {
+            let evict = evicted < excess && message.read_at.is_some();
+            evicted += usize::from(evict);
+            !evict
+        });
+
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        self.messages.push(Message {
+            id,
+            sender,
+            encrypted_message,
+            timestamp,
+            read_at: None,
+        });
+        Ok(id)
+    }
+
+    /// Removes the messages that the policy doesn't allow to be kept anymore and returns how many were removed.
+    pub fn apply_retention_policy(&mut self, policy: &RetentionPolicy, now: u64) -> usize {
+        let len_before = self.messages.len();
+        if let Some(max_age_seconds) = policy.max_age_seconds {
+            let oldest_kept = now.saturating_sub(max_age_seconds.saturating_mul(NANOS_IN_SEC));
+            self.messages
+                .retain(|message| message.timestamp >= oldest_kept);
+        }
+        self.evict_oldest(policy.max_messages());
+        len_before - self.messages.len()
+    }
+
+    fn evict_oldest(&mut self, max_messages: usize) {
+        let excess = self.messages.len().saturating_sub(max_messages);
+        self.messages.drain(..excess);
+    }
+
+    /// Returns up to `limit` messages starting with the first one whose ID is at least `cursor`.
+    pub fn page(&self, cursor: Option<MessageId>, limit: usize) -> MessagesPage {
+        let start = cursor.map_or(0, |cursor| {
+            self.messages.partition_point(|message| message.id < cursor)
+        });
+        let messages: Vec<Message> = self.messages[start..].iter().take(limit).cloned().collect();
+        let next_cursor = self
+            .messages
+            .get(start + messages.len())
+            .map(|message| message.id);
+        MessagesPage {
+            messages,
+            next_cursor,
+        }
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<&Message> {
+        self.position(id).map(|index| &self.messages[index])
+    }
+
+    pub fn remove(&mut self, id: MessageId) -> Option<Message> {
+        self.position(id).map(|index| self.messages.remove(index))
+    }
+
+    /// Marks the messages with the given IDs as read, unless they already are. Returns the IDs that were not found.
+    pub fn mark_as_read(&mut self, ids: &[MessageId], now: u64) -> Vec<MessageId> {
+        let mut not_found = Vec::new();
+        for id in ids {
+            match self.position(*id) {
+                Some(index) => {
+                    self.messages[index].read_at.get_or_insert(now);
+                }
+                None => not_found.push(*id),
+            }
+        }
+        not_found
+    }
+
+    fn position(&self, id: MessageId) -> Option<usize> {
+        self.messages
+            .binary_search_by_key(&id, |message| message.id)
+            .ok()
+    }
 }
 
 impl Storable for Inbox {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    /// Inboxes stored before messages had IDs get them assigned when they are loaded, which doesn't change them if
+    /// they are not stored again, since the IDs follow from the order of the messages.
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut inbox: Inbox = serde_cbor::from_slice(&bytes).expect("failed to deserialize");
+        inbox.assign_missing_ids();
+        inbox
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RetentionPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -31,7 +197,7 @@ impl Storable for Inbox {
 }
 
 impl Storable for Message {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -48,3 +214,98 @@ pub struct SendMessageRequest {
     #[serde(with = "serde_bytes")]
     pub encrypted_message: Vec<u8>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn inbox_with_messages(timestamps: &[u64]) -> Inbox {
+        let mut inbox = Inbox::default();
+        for timestamp in timestamps {
+            inbox
+                .push(sender(), vec![], *timestamp, &RetentionPolicy::default())
+                .unwrap();
+        }
+        inbox
+    }
+
+    fn ids(messages: &[Message]) -> Vec<MessageId> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn ids_are_stable_when_messages_are_removed() {
+        let mut inbox = inbox_with_messages(&[1, 2, 3]);
+        assert!(inbox.remove(1).is_some());
+        assert!(inbox.remove(1).is_none());
+        let id = inbox.push(sender(), vec![], 4, &RetentionPolicy::default());
+        assert_eq!(id, Ok(3));
+        assert_eq!(ids(&inbox.messages), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn pages_follow_the_cursor() {
+        let mut inbox = inbox_with_messages(&[1, 2, 3, 4, 5]);
+        inbox.remove(2);
+
+        let first = inbox.page(None, 2);
+        assert_eq!(ids(&first.messages), vec![0, 1]);
+        assert_eq!(first.next_cursor, Some(3));
+
+        let second = inbox.page(first.next_cursor, 2);
+        assert_eq!(ids(&second.messages), vec![3, 4]);
+        assert_eq!(second.next_cursor, None);
+
+        // A cursor pointing to a removed message continues with the next one.
+        assert_eq!(ids(&inbox.page(Some(2), 10).messages), vec![3, 4]);
+    }
+
+    #[test]
+    fn full_inboxes_only_evict_read_messages() {
+        let policy = RetentionPolicy {
+            max_age_seconds: None,
+            max_messages: Some(2),
+        };
+        let mut inbox = inbox_with_messages(&[0, 1]);
+        assert!(inbox.push(sender(), vec![], 2, &policy).is_err());
+        assert_eq!(ids(&inbox.messages), vec![0, 1]);
+
+        inbox.mark_as_read(&[1], 3);
+        assert_eq!(inbox.push(sender(), vec![], 4, &policy), Ok(2));
+        assert_eq!(ids(&inbox.messages), vec![0, 2]);
+    }
+
+    #[test]
+    fn retention_policy_removes_old_messages() {
+        let mut inbox = inbox_with_messages(&[0, 10 * NANOS_IN_SEC, 20 * NANOS_IN_SEC]);
+        let policy = RetentionPolicy {
+            max_age_seconds: Some(15),
+            max_messages: None,
+        };
+        assert_eq!(inbox.apply_retention_policy(&policy, 30 * NANOS_IN_SEC), 2);
+        assert_eq!(ids(&inbox.messages), vec![2]);
+    }
+
+    #[test]
+    fn marking_as_read_keeps_the_first_read_time() {
+        let mut inbox = inbox_with_messages(&[1, 2]);
+        assert_eq!(inbox.mark_as_read(&[0, 7], 100), vec![7]);
+        assert_eq!(inbox.mark_as_read(&[0, 1], 200), Vec::<MessageId>::new());
+        assert_eq!(inbox.get(0).unwrap().read_at, Some(100));
+        assert_eq!(inbox.get(1).unwrap().read_at, Some(200));
+    }
+
+    #[test]
+    fn messages_stored_without_ids_get_ids_assigned() {
+        let mut inbox = inbox_with_messages(&[1, 2]);
+        inbox.next_message_id = 0;
+        inbox.messages.iter_mut().for_each(|message| message.id = 0);
+        let inbox = Inbox::from_bytes(inbox.to_bytes());
+        assert_eq!(ids(&inbox.messages), vec![0, 1]);
+        assert_eq!(inbox.next_message_id, 2);
+    }
+}
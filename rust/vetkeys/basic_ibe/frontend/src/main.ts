@@ -3,13 +3,20 @@ import { Principal } from "@icp-sdk/core/principal";
 import {
     TransportSecretKey,
     DerivedPublicKey,
+    DerivedKeyMaterial,
     EncryptedVetKey,
     VetKey,
     IbeCiphertext,
     IbeIdentity,
     IbeSeed,
 } from "@icp-sdk/vetkeys";
-import { createActor, type Backend, type Inbox } from "./bindings/backend";
+import {
+    createActor,
+    type Backend,
+    type Group,
+    type GroupMessage,
+    type Message,
+} from "./bindings/backend";
 import { AuthClient, LocalStorage } from "@icp-sdk/auth/client";
 import { HttpAgent } from "@icp-sdk/core/agent";
 import { safeGetCanisterEnv } from "@icp-sdk/core/agent/canister-env";
@@ -20,6 +27,8 @@ const canisterEnv = safeGetCanisterEnv<{
 
 let ibePrivateKey: VetKey | undefined = undefined;
 let ibePublicKey: DerivedPublicKey | undefined = undefined;
+let groupPublicKey: DerivedPublicKey | undefined = undefined;
+const groupKeys = new Map<string, DerivedKeyMaterial>();
 let myPrincipal: Principal | undefined = undefined;
 let authClient: AuthClient | undefined;
 let basicIbeActor: Backend | undefined;
@@ -98,6 +107,240 @@ async function decryptMessage(encryptedMessage: Uint8Array): Promise<string> {
     return new TextDecoder().decode(plaintext);
 }
 
+async function getGroupPublicKey(): Promise<DerivedPublicKey> {
+    if (groupPublicKey) return groupPublicKey;
+    const actor = await getBasicIbeActor();
+    groupPublicKey = DerivedPublicKey.deserialize(
+        new Uint8Array(await actor.get_group_public_key()),
+    );
+    return groupPublicKey;
+}
+
+// Must match `group_key_input` in the backend.
+function groupKeyInput(groupId: bigint, keyEpoch: bigint): Uint8Array {
+    const input = new Uint8Array(16);
+    const view = new DataView(input.buffer);
+    view.setBigUint64(0, groupId);
+    view.setBigUint64(8, keyEpoch);
+    return input;
+}
+
+async function getGroupKey(
+    groupId: bigint,
+    keyEpoch: bigint,
+): Promise<DerivedKeyMaterial> {
+    const cacheKey = `${groupId}/${keyEpoch}`;
+    const cached = groupKeys.get(cacheKey);
+    if (cached) return cached;
+
+    const transportSecretKey = TransportSecretKey.random();
+    const actor = await getBasicIbeActor();
+    const result = await actor.get_my_encrypted_group_key(
+        groupId,
+        keyEpoch,
+        transportSecretKey.publicKeyBytes(),
+    );
+    if ("Err" in result) throw Error(result.Err);
+    const vetKey = EncryptedVetKey.deserialize(
+        Uint8Array.from(result.Ok),
+    ).decryptAndVerify(
+        transportSecretKey,
+        await getGroupPublicKey(),
+        groupKeyInput(groupId, keyEpoch),
+    );
+    const keyMaterial = await vetKey.asDerivedKeyMaterial();
+    groupKeys.set(cacheKey, keyMaterial);
+    return keyMaterial;
+}
+
+async function createGroup() {
+    const name = prompt("Enter the group name:");
+    if (!name) throw Error("Group name is required");
+
+    const members = (prompt("Enter the member principals, separated by commas:") ?? "")
+        .split(",")
+        .map((member) => member.trim())
+        .filter((member) => member !== "")
+        .map((member) => Principal.fromText(member));
+
+    const actor = await getBasicIbeActor();
+    const result = await actor.create_group(name, members);
+    if ("Err" in result) throw Error("Error creating group: " + result.Err);
+    alert(`Group created with ID ${result.Ok}`);
+    await showGroups();
+}
+
+async function showGroups() {
+    const actor = await getBasicIbeActor();
+    const groups: Group[] = [];
+    let cursor: [] | [bigint] = [];
+    for (;;) {
+        const page = await actor.get_my_groups(cursor);
+        groups.push(...page.groups);
+        if (page.next_cursor.length === 0) break;
+        cursor = [page.next_cursor[0]];
+    }
+    const messagesDiv = document.getElementById("messages")!;
+    messagesDiv.innerHTML = "";
+
+    if (groups.length === 0) {
+        const noGroupsDiv = document.createElement("div");
+        noGroupsDiv.className = "no-messages";
+        noGroupsDiv.textContent = "You are not a member of any group.";
+        messagesDiv.appendChild(noGroupsDiv);
+        return;
+    }
+
+    for (const group of groups) {
+        messagesDiv.appendChild(createGroupElement(group));
+    }
+}
+
+function createGroupElement(group: Group): HTMLDivElement {
+    const groupElement = document.createElement("div");
+    groupElement.className = "message";
+
+    const groupText = document.createElement("div");
+    groupText.className = "message-text";
+    groupText.textContent = `${group.name} (ID ${group.id}, key epoch ${group.key_epoch})`;
+
+    const groupInfo = document.createElement("div");
+    groupInfo.className = "message-info";
+    groupInfo.textContent = `Members: ${group.members
+        .map(([member]) => member.toString())
+        .join(", ")}`;
+
+    const groupActions = document.createElement("div");
+    groupActions.className = "message-actions";
+    const actions: [string, () => Promise<void>][] = [
+        ["Open", () => showGroupMessages(group)],
+        ["Send", () => sendGroupMessage(group)],
+        ["Add Member", () => addGroupMember(group)],
+        ["Remove Member", () => removeGroupMember(group)],
+    ];
+    for (const [label, action] of actions) {
+        const button = document.createElement("button");
+        button.textContent = label;
+        button.addEventListener("click", () => {
+            void (async () => {
+                try {
+                    await action();
+                } catch (error: unknown) {
+                    console.error(`Error in ${label}:`, error);
+                    alert((error as Error).message ?? String(error));
+                }
+            })();
+        });
+        groupActions.appendChild(button);
+    }
+
+    groupElement.appendChild(groupText);
+    groupElement.appendChild(groupInfo);
+    groupElement.appendChild(groupActions);
+    return groupElement;
+}
+
+async function sendGroupMessage(group: Group) {
+    const message = prompt(`Enter your message to ${group.name}:`);
+    if (!message) throw Error("Message is required");
+
+    const keyMaterial = await getGroupKey(group.id, group.key_epoch);
+    const encryptedMessage = await keyMaterial.encryptMessage(
+        message,
+        "group-message",
+        groupKeyInput(group.id, group.key_epoch),
+    );
+    const actor = await getBasicIbeActor();
+    const result = await actor.send_group_message(
+        group.id,
+        group.key_epoch,
+        encryptedMessage,
+    );
+    if ("Err" in result) throw Error("Error sending message: " + result.Err);
+    await showGroupMessages(group);
+}
+
+async function addGroupMember(group: Group) {
+    const member = prompt("Enter the principal of the new member:");
+    if (!member) throw Error("Member is required");
+
+    const actor = await getBasicIbeActor();
+    const result = await actor.add_group_member(
+        group.id,
+        Principal.fromText(member),
+    );
+    if ("Err" in result) throw Error("Error adding member: " + result.Err);
+    await showGroups();
+}
+
+async function removeGroupMember(group: Group) {
+    const member = prompt(
+        "Enter the principal of the member to remove, or your own to leave:",
+    );
+    if (!member) throw Error("Member is required");
+
+    const actor = await getBasicIbeActor();
+    const result = await actor.remove_group_member(
+        group.id,
+        Principal.fromText(member),
+    );
+    if ("Err" in result) throw Error("Error removing member: " + result.Err);
+    alert(`Member removed, the group key is now at epoch ${result.Ok}`);
+    await showGroups();
+}
+
+async function showGroupMessages(group: Group) {
+    const actor = await getBasicIbeActor();
+    const messages: GroupMessage[] = [];
+    let since: [] | [bigint] = [];
+    for (;;) {
+        const result = await actor.get_group_messages(group.id, since);
+        if ("Err" in result) throw Error("Error loading messages: " + result.Err);
+        messages.push(...result.Ok.messages);
+        if (!result.Ok.has_more || result.Ok.messages.length === 0) break;
+        since = [result.Ok.messages[result.Ok.messages.length - 1].id];
+    }
+
+    const messagesDiv = document.getElementById("messages")!;
+    messagesDiv.innerHTML = "";
+    const heading = document.createElement("h3");
+    heading.textContent = group.name;
+    messagesDiv.appendChild(heading);
+
+    if (messages.length === 0) {
+        const noMessagesDiv = document.createElement("div");
+        noMessagesDiv.className = "no-messages";
+        noMessagesDiv.textContent = "No messages in the group.";
+        messagesDiv.appendChild(noMessagesDiv);
+        return;
+    }
+
+    for (const message of messages.reverse()) {
+        let plaintextString: string;
+        try {
+            const keyMaterial = await getGroupKey(group.id, message.key_epoch);
+            plaintextString = new TextDecoder().decode(
+                await keyMaterial.decryptMessage(
+                    new Uint8Array(message.encrypted_message),
+                    "group-message",
+                    groupKeyInput(group.id, message.key_epoch),
+                ),
+            );
+        } catch {
+            // Members cannot obtain the keys of epochs before they joined.
+            plaintextString = "(cannot be decrypted)";
+        }
+        const messageElement = createMessageElement(
+            message.sender,
+            message.timestamp,
+            plaintextString,
+            message.id,
+        );
+        messageElement.querySelector(".message-actions")?.remove();
+        messagesDiv.appendChild(messageElement);
+    }
+}
+
 async function sendMessage() {
     const message = prompt("Enter your message:");
     if (!message) throw Error("Message is required");
@@ -123,7 +366,7 @@ async function sendMessage() {
             console.error("Error sending message:", result.Err);
             alert("Error sending message: " + result.Err);
         } else {
-            alert("Message sent successfully!");
+            alert(`Message sent successfully with ID ${result.Ok}!`);
         }
     } catch (error) {
         console.error("Error sending message:", error);
@@ -133,15 +376,33 @@ async function sendMessage() {
 
 async function showMessages() {
     const actor = await getBasicIbeActor();
-    const inbox = await actor.get_my_messages();
-    await displayMessages(inbox);
+    const messages: Message[] = [];
+    let cursor: [] | [bigint] = [];
+    for (;;) {
+        const page = await actor.get_my_messages(cursor);
+        messages.push(...page.messages);
+        if (page.next_cursor.length === 0) break;
+        cursor = [page.next_cursor[0]];
+    }
+    await displayMessages(messages);
+
+    const unread = messages
+        .filter((message) => message.read_at.length === 0)
+        .map((message) => message.id);
+    if (unread.length > 0) {
+        const result = await actor.mark_my_messages_as_read(unread);
+        if ("Err" in result) {
+            console.error("Error marking messages as read:", result.Err);
+        }
+    }
 }
 
 function createMessageElement(
     sender: Principal,
     timestamp: bigint,
     plaintextString: string,
-    index: number,
+    id: bigint,
+    isUnread: boolean = false,
 ): HTMLDivElement {
     const messageElement = document.createElement("div");
     messageElement.className = "message";
@@ -158,7 +419,7 @@ function createMessageElement(
 
     const senderInfo = document.createElement("div");
     senderInfo.className = "sender";
-    senderInfo.textContent = `From: ${sender.toString()}`;
+    senderInfo.textContent = `${isUnread ? "New from" : "From"}: ${sender.toString()}`;
 
     const timestampInfo = document.createElement("div");
     timestampInfo.className = "timestamp";
@@ -171,9 +432,15 @@ function createMessageElement(
     const deleteButton = document.createElement("button");
     deleteButton.className = "delete-button";
     deleteButton.textContent = "Delete";
-    deleteButton.dataset.index = index.toString();
+    deleteButton.dataset.id = id.toString();
+
+    const blockButton = document.createElement("button");
+    blockButton.className = "block-button";
+    blockButton.textContent = "Block Sender";
+    blockButton.dataset.sender = sender.toString();
 
     messageActions.appendChild(deleteButton);
+    messageActions.appendChild(blockButton);
     messageInfo.appendChild(senderInfo);
     messageInfo.appendChild(timestampInfo);
     messageContent.appendChild(messageText);
@@ -184,11 +451,11 @@ function createMessageElement(
     return messageElement;
 }
 
-async function displayMessages(inbox: Inbox) {
+async function displayMessages(messages: Message[]) {
     const messagesDiv = document.getElementById("messages")!;
     messagesDiv.innerHTML = "";
 
-    if (inbox.messages.length === 0) {
+    if (messages.length === 0) {
         const noMessagesDiv = document.createElement("div");
         noMessagesDiv.className = "no-messages";
         noMessagesDiv.textContent = "No messages in the inbox.";
@@ -197,8 +464,8 @@ async function displayMessages(inbox: Inbox) {
     }
 
     // Iterate through messages in reverse order
-    for (let i = inbox.messages.length - 1; i >= 0; i--) {
-        const message = inbox.messages[i];
+    for (let i = messages.length - 1; i >= 0; i--) {
+        const message = messages[i];
         const plaintextString = await decryptMessage(
             new Uint8Array(message.encrypted_message),
         );
@@ -207,7 +474,8 @@ async function displayMessages(inbox: Inbox) {
             message.sender,
             message.timestamp,
             plaintextString,
-            i,
+            message.id,
+            message.read_at.length === 0,
         );
         messagesDiv.appendChild(messageElement);
     }
@@ -217,7 +485,7 @@ async function displayMessages(inbox: Inbox) {
     deleteButtons.forEach((button) => {
         button.addEventListener("click", (e) => {
             const target = e.target as HTMLButtonElement;
-            const index = parseInt(target.dataset.index!);
+            const id = BigInt(target.dataset.id!);
 
             // Disable all delete buttons
             deleteButtons.forEach(
@@ -227,14 +495,11 @@ async function displayMessages(inbox: Inbox) {
             void (async () => {
                 try {
                     const actor = await getBasicIbeActor();
-                    const result = await actor.remove_my_message_by_index(
-                        BigInt(index),
-                    );
+                    const result = await actor.remove_my_message(id);
                     if ("Err" in result) {
                         console.error("Error deleting message:", result.Err);
                         alert("Error deleting message: " + result.Err);
                     } else {
-                        // Re-load all messages to refresh message indices
                         await showMessages();
                     }
                 } catch (error) {
@@ -246,6 +511,33 @@ async function displayMessages(inbox: Inbox) {
             })();
         });
     });
+
+    document.querySelectorAll(".block-button").forEach((button) => {
+        button.addEventListener("click", (e) => {
+            const sender = Principal.fromText(
+                (e.target as HTMLButtonElement).dataset.sender!,
+            );
+            if (!confirm(`Block all messages from ${sender.toString()}?`)) {
+                return;
+            }
+            void (async () => {
+                try {
+                    const actor = await getBasicIbeActor();
+                    const result = await actor.block_sender(sender);
+                    if ("Err" in result) {
+                        alert("Error blocking sender: " + result.Err);
+                    } else {
+                        alert(`${sender.toString()} is blocked.`);
+                    }
+                } catch (error) {
+                    console.error("Error blocking sender:", error);
+                    alert(
+                        "Error blocking sender: " + (error as Error).message,
+                    );
+                }
+            })();
+        });
+    });
 }
 
 export async function login(client: AuthClient): Promise<void> {
@@ -266,6 +558,7 @@ export function logout() {
     const messagesDiv = document.getElementById("messages")!;
     messagesDiv.innerHTML = "";
     ibePrivateKey = undefined;
+    groupKeys.clear();
     myPrincipal = undefined;
     basicIbeActor = undefined;
     updateUI(false);
@@ -334,6 +627,8 @@ document.querySelector<HTMLDivElement>("#app")!.innerHTML = `
     <div id="messageButtons" class="buttons">
       <button id="sendMessage">Send Message</button>
       <button id="showMessages">Show My Messages</button>
+      <button id="createGroup">Create Group</button>
+      <button id="showGroups">Show My Groups</button>
     </div>
     <div id="messages"></div>
   </div>
@@ -364,5 +659,26 @@ document.getElementById("showMessages")!.addEventListener("click", () => {
     })();
 });
 
+document.getElementById("createGroup")!.addEventListener("click", () => {
+    void (async () => {
+        try {
+            await createGroup();
+        } catch (error: unknown) {
+            console.error("Error in createGroup:", error);
+            alert((error as Error).message ?? String(error));
+        }
+    })();
+});
+document.getElementById("showGroups")!.addEventListener("click", () => {
+    void (async () => {
+        try {
+            await showGroups();
+        } catch (error: unknown) {
+            console.error("Error in showGroups:", error);
+            alert("Error loading groups: " + (error as Error).message);
+        }
+    })();
+});
+
 // Initialize auth
 void initAuth();
@@ -0,0 +1,241 @@
+use crate::types::{AuctionKind, BidCounter, DecryptedBid, EncryptedBid, LotStatus};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuctionOutcome {
+    pub status: LotStatus,
+    pub clearing_price: Option<u128>,
+}
+
+/// Determines the winner of a lot and the price they pay.
+///
+/// `reserve_price` is `None` if the creator did not set one, and an error if it could not be decrypted, in which case the
+/// lot is not sold. If multiple users provide the highest bid, the bid that was placed first, i.e., the one with the
+/// lowest bid counter, wins.
+pub fn auction_outcome(
+    kind: AuctionKind,
+    reserve_price: Option<Result<u128, String>>,
+    bids: &[(BidCounter, DecryptedBid)],
+) -> AuctionOutcome {
+    if bids.is_empty() {
+        return AuctionOutcome {
+            status: LotStatus::ClosedNoBids,
+            clearing_price: None,
+        };
+    }
+    let reserve_not_met = AuctionOutcome {
+        status: LotStatus::ClosedReserveNotMet,
+        clearing_price: None,
+    };
+    let reserve_price = match reserve_price {
+        None => None,
+        Some(Ok(reserve_price)) => Some(reserve_price),
+        Some(Err(_)) => return reserve_not_met,
+    };
+
+    let mut eligible_bids: Vec<&(BidCounter, DecryptedBid)> = bids
+        .iter()
+        .filter(|(_, bid)| bid.amount >= reserve_price.unwrap_or(0))
+        .collect();
+    eligible_bids.sort_by(|(counter_x, x), (counter_y, y)| {
+        y.amount.cmp(&x.amount).then(counter_x.cmp(counter_y))
+    });
+
+    let Some((_, winning_bid)) = eligible_bids.first() else {
+        return reserve_not_met;
+    };
+    let clearing_price = match kind {
+        AuctionKind::FirstPrice => winning_bid.amount,
+        // Without a competing bid or a reserve price, the winner pays their own bid rather than nothing.
+        AuctionKind::SecondPrice => eligible_bids
+            .get(1)
+            .map(|(_, second_bid)| second_bid.amount)
+            .or(reserve_price)
+            .unwrap_or(winning_bid.amount),
+    };
+    AuctionOutcome {
+        status: LotStatus::ClosedWithWinner(winning_bid.bidder),
+        clearing_price: Some(clearing_price),
+    }
+}
+
+/// Interprets a decrypted plaintext as a little-endian `u128` amount.
+pub fn decode_amount(decrypted_value: Result<Vec<u8>, String>) -> Result<u128, String> {
+    decrypted_value
+        .and_then(|v| {
+            v.as_slice()
+                .try_into()
+                .map_err(|_| "failed to convert amount to u128".to_string())
+        })
+        .map(u128::from_le_bytes)
+}
+
+/// Pairs each bid with its decrypted amount. Bids that failed to decrypt keep their error, so that the result stays
+/// aligned with `bid_counters`.
+pub fn decode_bids(
+    bid_counters: Vec<BidCounter>,
+    encrypted_bids: Vec<EncryptedBid>,
+    decrypted_values: Vec<Result<Vec<u8>, String>>,
+) -> Vec<(BidCounter, EncryptedBid, Result<DecryptedBid, String>)> {
+    bid_counters
+        .into_iter()
+        .zip(encrypted_bids)
+        .zip(decrypted_values)
+        .map(|((bid_counter, encrypted_bid), decrypted_value)| {
+            let decrypted_bid = decode_amount(decrypted_value).map(|amount| DecryptedBid {
+                amount,
+                bidder: encrypted_bid.bidder,
+            });
+            (bid_counter, encrypted_bid, decrypted_bid)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn bidder(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn bid(bid_counter: BidCounter, bidder_id: u8, amount: u128) -> (BidCounter, DecryptedBid) {
+        (
+            bid_counter,
+            DecryptedBid {
+                amount,
+                bidder: bidder(bidder_id),
+            },
+        )
+    }
+
+    fn won_by(bidder_id: u8, clearing_price: u128) -> AuctionOutcome {
+        AuctionOutcome {
+            status: LotStatus::ClosedWithWinner(bidder(bidder_id)),
+            clearing_price: Some(clearing_price),
+        }
+    }
+
+    #[test]
+    fn first_price_winner_pays_own_bid() {
+        let bids = [bid(0, 1, 10), bid(1, 2, 30), bid(2, 3, 20)];
+        assert_eq!(
+            auction_outcome(AuctionKind::FirstPrice, None, &bids),
+            won_by(2, 30)
+        );
+    }
+
+    #[test]
+    fn second_price_winner_pays_second_highest_bid() {
+        let bids = [bid(0, 1, 10), bid(1, 2, 30), bid(2, 3, 20)];
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, None, &bids),
+            won_by(2, 20)
+        );
+    }
+
+    #[test]
+    fn ties_are_won_by_the_earliest_bid() {
+        // The bids are deliberately not sorted by bid counter.
+        let bids = [bid(7, 1, 30), bid(3, 2, 30), bid(5, 3, 30), bid(1, 4, 10)];
+        assert_eq!(
+            auction_outcome(AuctionKind::FirstPrice, None, &bids),
+            won_by(2, 30)
+        );
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, None, &bids),
+            won_by(2, 30)
+        );
+    }
+
+    #[test]
+    fn reserve_price_excludes_lower_bids() {
+        let bids = [bid(0, 1, 10), bid(1, 2, 30)];
+        assert_eq!(
+            auction_outcome(AuctionKind::FirstPrice, Some(Ok(15)), &bids),
+            won_by(2, 30)
+        );
+        // The only other bid doesn't meet the reserve, so the winner pays the reserve price.
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, Some(Ok(15)), &bids),
+            won_by(2, 15)
+        );
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, Some(Ok(31)), &bids),
+            AuctionOutcome {
+                status: LotStatus::ClosedReserveNotMet,
+                clearing_price: None,
+            }
+        );
+    }
+
+    #[test]
+    fn second_price_without_reserve_and_single_bid_pays_own_bid() {
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, None, &[bid(0, 1, 10)]),
+            won_by(1, 10)
+        );
+    }
+
+    #[test]
+    fn undecryptable_reserve_price_prevents_sale() {
+        let bids = [bid(0, 1, 10)];
+        assert_eq!(
+            auction_outcome(
+                AuctionKind::FirstPrice,
+                Some(Err("failed to decrypt ibe ciphertext".to_string())),
+                &bids
+            ),
+            AuctionOutcome {
+                status: LotStatus::ClosedReserveNotMet,
+                clearing_price: None,
+            }
+        );
+    }
+
+    #[test]
+    fn no_bids() {
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, Some(Ok(5)), &[]),
+            AuctionOutcome {
+                status: LotStatus::ClosedNoBids,
+                clearing_price: None,
+            }
+        );
+    }
+
+    #[test]
+    fn undecryptable_bids_keep_their_bid_counter() {
+        let encrypted_bid = |bidder_id| EncryptedBid {
+            encrypted_amount: vec![],
+            bidder: bidder(bidder_id),
+        };
+        let decoded = decode_bids(
+            vec![4, 5, 6],
+            vec![encrypted_bid(1), encrypted_bid(2), encrypted_bid(3)],
+            vec![
+                Ok(50_u128.to_le_bytes().to_vec()),
+                Err("failed to decrypt ibe ciphertext".to_string()),
+                // Decrypts, but isn't a valid amount.
+                Ok(vec![1, 2, 3]),
+            ],
+        );
+        let decrypted_bids: Vec<(BidCounter, DecryptedBid)> = decoded
+            .into_iter()
+            .filter_map(|(bid_counter, _, decrypted_bid)| {
+                decrypted_bid.ok().map(|bid| (bid_counter, bid))
+            })
+            .collect();
+        assert_eq!(
+            decrypted_bids
+                .iter()
+                .map(|(bid_counter, bid)| (*bid_counter, bid.bidder, bid.amount))
+                .collect::<Vec<_>>(),
+            vec![(4, bidder(1), 50)]
+        );
+        assert_eq!(
+            auction_outcome(AuctionKind::SecondPrice, None, &decrypted_bids),
+            won_by(1, 50)
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Parameters of the job closing expired lots.
+
+const NANOS_IN_SEC: u64 = 1_000_000_000;
+
+/// The number of lots whose decryption keys are derived concurrently.
+pub const MAX_CONCURRENT_LOT_CLOSINGS: usize = 10;
+
+/// The instructions a single run of the closing job may use, counted over all its messages. Once exceeded, no further
+/// lots are taken and the remaining expired lots are closed by the next run.
+pub const INSTRUCTIONS_PER_RUN: u64 = 20_000_000_000;
+
+/// A lot taken by the closing job is rescheduled for this long, so that it is retried if the closing is interrupted,
+/// e.g., by an upgrade, without any failure being recorded.
+pub const LEASE_SECS: u64 = 10 * 60;
+
+const MAX_RETRY_DELAY_SECS: u64 = 60 * 60;
+
+/// Returns the time of the next attempt to close a lot after it failed `failed_attempts` times. The delay doubles with
+/// each failure, starting at `initial_delay_secs`, up to an hour.
+pub fn next_attempt_time(now: u64, failed_attempts: u32, initial_delay_secs: u64) -> u64 {
+    let factor = 1_u64
+        .checked_shl(failed_attempts.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    let delay_secs = initial_delay_secs
+        .saturating_mul(factor)
+        .min(MAX_RETRY_DELAY_SECS);
+    now + delay_secs * NANOS_IN_SEC
+}
+
+pub fn lease_end(now: u64) -> u64 {
+    now + LEASE_SECS * NANOS_IN_SEC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_an_hour() {
+        let delays: Vec<u64> = [1, 2, 3, 4, 10, 11, 64, u32::MAX]
+            .into_iter()
+            .map(|failed_attempts| next_attempt_time(0, failed_attempts, 5) / NANOS_IN_SEC)
+            .collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 2560, 3600, 3600, 3600]);
+    }
+}
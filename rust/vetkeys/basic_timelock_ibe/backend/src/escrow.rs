@@ -0,0 +1,262 @@
+//! Escrow of the bidders' deposits on an ICRC-2 ledger. Each lot has its own subaccount of the
+//! canister, into which the deposits are transferred when bids are placed, and out of which the
+//! winner's payment and the refunds are transferred when the lot is closed.
+
+use crate::types::{
+    LotId, LotStatus, SettlementTransfer, SettlementTransferKind, SettlementTransferStatus,
+};
+use candid::{Nat, Principal};
+use ic_cdk::call::Call;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+
+/// The ledger canister ID is injected as PUBLIC_CANISTER_ID:ledger at deploy time, see `icp.yaml`.
+pub fn ledger_id() -> Principal {
+    let id = ic_cdk::api::env_var_value("PUBLIC_CANISTER_ID:ledger");
+    Principal::from_text(&id).expect("invalid PUBLIC_CANISTER_ID:ledger")
+}
+
+/// Tags the escrow subaccounts, so that none of them, not even the one of lot 0, is the canister's
+/// default account.
+const ESCROW_SUBACCOUNT_TAG: u8 = 1;
+
+pub fn escrow_subaccount(lot_id: LotId) -> Subaccount {
+    let mut subaccount = [0; 32];
+    subaccount[0] = ESCROW_SUBACCOUNT_TAG;
+    subaccount[16..].copy_from_slice(&lot_id.to_be_bytes());
+    subaccount
+}
+
+pub fn escrow_account(lot_id: LotId) -> Account {
+    Account {
+        owner: ic_cdk::api::canister_self(),
+        subaccount: Some(escrow_subaccount(lot_id)),
+    }
+}
+
+/// Moves `amount` from the default account of `bidder` into the escrow of the lot, using the
+/// allowance the bidder gave the canister.
+pub async fn collect_deposit(lot_id: LotId, bidder: Principal, amount: u128) -> Result<(), String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: bidder,
+            subaccount: None,
+        },
+        to: escrow_account(lot_id),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(lot_memo(lot_id)),
+        created_at_time: None,
+    };
+    // Unbounded-wait calls always learn the outcome, so a deposit can't be taken without being recorded.
+    let result: Result<Nat, TransferFromError> =
+        Call::unbounded_wait(ledger_id(), "icrc2_transfer_from")
+            .with_arg(&args)
+            .await
+            .map_err(|e| format!("failed to call the ledger: {e}"))?
+            .candid()
+            .map_err(|e| format!("failed to decode the ledger response: {e}"))?;
+    result
+        .map(|_block_index| ())
+        .map_err(|e| format!("failed to collect the deposit: {e}"))
+}
+
+pub async fn ledger_fee() -> Result<u128, String> {
+    let fee: Nat = Call::unbounded_wait(ledger_id(), "icrc1_fee")
+        .await
+        .map_err(|e| format!("failed to call the ledger: {e}"))?
+        .candid()
+        .map_err(|e| format!("failed to decode the ledger response: {e}"))?;
+    u128::try_from(&fee.0).map_err(|_| format!("ledger fee {fee} is too large"))
+}
+
+/// Executes a transfer out of the escrow of the lot and updates its status. The ledger fee is
+/// deducted from the transferred amount. Retrying a transfer is safe because the ledger
+/// deduplicates transfers with the same `created_at_time` within its transaction window.
+///
+/// A transfer rejected because the fee changed or because `created_at_time` is outside the
+/// transaction window was not executed, so it stays pending with a fresh `created_at_time`, and
+/// is retried with the ledger fee of the next attempt.
+pub async fn execute_transfer(
+    lot_id: LotId,
+    transfer: &mut SettlementTransfer,
+    fee: u128,
+    now: u64,
+) {
+    let Some(amount) = transfer
+        .amount
+        .checked_sub(fee)
+        .filter(|amount| *amount > 0)
+    else {
+        transfer.status = SettlementTransferStatus::Failed(format!(
+            "the amount {} doesn't cover the ledger fee {fee}",
+            transfer.amount
+        ));
+        return;
+    };
+    let args = TransferArg {
+        from_subaccount: Some(escrow_subaccount(lot_id)),
+        to: Account {
+            owner: transfer.to,
+            subaccount: None,
+        },
+        fee: Some(Nat::from(fee)),
+        created_at_time: Some(transfer.created_at_time),
+        memo: Some(lot_memo(lot_id)),
+        amount: Nat::from(amount),
+    };
+    let response = match Call::unbounded_wait(ledger_id(), "icrc1_transfer")
+        .with_arg(&args)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            ic_cdk::println!("Failed to call the ledger to settle lot id {lot_id}: {e}");
+            return;
+        }
+    };
+    transfer.status = match response.candid::<Result<Nat, TransferError>>() {
+        Ok(Ok(block_index))
+        | Ok(Err(TransferError::Duplicate {
+            duplicate_of: block_index,
+        })) => match u64::try_from(&block_index.0) {
+            Ok(block_index) => SettlementTransferStatus::Completed { block_index },
+            Err(_) => SettlementTransferStatus::Failed(format!(
+                "transferred in block {block_index}, which is out of range"
+            )),
+        },
+        // These may succeed if retried with the same arguments.
+        Ok(Err(TransferError::TemporarilyUnavailable | TransferError::CreatedInFuture { .. })) => {
+            SettlementTransferStatus::Pending
+        }
+        Ok(Err(e @ (TransferError::BadFee { .. } | TransferError::TooOld))) => {
+            ic_cdk::println!("Retrying a transfer settling lot id {lot_id}: {e}");
+            transfer.created_at_time = now;
+            SettlementTransferStatus::Pending
+        }
+        Ok(Err(e)) => SettlementTransferStatus::Failed(e.to_string()),
+        Err(e) => {
+            ic_cdk::println!("Failed to decode the ledger response settling lot id {lot_id}: {e}");
+            SettlementTransferStatus::Pending
+        }
+    };
+}
+
+/// Plans the transfers that settle a closed lot: the winner pays the clearing price to the
+/// creator and gets the rest of their deposit back, and every other bidder gets their full
+/// deposit back.
+pub fn plan_settlement(
+    status: &LotStatus,
+    clearing_price: Option<u128>,
+    creator: Principal,
+    deposits: Vec<(Principal, u128)>,
+    created_at_time: u64,
+) -> Vec<SettlementTransfer> {
+    let transfer = |kind, to, amount| SettlementTransfer {
+        kind,
+        to,
+        amount,
+        created_at_time,
+        status: SettlementTransferStatus::Pending,
+    };
+    let mut transfers = Vec::new();
+    for (bidder, deposit) in deposits {
+        let mut refund = deposit;
+        if let (LotStatus::ClosedWithWinner(winner), Some(price)) = (status, clearing_price) {
+            if *winner == bidder {
+                // Bids that are not covered by the deposit are disqualified, so this is a no-op.
+                let price = price.min(deposit);
+                if price > 0 {
+                    transfers.push(transfer(SettlementTransferKind::Payment, creator, price));
+                }
+                refund = deposit - price;
+            }
+        }
+        if refund > 0 {
+            transfers.push(transfer(SettlementTransferKind::Refund, bidder, refund));
+        }
+    }
+    transfers
+}
+
+fn lot_memo(lot_id: LotId) -> Memo {
+    Memo::from(lot_id.to_be_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn transfer(kind: SettlementTransferKind, to: u8, amount: u128) -> SettlementTransfer {
+        SettlementTransfer {
+            kind,
+            to: principal(to),
+            amount,
+            created_at_time: 42,
+            status: SettlementTransferStatus::Pending,
+        }
+    }
+
+    #[test]
+    fn winner_pays_the_creator_and_losers_are_refunded() {
+        let deposits = vec![(principal(1), 100), (principal(2), 50), (principal(3), 80)];
+        assert_eq!(
+            plan_settlement(
+                &LotStatus::ClosedWithWinner(principal(3)),
+                Some(60),
+                principal(9),
+                deposits,
+                42
+            ),
+            vec![
+                transfer(SettlementTransferKind::Refund, 1, 100),
+                transfer(SettlementTransferKind::Refund, 2, 50),
+                transfer(SettlementTransferKind::Payment, 9, 60),
+                transfer(SettlementTransferKind::Refund, 3, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn winner_depositing_the_exact_price_gets_no_refund() {
+        assert_eq!(
+            plan_settlement(
+                &LotStatus::ClosedWithWinner(principal(1)),
+                Some(70),
+                principal(9),
+                vec![(principal(1), 70)],
+                42
+            ),
+            vec![transfer(SettlementTransferKind::Payment, 9, 70)]
+        );
+    }
+
+    #[test]
+    fn everyone_is_refunded_without_a_winner() {
+        let deposits = vec![(principal(1), 100), (principal(2), 0)];
+        for status in [LotStatus::ClosedNoBids, LotStatus::ClosedReserveNotMet] {
+            assert_eq!(
+                plan_settlement(&status, None, principal(9), deposits.clone(), 42),
+                vec![transfer(SettlementTransferKind::Refund, 1, 100)]
+            );
+        }
+    }
+
+    #[test]
+    fn escrow_subaccounts_are_distinct_per_lot_and_from_the_default_account() {
+        assert_ne!(escrow_subaccount(1), escrow_subaccount(2));
+        assert_ne!(
+            escrow_subaccount(0),
+            [0; 32],
+            "lot 0 must not use the default account"
+        );
+        assert_eq!(escrow_subaccount(0)[0], ESCROW_SUBACCOUNT_TAG);
+        assert_eq!(escrow_subaccount(1)[31], 1);
+    }
+}
@@ -1,14 +1,28 @@
+use crate::auction::{auction_outcome, decode_amount, decode_bids, AuctionOutcome};
+use crate::escrow::{execute_transfer, plan_settlement};
+use crate::timelock::{
+    TimeBucket, Timelocked, TimelockedId, TimelockedResponse, MAX_CONCURRENT_KEY_DERIVATIONS,
+    MAX_EXPIRED_REMOVALS_PER_RUN, MAX_TIMELOCKED, MAX_TIMELOCKED_CIPHERTEXT_SIZE,
+    MAX_TIMELOCKED_PER_SUBMITTER, TIMELOCK_DOMAIN_SEPARATOR,
+};
 use crate::types::{
-    BidCounter, DecryptedBid, EncryptedBid, LotId, LotInformation, VetKeyPublicKey,
+    AuctionKind, BidCounter, DecryptedBid, EncryptedBid, LotId, LotInformation, LotSettlement,
+    SettlementTransfer, SettlementTransferKind, SettlementTransferStatus, VetKeyPublicKey,
 };
 use candid::Principal;
 use ic_cdk_management_canister::{VetKDCurve, VetKDDeriveKeyArgs, VetKDKeyId, VetKDPublicKeyArgs};
 use ic_cdk::{init, post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BTreeMap as StableBTreeMap, Cell as StableCell, DefaultMemoryImpl};
-use ic_vetkeys::{DerivedPublicKey, EncryptedVetKey};
+use ic_vetkeys::{DerivedPublicKey, EncryptedVetKey, VetKey};
+use serde_bytes::ByteBuf;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 
+mod auction;
+mod closing;
+mod escrow;
+mod timelock;
 mod types;
 use types::*;
 
@@ -24,16 +38,64 @@ thread_local! {
     /// The bids include a bid counter to ensure that if multiple users provide the same highest bid, the bid that was placed first wins.
     /// The counter is not unique for a lot, it is monotonically increasing for all bids.
     static BIDS_ON_LOTS: RefCell<StableBTreeMap<(LotId, BidCounter, Principal), Bid, Memory>> = RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
     ));
+    /// The lots to be closed by the time they should be closed, which is the end time of the lot unless closing it was
+    /// already attempted.
     static OPEN_LOTS_DEADLINES: RefCell<StableBTreeMap<(u64, LotId), (), Memory>> = RefCell::new(StableBTreeMap::init(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
     ));
+    /// Reserve prices encrypted by the lot creators, decrypted together with the bids when the lot closes.
+    static ENCRYPTED_RESERVE_PRICES: RefCell<StableBTreeMap<LotId, Vec<u8>, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+    ));
+    /// The deposits escrowed for the bids, which are settled when the lot is closed.
+    static ESCROWED_DEPOSITS: RefCell<StableBTreeMap<(LotId, Principal), u128, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+    ));
+    static SETTLEMENTS: RefCell<StableBTreeMap<LotId, LotSettlement, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+    ));
+    /// The lots with pending settlement transfers by the time they should be executed next, see
+    /// `LotSettlement::next_attempt_time`.
+    static PENDING_SETTLEMENTS: RefCell<StableBTreeMap<(u64, LotId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+    ));
 
-    static IBE_PUBLIC_KEY: RefCell<Option<VetKeyPublicKey>> =  const { RefCell::new(None) };
+    /// Time-locked ciphertexts submitted to the generic time-lock service.
+    static TIMELOCKED: RefCell<StableBTreeMap<TimelockedId, Timelocked, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+    ));
+    /// The time-locked ciphertexts by the index of their time bucket, so that those of expired buckets come first.
+    static TIMELOCKED_BY_BUCKET: RefCell<StableBTreeMap<(u64, TimelockedId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+    ));
+    /// The ID of the next time-locked ciphertext. IDs are not reused once their ciphertext is removed.
+    static NEXT_TIMELOCKED_ID: RefCell<StableCell<TimelockedId, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        0,
+    )
+    .expect("failed to initialize the next time-locked ID"));
+    /// The number of time-locked ciphertexts by submitter.
+    static TIMELOCKED_PER_SUBMITTER: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+    ));
+    /// The time buckets with stored ciphertexts whose decryption keys are not published yet. Memories 8 and 11 were used
+    /// for the publication of time bucket keys in earlier versions and are no longer used.
+    static PENDING_TIME_BUCKETS: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+    ));
+    /// The published IBE decryption keys of elapsed time buckets.
+    static TIME_BUCKET_KEYS: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+    ));
+
+    /// The derived public keys by domain separator
+    static DERIVED_PUBLIC_KEYS: RefCell<BTreeMap<&'static str, VetKeyPublicKey>> = const { RefCell::new(BTreeMap::new()) };
 
     static BID_COUNTER: RefCell<BidCounter> = const { RefCell::new(0) };
 
+    /// The key name is set in `init`, so it owns memory 1, which earlier versions also used for the bids.
     static KEY_NAME: RefCell<StableCell<String, Memory>> =
         RefCell::new(StableCell::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
@@ -62,7 +124,12 @@ fn post_upgrade() {
 }
 
 #[update(guard = "is_authenticated")]
-fn create_lot(name: String, description: String, duration_seconds: u16) -> Result<LotId, String> {
+fn create_lot(
+    name: String,
+    description: String,
+    duration_seconds: u16,
+    auction_kind: AuctionKind,
+) -> Result<LotId, String> {
     let caller = ic_cdk::api::msg_caller();
 
     if duration_seconds == 0 {
@@ -83,6 +150,12 @@ fn create_lot(name: String, description: String, duration_seconds: u16) -> Resul
             end_time: start_time + duration_seconds as u64 * NANOS_IN_SEC,
             creator: caller,
             status: LotStatus::Open,
+            auction_kind,
+            has_reserve_price: false,
+            reserve_price: None,
+            clearing_price: None,
+            failed_closing_attempts: 0,
+            last_closing_error: None,
         };
 
         OPEN_LOTS_DEADLINES.with_borrow_mut(|open_lots_deadlines| {
@@ -97,27 +170,188 @@ fn create_lot(name: String, description: String, duration_seconds: u16) -> Resul
     Ok(lot_id)
 }
 
+/// Sets the reserve price of a lot, encrypted like a bid. Only the creator of the lot can set it, and only as long as
+/// no bid was placed, so that it cannot be adjusted to the interest in the lot.
+#[update(guard = "is_authenticated")]
+fn set_reserve_price(lot_id: LotId, encrypted_reserve_price: Vec<u8>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+
+    let lot = LOTS
+        .with_borrow(|lots| lots.get(&lot_id))
+        .ok_or_else(|| "lot not found".to_string())?;
+    if lot.creator != caller {
+        return Err("only the lot creator can set the reserve price".to_string());
+    }
+    if lot.status != LotStatus::Open || ic_cdk::api::time() >= lot.end_time {
+        return Err("lot is closed".to_string());
+    }
+    if !get_encrypted_bids_on_lot(lot_id).0.is_empty() {
+        return Err("the reserve price cannot be set once bids were placed".to_string());
+    }
+    if encrypted_reserve_price.len() > 1000 {
+        return Err("encrypted reserve price is too large to be valid".to_string());
+    }
+
+    ENCRYPTED_RESERVE_PRICES.with_borrow_mut(|reserve_prices| {
+        reserve_prices.insert(lot_id, encrypted_reserve_price);
+    });
+    LOTS.with_borrow_mut(|lots| {
+        lots.insert(
+            lot_id,
+            LotInformation {
+                has_reserve_price: true,
+                ..lot
+            },
+        );
+    });
+
+    Ok(())
+}
+
 #[update(guard = "is_authenticated")]
 async fn get_ibe_public_key() -> VetKeyPublicKey {
-    if let Some(key) = IBE_PUBLIC_KEY.with_borrow(|key| key.clone()) {
-        return key;
+    derived_public_key(DOMAIN_SEPARATOR)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(e))
+}
+
+async fn derived_public_key(domain_separator: &'static str) -> Result<VetKeyPublicKey, String> {
+    if let Some(key) = DERIVED_PUBLIC_KEYS.with_borrow(|keys| keys.get(domain_separator).cloned()) {
+        return Ok(key);
     }
 
     let request = VetKDPublicKeyArgs {
         canister_id: None,
-        context: DOMAIN_SEPARATOR.as_bytes().to_vec(),
+        context: domain_separator.as_bytes().to_vec(),
         key_id: key_id(),
     };
 
     let result = ic_cdk_management_canister::vetkd_public_key(&request)
         .await
-        .expect("call to vetkd_public_key failed");
+        .map_err(|e| format!("call to vetkd_public_key failed: {e}"))?;
+
+    DERIVED_PUBLIC_KEYS.with_borrow_mut(|keys| {
+        keys.insert(
+            domain_separator,
+            VetKeyPublicKey::from(result.public_key.clone()),
+        );
+    });
+
+    Ok(VetKeyPublicKey::from(result.public_key))
+}
+
+/// Returns the public key for time-locked encryption. A plaintext is time-locked by encrypting it for the identity of
+/// the time bucket of its unlock time, see `get_time_bucket`.
+#[update]
+async fn get_timelock_public_key() -> VetKeyPublicKey {
+    derived_public_key(TIMELOCK_DOMAIN_SEPARATOR)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(e))
+}
+
+/// Returns the time bucket whose decryption key is published once `unlock_time` has passed.
+#[query]
+fn get_time_bucket(unlock_time: u64) -> TimeBucket {
+    TimeBucket::containing(unlock_time)
+}
+
+/// Stores a ciphertext encrypted for the time bucket of `unlock_time`, whose decryption key is published once the
+/// bucket has elapsed. A principal may store at most `MAX_TIMELOCKED_PER_SUBMITTER` ciphertexts. The ciphertext is
+/// removed `TIMELOCKED_RETENTION_NANOS` after the bucket has elapsed, or when its submitter deletes it.
+#[update(guard = "is_authenticated")]
+fn submit_timelocked(ciphertext: Vec<u8>, unlock_time: u64) -> Result<TimelockedId, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if unlock_time <= ic_cdk::api::time() {
+        return Err("unlock time must be in the future".to_string());
+    }
+    if ciphertext.len() > MAX_TIMELOCKED_CIPHERTEXT_SIZE {
+        return Err("ciphertext is too large".to_string());
+    }
+    ic_vetkeys::IbeCiphertext::deserialize(&ciphertext)
+        .map_err(|e| format!("invalid ibe ciphertext: {e}"))?;
 
-    IBE_PUBLIC_KEY.with_borrow_mut(|key| {
-        key.replace(VetKeyPublicKey::from(result.public_key.clone()));
+    let submitted = TIMELOCKED_PER_SUBMITTER.with_borrow(|counts| counts.get(&caller).unwrap_or(0));
+    if submitted >= MAX_TIMELOCKED_PER_SUBMITTER {
+        return Err("too many time-locked ciphertexts submitted by the caller".to_string());
+    }
+    if TIMELOCKED.with_borrow(|timelocked| timelocked.len()) >= MAX_TIMELOCKED {
+        return Err("too many time-locked ciphertexts".to_string());
+    }
+
+    let time_bucket = TimeBucket::containing(unlock_time).index;
+    let id = NEXT_TIMELOCKED_ID.with_borrow_mut(|next_id| {
+        let id = *next_id.get();
+        next_id
+            .set(id + 1)
+            .expect("failed to set the next time-locked ID");
+        id
+    });
+    TIMELOCKED.with_borrow_mut(|timelocked| {
+        timelocked.insert(
+            id,
+            Timelocked {
+                submitter: caller,
+                ciphertext,
+                unlock_time,
+                time_bucket,
+            },
+        )
+    });
+    TIMELOCKED_BY_BUCKET.with_borrow_mut(|by_bucket| by_bucket.insert((time_bucket, id), ()));
+    PENDING_TIME_BUCKETS.with_borrow_mut(|pending| pending.insert(time_bucket, ()));
+    TIMELOCKED_PER_SUBMITTER.with_borrow_mut(|counts| counts.insert(caller, submitted + 1));
+
+    Ok(id)
+}
+
+/// Deletes a time-locked ciphertext. Only its submitter can delete it.
+#[update(guard = "is_authenticated")]
+fn delete_timelocked(id: TimelockedId) -> Result<(), String> {
+    let timelocked = TIMELOCKED
+        .with_borrow(|timelocked| timelocked.get(&id))
+        .ok_or("time-locked ciphertext not found")?;
+    if timelocked.submitter != ic_cdk::api::msg_caller() {
+        return Err("only the submitter can delete a time-locked ciphertext".to_string());
+    }
+    remove_timelocked(id, &timelocked);
+    Ok(())
+}
+
+fn remove_timelocked(id: TimelockedId, timelocked: &Timelocked) {
+    TIMELOCKED.with_borrow_mut(|all| all.remove(&id));
+    let bucket = timelocked.time_bucket;
+    let is_bucket_empty = TIMELOCKED_BY_BUCKET.with_borrow_mut(|by_bucket| {
+        by_bucket.remove(&(bucket, id));
+        by_bucket
+            .range((bucket, 0)..=(bucket, TimelockedId::MAX))
+            .next()
+            .is_none()
+    });
+    if is_bucket_empty {
+        PENDING_TIME_BUCKETS.with_borrow_mut(|pending| pending.remove(&bucket));
+    }
+    TIMELOCKED_PER_SUBMITTER.with_borrow_mut(|counts| {
+        match counts.get(&timelocked.submitter).unwrap_or(0) {
+            0 | 1 => counts.remove(&timelocked.submitter),
+            submitted => counts.insert(timelocked.submitter, submitted - 1),
+        }
     });
+}
 
-    VetKeyPublicKey::from(result.public_key)
+#[query]
+fn get_timelocked(id: TimelockedId) -> Option<TimelockedResponse> {
+    let timelocked = TIMELOCKED.with_borrow(|timelocked| timelocked.get(&id))?;
+    let decryption_key = get_time_bucket_key(timelocked.time_bucket);
+    Some(TimelockedResponse {
+        timelocked,
+        decryption_key,
+    })
+}
+
+/// Returns the IBE decryption key of a time bucket once it is published, i.e., shortly after the bucket has elapsed.
+#[query]
+fn get_time_bucket_key(index: u64) -> Option<ByteBuf> {
+    TIME_BUCKET_KEYS.with_borrow(|keys| keys.get(&index).map(ByteBuf::from))
 }
 
 #[query(guard = "is_authenticated")]
@@ -160,29 +394,32 @@ fn get_lots() -> (OpenLotsResponse, ClosedLotsResponse) {
     (open_lots, closed_lots)
 }
 
+/// Places a bid backed by a deposit of `deposit` tokens, which the canister transfers from the caller's account on the
+/// payment ledger into the escrow of the lot, so the caller must approve the transfer first. A bid replacing an
+/// earlier one of the caller may top up the deposit or leave it as it is by passing 0.
 #[update(guard = "is_authenticated")]
-fn place_bid(lot_id: u128, encrypted_amount: Vec<u8>) -> Result<(), String> {
+async fn place_bid(lot_id: u128, encrypted_amount: Vec<u8>, deposit: u128) -> Result<(), String> {
     let bidder = ic_cdk::api::msg_caller();
-    let now = ic_cdk::api::time();
 
-    LOTS.with_borrow(|lots| match lots.get(&lot_id) {
-        Some(LotInformation {
-            status: LotStatus::Open,
-            creator,
-            end_time,
-            ..
-        }) if creator != bidder && now < end_time => Ok(()),
-        Some(LotInformation { creator, .. }) if creator == bidder => {
-            Err("lot creator cannot bid".to_string())
-        }
-        Some(_) => Err("lot is closed".to_string()),
-        None => Err("lot not found".to_string()),
-    })?;
+    check_lot_accepts_bids_from(lot_id, bidder)?;
 
     if encrypted_amount.len() > 1000 {
         return Err("encrypted amount is too large to be valid".to_string());
     }
 
+    if deposit == 0 && escrowed_deposit(lot_id, bidder) == 0 {
+        return Err("a bid must be backed by a deposit".to_string());
+    }
+
+    if deposit > 0 {
+        escrow::collect_deposit(lot_id, bidder, deposit).await?;
+
+        // The lot may have closed while the deposit was collected. The deposit is still recorded, so that it is
+        // refunded when the lot is settled.
+        record_deposit(lot_id, bidder, deposit);
+        check_lot_accepts_bids_from(lot_id, bidder)?;
+    }
+
     BIDS_ON_LOTS.with_borrow_mut(|bids| {
         if let Some((existing_bid_key, _existing_bid)) = bids
             .range((lot_id, 0, Principal::management_canister())..)
@@ -210,61 +447,371 @@ fn place_bid(lot_id: u128, encrypted_amount: Vec<u8>) -> Result<(), String> {
     Ok(())
 }
 
+fn check_lot_accepts_bids_from(lot_id: LotId, bidder: Principal) -> Result<(), String> {
+    let now = ic_cdk::api::time();
+
+    LOTS.with_borrow(|lots| match lots.get(&lot_id) {
+        Some(LotInformation {
+            status: LotStatus::Open,
+            creator,
+            end_time,
+            ..
+        }) if creator != bidder && now < end_time => Ok(()),
+        Some(LotInformation { creator, .. }) if creator == bidder => {
+            Err("lot creator cannot bid".to_string())
+        }
+        Some(_) => Err("lot is closed".to_string()),
+        None => Err("lot not found".to_string()),
+    })
+}
+
+fn escrowed_deposit(lot_id: LotId, bidder: Principal) -> u128 {
+    ESCROWED_DEPOSITS.with_borrow(|deposits| deposits.get(&(lot_id, bidder)).unwrap_or(0))
+}
+
+/// Records a deposit that was transferred into the escrow of a lot. If the lot is already closed, the deposit is
+/// refunded together with the settlement of the lot.
+fn record_deposit(lot_id: LotId, bidder: Principal, deposit: u128) {
+    let lot_is_closed = LOTS.with_borrow(|lots| {
+        lots.get(&lot_id)
+            .is_some_and(|lot| lot.status != LotStatus::Open)
+    });
+    if lot_is_closed {
+        let now = ic_cdk::api::time();
+        let mut settlement =
+            SETTLEMENTS.with_borrow(|settlements| settlements.get(&lot_id).unwrap_or_default());
+        settlement.transfers.push(SettlementTransfer {
+            kind: SettlementTransferKind::Refund,
+            to: bidder,
+            amount: deposit,
+            created_at_time: now,
+            status: SettlementTransferStatus::Pending,
+        });
+        // A settlement that is already scheduled, possibly with a delay after failures, keeps its schedule.
+        if settlement.next_attempt_time.is_none() {
+            schedule_settlement(lot_id, &mut settlement, Some(now));
+        }
+        SETTLEMENTS.with_borrow_mut(|settlements| settlements.insert(lot_id, settlement));
+    } else {
+        let total = escrowed_deposit(lot_id, bidder) + deposit;
+        ESCROWED_DEPOSITS.with_borrow_mut(|deposits| deposits.insert((lot_id, bidder), total));
+    }
+}
+
+/// Returns the transfers settling a closed lot and their status, or `None` if the lot is not closed yet.
+#[query(guard = "is_authenticated")]
+fn get_lot_settlement(lot_id: LotId) -> Option<LotSettlement> {
+    SETTLEMENTS.with_borrow(|settlements| settlements.get(&lot_id))
+}
+
+/// Returns the ledger on which bids are paid, so that clients can approve deposits.
+#[query]
+fn get_payment_ledger() -> Principal {
+    escrow::ledger_id()
+}
+
 #[update(guard = "is_self_call")]
 fn start_lot_closing_timer_job_with_interval_secs(secs: u64) {
     let secs = std::time::Duration::from_secs(secs);
-    ic_cdk_timers::set_timer_interval(secs, || close_one_lot_if_any_is_open_and_expired());
+    ic_cdk_timers::set_timer_interval(secs, async || {
+        close_expired_lots().await;
+        publish_elapsed_time_bucket_keys().await;
+        remove_expired_timelocked();
+        settle_one_lot_if_any_is_pending().await;
+    });
 }
 
-async fn close_one_lot_if_any_is_open_and_expired() {
-    // get the lot with the earliest deadline (if any)
-    let maybe_deadline_and_lot_id =
-        OPEN_LOTS_DEADLINES.with_borrow(|open_lots_deadlines| open_lots_deadlines.iter().next());
-
-    let lot_id = match maybe_deadline_and_lot_id {
-        // if there was a lot and its deadline has passed, remove it from the open lots deadlines to prevent double processing
-        Some(((deadline, lot_id), ())) if deadline <= ic_cdk::api::time() => {
-            OPEN_LOTS_DEADLINES.with_borrow_mut(|open_lots_deadlines| {
-                open_lots_deadlines
-                    .remove(&(deadline, lot_id))
-                    .expect("failed to remove deadline from open lots deadlines")
-            });
-            lot_id
+/// Closes all expired lots, taking them in batches whose decryption keys are derived concurrently, until no expired lot
+/// is left or the instruction budget of the run is used up.
+async fn close_expired_lots() {
+    loop {
+        if ic_cdk::api::call_context_instruction_counter() > closing::INSTRUCTIONS_PER_RUN {
+            ic_cdk::println!("Instruction budget used up, deferring the remaining expired lots to the next run");
+            return;
         }
-        // if there was no lot or the deadline has not passed, return without doing anything
-        _ => return,
+        let batch = take_expired_lots(closing::MAX_CONCURRENT_LOT_CLOSINGS);
+        if batch.is_empty() {
+            return;
+        }
+        futures::future::join_all(
+            batch
+                .into_iter()
+                .map(|(lease_end, lot_id)| close_expired_lot(lot_id, lease_end)),
+        )
+        .await;
+    }
+}
+
+/// Takes up to `max_lots` lots whose deadline has passed. Instead of being removed from the deadlines, the lots are
+/// rescheduled at the end of a lease, so that they are retried if closing them is interrupted. Returns the lots along
+/// with their new deadlines.
+fn take_expired_lots(max_lots: usize) -> Vec<(u64, LotId)> {
+    let now = ic_cdk::api::time();
+    let lease_end = closing::lease_end(now);
+    OPEN_LOTS_DEADLINES.with_borrow_mut(|open_lots_deadlines| {
+        let expired: Vec<(u64, LotId)> = open_lots_deadlines
+            .iter()
+            .take_while(|((deadline, _), ())| *deadline <= now)
+            .take(max_lots)
+            .map(|(key, ())| key)
+            .collect();
+        expired
+            .into_iter()
+            .map(|(deadline, lot_id)| {
+                open_lots_deadlines.remove(&(deadline, lot_id));
+                open_lots_deadlines.insert((lease_end, lot_id), ());
+                (lease_end, lot_id)
+            })
+            .collect()
+    })
+}
+
+async fn close_expired_lot(lot_id: LotId, lease_end: u64) {
+    if let Err(e) = decrypt_and_close_lot(lot_id, lease_end).await {
+        ic_cdk::println!("Failed to close lot id {lot_id}: {e}");
+        record_closing_failure(lot_id, lease_end, e);
+    }
+}
+
+/// Records that closing the lot failed and reschedules it with a delay that increases with the number of failures.
+fn record_closing_failure(lot_id: LotId, lease_end: u64, error: String) {
+    let Some(lot) = LOTS.with_borrow(|lots| lots.get(&lot_id)) else {
+        return;
     };
+    let failed_closing_attempts = lot.failed_closing_attempts.saturating_add(1);
+    let retry_at = closing::next_attempt_time(
+        ic_cdk::api::time(),
+        failed_closing_attempts,
+        TIMER_INTERVAL_SECS,
+    );
+    OPEN_LOTS_DEADLINES.with_borrow_mut(|open_lots_deadlines| {
+        open_lots_deadlines.remove(&(lease_end, lot_id));
+        open_lots_deadlines.insert((retry_at, lot_id), ());
+    });
+    LOTS.with_borrow_mut(|lots| {
+        lots.insert(
+            lot_id,
+            LotInformation {
+                failed_closing_attempts,
+                last_closing_error: Some(error),
+                ..lot
+            },
+        );
+    });
+}
 
+async fn decrypt_and_close_lot(lot_id: LotId, lease_end: u64) -> Result<(), String> {
     let (bid_counters, encrypted_bids) = get_encrypted_bids_on_lot(lot_id);
-    let decrypted_bids = decrypt_bids(lot_id, encrypted_bids).await;
-    close_lot(lot_id, bid_counters, decrypted_bids);
+    let encrypted_reserve_price =
+        ENCRYPTED_RESERVE_PRICES.with_borrow(|reserve_prices| reserve_prices.get(&lot_id));
+
+    // The reserve price is encrypted for the same identity as the bids, so a single key decrypts all of them.
+    let mut ciphertexts: Vec<&[u8]> = encrypted_bids
+        .iter()
+        .map(|bid| bid.encrypted_amount.as_slice())
+        .collect();
+    ciphertexts.extend(encrypted_reserve_price.as_deref());
+    let mut decrypted_values =
+        decrypt_ciphertexts(lot_id.to_le_bytes().to_vec(), ciphertexts).await?;
+    let reserve_price = encrypted_reserve_price.map(|_| {
+        decode_amount(
+            decrypted_values
+                .pop()
+                .expect("bug: missing decrypted reserve price"),
+        )
+    });
+    if let Some(Err(e)) = &reserve_price {
+        ic_cdk::println!("Failed to decrypt reserve price for lot id {lot_id}: {e}");
+    }
+
+    let decrypted_bids = decode_bids(bid_counters, encrypted_bids, decrypted_values)
+        .into_iter()
+        .filter_map(|(bid_counter, encrypted_bid, decrypted_bid)| match decrypted_bid {
+            Ok(decrypted_bid) => Some((bid_counter, decrypted_bid)),
+            Err(e) => {
+                ic_cdk::println!(
+                    "Failed to decrypt bid for lot id {lot_id} by {}: {e}",
+                    encrypted_bid.bidder
+                );
+                None
+            }
+        })
+        .collect();
+    close_lot(lot_id, lease_end, reserve_price, decrypted_bids);
+    Ok(())
 }
 
-async fn decrypt_bids(lot_id: LotId, encrypted_bids: Vec<EncryptedBid>) -> Vec<DecryptedBid> {
-    let decrypted_values = decrypt_ciphertexts(
-        lot_id.to_le_bytes().to_vec(),
-        encrypted_bids
-            .iter()
-            .map(|bid| bid.encrypted_amount.as_slice())
-            .collect::<Vec<_>>(),
-    )
+/// Derives and publishes the decryption keys of elapsed time buckets for which ciphertexts are stored. Buckets without
+/// ciphertexts are skipped, so that no key is derived for them. If the key of a bucket fails to be derived, the bucket
+/// stays pending and is retried on the next run.
+async fn publish_elapsed_time_bucket_keys() {
+    let now = ic_cdk::api::time();
+    let elapsed: Vec<u64> = PENDING_TIME_BUCKETS.with_borrow(|pending| {
+        pending
+            .keys()
+            .take_while(|index| TimeBucket::from_index(*index).unlocks_at <= now)
+            .take(MAX_CONCURRENT_KEY_DERIVATIONS)
+            .collect()
+    });
+
+    futures::future::join_all(elapsed.into_iter().map(|index| async move {
+        let bucket = TimeBucket::from_index(index);
+        match derive_vetkey(TIMELOCK_DOMAIN_SEPARATOR, bucket.identity.into_vec()).await {
+            Ok(key) => {
+                TIME_BUCKET_KEYS
+                    .with_borrow_mut(|keys| keys.insert(index, key.serialize().to_vec()));
+                PENDING_TIME_BUCKETS.with_borrow_mut(|pending| pending.remove(&index));
+            }
+            Err(e) => ic_cdk::println!("Failed to derive the key of time bucket {index}: {e}"),
+        }
+    }))
     .await;
+}
 
-    convert_decrypted_values_to_decrypted_bids(lot_id, encrypted_bids, decrypted_values)
+/// Removes the ciphertexts and keys of time buckets whose retention period has passed, up to
+/// `MAX_EXPIRED_REMOVALS_PER_RUN` of each per run.
+fn remove_expired_timelocked() {
+    let now = ic_cdk::api::time();
+    let expired: Vec<TimelockedId> = TIMELOCKED_BY_BUCKET.with_borrow(|by_bucket| {
+        by_bucket
+            .keys()
+            .take_while(|(bucket, _)| TimeBucket::from_index(*bucket).is_expired(now))
+            .take(MAX_EXPIRED_REMOVALS_PER_RUN)
+            .map(|(_, id)| id)
+            .collect()
+    });
+    for id in expired {
+        if let Some(timelocked) = TIMELOCKED.with_borrow(|timelocked| timelocked.get(&id)) {
+            remove_timelocked(id, &timelocked);
+        }
+    }
+
+    TIME_BUCKET_KEYS.with_borrow_mut(|keys| {
+        for _ in 0..MAX_EXPIRED_REMOVALS_PER_RUN {
+            match keys.first_key_value() {
+                Some((index, _)) if TimeBucket::from_index(index).is_expired(now) => {
+                    keys.remove(&index)
+                }
+                _ => break,
+            };
+        }
+    });
+}
+
+/// Executes the pending transfers of the closed lot that is due first. While the transfers are executed, the lot is
+/// rescheduled at the end of a lease, so that it is retried if the run is interrupted. If transfers fail with an error
+/// that may go away, they stay pending and the lot is retried with a delay that increases with each failed run, so that
+/// it doesn't hold up the settlement of other lots. If runs overlap, a transfer may be executed twice, but the ledger
+/// deduplicates it.
+async fn settle_one_lot_if_any_is_pending() {
+    let now = ic_cdk::api::time();
+    let Some(lot_id) = PENDING_SETTLEMENTS.with_borrow(|pending| {
+        pending
+            .first_key_value()
+            .filter(|((next_attempt_time, _), ())| *next_attempt_time <= now)
+            .map(|((_, lot_id), ())| lot_id)
+    }) else {
+        return;
+    };
+    update_settlement(lot_id, |settlement| {
+        schedule_settlement(lot_id, settlement, Some(closing::lease_end(now)))
+    });
+
+    match escrow::ledger_fee().await {
+        Ok(fee) => {
+            let transfers = SETTLEMENTS
+                .with_borrow(|settlements| settlements.get(&lot_id))
+                .map(|settlement| settlement.transfers)
+                .unwrap_or_default();
+            for (index, mut transfer) in transfers.into_iter().enumerate() {
+                if transfer.status != SettlementTransferStatus::Pending {
+                    continue;
+                }
+                execute_transfer(lot_id, &mut transfer, fee, ic_cdk::api::time()).await;
+                // Transfers are only ever appended, so the index still refers to the same transfer.
+                update_settlement(lot_id, |settlement| settlement.transfers[index] = transfer);
+            }
+        }
+        Err(e) => ic_cdk::println!("Failed to get the ledger fee to settle lot id {lot_id}: {e}"),
+    }
+
+    update_settlement(lot_id, |settlement| {
+        if settlement.is_pending() {
+            settlement.failed_attempts = settlement.failed_attempts.saturating_add(1);
+            let retry_at = closing::next_attempt_time(
+                ic_cdk::api::time(),
+                settlement.failed_attempts,
+                TIMER_INTERVAL_SECS,
+            );
+            schedule_settlement(lot_id, settlement, Some(retry_at));
+        } else {
+            settlement.failed_attempts = 0;
+            schedule_settlement(lot_id, settlement, None);
+        }
+    });
+}
+
+/// Moves the lot to `next_attempt_time` in the queue of pending settlements, or removes it from the queue if `None`.
+fn schedule_settlement(
+    lot_id: LotId,
+    settlement: &mut LotSettlement,
+    next_attempt_time: Option<u64>,
+) {
+    PENDING_SETTLEMENTS.with_borrow_mut(|pending| {
+        if let Some(previous) = settlement.next_attempt_time {
+            pending.remove(&(previous, lot_id));
+        }
+        if let Some(next_attempt_time) = next_attempt_time {
+            pending.insert((next_attempt_time, lot_id), ());
+        }
+    });
+    settlement.next_attempt_time = next_attempt_time;
+}
+
+fn update_settlement(lot_id: LotId, f: impl FnOnce(&mut LotSettlement)) {
+    SETTLEMENTS.with_borrow_mut(|settlements| {
+        let mut settlement = settlements
+            .get(&lot_id)
+            .expect("bug: settlement disappeared");
+        f(&mut settlement);
+        settlements.insert(lot_id, settlement);
+    });
 }
 
 /// In the canister, using the IBE key derived from the identity decrypt a vector of ciphertexts, which makes them public.
-/// Returns a vector, where each value is either a decrypted plaintext or an error message.
+/// Returns a vector, where each value is either a decrypted plaintext or an error message, or an error if the key could
+/// not be derived.
 async fn decrypt_ciphertexts(
     identity: Vec<u8>,
     encrypted_values: Vec<&[u8]>,
-) -> Vec<Result<Vec<u8>, String>> {
+) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    let ibe_decryption_key = derive_vetkey(DOMAIN_SEPARATOR, identity).await?;
+
+    let mut decrypted_values = Vec::new();
+
+    for encrypted_value in encrypted_values.into_iter() {
+        let decrypted_value = ic_vetkeys::IbeCiphertext::deserialize(encrypted_value)
+            .map_err(|e| format!("failed to deserialize ibe ciphertext: {e}"))
+            .and_then(|c| {
+                c.decrypt(&ibe_decryption_key)
+                    .map_err(|_| "failed to decrypt ibe ciphertext".to_string())
+            });
+        decrypted_values.push(decrypted_value);
+    }
+    Ok(decrypted_values)
+}
+
+/// Derives the IBE decryption key for an identity, which makes it known to the canister.
+async fn derive_vetkey(
+    domain_separator: &'static str,
+    identity: Vec<u8>,
+) -> Result<VetKey, String> {
     let dummy_seed = vec![0; 32];
     let transport_secret_key = ic_vetkeys::TransportSecretKey::from_seed(dummy_seed.clone())
         .expect("failed to create transport secret key");
 
     let request = VetKDDeriveKeyArgs {
-        context: DOMAIN_SEPARATOR.as_bytes().to_vec(),
+        context: domain_separator.as_bytes().to_vec(),
         input: identity.clone(),
         key_id: key_id(),
         transport_public_key: transport_secret_key.public_key().to_vec(),
@@ -272,65 +819,17 @@ async fn decrypt_ciphertexts(
 
     let result = ic_cdk_management_canister::vetkd_derive_key(&request)
         .await
-        .expect("call to vetkd_derive_key failed");
+        .map_err(|e| format!("call to vetkd_derive_key failed: {e}"))?;
 
     let ibe_public_key =
-        DerivedPublicKey::deserialize(&get_ibe_public_key().await.into_vec()).unwrap();
-    let encrypted_vetkey = EncryptedVetKey::deserialize(&result.encrypted_key).unwrap();
+        DerivedPublicKey::deserialize(&derived_public_key(domain_separator).await?.into_vec())
+            .map_err(|e| format!("failed to deserialize ibe public key: {e:?}"))?;
+    let encrypted_vetkey = EncryptedVetKey::deserialize(&result.encrypted_key)
+        .map_err(|e| format!("failed to deserialize encrypted vetkey: {e}"))?;
 
-    let ibe_decryption_key = encrypted_vetkey
+    encrypted_vetkey
         .decrypt_and_verify(&transport_secret_key, &ibe_public_key, identity.as_ref())
-        .expect("failed to decrypt ibe key");
-
-    let mut decrypted_values = Vec::new();
-
-    for encrypted_value in encrypted_values.into_iter() {
-        let decrypted_value = ic_vetkeys::IbeCiphertext::deserialize(encrypted_value)
-            .map_err(|e| format!("failed to deserialize ibe ciphertext: {e}"))
-            .and_then(|c| {
-                c.decrypt(&ibe_decryption_key)
-                    .map_err(|_| "failed to decrypt ibe ciphertext".to_string())
-            });
-        decrypted_values.push(decrypted_value);
-    }
-    decrypted_values
-}
-
-fn convert_decrypted_values_to_decrypted_bids(
-    lot_id: LotId,
-    encrypted_bids: Vec<EncryptedBid>,
-    decrypted_values: Vec<Result<Vec<u8>, String>>,
-) -> Vec<DecryptedBid> {
-    let mut decrypted_bids = Vec::with_capacity(encrypted_bids.len());
-    for decrypted_value in decrypted_values {
-        let decrypted_bid: Result<u128, String> = decrypted_value
-            .and_then(|v| {
-                v.as_slice()
-                    .try_into()
-                    .map_err(|_| "failed to convert amount to u128".to_string())
-            })
-            .map(u128::from_le_bytes);
-        decrypted_bids.push(decrypted_bid);
-    }
-
-    encrypted_bids
-        .into_iter()
-        .zip(decrypted_bids)
-        .inspect(|(encrypted_bid, decrypted_bid)| {
-            if let Err(e) = decrypted_bid {
-                ic_cdk::println!(
-                    "Failed to decrypt bid for lot id {lot_id} by {}: {e}",
-                    encrypted_bid.bidder
-                );
-            }
-        })
-        .filter_map(|(encrypted_bid, decrypted_bid)| {
-            decrypted_bid.ok().map(|amount| DecryptedBid {
-                amount,
-                bidder: encrypted_bid.bidder,
-            })
-        })
-        .collect()
+        .map_err(|e| format!("failed to decrypt ibe key: {e}"))
 }
 
 fn get_encrypted_bids_on_lot(lot_id: LotId) -> (Vec<BidCounter>, Vec<EncryptedBid>) {
@@ -348,20 +847,83 @@ fn get_encrypted_bids_on_lot(lot_id: LotId) -> (Vec<BidCounter>, Vec<EncryptedBi
     })
 }
 
-fn close_lot(lot_id: LotId, bid_counters: Vec<BidCounter>, decrypted_bids: Vec<DecryptedBid>) {
-    let status = match decrypted_bids
+fn close_lot(
+    lot_id: LotId,
+    lease_end: u64,
+    reserve_price: Option<Result<u128, String>>,
+    decrypted_bids: Vec<(BidCounter, DecryptedBid)>,
+) {
+    let lot_information = LOTS.with_borrow(|lots| lots.get(&lot_id).unwrap());
+    let deposits: Vec<(Principal, u128)> = ESCROWED_DEPOSITS.with_borrow(|deposits| {
+        deposits
+            .range((lot_id, Principal::management_canister())..)
+            .take_while(|((this_lot_id, _), _)| *this_lot_id == lot_id)
+            .map(|((_, bidder), deposit)| (bidder, deposit))
+            .collect()
+    });
+
+    // A bid that is not covered by the bidder's deposit cannot be paid for, so it is disqualified.
+    let eligible_bids: Vec<(BidCounter, DecryptedBid)> = decrypted_bids
         .iter()
-        .rev() // reverse the bids to get the *oldest* maximum bid
-        .max_by(|x, y| x.amount.cmp(&y.amount))
-    {
-        Some(winner_bid) => LotStatus::ClosedWithWinner(winner_bid.bidder),
-        None => LotStatus::ClosedNoBids,
+        .filter(|(_, bid)| {
+            let deposit = deposits
+                .iter()
+                .find(|(bidder, _)| *bidder == bid.bidder)
+                .map_or(0, |(_, deposit)| *deposit);
+            let covered = bid.amount <= deposit;
+            if !covered {
+                ic_cdk::println!(
+                    "Bid on lot id {lot_id} by {} exceeds the deposit of {deposit}",
+                    bid.bidder
+                );
+            }
+            covered
+        })
+        .cloned()
+        .collect();
+    let AuctionOutcome {
+        status,
+        clearing_price,
+    } = auction_outcome(
+        lot_information.auction_kind,
+        reserve_price.clone(),
+        &eligible_bids,
+    );
+
+    let transfers = plan_settlement(
+        &status,
+        clearing_price,
+        lot_information.creator,
+        deposits.clone(),
+        ic_cdk::api::time(),
+    );
+    let mut settlement = LotSettlement {
+        transfers,
+        ..Default::default()
     };
+    if settlement.is_pending() {
+        schedule_settlement(lot_id, &mut settlement, Some(ic_cdk::api::time()));
+    }
+    SETTLEMENTS.with_borrow_mut(|settlements| {
+        settlements.insert(lot_id, settlement);
+    });
+    ESCROWED_DEPOSITS.with_borrow_mut(|escrowed_deposits| {
+        for (bidder, _) in &deposits {
+            escrowed_deposits.remove(&(lot_id, *bidder));
+        }
+    });
 
     BIDS_ON_LOTS.with_borrow_mut(|bids| {
-        for (bid_counter, decrypted_bid) in bid_counters.into_iter().zip(decrypted_bids.into_iter())
-        {
-            // replace the encrypted bid with the decrypted bid
+        // bids that failed to decrypt are ignored, so all encrypted bids are removed and only the decrypted ones are put back
+        let bid_keys: Vec<_> = bids
+            .range((lot_id, 0, Principal::management_canister())..)
+            .take_while(|((this_lot_id, _, _), _)| *this_lot_id == lot_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in bid_keys {
+            bids.remove(&key);
+        }
+        for (bid_counter, decrypted_bid) in decrypted_bids {
             bids.insert(
                 (lot_id, bid_counter, decrypted_bid.bidder),
                 Bid::Decrypted(decrypted_bid),
@@ -369,13 +931,19 @@ fn close_lot(lot_id: LotId, bid_counters: Vec<BidCounter>, decrypted_bids: Vec<D
         }
     });
 
+    ENCRYPTED_RESERVE_PRICES.with_borrow_mut(|reserve_prices| reserve_prices.remove(&lot_id));
+    OPEN_LOTS_DEADLINES.with_borrow_mut(|open_lots_deadlines| {
+        open_lots_deadlines.remove(&(lease_end, lot_id))
+    });
+
     LOTS.with_borrow_mut(|lots| {
-        let lot_information = lots.get(&lot_id).unwrap();
         lots.insert(
             lot_id,
             LotInformation {
                 id: lot_id,
                 status,
+                reserve_price: reserve_price.and_then(Result::ok),
+                clearing_price,
                 ..lot_information
             },
         );
@@ -0,0 +1,126 @@
+//! A generic time-lock encryption service. Time is divided into buckets, and a plaintext is time-locked by encrypting
+//! it with IBE for the identity of the bucket containing its unlock time. Once a bucket for which ciphertexts were
+//! submitted has elapsed, the canister publishes the IBE decryption key of the bucket, with which anyone can decrypt
+//! the ciphertexts for it off-chain.
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::borrow::Cow;
+
+pub type TimelockedId = u64;
+
+pub const TIME_BUCKET_SECS: u64 = 60;
+const TIME_BUCKET_NANOS: u64 = TIME_BUCKET_SECS * 1_000_000_000;
+
+/// The domain separator of the time bucket keys, which differs from the one of the auction, so that the published keys
+/// are unrelated to the keys decrypting bids.
+pub const TIMELOCK_DOMAIN_SEPARATOR: &str = "basic_timelock_ibe_example_dapp_timelock";
+
+pub const MAX_TIMELOCKED_CIPHERTEXT_SIZE: usize = 10_000;
+
+/// The number of time-locked ciphertexts the canister stores in total, and per submitter.
+pub const MAX_TIMELOCKED: u64 = 10_000;
+pub const MAX_TIMELOCKED_PER_SUBMITTER: u64 = 100;
+
+/// How long time-locked ciphertexts and the key of their bucket are kept after the bucket has elapsed, so that the
+/// storage is freed for new submissions.
+pub const TIMELOCKED_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// The number of expired ciphertexts and keys removed in a single run of the timer.
+pub const MAX_EXPIRED_REMOVALS_PER_RUN: usize = 100;
+
+/// The number of time bucket keys derived concurrently in a single run of the timer. As a run is started every few
+/// seconds and a bucket lasts a minute, the timer catches up quickly after it was stopped.
+pub const MAX_CONCURRENT_KEY_DERIVATIONS: usize = 10;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TimeBucket {
+    pub index: u64,
+    /// The IBE identity to encrypt for
+    pub identity: ByteBuf,
+    /// The time from which on the decryption key of the bucket is published, which is the end of the bucket
+    pub unlocks_at: u64,
+}
+
+impl TimeBucket {
+    /// Returns the first bucket whose key is published no earlier than `unlock_time`.
+    pub fn containing(unlock_time: u64) -> Self {
+        Self::from_index(unlock_time.div_ceil(TIME_BUCKET_NANOS))
+    }
+
+    pub fn from_index(index: u64) -> Self {
+        Self {
+            index,
+            identity: ByteBuf::from(index.to_be_bytes().to_vec()),
+            unlocks_at: index.saturating_mul(TIME_BUCKET_NANOS),
+        }
+    }
+
+    /// Whether the ciphertexts for the bucket and its key are removed at time `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.unlocks_at.saturating_add(TIMELOCKED_RETENTION_NANOS) <= now
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Timelocked {
+    pub submitter: Principal,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+    pub unlock_time: u64,
+    pub time_bucket: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TimelockedResponse {
+    pub timelocked: Timelocked,
+    /// The IBE decryption key of the time bucket, once it is published
+    pub decryption_key: Option<ByteBuf>,
+}
+
+impl Storable for Timelocked {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlock_times_are_rounded_up_to_the_end_of_their_bucket() {
+        assert_eq!(TimeBucket::containing(0).index, 0);
+        assert_eq!(TimeBucket::containing(1).index, 1);
+        assert_eq!(TimeBucket::containing(TIME_BUCKET_NANOS).index, 1);
+        assert_eq!(TimeBucket::containing(TIME_BUCKET_NANOS + 1).index, 2);
+        for unlock_time in [1, TIME_BUCKET_NANOS - 1, 5 * TIME_BUCKET_NANOS + 7] {
+            let bucket = TimeBucket::containing(unlock_time);
+            assert!(bucket.unlocks_at >= unlock_time);
+            assert!(bucket.unlocks_at - unlock_time < TIME_BUCKET_NANOS);
+        }
+    }
+
+    #[test]
+    fn buckets_expire_after_the_retention_period() {
+        let bucket = TimeBucket::from_index(10);
+        assert!(!bucket.is_expired(bucket.unlocks_at));
+        assert!(!bucket.is_expired(bucket.unlocks_at + TIMELOCKED_RETENTION_NANOS - 1));
+        assert!(bucket.is_expired(bucket.unlocks_at + TIMELOCKED_RETENTION_NANOS));
+    }
+
+    #[test]
+    fn bucket_identities_are_big_endian_indices() {
+        assert_eq!(
+            TimeBucket::from_index(258).identity.into_vec(),
+            vec![0, 0, 0, 0, 0, 0, 1, 2]
+        );
+    }
+}
@@ -28,7 +28,7 @@ pub enum Bid {
 }
 
 impl Storable for EncryptedBid {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -40,7 +40,7 @@ impl Storable for EncryptedBid {
 }
 
 impl Storable for DecryptedBid {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -52,7 +52,7 @@ impl Storable for DecryptedBid {
 }
 
 impl Storable for Bid {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
@@ -72,6 +72,31 @@ pub struct LotInformation {
     pub end_time: u64,
     pub creator: Principal,
     pub status: LotStatus,
+    #[serde(default)]
+    pub auction_kind: AuctionKind,
+    /// Whether the creator set an encrypted reserve price. The reserve price itself is only revealed once the lot is closed.
+    #[serde(default)]
+    pub has_reserve_price: bool,
+    #[serde(default)]
+    pub reserve_price: Option<u128>,
+    /// The price the winner pays, set once the lot is closed with a winner
+    #[serde(default)]
+    pub clearing_price: Option<u128>,
+    /// The number of failed attempts to close the lot since it expired. Closing is retried with an increasing delay.
+    #[serde(default)]
+    pub failed_closing_attempts: u32,
+    #[serde(default)]
+    pub last_closing_error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AuctionKind {
+    /// The highest bidder wins and pays their own bid
+    #[default]
+    FirstPrice,
+    /// The highest bidder wins and pays the second highest bid, or the reserve price if no other bid meets it. A
+    /// single bid without a reserve price pays its own amount.
+    SecondPrice,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -82,6 +107,8 @@ pub enum LotStatus {
     ClosedWithWinner(Principal),
     /// The auction is closed and no bids were made
     ClosedNoBids,
+    /// The auction is closed and no bid met the reserve price
+    ClosedReserveNotMet,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
@@ -97,7 +124,69 @@ pub struct ClosedLotsResponse {
 }
 
 impl Storable for LotInformation {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SettlementTransferKind {
+    /// The winner's payment of the clearing price to the lot creator
+    Payment,
+    /// The return of (the rest of) a bidder's deposit
+    Refund,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum SettlementTransferStatus {
+    /// The transfer is not executed yet, or failed with an error that may go away and is retried
+    Pending,
+    /// The transfer was executed in the ledger block with the given index
+    Completed { block_index: u64 },
+    /// The transfer was rejected by the ledger and is not retried
+    Failed(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SettlementTransfer {
+    pub kind: SettlementTransferKind,
+    pub to: Principal,
+    /// The amount taken from the escrow, including the ledger fee
+    pub amount: u128,
+    /// Fixed when the lot is closed, so that the ledger deduplicates retries of the transfer. Only renewed if the ledger
+    /// rejects the transfer as too old.
+    pub created_at_time: u64,
+    pub status: SettlementTransferStatus,
+}
+
+/// The transfers out of the escrow of a closed lot.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LotSettlement {
+    pub transfers: Vec<SettlementTransfer>,
+    /// The number of consecutive settlement runs that left transfers pending
+    #[serde(default)]
+    pub failed_attempts: u32,
+    /// When the pending transfers are executed next, or `None` if no transfer is pending
+    #[serde(default)]
+    pub next_attempt_time: Option<u64>,
+}
+
+impl LotSettlement {
+    pub fn is_pending(&self) -> bool {
+        self.transfers
+            .iter()
+            .any(|transfer| transfer.status == SettlementTransferStatus::Pending)
+    }
+}
+
+impl Storable for LotSettlement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize"))
     }
 
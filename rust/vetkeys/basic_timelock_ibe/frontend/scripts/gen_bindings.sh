@@ -17,3 +17,10 @@ npx --yes @icp-sdk/bindgen \
     --did-file "$EXAMPLE_ROOT/backend/backend.did" \
     --out-dir "$FRONTEND_DIR/src/declarations/basic_timelock_ibe" \
     --declarations-flat --force
+
+rm -rf "$FRONTEND_DIR/src/declarations/ledger"
+mkdir -p "$FRONTEND_DIR/src/declarations/ledger"
+npx --yes @icp-sdk/bindgen \
+    --did-file "$SCRIPT_DIR/ledger.did" \
+    --out-dir "$FRONTEND_DIR/src/declarations/ledger" \
+    --declarations-flat --force
@@ -1,5 +1,15 @@
 import "./style.css";
-import { createActor, type Backend, type LotInformation } from "./declarations/basic_timelock_ibe/backend";
+import {
+    createActor,
+    type AuctionKind,
+    type Backend,
+    type LotInformation,
+    type LotSettlement,
+} from "./declarations/basic_timelock_ibe/backend";
+import {
+    createActor as createLedgerActor,
+    type Ledger,
+} from "./declarations/ledger/ledger";
 import { Principal } from "@icp-sdk/core/principal";
 import {
     DerivedPublicKey,
@@ -19,26 +29,50 @@ let ibePublicKey: DerivedPublicKey | undefined = undefined;
 let myPrincipal: Principal | undefined = undefined;
 let authClient: AuthClient | undefined;
 let basicTimelockIbeCanister: Backend | undefined;
+let ledgerCanister: Ledger | undefined;
 
-async function getBasicTimelockIbeCanister(): Promise<Backend> {
-    if (basicTimelockIbeCanister) return basicTimelockIbeCanister;
-    const canisterId = canisterEnv?.["PUBLIC_CANISTER_ID:basic_timelock_ibe"];
-    if (!canisterId) throw Error("Canister ID for basic_timelock_ibe is not set");
+async function createAgent(): Promise<HttpAgent> {
     if (!authClient) {
         throw Error("Auth client is not initialized");
     }
 
-    const agent = await HttpAgent.create({
+    return await HttpAgent.create({
         identity: await authClient.getIdentity(),
         host: window.location.origin,
         rootKey: canisterEnv?.IC_ROOT_KEY,
     });
+}
+
+function getBasicTimelockIbeCanisterId(): string {
+    const canisterId = canisterEnv?.["PUBLIC_CANISTER_ID:basic_timelock_ibe"];
+    if (!canisterId) throw Error("Canister ID for basic_timelock_ibe is not set");
+    return canisterId;
+}
+
+async function getBasicTimelockIbeCanister(): Promise<Backend> {
+    if (basicTimelockIbeCanister) return basicTimelockIbeCanister;
 
-    basicTimelockIbeCanister = createActor(canisterId, { agent });
+    basicTimelockIbeCanister = createActor(getBasicTimelockIbeCanisterId(), {
+        agent: await createAgent(),
+    });
 
     return basicTimelockIbeCanister;
 }
 
+// The ledger holding the bid deposits is configured in the backend.
+async function getLedgerCanister(): Promise<Ledger> {
+    if (ledgerCanister) return ledgerCanister;
+    const ledgerId = await (
+        await getBasicTimelockIbeCanister()
+    ).get_payment_ledger();
+
+    ledgerCanister = createLedgerActor(ledgerId.toText(), {
+        agent: await createAgent(),
+    });
+
+    return ledgerCanister;
+}
+
 export async function login(client: AuthClient): Promise<void> {
     try {
         const identity = await client.signIn({
@@ -55,6 +89,7 @@ export function logout() {
     void authClient?.signOut();
     myPrincipal = undefined;
     basicTimelockIbeCanister = undefined;
+    ledgerCanister = undefined;
     updateUI(false);
 
     // Reset the lots list and form visibility
@@ -140,6 +175,17 @@ document.querySelector<HTMLDivElement>("#app")!.innerHTML = `
           <label for="lotDuration">Duration (seconds)</label>
           <input type="number" id="lotDuration" min="1" required>
         </div>
+        <div>
+          <label for="lotAuctionKind">Auction type</label>
+          <select id="lotAuctionKind">
+            <option value="FirstPrice">First price (the winner pays their bid)</option>
+            <option value="SecondPrice">Second price (the winner pays the second highest bid)</option>
+          </select>
+        </div>
+        <div>
+          <label for="lotReservePrice">Secret reserve price (optional)</label>
+          <input type="number" id="lotReservePrice" min="1">
+        </div>
         <button type="submit">Submit</button>
       </form>
     </div>
@@ -171,7 +217,19 @@ document.getElementById("createLotForm")!.addEventListener("submit", (e) => {
     const duration = parseInt(
         (document.getElementById("lotDuration") as HTMLInputElement).value,
     );
-    void createLot(name, description, duration);
+    const auctionKind = (
+        document.getElementById("lotAuctionKind") as HTMLSelectElement
+    ).value;
+    const reservePrice = (
+        document.getElementById("lotReservePrice") as HTMLInputElement
+    ).value;
+    void createLot(
+        name,
+        description,
+        duration,
+        auctionKind === "SecondPrice" ? { SecondPrice: null } : { FirstPrice: null },
+        reservePrice === "" ? undefined : BigInt(reservePrice),
+    );
 });
 
 async function getIbePublicKey(): Promise<DerivedPublicKey> {
@@ -202,13 +260,36 @@ async function createLot(
     name: string,
     description: string,
     durationSeconds: number,
+    auctionKind: AuctionKind,
+    reservePrice: bigint | undefined,
 ) {
-    const result = await (await getBasicTimelockIbeCanister()).create_lot(
+    const canister = await getBasicTimelockIbeCanister();
+    const result = await canister.create_lot(
         name,
         description,
         durationSeconds,
+        auctionKind,
     );
     if ("Ok" in result) {
+        // The reserve price is encrypted for the lot ID, just like the bids,
+        // so it can only be set once the lot exists.
+        if (reservePrice !== undefined) {
+            const encryptedReservePrice = await encrypt(
+                u128ToLeBytes(reservePrice),
+                u128ToLeBytes(result.Ok),
+            );
+            const reserveResult = await canister.set_reserve_price(
+                result.Ok,
+                encryptedReservePrice,
+            );
+            if ("Err" in reserveResult) {
+                alert(
+                    `Lot created with ID ${result.Ok.toString()}, but failed to set the reserve price: ${reserveResult.Err}`,
+                );
+                document.getElementById("lotForm")!.classList.toggle("hidden", true);
+                return;
+            }
+        }
         alert(`Lot created successfully with ID: ${result.Ok.toString()}`);
     } else {
         alert(`Failed to create lot: ${result.Err}`);
@@ -261,6 +342,30 @@ function getStatusForClosedLot(
     }
 }
 
+function formatAuctionKind(lot: LotInformation): string {
+    const kind =
+        "SecondPrice" in lot.auction_kind ? "Second price" : "First price";
+    return lot.has_reserve_price ? `${kind}, with a secret reserve price` : kind;
+}
+
+function formatSettlement(settlement: [] | [LotSettlement]): string {
+    if (settlement.length === 0 || settlement[0].transfers.length === 0) {
+        return " nothing to settle";
+    }
+    return settlement[0].transfers
+        .map((transfer) => {
+            const kind = "Payment" in transfer.kind ? "Payment" : "Refund";
+            const status =
+                "Completed" in transfer.status
+                    ? `completed in block ${transfer.status.Completed.block_index}`
+                    : "Failed" in transfer.status
+                      ? `failed: ${transfer.status.Failed}`
+                      : "pending";
+            return `<br>${kind} of ${transfer.amount} to ${formatPrincipal(transfer.to)}: ${status}`;
+        })
+        .join("");
+}
+
 function formatPrincipal(
     principal: Principal,
     isWinner: boolean = false,
@@ -328,7 +433,9 @@ async function listLots() {
           <h5>Name: ${lot.name}</h5>
           <p>Description: ${lot.description}</p>
           <p>Creator: ${lot.creator.toText()}</p>
+          <p>Auction type: ${formatAuctionKind(lot)}</p>
           <p>Closing in: ${formatCountdown(lot.end_time)}</p>
+          ${lot.last_closing_error.length > 0 ? `<p>Closing failed ${lot.failed_closing_attempts} time(s) and is retried: ${lot.last_closing_error[0]}</p>` : ""}
           ${status}
           <p>Bidders:${openLots.bidders[index].length === 0 ? " no bidders yet" : openLots.bidders[index].map((bidder) => "<br>" + formatPrincipal(bidder)).join("")}</p>
           ${
@@ -339,6 +446,10 @@ async function listLots() {
               <label for="bidAmount-${lot.id}">Bid Amount:</label>
               <input type="number" id="bidAmount-${lot.id}" min="1" required>
             </div>
+            <div>
+              <label for="bidDeposit-${lot.id}">Deposit (at least the bid amount, 0 to keep an earlier deposit):</label>
+              <input type="number" id="bidDeposit-${lot.id}" min="0" required>
+            </div>
             <button type="submit">Place Bid</button>
           </form>
           `
@@ -358,7 +469,14 @@ async function listLots() {
                                     ) as HTMLInputElement
                                 ).value,
                             );
-                            void placeBid(lot.id, amount);
+                            const deposit = BigInt(
+                                (
+                                    document.getElementById(
+                                        `bidDeposit-${lot.id}`,
+                                    ) as HTMLInputElement
+                                ).value,
+                            );
+                            void placeBid(lot.id, amount, deposit);
                         });
                     }
                 }
@@ -382,6 +500,11 @@ async function listLots() {
             closedLots.lots.reverse();
             closedLots.bids.reverse();
 
+            const canister = await getBasicTimelockIbeCanister();
+            const settlements = await Promise.all(
+                closedLots.lots.map((lot) => canister.get_lot_settlement(lot.id)),
+            );
+
             closedLots.lots.forEach((lot, index) => {
                 const lotDiv = document.createElement("div");
                 lotDiv.className = "lot";
@@ -399,7 +522,10 @@ async function listLots() {
           <h5>Name: ${lot.name}</h5>
           <p>Description: ${lot.description}</p>
           <p>Creator: ${formatPrincipal(lot.creator)}</p>
-          <p>Winner: ${"ClosedWithWinner" in lot.status ? formatPrincipal(lot.status.ClosedWithWinner, isWinner) : "No winner"}</p>
+          <p>Auction type: ${formatAuctionKind(lot)}</p>
+          <p>Winner: ${"ClosedWithWinner" in lot.status ? formatPrincipal(lot.status.ClosedWithWinner, isWinner) : "ClosedReserveNotMet" in lot.status ? "No winner, the reserve price was not met" : "No winner"}</p>
+          ${lot.clearing_price.length > 0 ? `<p>Price paid: ${lot.clearing_price[0]}</p>` : ""}
+          ${lot.reserve_price.length > 0 ? `<p>Reserve price: ${lot.reserve_price[0]}</p>` : ""}
           <p>Ended at: ${new Date(Number(lot.end_time) / 1000000).toLocaleString()}</p>
           ${status}
           <p>Bids: ${
@@ -418,6 +544,7 @@ async function listLots() {
                         )
                         .join("")
           }</p>
+          <p>Settlement: ${formatSettlement(settlements[index])}</p>
         `;
 
                 fragment.appendChild(lotDiv);
@@ -433,8 +560,33 @@ async function listLots() {
     }
 }
 
-async function placeBid(lotId: bigint, amount: number) {
+async function placeBid(lotId: bigint, amount: number, deposit: bigint) {
     try {
+        // Allow the backend to transfer the deposit, and the fee of that transfer, into the escrow of the lot.
+        if (deposit > 0n) {
+            const ledger = await getLedgerCanister();
+            const fee = await ledger.icrc1_fee();
+            const approval = await ledger.icrc2_approve({
+                spender: {
+                    owner: Principal.fromText(getBasicTimelockIbeCanisterId()),
+                    subaccount: [],
+                },
+                amount: deposit + fee,
+                fee: [],
+                memo: [],
+                from_subaccount: [],
+                created_at_time: [],
+                expected_allowance: [],
+                expires_at: [],
+            });
+            if ("Err" in approval) {
+                alert(
+                    `Failed to approve the deposit: ${JSON.stringify(approval.Err, (_, v: unknown) => (typeof v === "bigint" ? v.toString() : v))}`,
+                );
+                return;
+            }
+        }
+
         const lotIdBytes = u128ToLeBytes(lotId);
         const amountBytes = u128ToLeBytes(BigInt(amount));
 
@@ -445,6 +597,7 @@ async function placeBid(lotId: bigint, amount: number) {
         const result = await (await getBasicTimelockIbeCanister()).place_bid(
             lotId,
             encryptedAmount,
+            deposit,
         );
         if ("Err" in result) {
             alert(`Failed to place bid: ${result.Err}`);
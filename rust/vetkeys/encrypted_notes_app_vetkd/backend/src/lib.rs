@@ -12,29 +12,212 @@ use std::cell::RefCell;
 type PrincipalName = String;
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type NoteId = u128;
+type Version = u64;
+type KeyEpoch = u64;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum Role {
+    /// May read the note and its history.
+    Reader,
+    /// May additionally update the note and restore earlier versions.
+    Editor,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Share {
+    user: PrincipalName,
+    role: Role,
+    /// The time (in nanoseconds since the UNIX epoch) from which on the share no longer grants any access.
+    expires_at: Option<u64>,
+}
+
+impl Share {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct EncryptedNote {
     id: NoteId,
     encrypted_text: String,
+    /// The key epoch of the note key that [encrypted_text] is encrypted with.
+    encrypted_text_key_epoch: KeyEpoch,
     owner: PrincipalName,
-    /// Principals with whom this note is shared. Does not include the owner.
+    /// The user who created the note, which is needed to derive the note key of key epoch 0.
+    creator: PrincipalName,
+    /// Users with whom this note is shared. Does not include the owner.
     /// Needed to be able to efficiently show in the UI with whom this note is shared.
-    users: Vec<PrincipalName>,
+    shares: Vec<Share>,
+    /// The key epoch of the note key that updates must be encrypted with. A new key epoch
+    /// starts whenever a user loses access to the note, so that they cannot decrypt later updates.
+    key_epoch: KeyEpoch,
 }
 
 impl EncryptedNote {
-    pub fn is_authorized(&self, user: &PrincipalName) -> bool {
-        user == &self.owner || self.users.contains(user)
+    pub fn new(id: NoteId, owner: PrincipalName) -> Self {
+        Self {
+            id,
+            encrypted_text: String::new(),
+            encrypted_text_key_epoch: 0,
+            owner: owner.clone(),
+            creator: owner,
+            shares: vec![],
+            key_epoch: 0,
+        }
+    }
+
+    fn active_share(&self, user: &PrincipalName, now: u64) -> Option<&Share> {
+        self.shares
+            .iter()
+            .find(|share| &share.user == user && share.is_active(now))
+    }
+
+    pub fn is_authorized(&self, user: &PrincipalName, now: u64) -> bool {
+        user == &self.owner || self.active_share(user, now).is_some()
+    }
+
+    pub fn can_edit(&self, user: &PrincipalName, now: u64) -> bool {
+        user == &self.owner
+            || self
+                .active_share(user, now)
+                .is_some_and(|share| share.role == Role::Editor)
+    }
+
+    /// Users who may access the note may obtain the keys of all key epochs up to the
+    /// current one, which are needed to decrypt the earlier versions of the note.
+    pub fn may_get_key(&self, user: &PrincipalName, key_epoch: KeyEpoch, now: u64) -> bool {
+        self.is_authorized(user, now) && key_epoch <= self.key_epoch
+    }
+
+    /// Shares the note with [user], or updates the role and expiry of an existing share.
+    pub fn set_share(
+        &mut self,
+        user: PrincipalName,
+        role: Role,
+        expires_at: Option<u64>,
+    ) -> Result<(), String> {
+        if user == self.owner {
+            return Err("the note cannot be shared with its owner".to_string());
+        }
+        if let Some(share) = self.shares.iter_mut().find(|share| share.user == user) {
+            share.role = role;
+            share.expires_at = expires_at;
+            return Ok(());
+        }
+        if self.shares.len() >= MAX_SHARES_PER_NOTE {
+            return Err(format!("note {} is shared with too many users", self.id));
+        }
+        self.shares.push(Share {
+            user,
+            role,
+            expires_at,
+        });
+        Ok(())
+    }
+
+    /// Unshares the note with [user] and starts a new key epoch.
+    /// Returns whether the note was shared with [user].
+    pub fn remove_share(&mut self, user: &PrincipalName) -> bool {
+        let shares_before = self.shares.len();
+        self.shares.retain(|share| &share.user != user);
+        let removed = self.shares.len() < shares_before;
+        if removed {
+            self.key_epoch += 1;
+        }
+        removed
+    }
+
+    /// Removes the expired shares and starts a new key epoch if there were any.
+    /// Returns the users whose shares were removed.
+    pub fn remove_expired_shares(&mut self, now: u64) -> Vec<PrincipalName> {
+        let (active, expired): (Vec<Share>, Vec<Share>) = std::mem::take(&mut self.shares)
+            .into_iter()
+            .partition(|share| share.is_active(now));
+        self.shares = active;
+        if !expired.is_empty() {
+            self.key_epoch += 1;
+        }
+        expired.into_iter().map(|share| share.user).collect()
+    }
+
+    /// Makes [new_owner] the owner of the note. The previous owner keeps editing access.
+    /// Since nobody loses access, the key epoch does not change.
+    pub fn transfer_ownership(&mut self, new_owner: PrincipalName) -> Result<(), String> {
+        if new_owner == self.owner {
+            return Err(format!("{new_owner} already owns note {}", self.id));
+        }
+        let was_shared = self.shares.iter().any(|share| share.user == new_owner);
+        if !was_shared && self.shares.len() >= MAX_SHARES_PER_NOTE {
+            return Err(format!("note {} is shared with too many users", self.id));
+        }
+        self.shares.retain(|share| share.user != new_owner);
+        let previous_owner = std::mem::replace(&mut self.owner, new_owner);
+        self.shares.push(Share {
+            user: previous_owner,
+            role: Role::Editor,
+            expires_at: None,
+        });
+        Ok(())
+    }
+
+    /// The vetKD input from which the note key of [key_epoch] is derived. For key epoch 0,
+    /// this is the note ID followed by the creator, which is how note keys were derived
+    /// before key epochs were introduced. For later key epochs, it is the note ID followed
+    /// by a zero byte and the key epoch. Since principals in text form never contain a zero
+    /// byte, the inputs of different key epochs are distinct.
+    pub fn key_input(&self, key_epoch: KeyEpoch) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&self.id.to_be_bytes()); // fixed-size encoding
+        if key_epoch == 0 {
+            buf.extend_from_slice(self.creator.as_bytes());
+        } else {
+            buf.push(0);
+            buf.extend_from_slice(&key_epoch.to_be_bytes());
+        }
+        buf // prefix-free
+    }
+}
+
+/// The format in which notes were stored before roles and key epochs were introduced.
+#[derive(CandidType, Deserialize)]
+struct LegacyEncryptedNote {
+    id: NoteId,
+    encrypted_text: String,
+    owner: PrincipalName,
+    users: Vec<PrincipalName>,
+}
+
+impl From<LegacyEncryptedNote> for EncryptedNote {
+    fn from(note: LegacyEncryptedNote) -> Self {
+        Self {
+            id: note.id,
+            encrypted_text: note.encrypted_text,
+            encrypted_text_key_epoch: 0,
+            creator: note.owner.clone(),
+            owner: note.owner,
+            // Previously, all users with whom a note was shared could edit it.
+            shares: note
+                .users
+                .into_iter()
+                .map(|user| Share {
+                    user,
+                    role: Role::Editor,
+                    expires_at: None,
+                })
+                .collect(),
+            key_epoch: 0,
+        }
     }
 }
 
 impl Storable for EncryptedNote {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyEncryptedNote).unwrap().into())
     }
     const BOUND: Bound = Bound::Unbounded;
 }
@@ -51,7 +234,67 @@ impl NoteIds {
 }
 
 impl Storable for NoteIds {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct NoteVersion {
+    version: Version,
+    encrypted_text: String,
+    /// The key epoch of the note key that [encrypted_text] is encrypted with.
+    key_epoch: KeyEpoch,
+    /// The user who made the edit, which is either the owner or a user with whom the note is shared.
+    author: PrincipalName,
+    timestamp: u64,
+    /// The version whose text was restored by this edit, if any.
+    restored_from: Option<Version>,
+}
+
+/// The most recent versions of a note, oldest first.
+#[derive(CandidType, Deserialize, Default)]
+pub struct NoteHistory {
+    versions: Vec<NoteVersion>,
+}
+
+impl NoteHistory {
+    /// Appends a new version, evicting the oldest one if there are already
+    /// [MAX_VERSIONS_PER_NOTE] versions. Returns the number of the new version.
+    pub fn push(
+        &mut self,
+        encrypted_text: String,
+        key_epoch: KeyEpoch,
+        author: PrincipalName,
+        timestamp: u64,
+        restored_from: Option<Version>,
+    ) -> Version {
+        let version = self.versions.last().map_or(1, |last| last.version + 1);
+        if self.versions.len() >= MAX_VERSIONS_PER_NOTE {
+            self.versions.remove(0);
+        }
+        self.versions.push(NoteVersion {
+            version,
+            encrypted_text,
+            key_epoch,
+            author,
+            timestamp,
+            restored_from,
+        });
+        version
+    }
+
+    pub fn get(&self, version: Version) -> Option<&NoteVersion> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+}
+
+impl Storable for NoteHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -70,6 +313,7 @@ static MAX_USERS: u64 = 1_000;
 static MAX_NOTES_PER_USER: usize = 500;
 static MAX_NOTE_CHARS: usize = 1000;
 static MAX_SHARES_PER_NOTE: usize = 50;
+static MAX_VERSIONS_PER_NOTE: usize = 10;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -99,6 +343,13 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))),
         )
     );
+
+    static NOTE_HISTORIES: RefCell<StableBTreeMap<NoteId, NoteHistory, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4))),
+        )
+    );
+
     static KEY_NAME: RefCell<StableCell<String, Memory>> =
         RefCell::new(StableCell::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
@@ -163,28 +414,20 @@ fn whoami() -> String {
 #[update]
 fn get_notes() -> Vec<EncryptedNote> {
     let user_str = caller().to_string();
-    NOTES.with_borrow(|notes| {
-        let owned = NOTE_OWNERS.with_borrow(|ids| {
-            ids.get(&user_str)
-                .unwrap_or_default()
-                .iter()
-                .map(|id| notes.get(id).ok_or(format!("missing note with ID {id}")))
-                .collect::<Result<Vec<_>, _>>()
-                .unwrap_or_else(|err| ic_cdk::trap(&err))
-        });
-        let shared = NOTE_SHARES.with_borrow(|ids| {
-            ids.get(&user_str)
-                .unwrap_or_default()
-                .iter()
-                .map(|id| notes.get(id).ok_or(format!("missing note with ID {id}")))
-                .collect::<Result<Vec<_>, _>>()
+    let owned_ids = NOTE_OWNERS.with_borrow(|ids| ids.get(&user_str).unwrap_or_default());
+    let shared_ids = NOTE_SHARES.with_borrow(|ids| ids.get(&user_str).unwrap_or_default());
+    let now = ic_cdk::api::time();
+    owned_ids
+        .iter()
+        .chain(shared_ids.iter())
+        .map(|id| {
+            get_note(*id)
+                .ok_or(format!("missing note with ID {id}"))
                 .unwrap_or_else(|err| ic_cdk::trap(&err))
-        });
-        let mut result = Vec::with_capacity(owned.len() + shared.len());
-        result.extend(owned);
-        result.extend(shared);
-        result
-    })
+        })
+        // Removing the caller's expired share also removes the note from their shared notes.
+        .filter(|note| note.is_authorized(&user_str, now))
+        .collect()
 }
 
 /// Delete this [caller]'s note with given id. If none of the
@@ -216,41 +459,127 @@ fn delete_note(note_id: u128) {
                 }
             });
             NOTE_SHARES.with_borrow_mut(|share_to_nids| {
-                for share in note_to_delete.users {
-                    if let Some(mut share_ids) = share_to_nids.get(&share) {
+                for share in note_to_delete.shares {
+                    if let Some(mut share_ids) = share_to_nids.get(&share.user) {
                         share_ids.ids.retain(|&id| id != note_id);
                         if !share_ids.ids.is_empty() {
-                            share_to_nids.insert(share, share_ids);
+                            share_to_nids.insert(share.user, share_ids);
                         } else {
-                            share_to_nids.remove(&share);
+                            share_to_nids.remove(&share.user);
                         }
                     }
                 }
             });
             notes.remove(&note_id);
+            NOTE_HISTORIES.with_borrow_mut(|histories| histories.remove(&note_id));
         }
     });
 }
 
-/// Replaces the encrypted text of note with ID [id] with [encrypted_text].
+/// Replaces the encrypted text of note with ID [id] with [encrypted_text],
+/// which is encrypted with the note key of [key_epoch], and appends it as a
+/// new version to the note's history.
 ///
 /// Panics:
 ///     [caller] is the anonymous identity
-///     [caller] is not the note's owner and not a user with whom the note is shared
+///     [caller] is not the note's owner and not an editor with whom the note is shared
 ///     [encrypted_text] exceeds [MAX_NOTE_CHARS]
+///     [key_epoch] is not the note's current key epoch
 #[update]
-fn update_note(id: NoteId, encrypted_text: String) {
+fn update_note(id: NoteId, encrypted_text: String, key_epoch: KeyEpoch) {
     let user_str = caller().to_string();
 
-    NOTES.with_borrow_mut(|notes| {
-        if let Some(mut note_to_update) = notes.get(&id) {
-            if !note_to_update.is_authorized(&user_str) {
-                ic_cdk::trap("unauthorized update");
-            }
-            assert!(encrypted_text.chars().count() <= MAX_NOTE_CHARS);
-            note_to_update.encrypted_text = encrypted_text;
-            notes.insert(id, note_to_update);
+    if let Some(mut note_to_update) = get_note(id) {
+        if !note_to_update.can_edit(&user_str, ic_cdk::api::time()) {
+            ic_cdk::trap("unauthorized update");
         }
+        assert!(encrypted_text.chars().count() <= MAX_NOTE_CHARS);
+        if key_epoch != note_to_update.key_epoch {
+            ic_cdk::trap(format!(
+                "the note must be encrypted with the key of key epoch {}",
+                note_to_update.key_epoch
+            ));
+        }
+        note_to_update.encrypted_text = encrypted_text.clone();
+        note_to_update.encrypted_text_key_epoch = key_epoch;
+        NOTES.with_borrow_mut(|notes| notes.insert(id, note_to_update));
+        add_version(id, encrypted_text, key_epoch, user_str, None);
+    }
+}
+
+/// Returns the most recent versions of the note with ID [id], oldest first.
+/// At most [MAX_VERSIONS_PER_NOTE] versions are kept.
+///
+/// Panics:
+///     [caller] is the anonymous identity
+///     [caller] is not the note's owner and not a user with whom the note is shared
+///     the note does not exist
+#[update]
+fn get_note_history(id: NoteId) -> Vec<NoteVersion> {
+    let user_str = caller().to_string();
+    let note =
+        get_note(id).unwrap_or_else(|| ic_cdk::trap(format!("note with ID {id} does not exist")));
+    if !note.is_authorized(&user_str, ic_cdk::api::time()) {
+        ic_cdk::trap("unauthorized history request");
+    }
+    NOTE_HISTORIES.with_borrow(|histories| histories.get(&id).unwrap_or_default().versions)
+}
+
+/// Replaces the encrypted text of note with ID [id] with the one of [version]
+/// and appends it as a new version to the note's history.
+///
+/// Returns:
+///      Future of the number of the new version
+/// Panics:
+///     [caller] is the anonymous identity
+///     [caller] is not the note's owner and not an editor with whom the note is shared
+///     the note or the version does not exist
+#[update]
+fn restore_version(id: NoteId, version: Version) -> Version {
+    let user_str = caller().to_string();
+
+    let mut note =
+        get_note(id).unwrap_or_else(|| ic_cdk::trap(format!("note with ID {id} does not exist")));
+    if !note.can_edit(&user_str, ic_cdk::api::time()) {
+        ic_cdk::trap("unauthorized restore");
+    }
+    let restored = NOTE_HISTORIES
+        .with_borrow(|histories| {
+            histories
+                .get(&id)
+                .and_then(|history| history.get(version).cloned())
+        })
+        .unwrap_or_else(|| ic_cdk::trap(format!("version {version} of note {id} does not exist")));
+    note.encrypted_text = restored.encrypted_text.clone();
+    note.encrypted_text_key_epoch = restored.key_epoch;
+    NOTES.with_borrow_mut(|notes| notes.insert(id, note));
+    add_version(
+        id,
+        restored.encrypted_text,
+        restored.key_epoch,
+        user_str,
+        Some(version),
+    )
+}
+
+fn add_version(
+    id: NoteId,
+    encrypted_text: String,
+    key_epoch: KeyEpoch,
+    author: PrincipalName,
+    restored_from: Option<Version>,
+) -> Version {
+    NOTE_HISTORIES.with_borrow_mut(|histories| {
+        let mut history = histories.get(&id).unwrap_or_default();
+        let version = history.push(
+            encrypted_text,
+            key_epoch,
+            author,
+            ic_cdk::api::time(),
+            restored_from,
+        );
+        histories.insert(id, history);
+        version
     })
 }
 
@@ -269,12 +598,7 @@ fn create_note() -> NoteId {
     NOTES.with_borrow_mut(|id_to_note| {
         NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
             let next_note_id = NEXT_NOTE_ID.with_borrow(|id| *id.get());
-            let new_note = EncryptedNote {
-                id: next_note_id,
-                owner: owner.clone(),
-                users: vec![],
-                encrypted_text: String::new(),
-            };
+            let new_note = EncryptedNote::new(next_note_id, owner.clone());
 
             if let Some(mut owner_nids) = owner_to_nids.get(&owner) {
                 assert!(owner_nids.ids.len() < MAX_NOTES_PER_USER);
@@ -305,41 +629,31 @@ fn create_note() -> NoteId {
     })
 }
 
-/// Shares the note with ID `note_id`` with the `user`.
-/// Has no effect if the note is already shared with that user.
+/// Shares the note with ID `note_id`` with the `user` in the given `role`
+/// until `expires_at` (in nanoseconds since the UNIX epoch), if set.
+/// If the note is already shared with that user, updates the role and expiry.
 ///
 /// Panics:
 ///      [caller] is the anonymous identity
 ///      [caller] is not the owner of note with id `note_id`
+///      `user` is the owner of the note
+///      the note is already shared with [MAX_SHARES_PER_NOTE] users
 #[update]
-fn add_user(note_id: NoteId, user: PrincipalName) {
+fn add_user(note_id: NoteId, user: PrincipalName, role: Role, expires_at: Option<u64>) {
     let caller_str = caller().to_string();
-    NOTES.with_borrow_mut(|notes| {
-        NOTE_SHARES.with_borrow_mut(|user_to_nids| {
-            if let Some(mut note) = notes.get(&note_id) {
-                let owner = &note.owner;
-                if owner != &caller_str {
-                    ic_cdk::trap("only the owner can share the note");
-                }
-                assert!(note.users.len() < MAX_SHARES_PER_NOTE);
-                if !note.users.contains(&user) {
-                    note.users.push(user.clone());
-                    notes.insert(note_id, note);
-                }
-                if let Some(mut user_ids) = user_to_nids.get(&user) {
-                    if !user_ids.ids.contains(&note_id) {
-                        user_ids.ids.push(note_id);
-                        user_to_nids.insert(user, user_ids);
-                    }
-                } else {
-                    user_to_nids.insert(user, NoteIds { ids: vec![note_id] });
-                }
-            }
-        })
-    });
+    if let Some(mut note) = get_note(note_id) {
+        if note.owner != caller_str {
+            ic_cdk::trap("only the owner can share the note");
+        }
+        note.set_share(user.clone(), role, expires_at)
+            .unwrap_or_else(|err| ic_cdk::trap(&err));
+        NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+        NOTE_SHARES.with_borrow_mut(|user_to_nids| index_note(user_to_nids, user, note_id));
+    }
 }
 
-/// Unshares the note with ID `note_id`` with the `user`.
+/// Unshares the note with ID `note_id`` with the `user` and starts a new key epoch,
+/// so that the user cannot decrypt later updates of the note.
 /// Has no effect if the note is not shared with that user.
 ///
 /// Panics:
@@ -348,27 +662,99 @@ fn add_user(note_id: NoteId, user: PrincipalName) {
 #[update]
 fn remove_user(note_id: NoteId, user: PrincipalName) {
     let caller_str = caller().to_string();
-    NOTES.with_borrow_mut(|notes| {
-        NOTE_SHARES.with_borrow_mut(|user_to_nids| {
-            if let Some(mut note) = notes.get(&note_id) {
-                let owner = &note.owner;
-                if owner != &caller_str {
-                    ic_cdk::trap("only the owner can share the note");
-                }
-                note.users.retain(|u| u != &user);
-                notes.insert(note_id, note);
+    if let Some(mut note) = get_note(note_id) {
+        if note.owner != caller_str {
+            ic_cdk::trap("only the owner can share the note");
+        }
+        if note.remove_share(&user) {
+            NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+            NOTE_SHARES.with_borrow_mut(|user_to_nids| unindex_note(user_to_nids, &user, note_id));
+        }
+    }
+}
 
-                if let Some(mut user_ids) = user_to_nids.get(&user) {
-                    user_ids.ids.retain(|&id| id != note_id);
-                    if !user_ids.ids.is_empty() {
-                        user_to_nids.insert(user, user_ids);
-                    } else {
-                        user_to_nids.remove(&user);
-                    }
-                }
-            }
-        })
+/// Makes `new_owner` the owner of the note with ID `note_id`. The [caller]
+/// keeps editing access to the note.
+///
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not the owner of note with id `note_id`
+///      `new_owner` is not a valid principal or is the anonymous principal
+///      `new_owner` already has [MAX_NOTES_PER_USER] notes
+///      This is the first note for `new_owner` and [MAX_USERS] is exceeded
+#[update]
+fn transfer_ownership(note_id: NoteId, new_owner: PrincipalName) {
+    let caller_str = caller().to_string();
+    let new_owner_principal = Principal::from_text(&new_owner)
+        .unwrap_or_else(|_| ic_cdk::trap(format!("invalid principal {new_owner}")));
+    if new_owner_principal == Principal::anonymous() {
+        ic_cdk::trap("the anonymous principal cannot own notes");
+    }
+    let mut note = get_note(note_id)
+        .unwrap_or_else(|| ic_cdk::trap(format!("note with ID {note_id} does not exist")));
+    if note.owner != caller_str {
+        ic_cdk::trap("only the owner can transfer the note");
+    }
+    note.transfer_ownership(new_owner.clone())
+        .unwrap_or_else(|err| ic_cdk::trap(&err));
+
+    NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
+        let new_owner_nids = owner_to_nids.get(&new_owner);
+        match &new_owner_nids {
+            Some(nids) => assert!(nids.ids.len() < MAX_NOTES_PER_USER),
+            None => assert!(owner_to_nids.len() < MAX_USERS),
+        }
+        unindex_note(owner_to_nids, &caller_str, note_id);
+        index_note(owner_to_nids, new_owner.clone(), note_id);
+    });
+    NOTE_SHARES.with_borrow_mut(|user_to_nids| {
+        unindex_note(user_to_nids, &new_owner, note_id);
+        index_note(user_to_nids, caller_str, note_id);
     });
+    NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+}
+
+/// Returns the note with ID [id] after removing its expired shares, which
+/// starts a new key epoch if there were any.
+fn get_note(id: NoteId) -> Option<EncryptedNote> {
+    let mut note = NOTES.with_borrow(|notes| notes.get(&id))?;
+    let expired_users = note.remove_expired_shares(ic_cdk::api::time());
+    if !expired_users.is_empty() {
+        NOTE_SHARES.with_borrow_mut(|user_to_nids| {
+            for user in &expired_users {
+                unindex_note(user_to_nids, user, id);
+            }
+        });
+        NOTES.with_borrow_mut(|notes| notes.insert(id, note.clone()));
+    }
+    Some(note)
+}
+
+fn index_note(
+    user_to_nids: &mut StableBTreeMap<PrincipalName, NoteIds, Memory>,
+    user: PrincipalName,
+    note_id: NoteId,
+) {
+    let mut user_ids = user_to_nids.get(&user).unwrap_or_default();
+    if !user_ids.ids.contains(&note_id) {
+        user_ids.ids.push(note_id);
+        user_to_nids.insert(user, user_ids);
+    }
+}
+
+fn unindex_note(
+    user_to_nids: &mut StableBTreeMap<PrincipalName, NoteIds, Memory>,
+    user: &PrincipalName,
+    note_id: NoteId,
+) {
+    if let Some(mut user_ids) = user_to_nids.get(user) {
+        user_ids.ids.retain(|&id| id != note_id);
+        if !user_ids.ids.is_empty() {
+            user_to_nids.insert(user.clone(), user_ids);
+        } else {
+            user_to_nids.remove(user);
+        }
+    }
 }
 
 use ic_cdk_management_canister::{
@@ -394,29 +780,23 @@ async fn symmetric_key_verification_key_for_note() -> String {
 #[update]
 async fn encrypted_symmetric_key_for_note(
     note_id: NoteId,
+    key_epoch: KeyEpoch,
     transport_public_key: Vec<u8>,
 ) -> String {
     let user_str = caller().to_string();
-    let request = NOTES.with_borrow(|notes| {
-        if let Some(note) = notes.get(&note_id) {
-            if !note.is_authorized(&user_str) {
-                ic_cdk::trap(format!("unauthorized key request by user {user_str}"));
-            }
-            VetKDDeriveKeyArgs {
-                input: {
-                    let mut buf = vec![];
-                    buf.extend_from_slice(&note_id.to_be_bytes()); // fixed-size encoding
-                    buf.extend_from_slice(note.owner.as_bytes());
-                    buf // prefix-free
-                },
-                context: b"note_symmetric_key".to_vec(),
-                key_id: key_id(),
-                transport_public_key,
-            }
-        } else {
-            ic_cdk::trap(format!("note with ID {note_id} does not exist"));
+    let request = if let Some(note) = get_note(note_id) {
+        if !note.may_get_key(&user_str, key_epoch, ic_cdk::api::time()) {
+            ic_cdk::trap(format!("unauthorized key request by user {user_str}"));
         }
-    });
+        VetKDDeriveKeyArgs {
+            input: note.key_input(key_epoch),
+            context: b"note_symmetric_key".to_vec(),
+            key_id: key_id(),
+            transport_public_key,
+        }
+    } else {
+        ic_cdk::trap(format!("note with ID {note_id} does not exist"));
+    };
 
     let response: VetKDDeriveKeyResult = ic_cdk_management_canister::vetkd_derive_key(&request)
         .await
@@ -431,3 +811,138 @@ fn key_id() -> VetKDKeyId {
         name: KEY_NAME.with_borrow(|key_name| key_name.get().clone()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_keeps_the_most_recent_versions() {
+        let mut history = NoteHistory::default();
+        for i in 1..=MAX_VERSIONS_PER_NOTE as u64 + 2 {
+            let version = history.push(format!("text {i}"), 0, "user".to_string(), i, None);
+            assert_eq!(version, i);
+        }
+        assert_eq!(history.versions.len(), MAX_VERSIONS_PER_NOTE);
+        assert!(history.get(1).is_none());
+        assert!(history.get(2).is_none());
+        assert_eq!(history.get(3).unwrap().encrypted_text, "text 3");
+        assert_eq!(
+            history.versions.last().unwrap().version,
+            MAX_VERSIONS_PER_NOTE as u64 + 2
+        );
+    }
+
+    #[test]
+    fn restoring_appends_a_new_version() {
+        let mut history = NoteHistory::default();
+        history.push("first".to_string(), 0, "owner".to_string(), 1, None);
+        history.push("second".to_string(), 1, "editor".to_string(), 2, None);
+        let first = history.get(1).unwrap().clone();
+        assert_eq!(
+            history.push(
+                first.encrypted_text,
+                first.key_epoch,
+                "editor".to_string(),
+                3,
+                Some(1)
+            ),
+            3
+        );
+        let restored = history.get(3).unwrap();
+        assert_eq!(restored.encrypted_text, "first");
+        assert_eq!(restored.author, "editor");
+        assert_eq!(restored.restored_from, Some(1));
+        assert_eq!(restored.key_epoch, 0);
+    }
+
+    fn user(name: &str) -> PrincipalName {
+        name.to_string()
+    }
+
+    #[test]
+    fn readers_cannot_edit_and_expired_shares_grant_no_access() {
+        let mut note = EncryptedNote::new(1, user("owner"));
+        note.set_share(user("reader"), Role::Reader, None).unwrap();
+        note.set_share(user("editor"), Role::Editor, Some(10))
+            .unwrap();
+        assert!(note.set_share(user("owner"), Role::Reader, None).is_err());
+
+        assert!(note.can_edit(&user("owner"), 0));
+        assert!(note.is_authorized(&user("reader"), 0));
+        assert!(!note.can_edit(&user("reader"), 0));
+        assert!(note.can_edit(&user("editor"), 9));
+        assert!(!note.is_authorized(&user("editor"), 10));
+        assert!(!note.may_get_key(&user("editor"), 0, 10));
+        assert!(!note.is_authorized(&user("stranger"), 0));
+    }
+
+    #[test]
+    fn losing_access_starts_a_new_key_epoch() {
+        let mut note = EncryptedNote::new(1, user("owner"));
+        note.set_share(user("a"), Role::Editor, None).unwrap();
+        note.set_share(user("b"), Role::Reader, Some(10)).unwrap();
+        note.set_share(user("a"), Role::Reader, None).unwrap();
+        assert_eq!(note.key_epoch, 0);
+
+        assert!(note.remove_expired_shares(9).is_empty());
+        assert_eq!(note.remove_expired_shares(10), vec![user("b")]);
+        assert_eq!(note.key_epoch, 1);
+
+        assert!(!note.remove_share(&user("b")));
+        assert!(note.remove_share(&user("a")));
+        assert_eq!(note.key_epoch, 2);
+        assert!(note.may_get_key(&user("owner"), 2, 0));
+        assert!(!note.may_get_key(&user("owner"), 3, 0));
+    }
+
+    #[test]
+    fn previous_owner_keeps_editing_access() {
+        let mut note = EncryptedNote::new(1, user("owner"));
+        note.set_share(user("new"), Role::Reader, None).unwrap();
+        note.transfer_ownership(user("new")).unwrap();
+        assert_eq!(note.owner, "new");
+        assert_eq!(note.creator, "owner");
+        assert_eq!(
+            note.shares,
+            vec![Share {
+                user: user("owner"),
+                role: Role::Editor,
+                expires_at: None,
+            }]
+        );
+        assert_eq!(note.key_epoch, 0);
+        assert!(note.transfer_ownership(user("new")).is_err());
+    }
+
+    #[test]
+    fn key_inputs_differ_per_epoch_and_are_independent_of_the_owner() {
+        let mut note = EncryptedNote::new(1, user("owner"));
+        let epoch_0_input = note.key_input(0);
+        assert_eq!(
+            epoch_0_input,
+            [1u128.to_be_bytes().as_slice(), b"owner"].concat()
+        );
+        note.transfer_ownership(user("new")).unwrap();
+        assert_eq!(note.key_input(0), epoch_0_input);
+        assert_ne!(note.key_input(1), note.key_input(2));
+        assert_eq!(note.key_input(1).len(), 25);
+    }
+
+    #[test]
+    fn legacy_notes_are_shared_with_editors() {
+        let legacy = LegacyEncryptedNote {
+            id: 7,
+            encrypted_text: "text".to_string(),
+            owner: user("owner"),
+            users: vec![user("a")],
+        };
+        let note = EncryptedNote::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+        assert_eq!(note.creator, "owner");
+        assert_eq!(note.key_epoch, 0);
+        assert!(note.can_edit(&user("a"), 0));
+
+        let decoded = EncryptedNote::from_bytes(note.to_bytes());
+        assert_eq!(decoded, note);
+    }
+}
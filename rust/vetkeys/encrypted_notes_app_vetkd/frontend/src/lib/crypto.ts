@@ -7,30 +7,30 @@ export class CryptoService {
 
   constructor(private actor: BackendActor) {}
 
-  public async encryptWithNoteKey(note_id: bigint, owner: string, data: string): Promise<string> {
-    const keyMaterial = await this.fetchNoteKeyMaterial(note_id, owner);
-    const associatedData = buildInput(note_id, owner);
+  public async encryptWithNoteKey(note_id: bigint, creator: string, key_epoch: bigint, data: string): Promise<string> {
+    const keyMaterial = await this.fetchNoteKeyMaterial(note_id, creator, key_epoch);
+    const associatedData = buildInput(note_id, creator, key_epoch);
     const encrypted = await keyMaterial.encryptMessage(data, "note-key", associatedData);
     return String.fromCharCode(...encrypted);
   }
 
-  public async decryptWithNoteKey(note_id: bigint, owner: string, data: string): Promise<string> {
-    const keyMaterial = await this.fetchNoteKeyMaterial(note_id, owner);
-    const associatedData = buildInput(note_id, owner);
+  public async decryptWithNoteKey(note_id: bigint, creator: string, key_epoch: bigint, data: string): Promise<string> {
+    const keyMaterial = await this.fetchNoteKeyMaterial(note_id, creator, key_epoch);
+    const associatedData = buildInput(note_id, creator, key_epoch);
     const ciphertext = Uint8Array.from([...data].map(ch => ch.charCodeAt(0)));
     const decrypted = await keyMaterial.decryptMessage(ciphertext, "note-key", associatedData);
     return String.fromCharCode(...decrypted);
   }
 
-  private async fetchNoteKeyMaterial(note_id: bigint, owner: string): Promise<vetkd.DerivedKeyMaterial> {
-    const cacheKey = `${note_id}_${owner}`;
+  private async fetchNoteKeyMaterial(note_id: bigint, creator: string, key_epoch: bigint): Promise<vetkd.DerivedKeyMaterial> {
+    const cacheKey = `${note_id}_${creator}_${key_epoch}`;
 
     // 1. In-memory cache (fastest, session-scoped)
     const memCached = this.keyMaterialCache.get(cacheKey);
     if (memCached) return memCached;
 
     // 2. IndexedDB cache (persisted across sessions via getCryptoKey/fromCryptoKey)
-    const storedCryptoKey: CryptoKey | undefined = await get([note_id.toString(), owner]);
+    const storedCryptoKey: CryptoKey | undefined = await get([note_id.toString(), creator, key_epoch.toString()]);
     if (storedCryptoKey) {
       const keyMaterial = await vetkd.DerivedKeyMaterial.fromCryptoKey(storedCryptoKey);
       this.keyMaterialCache.set(cacheKey, keyMaterial);
@@ -39,27 +39,35 @@ export class CryptoService {
 
     // 3. Fetch from canister (first access)
     const tsk = vetkd.TransportSecretKey.random();
-    const ek_bytes_hex = await this.actor.encrypted_symmetric_key_for_note(note_id, tsk.publicKeyBytes());
+    const ek_bytes_hex = await this.actor.encrypted_symmetric_key_for_note(note_id, key_epoch, tsk.publicKeyBytes());
     const encryptedVetKey = vetkd.EncryptedVetKey.deserialize(hex_decode(ek_bytes_hex));
     const pk_bytes_hex = await this.actor.symmetric_key_verification_key_for_note();
     const dpk = vetkd.DerivedPublicKey.deserialize(hex_decode(pk_bytes_hex));
-    const input = buildInput(note_id, owner);
+    const input = buildInput(note_id, creator, key_epoch);
     const vetKey = encryptedVetKey.decryptAndVerify(tsk, dpk, input);
     const keyMaterial = await vetKey.asDerivedKeyMaterial();
 
     // Store the underlying non-extractable CryptoKey in IndexedDB (same pattern as EncryptedMaps)
-    await set([note_id.toString(), owner], keyMaterial.getCryptoKey());
+    await set([note_id.toString(), creator, key_epoch.toString()], keyMaterial.getCryptoKey());
     this.keyMaterialCache.set(cacheKey, keyMaterial);
     return keyMaterial;
   }
 }
 
-function buildInput(note_id: bigint, owner: string): Uint8Array {
+// Must match `EncryptedNote::key_input` in the backend: the note ID followed by
+// the creator for key epoch 0, and by a zero byte and the key epoch otherwise.
+function buildInput(note_id: bigint, creator: string, key_epoch: bigint): Uint8Array {
   const note_id_bytes = bigintTo128BitBigEndianUint8Array(note_id);
-  const owner_utf8 = new TextEncoder().encode(owner);
-  const input = new Uint8Array(note_id_bytes.length + owner_utf8.length);
+  let suffix: Uint8Array;
+  if (key_epoch === BigInt(0)) {
+    suffix = new TextEncoder().encode(creator);
+  } else {
+    suffix = new Uint8Array(9);
+    new DataView(suffix.buffer).setBigUint64(1, key_epoch);
+  }
+  const input = new Uint8Array(note_id_bytes.length + suffix.length);
   input.set(note_id_bytes);
-  input.set(owner_utf8, note_id_bytes.length);
+  input.set(suffix, note_id_bytes.length);
   return input;
 }
 
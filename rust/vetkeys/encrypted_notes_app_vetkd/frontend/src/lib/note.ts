@@ -1,4 +1,4 @@
-import type { EncryptedNote } from '../bindings/backend';
+import type { EncryptedNote, Share } from '../bindings/backend';
 import type { CryptoService } from './crypto';
 import type { Principal } from '@icp-sdk/core/principal';
 
@@ -10,10 +10,13 @@ export interface NoteModel {
   updatedAt: number;
   tags: Array<string>;
   owner: string;
-  users: Array<string>;
+  creator: string;
+  shares: Array<Share>;
+  /// The key epoch of the note key that updates must be encrypted with
+  keyEpoch: bigint;
 }
 
-type SerializableNoteModel = Omit<NoteModel, 'id' | 'owner' | 'users'>;
+type SerializableNoteModel = Omit<NoteModel, 'id' | 'owner' | 'creator' | 'shares' | 'keyEpoch'>;
 
 export function noteFromContent(content: string, tags: string[], self_principal: Principal): NoteModel {
   const title = extractTitle(content);
@@ -27,14 +30,16 @@ export function noteFromContent(content: string, tags: string[], self_principal:
     updatedAt: creationTime,
     tags,
     owner: self_principal.toString(),
-    users: [""],
+    creator: self_principal.toString(),
+    shares: [],
+    keyEpoch: BigInt(0),
   };
 }
 
 export async function serialize(
   note: NoteModel,
   cryptoService: CryptoService
-): Promise<EncryptedNote> {
+): Promise<{ encrypted_text: string; key_epoch: bigint }> {
   const serializableNote: SerializableNoteModel = {
     title: note.title,
     content: note.content,
@@ -44,14 +49,13 @@ export async function serialize(
   };
   const encryptedNote = await cryptoService.encryptWithNoteKey(
     note.id,
-    note.owner,
+    note.creator,
+    note.keyEpoch,
     JSON.stringify(serializableNote)
   );
   return {
-    id: note.id,
     encrypted_text: encryptedNote,
-    owner: note.owner,
-    users: note.users,
+    key_epoch: note.keyEpoch,
   };
 }
 
@@ -59,12 +63,19 @@ export async function deserialize(
   enote: EncryptedNote,
   cryptoService: CryptoService
 ): Promise<NoteModel> {
-  const serializedNote = await cryptoService.decryptWithNoteKey(enote.id, enote.owner, enote.encrypted_text);
+  const serializedNote = await cryptoService.decryptWithNoteKey(
+    enote.id,
+    enote.creator,
+    enote.encrypted_text_key_epoch,
+    enote.encrypted_text
+  );
   const deserializedNote: SerializableNoteModel = JSON.parse(serializedNote);
   return {
     id: enote.id,
     owner: enote.owner,
-    users: enote.users,
+    creator: enote.creator,
+    shares: enote.shares,
+    keyEpoch: enote.key_epoch,
     ...deserializedNote,
   };
 }
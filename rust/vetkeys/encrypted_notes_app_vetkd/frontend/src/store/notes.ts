@@ -1,6 +1,6 @@
 import { writable } from 'svelte/store';
 import type { BackendActor } from '../lib/actor';
-import type { EncryptedNote } from '../bindings/backend';
+import type { EncryptedNote, NoteVersion, Role } from '../bindings/backend';
 import type { CryptoService } from '../lib/crypto';
 import { deserialize, serialize } from '../lib/note';
 import type { NoteModel } from '../lib/note';
@@ -70,8 +70,8 @@ export async function addNote(
 ) {
   const new_id: bigint = await actor.create_note();
   note.id = new_id;
-  const encryptedNote = (await serialize(note, crypto)).encrypted_text;
-  await actor.update_note(new_id, encryptedNote);
+  const encryptedNote = await serialize(note, crypto);
+  await actor.update_note(new_id, encryptedNote.encrypted_text, encryptedNote.key_epoch);
 }
 export async function updateNote(
   note: NoteModel,
@@ -79,15 +79,66 @@ export async function updateNote(
   crypto: CryptoService
 ) {
   const encryptedNote = await serialize(note, crypto);
-  await actor.update_note(note.id, encryptedNote.encrypted_text);
+  await actor.update_note(note.id, encryptedNote.encrypted_text, encryptedNote.key_epoch);
+}
+
+export interface NoteVersionModel {
+  version: bigint;
+  author: string;
+  timestamp: number;
+  restoredFrom?: bigint;
+  note: NoteModel;
+}
+
+export async function getNoteHistory(
+  note: NoteModel,
+  actor: BackendActor,
+  crypto: CryptoService
+): Promise<NoteVersionModel[]> {
+  const versions: NoteVersion[] = await actor.get_note_history(note.id);
+  return await Promise.all(
+    versions.map(async (version) => ({
+      version: version.version,
+      author: version.author,
+      // Convert nanoseconds to milliseconds
+      timestamp: Number(version.timestamp / BigInt(1_000_000)),
+      restoredFrom: version.restored_from[0],
+      note: await deserialize(
+        {
+          id: note.id,
+          owner: note.owner,
+          creator: note.creator,
+          shares: note.shares,
+          key_epoch: note.keyEpoch,
+          encrypted_text: version.encrypted_text,
+          encrypted_text_key_epoch: version.key_epoch,
+        },
+        crypto
+      ),
+    }))
+  );
+}
+
+export async function restoreVersion(
+  id: bigint,
+  version: bigint,
+  actor: BackendActor
+): Promise<bigint> {
+  return await actor.restore_version(id, version);
 }
 
 export async function addUser(
   id: bigint,
   user: string,
+  role: Role,
+  expiresAt: Date | undefined,
   actor: BackendActor,
 ) {
-  await actor.add_user(id, user);
+  // The backend expects the expiry in nanoseconds since the UNIX epoch.
+  const expiresAtNanos: [] | [bigint] = expiresAt
+    ? [BigInt(expiresAt.getTime()) * BigInt(1_000_000)]
+    : [];
+  await actor.add_user(id, user, role, expiresAtNanos);
 }
 
 export async function removeUser(
@@ -98,6 +149,14 @@ export async function removeUser(
   await actor.remove_user(id, user);
 }
 
+export async function transferOwnership(
+  id: bigint,
+  newOwner: string,
+  actor: BackendActor,
+) {
+  await actor.transfer_ownership(id, newOwner);
+}
+
 auth.subscribe(async ($auth) => {
   if ($auth.state === 'initialized') {
     if (notePollerHandle !== null) {
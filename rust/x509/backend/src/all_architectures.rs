@@ -1,4 +1,4 @@
-use candid::CandidType;
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
 /// The threshold signing key to use for the CA.
@@ -8,12 +8,63 @@ use serde::{Deserialize, Serialize};
 /// Available key names:
 ///   - `"test_key_1"` — mainnet test key (works on the local network too)
 ///   - `"key_1"`      — mainnet production key
-#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum CaKeyInformation {
     Ed25519(String),
     EcdsaSecp256k1(String),
 }
 
+/// How a caller has to prove ownership of the subject of a certificate
+/// signing request before the CA signs it. Configured at init.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub enum OwnershipPolicy {
+    /// Every request is signed. Insecure, only suitable for testing.
+    AcceptAll,
+    /// The common name of the subject must be the caller's principal.
+    #[default]
+    CommonNameIsCaller,
+    /// The common name of the subject is a domain, and the caller has to
+    /// serve a token obtained with `ownership_challenge` under that domain,
+    /// similar to the ACME HTTP-01 challenge.
+    Http01Challenge(Http01ChallengeConfig),
+    /// Controllers maintain a list of the subjects that each principal may
+    /// request certificates for, see `set_allowed_subjects`.
+    SubjectAllowlist,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Http01ChallengeConfig {
+    /// The URL the token is fetched from, with `{domain}` and `{token}`
+    /// placeholders. Defaults to
+    /// `https://{domain}/.well-known/acme-challenge/{token}`. Overriding it,
+    /// e.g., to point to a local stand-in server, means that the challenge no
+    /// longer proves ownership of the domain.
+    pub url_template: Option<String>,
+}
+
+impl Http01ChallengeConfig {
+    pub const DEFAULT_URL_TEMPLATE: &str = "https://{domain}/.well-known/acme-challenge/{token}";
+
+    pub fn challenge_url(&self, domain: &str, token: &str) -> String {
+        self.url_template
+            .as_deref()
+            .unwrap_or(Self::DEFAULT_URL_TEMPLATE)
+            .replace("{domain}", domain)
+            .replace("{token}", token)
+    }
+}
+
+/// A pending HTTP-01 challenge. The caller proves ownership of `domain` by
+/// serving `token` as the body of a response to a GET request for `url`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OwnershipChallenge {
+    pub domain: String,
+    pub token: String,
+    pub url: String,
+    /// Expiry time in nanoseconds since the UNIX epoch.
+    pub expires_at: u64,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 pub struct X509CertificateString {
     pub x509_certificate_string: String,
@@ -22,4 +73,162 @@ pub struct X509CertificateString {
 #[derive(CandidType, Deserialize, Debug)]
 pub struct PemCertificateRequest {
     pub pem_certificate_request: String,
+    /// The name of the intermediate CA that should sign the certificate. If
+    /// not set, the root CA signs it.
+    pub intermediate_ca: Option<String>,
+    /// The requested validity period in seconds, starting now. It must end
+    /// before the validity of the issuing CA ends. If not set, the
+    /// certificate has the same validity period as the issuing CA.
+    pub validity_seconds: Option<u64>,
+}
+
+/// The URI that names `principal` in the subject alternative name of a
+/// certificate.
+pub fn principal_uri(principal: &Principal) -> String {
+    format!("icp:{}", principal.to_text())
+}
+
+/// Constraints on the certificates that an intermediate CA may issue.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntermediateCaConstraints {
+    /// The maximum number of CA certificates that may follow the intermediate
+    /// CA certificate in a certification path. `Some(0)` means that the
+    /// intermediate CA may only issue end-entity certificates.
+    pub path_length: Option<u8>,
+    /// DNS names that the names in issued certificates must be within, e.g.,
+    /// `example.com` permits `example.com` and `www.example.com`. If empty, all
+    /// names that are not excluded are permitted.
+    pub permitted_dns_names: Vec<String>,
+    /// DNS names that the names in issued certificates must not be within.
+    pub excluded_dns_names: Vec<String>,
+}
+
+impl IntermediateCaConstraints {
+    /// Checks `dns_name` against the name constraints, following the rules of
+    /// [RFC 5280 Section
+    /// 4.2.1.10](https://datatracker.ietf.org/doc/html/rfc5280#section-4.2.1.10).
+    pub fn permits_dns_name(&self, dns_name: &str) -> bool {
+        let within = |constraint: &String| dns_name_is_within(dns_name, constraint);
+        (self.permitted_dns_names.is_empty() || self.permitted_dns_names.iter().any(within))
+            && !self.excluded_dns_names.iter().any(within)
+    }
+}
+
+fn dns_name_is_within(dns_name: &str, constraint: &str) -> bool {
+    let dns_name = dns_name.to_ascii_lowercase();
+    let constraint = constraint.to_ascii_lowercase();
+    if constraint.is_empty() {
+        return true;
+    }
+    if constraint.starts_with('.') {
+        // a leading period only matches subdomains
+        return dns_name.ends_with(&constraint);
+    }
+    dns_name == constraint || dns_name.ends_with(&format!(".{constraint}"))
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IntermediateCa {
+    pub name: String,
+    pub serial_number: u32,
+    pub constraints: IntermediateCaConstraints,
+    pub x509_certificate_string: String,
+}
+
+/// The reason for revoking a certificate, see [RFC 5280 Section
+/// 5.3.1](https://datatracker.ietf.org/doc/html/rfc5280#section-5.3.1).
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    PrivilegeWithdrawn,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Revocation {
+    /// Revocation time in nanoseconds since the UNIX epoch.
+    pub revoked_at: u64,
+    pub revoked_by: Principal,
+    pub reason: RevocationReason,
+}
+
+/// A child certificate issued by the CA.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuedCertificate {
+    pub serial_number: u32,
+    pub subject: String,
+    pub issuer: String,
+    /// The name of the intermediate CA that issued the certificate, or `None`
+    /// if the root CA issued it.
+    pub intermediate_ca: Option<String>,
+    /// The principal that requested the certificate.
+    pub requester: Principal,
+    /// Start of the validity period in nanoseconds since the UNIX epoch.
+    pub not_before: u64,
+    /// End of the validity period in nanoseconds since the UNIX epoch.
+    pub not_after: u64,
+    pub revocation: Option<Revocation>,
+}
+
+/// The status of a certificate, similar to an OCSP response.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Good,
+    Revoked {
+        revoked_at: u64,
+        reason: RevocationReason,
+    },
+    /// The CA did not issue a certificate with the given serial number.
+    Unknown,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct CertificateRevocationList {
+    pub pem_crl: String,
+    pub der_crl: Vec<u8>,
+    /// The monotonically increasing CRL number.
+    pub crl_number: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http01_challenge_url() {
+        let default = Http01ChallengeConfig::default();
+        assert_eq!(
+            default.challenge_url("example.com", "abc"),
+            "https://example.com/.well-known/acme-challenge/abc"
+        );
+        let local = Http01ChallengeConfig {
+            url_template: Some("http://localhost:8000/{domain}/{token}".to_string()),
+        };
+        assert_eq!(
+            local.challenge_url("example.com", "abc"),
+            "http://localhost:8000/example.com/abc"
+        );
+    }
+
+    #[test]
+    fn dns_name_constraints() {
+        let constraints = IntermediateCaConstraints {
+            path_length: Some(0),
+            permitted_dns_names: vec!["example.com".to_string(), ".example.org".to_string()],
+            excluded_dns_names: vec!["internal.example.com".to_string()],
+        };
+        assert!(constraints.permits_dns_name("example.com"));
+        assert!(constraints.permits_dns_name("WWW.Example.com"));
+        assert!(!constraints.permits_dns_name("badexample.com"));
+        assert!(!constraints.permits_dns_name("example.org"));
+        assert!(constraints.permits_dns_name("www.example.org"));
+        assert!(!constraints.permits_dns_name("internal.example.com"));
+        assert!(!constraints.permits_dns_name("db.internal.example.com"));
+
+        assert!(IntermediateCaConstraints::default().permits_dns_name("anything.test"));
+    }
 }
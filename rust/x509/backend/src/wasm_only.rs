@@ -1,9 +1,13 @@
-use crate::{CaKeyInformation, PemCertificateRequest, X509CertificateString};
+use crate::{
+    CaKeyInformation, CertificateRevocationList, CertificateStatus, IntermediateCa,
+    IntermediateCaConstraints, IssuedCertificate, OwnershipChallenge, OwnershipPolicy,
+    PemCertificateRequest, RevocationReason, X509CertificateString,
+};
 
 use candid::Principal;
 use der::{asn1::BitString, pem::LineEnding, DecodePem, Encode, EncodePem};
 use ic_cdk::export_candid;
-use ic_cdk::{api::time, init, update};
+use ic_cdk::{api::time, init, post_upgrade, query, update};
 use ic_cdk_management_canister::{
     self as management_canister, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs,
     SchnorrAlgorithm, SchnorrKeyId, SchnorrPublicKeyArgs,
@@ -12,11 +16,16 @@ use pkcs8::AssociatedOid;
 use signature::Keypair;
 use spki::{AlgorithmIdentifier, DynSignatureAlgorithmIdentifier, EncodePublicKey};
 use std::{
-    cell::OnceCell, cell::RefCell, convert::TryFrom, convert::TryInto, error::Error,
-    ops::Deref, str::FromStr, time::Duration,
+    cell::RefCell, collections::BTreeMap, convert::TryFrom, convert::TryInto, error::Error,
+    str::FromStr, time::Duration,
 };
 use x509_cert::{
     builder::{Builder, CertificateBuilder, Profile},
+    ext::pkix::{
+        constraints::name::{GeneralSubtree, NameConstraints},
+        name::GeneralName,
+        ExtendedKeyUsage, SubjectAltName,
+    },
     name::Name,
     request::CertReq,
     serial_number::SerialNumber,
@@ -24,7 +33,13 @@ use x509_cert::{
     time::{Time, Validity},
 };
 
+mod config;
+mod extensions;
+mod intermediate_ca;
+mod ownership;
+mod revocation;
 mod signer;
+mod storage;
 use signer::{EcdsaSecp256k1Signer, Ed25519Signer, Sign};
 
 type CanisterId = Principal;
@@ -62,31 +77,36 @@ impl TryFrom<&CaKeyInformation> for EcdsaKeyId {
 }
 
 thread_local! {
-    // Overwritten by #[init] on first deploy. After a canister upgrade #[init] is not
-    // re-run, so this default becomes active again. Production canisters should persist
-    // the init arg to stable memory and restore it in a #[post_upgrade] hook.
-    static CA_KEY_INFORMATION: RefCell<CaKeyInformation> = RefCell::new(CaKeyInformation::Ed25519("test_key_1".to_string()));
-
-    // cache the public key and certificate to avoid fetching them multiple times
-    static ROOT_CA_PUBLIC_KEY: OnceCell<Vec<u8>> = OnceCell::new();
-    static ROOT_CA_CERTIFICATE_PEM: OnceCell<String> = OnceCell::new();
-
-    static CHILD_CERTIFICATE_SERIAL_NUMBER: RefCell<u32> = RefCell::new(1);
+    // cache the public keys to avoid fetching them multiple times
+    static PUBLIC_KEYS: RefCell<BTreeMap<Vec<Vec<u8>>, Vec<u8>>> =
+        const { RefCell::new(BTreeMap::new()) };
 }
 
+/// The ownership policy is required, so that a CA that signs every request,
+/// which is only suitable for testing, is never deployed by accident.
 #[init]
-fn init(ca_key_information: CaKeyInformation) {
-    CA_KEY_INFORMATION.with(|value| {
-        *value.borrow_mut() = ca_key_information;
-    });
+fn init(ca_key_information: CaKeyInformation, ownership_policy: OwnershipPolicy) {
+    config::init(ca_key_information, ownership_policy);
+}
+
+/// The configuration is persisted in stable memory, so the arguments are
+/// optional. The CA key information must match the persisted one, and a
+/// given ownership policy replaces the persisted one.
+#[post_upgrade]
+fn post_upgrade(
+    ca_key_information: Option<CaKeyInformation>,
+    ownership_policy: Option<OwnershipPolicy>,
+) {
+    if let Err(e) = config::post_upgrade(ca_key_information, ownership_policy) {
+        ic_cdk::trap(e);
+    }
 }
 
 #[update]
 async fn root_ca_certificate() -> Result<X509CertificateString, String> {
     // if the certificate is already cached, return it
 
-    if let Some(certificate) = ROOT_CA_CERTIFICATE_PEM.with(|inner| inner.get().map(|v| v.clone()))
-    {
+    if let Some(certificate) = config::root_ca_certificate() {
         return Ok(X509CertificateString {
             x509_certificate_string: certificate,
         });
@@ -101,76 +121,20 @@ async fn root_ca_certificate() -> Result<X509CertificateString, String> {
     )
     .unwrap();
 
-    let newly_constructed_x509_certificate_string = match CA_KEY_INFORMATION
-        .with(|value| value.borrow().clone())
-    {
-        CaKeyInformation::Ed25519(_) => {
-            let signer = Ed25519Signer::new()
-                .await
-                .map_err(|e| format!("failed to create Ed25519 signer: {e:?}"))?;
-
-            let subject_public_key =
-                der::asn1::BitString::new(0, root_ca_public_key_bytes().await?)
-                    .map_err(|e| format!("source: {:?}", e.source()))?;
-
-            let pub_key = SubjectPublicKeyInfoOwned {
-                algorithm: signer.signature_algorithm_identifier().unwrap(),
-                subject_public_key,
-            };
-
-            pem_certificate_signed_by_root_ca(
-                Profile::Root,
-                serial_number,
-                validity(),
-                subject,
-                pub_key,
-                signer,
-            )
-            .await
-            .map_err(|e| format!("failed to create root certificate: {e:?}"))?
-        }
-        CaKeyInformation::EcdsaSecp256k1(_) => {
-            let signer = EcdsaSecp256k1Signer::new()
-                .await
-                .map_err(|e| format!("failed to create ECDSA secp256k1 signer: {e:?}"))?;
-
-            let public_key_bytes_compressed = root_ca_public_key_bytes().await?;
-            let public_key_bytes_uncompressed =
-                k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key_bytes_compressed.as_slice())
-                    .map_err(|e| format!("malformed public key: {e:?}"))?
-                    .to_encoded_point(false);
-
-            let subject_public_key =
-                der::asn1::BitString::new(0, public_key_bytes_uncompressed.as_bytes())
-                    .map_err(|e| format!("source: {:?}", e.source()))?;
-
-            let pub_key = SubjectPublicKeyInfoOwned {
-                algorithm: AlgorithmIdentifier {
-                    // Public Key Algorithm: id-ecPublicKey (1.2.840.10045.2.1)
-                    oid: pkcs8::ObjectIdentifier::new_unwrap("1.2.840.10045.2.1"),
-                    parameters: Some(der::Any::from(k256::Secp256k1::OID)),
-                },
-                subject_public_key,
-            };
-
-            pem_certificate_signed_by_root_ca(
-                Profile::Root,
-                serial_number,
-                validity(),
-                subject,
-                pub_key,
-                signer,
-            )
-            .await
-            .map_err(|e| format!("failed to create root certificate: {e:?}"))?
-        }
-    };
+    let newly_constructed_x509_certificate_string = pem_certificate_signed_by_ca(
+        derivation_path(),
+        Profile::Root,
+        serial_number,
+        validity(),
+        subject,
+        subject_public_key_info(&derivation_path()).await?,
+        vec![],
+    )
+    .await
+    .map_err(|e| format!("failed to create root certificate: {e:?}"))?;
 
-    let x509_certificate_string = ROOT_CA_CERTIFICATE_PEM.with(move |inner| {
-        inner
-            .get_or_init(|| newly_constructed_x509_certificate_string)
-            .clone()
-    });
+    let x509_certificate_string =
+        config::get_or_init_root_ca_certificate(newly_constructed_x509_certificate_string);
 
     Ok(X509CertificateString {
         x509_certificate_string,
@@ -187,88 +151,313 @@ async fn child_certificate(
 
     verify_certificate_request_signature(&cert_req)?;
 
-    if !cert_req.info.attributes.is_empty() {
-        return Err("Attributes are currently not supported in this example".to_string());
+    let requested_extensions = extensions::requested_extensions(&cert_req)?;
+    let caller = ic_cdk::api::msg_caller();
+    ownership::check_subject_alt_names(
+        requested_extensions.subject_alt_names(),
+        &cert_req.info.subject,
+        caller,
+    )?;
+
+    let issuing_ca = issuing_ca(certificate_request_info.intermediate_ca).await?;
+    if let Some(constraints) = &issuing_ca.constraints {
+        check_name_constraints(
+            &cert_req.info.subject,
+            requested_extensions.dns_names(),
+            constraints,
+        )?;
     }
+    let validity = child_certificate_validity(
+        issuing_ca.certificate.tbs_certificate.validity,
+        certificate_request_info.validity_seconds,
+    )?;
 
-    prove_ownership(&cert_req, ic_cdk::api::msg_caller() /*, ... */)?;
-
-    let root_certificate_pem = root_ca_certificate().await?;
-    let root_certificate =
-        x509_cert::Certificate::from_pem(root_certificate_pem.x509_certificate_string.as_str())
-            .map_err(|e| format!("failed to parse PEM root CA certificate: {e:?}"))?;
+    ownership::prove_ownership(&cert_req, caller, time()).await?;
 
+    let issuer = issuing_ca.certificate.tbs_certificate.subject;
     let profile = Profile::Leaf {
-        issuer: root_certificate.tbs_certificate.subject.clone(),
-        enable_key_agreement: false,
-        enable_key_encipherment: false,
+        issuer: issuer.clone(),
+        enable_key_agreement: requested_extensions.key_agreement(),
+        enable_key_encipherment: requested_extensions.key_encipherment(),
     };
+    let mut additional_extensions = vec![];
+    if let Some(subject_alt_name) = requested_extensions.subject_alt_name {
+        additional_extensions.push(AdditionalExtension::SubjectAltName(subject_alt_name));
+    }
+    if let Some(extended_key_usage) = requested_extensions.extended_key_usage {
+        additional_extensions.push(AdditionalExtension::ExtendedKeyUsage(extended_key_usage));
+    }
 
-    let serial_number = SerialNumber::from(next_child_certificate_serial_number());
-
-    // For simplicity of this example, let's just use the same validity
-    // period as the root certificate. In a real application, the validity
-    // would normally not start in the past and might end well before the root
-    // certificate validity ends. Also, the validity of the child
-    // ceritifcate should always be in the time frame of the root
-    // certificate's validity.
-    let validity = root_certificate.tbs_certificate.validity.clone();
-
-    let x509_certificate_string = {
-        match CA_KEY_INFORMATION.with(|value| value.borrow().clone()) {
-            CaKeyInformation::Ed25519(_) => {
-                let signer = Ed25519Signer::new()
-                    .await
-                    .map_err(|e| format!("failed to create Ed25519 signer: {e:?}"))?;
-                pem_certificate_signed_by_root_ca(
-                    profile,
-                    serial_number,
-                    validity,
-                    cert_req.info.subject.clone(),
-                    cert_req.info.public_key.clone(),
-                    signer,
-                )
-                .await
-                .map_err(|e| format!("failed to create child certificate: {e:?}"))?
-            }
-            CaKeyInformation::EcdsaSecp256k1(_) => {
-                let signer = EcdsaSecp256k1Signer::new()
-                    .await
-                    .map_err(|e| format!("failed to create Ed25519 signer: {e:?}"))?;
-                pem_certificate_signed_by_root_ca(
-                    profile,
-                    serial_number,
-                    validity,
-                    cert_req.info.subject.clone(),
-                    cert_req.info.public_key.clone(),
-                    signer,
-                )
-                .await
-                .map_err(|e| format!("failed to create child certificate: {e:?}"))?
-            }
-        }
+    let issued_certificate = IssuedCertificate {
+        serial_number: next_child_certificate_serial_number(),
+        subject: cert_req.info.subject.to_string(),
+        issuer: issuer.to_string(),
+        intermediate_ca: issuing_ca.name,
+        requester: caller,
+        not_before: nanos_since_epoch(validity.not_before),
+        not_after: nanos_since_epoch(validity.not_after),
+        revocation: None,
     };
+    let serial_number = SerialNumber::from(issued_certificate.serial_number);
+
+    let x509_certificate_string = pem_certificate_signed_by_ca(
+        issuing_ca.derivation_path,
+        profile,
+        serial_number,
+        validity,
+        cert_req.info.subject.clone(),
+        cert_req.info.public_key.clone(),
+        additional_extensions,
+    )
+    .await
+    .map_err(|e| format!("failed to create child certificate: {e:?}"))?;
+
+    revocation::register(issued_certificate);
 
     Ok(X509CertificateString {
         x509_certificate_string,
     })
 }
 
+#[query]
+fn ownership_policy() -> OwnershipPolicy {
+    ownership::policy()
+}
+
+/// Creates an HTTP-01 challenge for `domain`. To prove ownership of the
+/// domain, the caller serves the token at the URL of the challenge and then
+/// requests a certificate with the domain as common name.
+#[update]
+async fn ownership_challenge(domain: String) -> Result<OwnershipChallenge, String> {
+    ownership::create_challenge(ic_cdk::api::msg_caller(), domain, time()).await
+}
+
+/// Sets the subjects that `principal` may request certificates for if the
+/// CA uses the `SubjectAllowlist` policy. Only controllers of the canister
+/// may set them.
+#[update]
+fn set_allowed_subjects(principal: Principal, subjects: Vec<String>) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        return Err("only controllers may set allowed subjects".to_string());
+    }
+    ownership::set_allowed_subjects(principal, subjects)
+}
+
+#[query]
+fn allowed_subjects(principal: Principal) -> Vec<String> {
+    ownership::allowed_subjects(principal)
+}
+
+/// Creates an intermediate CA certificate signed by the root CA. The
+/// intermediate CA has its own threshold key, derived with a derivation path
+/// specific to its name. Only controllers of the canister may create
+/// intermediate CAs.
+#[update]
+async fn create_intermediate_ca(
+    name: String,
+    constraints: IntermediateCaConstraints,
+) -> Result<IntermediateCa, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("only controllers may create intermediate CAs".to_string());
+    }
+    intermediate_ca::validate_name(&name)?;
+    if intermediate_ca::get(&name).is_some() {
+        return Err(format!("intermediate CA {name} already exists"));
+    }
+    let extensions = match name_constraints(&constraints)? {
+        Some(name_constraints) => vec![AdditionalExtension::NameConstraints(name_constraints)],
+        None => vec![],
+    };
+
+    let root_ca = issuing_ca(None).await?;
+    let root_subject = root_ca.certificate.tbs_certificate.subject;
+    let validity = root_ca.certificate.tbs_certificate.validity;
+    let subject = Name::from_str(&format!("CN={name},O=ICP Example CA Inc,C=US"))
+        .map_err(|e| format!("invalid intermediate CA name: {e:?}"))?;
+    let subject_public_key_info =
+        subject_public_key_info(&intermediate_ca::derivation_path(&name)).await?;
+
+    let issued_certificate = IssuedCertificate {
+        serial_number: next_child_certificate_serial_number(),
+        subject: subject.to_string(),
+        issuer: root_subject.to_string(),
+        intermediate_ca: None,
+        requester: caller,
+        not_before: nanos_since_epoch(validity.not_before),
+        not_after: nanos_since_epoch(validity.not_after),
+        revocation: None,
+    };
+
+    let x509_certificate_string = pem_certificate_signed_by_ca(
+        root_ca.derivation_path,
+        Profile::SubCA {
+            issuer: root_subject,
+            path_len_constraint: constraints.path_length,
+        },
+        SerialNumber::from(issued_certificate.serial_number),
+        validity,
+        subject,
+        subject_public_key_info,
+        extensions,
+    )
+    .await
+    .map_err(|e| format!("failed to create intermediate CA certificate: {e:?}"))?;
+
+    // another call may have created an intermediate CA with the same name
+    // while we were waiting for the signature
+    if intermediate_ca::get(&name).is_some() {
+        return Err(format!("intermediate CA {name} already exists"));
+    }
+    let intermediate_ca = IntermediateCa {
+        name,
+        serial_number: issued_certificate.serial_number,
+        constraints,
+        x509_certificate_string,
+    };
+    revocation::register(issued_certificate);
+    intermediate_ca::insert(intermediate_ca.clone());
+
+    Ok(intermediate_ca)
+}
+
+#[query]
+fn intermediate_ca(name: String) -> Option<IntermediateCa> {
+    intermediate_ca::get(&name)
+}
+
+/// Revokes a certificate. Only the principal that requested the certificate
+/// or a controller of the canister may revoke it.
+#[update]
+async fn revoke(serial_number: u32, reason: RevocationReason) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let revoked = revocation::revoke(
+        serial_number,
+        reason,
+        caller,
+        ic_cdk::api::is_controller(&caller),
+        time(),
+    )?;
+
+    // The revocation is recorded even if the CRL cannot be regenerated right
+    // away. In that case, the next call to `certificate_revocation_list`
+    // regenerates it.
+    if let Err(e) = regenerate_certificate_revocation_list(revoked.intermediate_ca).await {
+        ic_cdk::println!("failed to regenerate CRL after revoking {serial_number}: {e}");
+    }
+    Ok(())
+}
+
+#[query]
+fn certificate_status(serial_number: u32) -> CertificateStatus {
+    revocation::status(serial_number)
+}
+
+#[query]
+fn issued_certificate(serial_number: u32) -> Option<IssuedCertificate> {
+    revocation::issued_certificate(serial_number)
+}
+
+/// Returns the CRL of the given intermediate CA, or of the root CA if no
+/// intermediate CA is given.
+#[update]
+async fn certificate_revocation_list(
+    intermediate_ca: Option<String>,
+) -> Result<CertificateRevocationList, String> {
+    // if the cached CRL is up to date, return it
+    if let Some(crl) = revocation::cached_crl(&intermediate_ca, time()) {
+        return Ok(crl);
+    }
+    regenerate_certificate_revocation_list(intermediate_ca).await
+}
+
+async fn regenerate_certificate_revocation_list(
+    intermediate_ca: Option<String>,
+) -> Result<CertificateRevocationList, String> {
+    let issuing_ca = issuing_ca(intermediate_ca).await?;
+    let issuer = issuing_ca.certificate.tbs_certificate.subject;
+    let issuer_public_key_info = issuing_ca
+        .certificate
+        .tbs_certificate
+        .subject_public_key_info;
 
+    let snapshot = revocation::snapshot(issuing_ca.name);
+
+    match config::ca_key_information() {
+        CaKeyInformation::Ed25519(_) => {
+            let signer = Ed25519Signer::new(issuing_ca.derivation_path)
+                .await
+                .map_err(|e| format!("failed to create Ed25519 signer: {e:?}"))?;
+            revocation::signed_crl(snapshot, issuer, issuer_public_key_info, time(), signer).await
+        }
+        CaKeyInformation::EcdsaSecp256k1(_) => {
+            let signer = EcdsaSecp256k1Signer::new(issuing_ca.derivation_path)
+                .await
+                .map_err(|e| format!("failed to create ECDSA secp256k1 signer: {e:?}"))?;
+            revocation::signed_crl(snapshot, issuer, issuer_public_key_info, time(), signer).await
+        }
+    }
+    .map_err(|e| format!("failed to create certificate revocation list: {e:?}"))
+}
+
+/// The CA that signs a certificate.
+struct IssuingCa {
+    /// The name of the intermediate CA, or `None` for the root CA.
+    name: Option<String>,
+    certificate: x509_cert::Certificate,
+    derivation_path: Vec<Vec<u8>>,
+    constraints: Option<IntermediateCaConstraints>,
+}
+
+async fn issuing_ca(intermediate_ca: Option<String>) -> Result<IssuingCa, String> {
+    match intermediate_ca {
+        None => {
+            let root_certificate_pem = root_ca_certificate().await?;
+            let certificate = x509_cert::Certificate::from_pem(
+                root_certificate_pem.x509_certificate_string.as_str(),
+            )
+            .map_err(|e| format!("failed to parse PEM root CA certificate: {e:?}"))?;
+            Ok(IssuingCa {
+                name: None,
+                certificate,
+                derivation_path: derivation_path(),
+                constraints: None,
+            })
+        }
+        Some(name) => {
+            let intermediate_ca = intermediate_ca::get(&name)
+                .ok_or_else(|| format!("unknown intermediate CA {name}"))?;
+            if revocation::status(intermediate_ca.serial_number) != CertificateStatus::Good {
+                return Err(format!("intermediate CA {name} is revoked"));
+            }
+            let certificate =
+                x509_cert::Certificate::from_pem(intermediate_ca.x509_certificate_string.as_str())
+                    .map_err(|e| {
+                        format!("failed to parse PEM intermediate CA certificate: {e:?}")
+                    })?;
+            Ok(IssuingCa {
+                derivation_path: intermediate_ca::derivation_path(&name),
+                name: Some(name),
+                certificate,
+                constraints: Some(intermediate_ca.constraints),
+            })
+        }
+    }
+}
 
-async fn root_ca_public_key_bytes() -> Result<Vec<u8>, String> {
+async fn public_key_bytes(derivation_path: &[Vec<u8>]) -> Result<Vec<u8>, String> {
     // if the public key is already cached, return it
-    if let Some(public_key) = ROOT_CA_PUBLIC_KEY.with(|inner| inner.get().map(|v| v.clone())) {
+    if let Some(public_key) = PUBLIC_KEYS.with(|inner| inner.borrow().get(derivation_path).cloned())
+    {
         return Ok(public_key);
     };
 
-    let result = match CA_KEY_INFORMATION.with(|value| value.borrow().clone()) {
+    let result = match config::ca_key_information() {
         CaKeyInformation::Ed25519(_) => {
             let args = SchnorrPublicKeyArgs {
                 canister_id: None,
-                derivation_path: derivation_path(),
-                key_id: CA_KEY_INFORMATION
-                    .with(|value| SchnorrKeyId::try_from(value.borrow().deref()))?,
+                derivation_path: derivation_path.to_vec(),
+                key_id: SchnorrKeyId::try_from(&config::ca_key_information())?,
             };
             let response = management_canister::schnorr_public_key(&args)
                 .await
@@ -278,9 +467,8 @@ async fn root_ca_public_key_bytes() -> Result<Vec<u8>, String> {
         CaKeyInformation::EcdsaSecp256k1(_) => {
             let args = EcdsaPublicKeyArgs {
                 canister_id: None,
-                derivation_path: derivation_path(),
-                key_id: CA_KEY_INFORMATION
-                    .with(|value| EcdsaKeyId::try_from(value.borrow().deref()))?,
+                derivation_path: derivation_path.to_vec(),
+                key_id: EcdsaKeyId::try_from(&config::ca_key_information())?,
             };
             let response = management_canister::ecdsa_public_key(&args)
                 .await
@@ -289,18 +477,115 @@ async fn root_ca_public_key_bytes() -> Result<Vec<u8>, String> {
         }
     };
 
-    // try to initialize the cache with the fetched public key or return the
-    // cached value, because we were making an async call between the cache
-    // check and cache initialization
-    Ok(ROOT_CA_PUBLIC_KEY.with(move |inner| inner.get_or_init(|| result).clone()))
+    // the key derived for a derivation path never changes, so it does not
+    // matter if another call cached it while we were waiting for the response
+    PUBLIC_KEYS.with(|inner| {
+        inner
+            .borrow_mut()
+            .insert(derivation_path.to_vec(), result.clone())
+    });
+    Ok(result)
+}
+
+/// The subject public key info of the CA key for `derivation_path`.
+async fn subject_public_key_info(
+    derivation_path: &[Vec<u8>],
+) -> Result<SubjectPublicKeyInfoOwned, String> {
+    let public_key_bytes = public_key_bytes(derivation_path).await?;
+    match config::ca_key_information() {
+        CaKeyInformation::Ed25519(_) => {
+            let subject_public_key = der::asn1::BitString::new(0, public_key_bytes)
+                .map_err(|e| format!("source: {:?}", e.source()))?;
+
+            Ok(SubjectPublicKeyInfoOwned {
+                algorithm: AlgorithmIdentifier {
+                    oid: ed25519::pkcs8::ALGORITHM_OID,
+                    parameters: None,
+                },
+                subject_public_key,
+            })
+        }
+        CaKeyInformation::EcdsaSecp256k1(_) => {
+            let public_key_bytes_uncompressed =
+                k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key_bytes.as_slice())
+                    .map_err(|e| format!("malformed public key: {e:?}"))?
+                    .to_encoded_point(false);
+
+            let subject_public_key =
+                der::asn1::BitString::new(0, public_key_bytes_uncompressed.as_bytes())
+                    .map_err(|e| format!("source: {:?}", e.source()))?;
+
+            Ok(SubjectPublicKeyInfoOwned {
+                algorithm: AlgorithmIdentifier {
+                    // Public Key Algorithm: id-ecPublicKey (1.2.840.10045.2.1)
+                    oid: pkcs8::ObjectIdentifier::new_unwrap("1.2.840.10045.2.1"),
+                    parameters: Some(der::Any::from(k256::Secp256k1::OID)),
+                },
+                subject_public_key,
+            })
+        }
+    }
+}
+
+/// Extensions that are added to a certificate in addition to the ones that
+/// the [`Profile`] implies.
+enum AdditionalExtension {
+    NameConstraints(NameConstraints),
+    SubjectAltName(SubjectAltName),
+    ExtendedKeyUsage(ExtendedKeyUsage),
 }
 
-async fn pem_certificate_signed_by_root_ca<Signer>(
+/// Creates a certificate signed with the CA key for `issuer_derivation_path`.
+async fn pem_certificate_signed_by_ca(
+    issuer_derivation_path: Vec<Vec<u8>>,
     profile: Profile,
     serial_number: SerialNumber,
     validity: Validity,
     subject: Name,
     subject_public_key_info: SubjectPublicKeyInfoOwned,
+    extensions: Vec<AdditionalExtension>,
+) -> Result<String, String> {
+    match config::ca_key_information() {
+        CaKeyInformation::Ed25519(_) => {
+            let signer = Ed25519Signer::new(issuer_derivation_path)
+                .await
+                .map_err(|e| format!("failed to create Ed25519 signer: {e:?}"))?;
+            pem_certificate_signed_by(
+                profile,
+                serial_number,
+                validity,
+                subject,
+                subject_public_key_info,
+                extensions,
+                signer,
+            )
+            .await
+        }
+        CaKeyInformation::EcdsaSecp256k1(_) => {
+            let signer = EcdsaSecp256k1Signer::new(issuer_derivation_path)
+                .await
+                .map_err(|e| format!("failed to create ECDSA secp256k1 signer: {e:?}"))?;
+            pem_certificate_signed_by(
+                profile,
+                serial_number,
+                validity,
+                subject,
+                subject_public_key_info,
+                extensions,
+                signer,
+            )
+            .await
+        }
+    }
+}
+
+async fn pem_certificate_signed_by<Signer>(
+    profile: Profile,
+    serial_number: SerialNumber,
+    validity: Validity,
+    subject: Name,
+    subject_public_key_info: SubjectPublicKeyInfoOwned,
+    extensions: Vec<AdditionalExtension>,
     signer: Signer,
 ) -> Result<String, String>
 where
@@ -318,6 +603,21 @@ where
     )
     .expect("Create certificate");
 
+    for extension in extensions {
+        match extension {
+            AdditionalExtension::NameConstraints(name_constraints) => {
+                builder.add_extension(&name_constraints)
+            }
+            AdditionalExtension::SubjectAltName(subject_alt_name) => {
+                builder.add_extension(&subject_alt_name)
+            }
+            AdditionalExtension::ExtendedKeyUsage(extended_key_usage) => {
+                builder.add_extension(&extended_key_usage)
+            }
+        }
+        .map_err(|e| format!("failed to add extension: {e:?}"))?;
+    }
+
     let blob = builder
         .finalize()
         .map_err(|e| format!("failed to finalize certificate builder: {e:?}"))?;
@@ -334,49 +634,116 @@ where
         .map_err(|e| format!("failed to encode certificate: {e:?}"))
 }
 
+/// The name constraints extension for `constraints`, if it constrains names at all.
+fn name_constraints(
+    constraints: &IntermediateCaConstraints,
+) -> Result<Option<NameConstraints>, String> {
+    fn subtrees(dns_names: &[String]) -> Result<Option<Vec<GeneralSubtree>>, String> {
+        if dns_names.is_empty() {
+            return Ok(None);
+        }
+        dns_names
+            .iter()
+            .map(|dns_name| {
+                Ok(GeneralSubtree {
+                    base: GeneralName::DnsName(
+                        der::asn1::Ia5String::new(dns_name)
+                            .map_err(|e| format!("invalid DNS name {dns_name}: {e:?}"))?,
+                    ),
+                    minimum: 0,
+                    maximum: None,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Some)
+    }
+    let permitted_subtrees = subtrees(&constraints.permitted_dns_names)?;
+    let excluded_subtrees = subtrees(&constraints.excluded_dns_names)?;
+    if permitted_subtrees.is_none() && excluded_subtrees.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(NameConstraints {
+        permitted_subtrees,
+        excluded_subtrees,
+    }))
+}
+
+/// Checks that the common name of `subject` and the DNS names in the subject
+/// alternative name are within the name constraints.
+fn check_name_constraints<'a>(
+    subject: &Name,
+    dns_names: impl IntoIterator<Item = &'a str>,
+    constraints: &IntermediateCaConstraints,
+) -> Result<(), String> {
+    if constraints.permitted_dns_names.is_empty() && constraints.excluded_dns_names.is_empty() {
+        return Ok(());
+    }
+    let common_name = common_name(subject).ok_or_else(|| {
+        "the subject needs a common name to be checked against the name constraints".to_string()
+    })?;
+    if !constraints.permits_dns_name(&common_name) {
+        return Err(format!(
+            "the common name {common_name} is not permitted by the name constraints of the intermediate CA"
+        ));
+    }
+    for dns_name in dns_names {
+        if !constraints.permits_dns_name(dns_name) {
+            return Err(format!(
+                "the DNS name {dns_name} is not permitted by the name constraints of the intermediate CA"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn common_name(subject: &Name) -> Option<String> {
+    // Common Name: id-at-commonName (2.5.4.3)
+    let common_name_oid = pkcs8::ObjectIdentifier::new_unwrap("2.5.4.3");
+    subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .find(|attribute| attribute.oid == common_name_oid)
+        .and_then(|attribute| String::from_utf8(attribute.value.value().to_vec()).ok())
+}
+
 fn verify_certificate_request_signature(certificate_request: &CertReq) -> Result<(), String> {
-    fn certificate_as_message_and_public_key_bytes(
-        certificate_request: &CertReq,
-    ) -> (Vec<u8>, Vec<u8>) {
-        let mut message = vec![];
-        certificate_request
-            .info
-            .encode(&mut message)
-            .expect("failed to encode certificate request info");
-
-        let public_key_bytes = certificate_request
-            .info
-            .public_key
-            .subject_public_key
-            .raw_bytes()
-            .to_vec();
-
-        (message, public_key_bytes)
-    }
-    let result = match &certificate_request.algorithm {
-        AlgorithmIdentifier::<der::Any> {
-            oid: ed25519::pkcs8::ALGORITHM_OID,
-            parameters: None,
-        } => {
-            let (message, public_key_bytes) =
-                certificate_as_message_and_public_key_bytes(certificate_request);
-            verify_ed25519_signature(
-                certificate_request.signature.raw_bytes(),
-                message.as_slice(),
-                public_key_bytes.as_slice(),
-            )
+    let mut message = vec![];
+    certificate_request
+        .info
+        .encode(&mut message)
+        .expect("failed to encode certificate request info");
+    let public_key = &certificate_request.info.public_key;
+    let signature = certificate_request.signature.raw_bytes();
+    let algorithm = &certificate_request.algorithm;
+
+    let result = match (algorithm.oid, &algorithm.parameters) {
+        (ed25519::pkcs8::ALGORITHM_OID, None) => verify_ed25519_signature(
+            signature,
+            message.as_slice(),
+            public_key.subject_public_key.raw_bytes(),
+        ),
+        // ECDSA signature algorithms have no parameters, see RFC 5758
+        (ecdsa::ECDSA_SHA256_OID, None) => {
+            verify_ecdsa_signature(signature, &message, HashAlgorithm::Sha256, public_key)
         }
-        AlgorithmIdentifier::<der::Any> {
-            oid: ecdsa::ECDSA_SHA256_OID,
-            parameters: None, // secp256k1 is non-standard and is sometimes encoded as None
-        } => {
-            let (message, public_key_bytes) =
-                certificate_as_message_and_public_key_bytes(certificate_request);
-            verify_ecdsa_secp256k1_signature(
-                certificate_request.signature.raw_bytes(),
-                message.as_slice(),
-                public_key_bytes.as_slice(),
-            )
+        (ecdsa::ECDSA_SHA384_OID, None) => {
+            verify_ecdsa_signature(signature, &message, HashAlgorithm::Sha384, public_key)
+        }
+        (ecdsa::ECDSA_SHA512_OID, None) => {
+            verify_ecdsa_signature(signature, &message, HashAlgorithm::Sha512, public_key)
+        }
+        (SHA256_WITH_RSA_ENCRYPTION_OID, _) => {
+            verify_rsa_pkcs1v15_signature(signature, &message, HashAlgorithm::Sha256, public_key)
+        }
+        (SHA384_WITH_RSA_ENCRYPTION_OID, _) => {
+            verify_rsa_pkcs1v15_signature(signature, &message, HashAlgorithm::Sha384, public_key)
+        }
+        (SHA512_WITH_RSA_ENCRYPTION_OID, _) => {
+            verify_rsa_pkcs1v15_signature(signature, &message, HashAlgorithm::Sha512, public_key)
+        }
+        (RSA_PSS_OID, parameters) => {
+            verify_rsa_pss_signature(signature, &message, parameters.as_ref(), public_key)
         }
         _ => Err(format!(
             "unsupported algorithm: {:?}",
@@ -430,24 +797,182 @@ fn verify_ecdsa_secp256k1_signature(
     result
 }
 
-fn next_child_certificate_serial_number() -> u32 {
-    CHILD_CERTIFICATE_SERIAL_NUMBER.with(|state| {
-        let mut serial_number = state.borrow_mut();
-        *serial_number += 1;
-        *serial_number
-    })
+// sha256WithRSAEncryption (1.2.840.113549.1.1.11)
+const SHA256_WITH_RSA_ENCRYPTION_OID: pkcs8::ObjectIdentifier =
+    pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+// sha384WithRSAEncryption (1.2.840.113549.1.1.12)
+const SHA384_WITH_RSA_ENCRYPTION_OID: pkcs8::ObjectIdentifier =
+    pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12");
+// sha512WithRSAEncryption (1.2.840.113549.1.1.13)
+const SHA512_WITH_RSA_ENCRYPTION_OID: pkcs8::ObjectIdentifier =
+    pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13");
+// RSASSA-PSS: id-RSASSA-PSS (1.2.840.113549.1.1.10)
+const RSA_PSS_OID: pkcs8::ObjectIdentifier =
+    pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.10");
+// Mask generation function: id-mgf1 (1.2.840.113549.1.1.8)
+const MGF1_OID: pkcs8::ObjectIdentifier =
+    pkcs8::ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.8");
+// RSA keys shorter than this are considered insecure
+const MIN_RSA_KEY_BITS: usize = 2048;
+
+/// The hash algorithms that CSR signatures may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
 }
 
-fn prove_ownership(_cert_req: &CertReq, _caller: Principal /*, ... */) -> Result<(), String> {
-    // ********** This is a placeholder for a real implementation. **********
-    // ********** In a real implementation, the subject would have **********
-    // ********** to prove ownership of the subject name.          **********
-    Ok(())
+impl HashAlgorithm {
+    /// The hash algorithm with the given OID, e.g., in RSASSA-PSS parameters.
+    fn from_digest_oid(oid: pkcs8::ObjectIdentifier) -> Result<Self, String> {
+        use sha2::digest::const_oid::AssociatedOid;
+        match oid {
+            sha2::Sha256::OID => Ok(Self::Sha256),
+            sha2::Sha384::OID => Ok(Self::Sha384),
+            sha2::Sha512::OID => Ok(Self::Sha512),
+            _ => Err(format!("unsupported hash algorithm {oid}")),
+        }
+    }
+
+    fn digest(self, message: &[u8]) -> Vec<u8> {
+        use sha2::Digest;
+        match self {
+            Self::Sha256 => sha2::Sha256::digest(message).to_vec(),
+            Self::Sha384 => sha2::Sha384::digest(message).to_vec(),
+            Self::Sha512 => sha2::Sha512::digest(message).to_vec(),
+        }
+    }
+}
+
+fn verify_ecdsa_signature(
+    der_signature_bytes: &[u8],
+    message_bytes: &[u8],
+    hash_algorithm: HashAlgorithm,
+    public_key: &SubjectPublicKeyInfoOwned,
+) -> Result<(), String> {
+    use signature::hazmat::PrehashVerifier;
+
+    let curve = public_key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.decode_as::<pkcs8::ObjectIdentifier>().ok())
+        .ok_or_else(|| "missing named curve in ECDSA public key".to_string())?;
+    let public_key_bytes = public_key.subject_public_key.raw_bytes();
+    let prehash = hash_algorithm.digest(message_bytes);
+    match curve {
+        k256::Secp256k1::OID if hash_algorithm == HashAlgorithm::Sha256 => {
+            verify_ecdsa_secp256k1_signature(der_signature_bytes, message_bytes, public_key_bytes)
+        }
+        p256::NistP256::OID => {
+            let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key_bytes)
+                .map_err(|e| format!("malformed public key: {e:?}"))?;
+            let signature = p256::ecdsa::Signature::from_der(der_signature_bytes)
+                .map_err(|e| format!("malformed signature: {e:?}"))?;
+            verifying_key
+                .verify_prehash(&prehash, &signature)
+                .map_err(|e| format!("invalid signature: {e:?}"))
+        }
+        p384::NistP384::OID => {
+            let verifying_key = p384::ecdsa::VerifyingKey::from_sec1_bytes(public_key_bytes)
+                .map_err(|e| format!("malformed public key: {e:?}"))?;
+            let signature = p384::ecdsa::Signature::from_der(der_signature_bytes)
+                .map_err(|e| format!("malformed signature: {e:?}"))?;
+            verifying_key
+                .verify_prehash(&prehash, &signature)
+                .map_err(|e| format!("invalid signature: {e:?}"))
+        }
+        _ => Err(format!(
+            "unsupported curve {curve} with hash algorithm {hash_algorithm:?}"
+        )),
+    }
+}
+
+fn rsa_public_key(public_key: &SubjectPublicKeyInfoOwned) -> Result<rsa::RsaPublicKey, String> {
+    use rsa::{pkcs1::DecodeRsaPublicKey, traits::PublicKeyParts};
+
+    if ![rsa::pkcs1::ALGORITHM_OID, RSA_PSS_OID].contains(&public_key.algorithm.oid) {
+        return Err(format!(
+            "expected an RSA public key but got {}",
+            public_key.algorithm.oid
+        ));
+    }
+    let public_key = rsa::RsaPublicKey::from_pkcs1_der(public_key.subject_public_key.raw_bytes())
+        .map_err(|e| format!("malformed public key: {e:?}"))?;
+    if public_key.n().bits() < MIN_RSA_KEY_BITS {
+        return Err(format!(
+            "RSA keys must have at least {MIN_RSA_KEY_BITS} bits"
+        ));
+    }
+    Ok(public_key)
+}
+
+fn verify_rsa_pkcs1v15_signature(
+    signature_bytes: &[u8],
+    message_bytes: &[u8],
+    hash_algorithm: HashAlgorithm,
+    public_key: &SubjectPublicKeyInfoOwned,
+) -> Result<(), String> {
+    let scheme = match hash_algorithm {
+        HashAlgorithm::Sha256 => rsa::Pkcs1v15Sign::new::<sha2::Sha256>(),
+        HashAlgorithm::Sha384 => rsa::Pkcs1v15Sign::new::<sha2::Sha384>(),
+        HashAlgorithm::Sha512 => rsa::Pkcs1v15Sign::new::<sha2::Sha512>(),
+    };
+    rsa_public_key(public_key)?
+        .verify(
+            scheme,
+            &hash_algorithm.digest(message_bytes),
+            signature_bytes,
+        )
+        .map_err(|e| format!("invalid signature: {e:?}"))
+}
+
+fn verify_rsa_pss_signature(
+    signature_bytes: &[u8],
+    message_bytes: &[u8],
+    parameters: Option<&der::Any>,
+    public_key: &SubjectPublicKeyInfoOwned,
+) -> Result<(), String> {
+    let parameters: rsa::pkcs1::RsaPssParams = parameters
+        .ok_or_else(|| "missing RSASSA-PSS parameters".to_string())?
+        .decode_as()
+        .map_err(|e| format!("malformed RSASSA-PSS parameters: {e:?}"))?;
+    // The hash algorithm defaults to SHA-1, which is not supported.
+    let hash_algorithm = HashAlgorithm::from_digest_oid(parameters.hash.oid)?;
+    let mask_generation_hash = parameters
+        .mask_gen
+        .parameters
+        .map(|mask_generation_hash| mask_generation_hash.oid);
+    if parameters.mask_gen.oid != MGF1_OID || mask_generation_hash != Some(parameters.hash.oid) {
+        return Err("RSASSA-PSS signatures must use MGF1 with the same hash algorithm".to_string());
+    }
+    if parameters.trailer_field != rsa::pkcs1::TrailerField::BC {
+        return Err("unsupported RSASSA-PSS trailer field".to_string());
+    }
+
+    let salt_length = parameters.salt_len as usize;
+    let scheme = match hash_algorithm {
+        HashAlgorithm::Sha256 => rsa::Pss::new_with_salt::<sha2::Sha256>(salt_length),
+        HashAlgorithm::Sha384 => rsa::Pss::new_with_salt::<sha2::Sha384>(salt_length),
+        HashAlgorithm::Sha512 => rsa::Pss::new_with_salt::<sha2::Sha512>(salt_length),
+    };
+    rsa_public_key(public_key)?
+        .verify(
+            scheme,
+            &hash_algorithm.digest(message_bytes),
+            signature_bytes,
+        )
+        .map_err(|e| format!("invalid signature: {e:?}"))
+}
+
+fn next_child_certificate_serial_number() -> u32 {
+    config::next_serial_number(revocation::last_serial_number().unwrap_or(0))
 }
 
 fn derivation_path() -> Vec<Vec<u8>> {
-    // Empty derivation path: all callers share the same root CA key.
-    // A real CA would use a per-canister or per-caller path to isolate keys.
+    // Empty derivation path for the root CA key. Intermediate CAs have their
+    // own keys, see `intermediate_ca::derivation_path`.
     vec![]
 }
 
@@ -463,6 +988,47 @@ fn validity() -> Validity {
     }
 }
 
+/// The validity of a child certificate issued by a CA with `issuer_validity`.
+fn child_certificate_validity(
+    issuer_validity: Validity,
+    validity_seconds: Option<u64>,
+) -> Result<Validity, String> {
+    let Some(validity_seconds) = validity_seconds else {
+        // For simplicity of this example, use the same validity period as
+        // the issuing CA certificate if the caller does not request one.
+        // Normally, the validity would not start in the past and would end
+        // well before the CA certificate validity ends.
+        return Ok(issuer_validity);
+    };
+    if validity_seconds == 0 {
+        return Err("the requested validity must be at least one second".to_string());
+    }
+    let now = Duration::from_secs(Duration::from_nanos(time()).as_secs());
+    let not_after = now
+        .checked_add(Duration::from_secs(validity_seconds))
+        .filter(|not_after| *not_after <= issuer_validity.not_after.to_unix_duration())
+        .ok_or_else(|| {
+            format!(
+                "the requested validity of {validity_seconds} seconds ends after the validity of the issuing CA ends at {}",
+                issuer_validity.not_after
+            )
+        })?;
+    let to_time = |since_epoch: Duration| {
+        std::time::SystemTime::UNIX_EPOCH
+            .checked_add(since_epoch)
+            .and_then(|system_time| Time::try_from(system_time).ok())
+            .ok_or_else(|| "failed to convert time".to_string())
+    };
+    Ok(Validity {
+        not_before: to_time(now)?,
+        not_after: to_time(not_after)?,
+    })
+}
+
+fn nanos_since_epoch(time: Time) -> u64 {
+    time.to_unix_duration().as_nanos() as u64
+}
+
 getrandom::register_custom_getrandom!(always_fail);
 pub fn always_fail(_buf: &mut [u8]) -> Result<(), getrandom::Error> {
     Err(getrandom::Error::UNSUPPORTED)
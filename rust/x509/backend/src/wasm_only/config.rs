@@ -0,0 +1,149 @@
+//! CA state that must survive upgrades: the init arguments, the serial number
+//! counter, and the root CA certificate.
+
+use crate::{CaKeyInformation, OwnershipPolicy};
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, StableCell, Storable};
+use serde::Deserialize;
+use std::{borrow::Cow, cell::RefCell};
+
+use super::storage::{
+    memory, Memory, CA_CONFIG_MEMORY_ID, ROOT_CA_CERTIFICATE_MEMORY_ID, SERIAL_NUMBER_MEMORY_ID,
+};
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct CaConfig {
+    /// `None` until the canister is initialized, or if it was upgraded from
+    /// a version that did not persist its configuration.
+    ca_key_information: Option<CaKeyInformation>,
+    ownership_policy: OwnershipPolicy,
+}
+
+impl Storable for CaConfig {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, CaConfig).expect("failed to deserialize CaConfig")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self).expect("failed to serialize CaConfig").into()
+    }
+}
+
+thread_local! {
+    static CA_CONFIG: RefCell<StableCell<CaConfig, Memory>> = RefCell::new(
+        StableCell::init(memory(CA_CONFIG_MEMORY_ID), CaConfig::default())
+            .expect("failed to initialize the CA configuration"),
+    );
+
+    // the serial number of the root CA certificate is 1
+    static LAST_SERIAL_NUMBER: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(memory(SERIAL_NUMBER_MEMORY_ID), 1)
+            .expect("failed to initialize the serial number counter"),
+    );
+
+    // empty until the root CA certificate is created
+    static ROOT_CA_CERTIFICATE_PEM: RefCell<StableCell<String, Memory>> = RefCell::new(
+        StableCell::init(memory(ROOT_CA_CERTIFICATE_MEMORY_ID), String::new())
+            .expect("failed to initialize the root CA certificate"),
+    );
+}
+
+pub fn init(ca_key_information: CaKeyInformation, ownership_policy: OwnershipPolicy) {
+    set_config(CaConfig {
+        ca_key_information: Some(ca_key_information),
+        ownership_policy,
+    });
+}
+
+/// Checks the upgrade arguments against the persisted configuration. The CA
+/// key cannot be changed, because the root CA certificate and all
+/// certificates issued so far are bound to it. The ownership policy is
+/// replaced if one is given. Both are required if the configuration was not
+/// persisted.
+pub fn post_upgrade(
+    ca_key_information: Option<CaKeyInformation>,
+    ownership_policy: Option<OwnershipPolicy>,
+) -> Result<(), String> {
+    let mut config = CA_CONFIG.with(|cell| cell.borrow().get().clone());
+    match (&config.ca_key_information, ca_key_information) {
+        (Some(persisted), Some(given)) if *persisted != given => {
+            return Err(format!(
+                "the CA key cannot be changed by an upgrade: the canister uses {persisted:?}, but the upgrade argument is {given:?}"
+            ));
+        }
+        (None, None) => {
+            return Err(
+                "the CA key information must be passed when upgrading a canister that did not persist it"
+                    .to_string(),
+            );
+        }
+        (None, _) if ownership_policy.is_none() => {
+            return Err(
+                "the ownership policy must be passed when upgrading a canister that did not persist it"
+                    .to_string(),
+            );
+        }
+        (None, given) => config.ca_key_information = given,
+        (Some(_), _) => {}
+    }
+    if let Some(ownership_policy) = ownership_policy {
+        config.ownership_policy = ownership_policy;
+    }
+    set_config(config);
+    Ok(())
+}
+
+pub fn ca_key_information() -> CaKeyInformation {
+    CA_CONFIG.with(|cell| {
+        cell.borrow()
+            .get()
+            .ca_key_information
+            .clone()
+            .expect("the CA key information is set in init and post_upgrade")
+    })
+}
+
+pub fn ownership_policy() -> OwnershipPolicy {
+    CA_CONFIG.with(|cell| cell.borrow().get().ownership_policy.clone())
+}
+
+/// Returns a serial number that is larger than all serial numbers issued so
+/// far. `last_registered` is the largest serial number in the registry of
+/// issued certificates, which may be ahead of the counter if the canister
+/// was upgraded from a version that did not persist the counter.
+pub fn next_serial_number(last_registered: u32) -> u32 {
+    LAST_SERIAL_NUMBER.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let serial_number = (*cell.get()).max(last_registered) + 1;
+        cell.set(serial_number)
+            .expect("failed to store the serial number counter");
+        serial_number
+    })
+}
+
+pub fn root_ca_certificate() -> Option<String> {
+    ROOT_CA_CERTIFICATE_PEM
+        .with(|cell| Some(cell.borrow().get().clone()).filter(|pem| !pem.is_empty()))
+}
+
+/// Stores `pem` as the root CA certificate unless another call stored one
+/// first, and returns the stored certificate.
+pub fn get_or_init_root_ca_certificate(pem: String) -> String {
+    ROOT_CA_CERTIFICATE_PEM.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if cell.get().is_empty() {
+            cell.set(pem)
+                .expect("failed to store the root CA certificate");
+        }
+        cell.get().clone()
+    })
+}
+
+fn set_config(config: CaConfig) {
+    CA_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to store the CA configuration")
+    });
+}
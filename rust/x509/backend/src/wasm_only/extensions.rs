@@ -0,0 +1,146 @@
+//! Extensions that a CSR requests with the extensionRequest attribute, see
+//! [RFC 2985 Section 5.4.2](https://datatracker.ietf.org/doc/html/rfc2985#section-5.4.2).
+
+use der::Decode;
+use pkcs8::{AssociatedOid, ObjectIdentifier};
+use x509_cert::{
+    ext::pkix::{name::GeneralName, ExtendedKeyUsage, KeyUsage, KeyUsages, SubjectAltName},
+    request::{CertReq, ExtensionReq},
+};
+
+/// The extensions requested by a CSR that are carried into the certificate.
+#[derive(Default)]
+pub struct RequestedExtensions {
+    pub subject_alt_name: Option<SubjectAltName>,
+    pub extended_key_usage: Option<ExtendedKeyUsage>,
+    pub key_usage: Option<KeyUsage>,
+}
+
+impl RequestedExtensions {
+    /// The DNS names in the subject alternative name extension.
+    pub fn dns_names(&self) -> impl Iterator<Item = &str> {
+        self.subject_alt_names().filter_map(|name| match name {
+            GeneralName::DnsName(dns_name) => Some(dns_name.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn subject_alt_names(&self) -> impl Iterator<Item = &GeneralName> {
+        self.subject_alt_name.iter().flat_map(|san| san.0.iter())
+    }
+
+    pub fn key_encipherment(&self) -> bool {
+        self.key_usage
+            .is_some_and(|key_usage| key_usage.key_encipherment())
+    }
+
+    pub fn key_agreement(&self) -> bool {
+        self.key_usage
+            .is_some_and(|key_usage| key_usage.key_agreement())
+    }
+}
+
+/// Parses the attributes of `cert_req`. The only supported attribute is the
+/// extensionRequest attribute, which may contain the subject alternative
+/// name, extended key usage, and key usage extensions.
+pub fn requested_extensions(cert_req: &CertReq) -> Result<RequestedExtensions, String> {
+    let mut requested = RequestedExtensions::default();
+    for attribute in cert_req.info.attributes.iter() {
+        if attribute.oid != ExtensionReq::OID {
+            return Err(format!("unsupported attribute {}", attribute.oid));
+        }
+        let [value] = attribute.values.as_slice() else {
+            return Err("the extensionRequest attribute must have exactly one value".to_string());
+        };
+        let extension_req: ExtensionReq = value
+            .decode_as()
+            .map_err(|e| format!("malformed extensionRequest attribute: {e:?}"))?;
+        for extension in extension_req.0 {
+            let extn_value = extension.extn_value.as_bytes();
+            match extension.extn_id {
+                SubjectAltName::OID => {
+                    let san = SubjectAltName::from_der(extn_value)
+                        .map_err(|e| format!("malformed subject alternative name: {e:?}"))?;
+                    validate_subject_alt_name(&san)?;
+                    set_once(
+                        &mut requested.subject_alt_name,
+                        san,
+                        "subject alternative name",
+                    )?;
+                }
+                ExtendedKeyUsage::OID => {
+                    let eku = ExtendedKeyUsage::from_der(extn_value)
+                        .map_err(|e| format!("malformed extended key usage: {e:?}"))?;
+                    validate_extended_key_usage(&eku)?;
+                    set_once(&mut requested.extended_key_usage, eku, "extended key usage")?;
+                }
+                KeyUsage::OID => {
+                    let key_usage = KeyUsage::from_der(extn_value)
+                        .map_err(|e| format!("malformed key usage: {e:?}"))?;
+                    validate_key_usage(&key_usage)?;
+                    set_once(&mut requested.key_usage, key_usage, "key usage")?;
+                }
+                other => return Err(format!("unsupported extension {other}")),
+            }
+        }
+    }
+    Ok(requested)
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, name: &str) -> Result<(), String> {
+    if slot.replace(value).is_some() {
+        return Err(format!("the {name} extension is requested more than once"));
+    }
+    Ok(())
+}
+
+fn validate_subject_alt_name(san: &SubjectAltName) -> Result<(), String> {
+    for name in san.0.iter() {
+        match name {
+            GeneralName::DnsName(_) | GeneralName::UniformResourceIdentifier(_) => {}
+            GeneralName::IpAddress(ip_address) => {
+                if ![4, 16].contains(&ip_address.as_bytes().len()) {
+                    return Err("malformed IP address in subject alternative name".to_string());
+                }
+            }
+            other => {
+                return Err(format!(
+                    "unsupported name in subject alternative name: {other:?}"
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_extended_key_usage(eku: &ExtendedKeyUsage) -> Result<(), String> {
+    let supported = [
+        // TLS WWW server authentication: id-kp-serverAuth (1.3.6.1.5.5.7.3.1)
+        ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.1"),
+        // TLS WWW client authentication: id-kp-clientAuth (1.3.6.1.5.5.7.3.2)
+        ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.2"),
+        // Signing of downloadable executable code: id-kp-codeSigning (1.3.6.1.5.5.7.3.3)
+        ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.3"),
+    ];
+    match eku.0.iter().find(|oid| !supported.contains(oid)) {
+        Some(oid) => Err(format!("unsupported extended key usage {oid}")),
+        None => Ok(()),
+    }
+}
+
+fn validate_key_usage(key_usage: &KeyUsage) -> Result<(), String> {
+    // The certificate always allows digital signatures and non-repudiation,
+    // and optionally key encipherment and key agreement. Everything else,
+    // in particular signing certificates and CRLs, is reserved for CAs.
+    let supported = KeyUsages::DigitalSignature
+        | KeyUsages::NonRepudiation
+        | KeyUsages::KeyEncipherment
+        | KeyUsages::KeyAgreement;
+    if !(key_usage.0 & !supported).is_empty() {
+        return Err(format!(
+            "unsupported key usage {:?}",
+            key_usage.0 & !supported
+        ));
+    }
+    Ok(())
+}
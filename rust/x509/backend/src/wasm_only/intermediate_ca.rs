@@ -0,0 +1,64 @@
+//! Intermediate CAs signed by the root CA. Each intermediate CA has its own
+//! threshold key, derived with a derivation path specific to its name.
+
+use crate::IntermediateCa;
+
+use candid::{Decode, Encode};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use super::storage::{memory, Memory, INTERMEDIATE_CAS_MEMORY_ID};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+impl Storable for IntermediateCa {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, IntermediateCa).expect("failed to deserialize IntermediateCa")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self)
+            .expect("failed to serialize IntermediateCa")
+            .into()
+    }
+}
+
+thread_local! {
+    static INTERMEDIATE_CAS: RefCell<StableBTreeMap<String, IntermediateCa, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(INTERMEDIATE_CAS_MEMORY_ID)));
+}
+
+pub fn get(name: &str) -> Option<IntermediateCa> {
+    INTERMEDIATE_CAS.with(|cas| cas.borrow().get(&name.to_string()))
+}
+
+pub fn insert(intermediate_ca: IntermediateCa) {
+    INTERMEDIATE_CAS.with(|cas| {
+        cas.borrow_mut()
+            .insert(intermediate_ca.name.clone(), intermediate_ca)
+    });
+}
+
+/// The names of intermediate CAs end up in the common name of their
+/// certificates, so they are restricted to characters that need no escaping.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "the name of an intermediate CA must have between 1 and {MAX_NAME_LENGTH} characters"
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(
+            "the name of an intermediate CA may only contain ASCII letters, digits, '-', '_', and '.'"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+pub fn derivation_path(name: &str) -> Vec<Vec<u8>> {
+    vec![b"intermediate_ca".to_vec(), name.as_bytes().to_vec()]
+}
@@ -0,0 +1,319 @@
+//! Policies that a caller has to satisfy to prove ownership of the subject of
+//! a certificate signing request, see [`OwnershipPolicy`].
+
+use crate::{principal_uri, Http01ChallengeConfig, OwnershipChallenge, OwnershipPolicy};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk_management_canister::{
+    self as management_canister, transform_context_from_query, HttpMethod, HttpRequestArgs,
+    HttpRequestResult, TransformArgs,
+};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::Deserialize;
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, str::FromStr};
+use x509_cert::{ext::pkix::name::GeneralName, name::Name, request::CertReq};
+
+use super::storage::{memory, Memory, ALLOWED_SUBJECTS_MEMORY_ID};
+
+const CHALLENGE_VALIDITY_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_PENDING_CHALLENGES_PER_CALLER: usize = 10;
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+const MAX_DOMAIN_LENGTH: usize = 253;
+// the token plus some slack for the headers of the response
+const MAX_CHALLENGE_RESPONSE_BYTES: u64 = 4_096;
+
+#[derive(CandidType, Deserialize, Default)]
+struct AllowedSubjects(Vec<String>);
+
+impl Storable for AllowedSubjects {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, AllowedSubjects).expect("failed to deserialize AllowedSubjects")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self)
+            .expect("failed to serialize AllowedSubjects")
+            .into()
+    }
+}
+
+thread_local! {
+    // Pending HTTP-01 challenges, keyed by the caller and the domain. They are
+    // short-lived, so losing them on upgrade only means requesting new ones.
+    static CHALLENGES: RefCell<BTreeMap<(Principal, String), OwnershipChallenge>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    static ALLOWED_SUBJECTS: RefCell<StableBTreeMap<Principal, AllowedSubjects, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ALLOWED_SUBJECTS_MEMORY_ID)));
+}
+
+pub fn policy() -> OwnershipPolicy {
+    super::config::ownership_policy()
+}
+
+/// Creates a new HTTP-01 challenge for `caller` and `domain`, replacing any
+/// pending challenge for the same domain. The number of pending challenges
+/// is limited per caller and in total, and expired challenges are removed.
+pub async fn create_challenge(
+    caller: Principal,
+    domain: String,
+    now: u64,
+) -> Result<OwnershipChallenge, String> {
+    let OwnershipPolicy::Http01Challenge(config) = policy() else {
+        return Err("the CA is not configured to use HTTP-01 challenges".to_string());
+    };
+    reject_anonymous(caller)?;
+    let domain = normalize_domain(&domain)?;
+    // checked before obtaining the randomness, which costs cycles, and again
+    // after, because concurrent calls may have added challenges meanwhile
+    check_challenge_limits(caller, &domain, now)?;
+
+    let random_bytes = management_canister::raw_rand()
+        .await
+        .map_err(|e| format!("failed to obtain randomness: {e:?}"))?;
+    let token: String = random_bytes.iter().map(|b| format!("{b:02x}")).collect();
+
+    let challenge = OwnershipChallenge {
+        url: config.challenge_url(&domain, &token),
+        domain: domain.clone(),
+        token,
+        expires_at: now.saturating_add(CHALLENGE_VALIDITY_NANOS),
+    };
+    check_challenge_limits(caller, &domain, now)?;
+    CHALLENGES.with(|challenges| {
+        challenges
+            .borrow_mut()
+            .insert((caller, domain), challenge.clone())
+    });
+    Ok(challenge)
+}
+
+/// Removes the expired challenges and checks that a challenge for `caller`
+/// and `domain` can be added without exceeding the limits.
+fn check_challenge_limits(caller: Principal, domain: &str, now: u64) -> Result<(), String> {
+    CHALLENGES.with(|challenges| {
+        let mut challenges = challenges.borrow_mut();
+        challenges.retain(|_, challenge| challenge.expires_at > now);
+        let key = (caller, domain.to_string());
+        if challenges.contains_key(&key) {
+            // the new challenge replaces the pending one
+            return Ok(());
+        }
+        let pending_for_caller = challenges
+            .range((caller, String::new())..)
+            .take_while(|((principal, _), _)| *principal == caller)
+            .count();
+        if pending_for_caller >= MAX_PENDING_CHALLENGES_PER_CALLER {
+            return Err(format!(
+                "{caller} has too many pending ownership challenges, use or let them expire first"
+            ));
+        }
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            return Err("too many pending ownership challenges, try again later".to_string());
+        }
+        Ok(())
+    })
+}
+
+pub fn set_allowed_subjects(principal: Principal, subjects: Vec<String>) -> Result<(), String> {
+    // store the subjects in the same form as `Name` displays them, so that
+    // they can be compared with the subjects of requests
+    let subjects = subjects
+        .iter()
+        .map(|subject| {
+            Name::from_str(subject)
+                .map(|name| name.to_string())
+                .map_err(|e| format!("invalid subject {subject}: {e:?}"))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    ALLOWED_SUBJECTS.with(|allowed| {
+        let mut allowed = allowed.borrow_mut();
+        if subjects.is_empty() {
+            allowed.remove(&principal);
+        } else {
+            allowed.insert(principal, AllowedSubjects(subjects));
+        }
+    });
+    Ok(())
+}
+
+pub fn allowed_subjects(principal: Principal) -> Vec<String> {
+    ALLOWED_SUBJECTS.with(|allowed| allowed.borrow().get(&principal).unwrap_or_default().0)
+}
+
+/// Checks that `caller` owns the subject of `cert_req` according to the
+/// configured policy.
+pub async fn prove_ownership(
+    cert_req: &CertReq,
+    caller: Principal,
+    now: u64,
+) -> Result<(), String> {
+    let subject = &cert_req.info.subject;
+    match policy() {
+        OwnershipPolicy::AcceptAll => Ok(()),
+        OwnershipPolicy::CommonNameIsCaller => {
+            reject_anonymous(caller)?;
+            if common_name(subject)? != caller.to_text() {
+                return Err(format!(
+                    "the common name of the subject must be the caller's principal {caller}"
+                ));
+            }
+            Ok(())
+        }
+        OwnershipPolicy::Http01Challenge(config) => {
+            reject_anonymous(caller)?;
+            let domain = normalize_domain(&common_name(subject)?)?;
+            verify_challenge(&config, caller, domain, now).await
+        }
+        OwnershipPolicy::SubjectAllowlist => {
+            reject_anonymous(caller)?;
+            let subject = subject.to_string();
+            if !allowed_subjects(caller).contains(&subject) {
+                return Err(format!(
+                    "{caller} is not allowed to request certificates for {subject}"
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Checks that `caller` owns the names in the subject alternative name of a
+/// request whose subject passed [`prove_ownership`]. Besides the common name
+/// proven by the policy, the caller can only prove that it owns its own
+/// principal.
+pub fn check_subject_alt_names<'a>(
+    names: impl IntoIterator<Item = &'a GeneralName>,
+    subject: &Name,
+    caller: Principal,
+) -> Result<(), String> {
+    let policy = policy();
+    if policy == OwnershipPolicy::AcceptAll {
+        return Ok(());
+    }
+    for name in names {
+        match name {
+            GeneralName::UniformResourceIdentifier(uri) => {
+                if uri.as_str() != principal_uri(&caller) {
+                    return Err(format!(
+                        "the only URI allowed in the subject alternative name is {}",
+                        principal_uri(&caller)
+                    ));
+                }
+            }
+            GeneralName::DnsName(dns_name) => {
+                let proven = match policy {
+                    OwnershipPolicy::Http01Challenge(_) | OwnershipPolicy::SubjectAllowlist => {
+                        common_name(subject)?.eq_ignore_ascii_case(dns_name.as_str())
+                    }
+                    _ => false,
+                };
+                if !proven {
+                    return Err(format!(
+                        "ownership of the DNS name {} in the subject alternative name cannot be proven",
+                        dns_name.as_str()
+                    ));
+                }
+            }
+            other => {
+                return Err(format!(
+                    "ownership of {other:?} in the subject alternative name cannot be proven"
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn verify_challenge(
+    config: &Http01ChallengeConfig,
+    caller: Principal,
+    domain: String,
+    now: u64,
+) -> Result<(), String> {
+    let key = (caller, domain);
+    let challenge = CHALLENGES
+        .with(|challenges| challenges.borrow().get(&key).cloned())
+        .filter(|challenge| challenge.expires_at > now)
+        .ok_or_else(|| {
+            format!(
+                "no pending ownership challenge for {}, request one with ownership_challenge",
+                key.1
+            )
+        })?;
+
+    let args = HttpRequestArgs {
+        url: config.challenge_url(&challenge.domain, &challenge.token),
+        max_response_bytes: Some(MAX_CHALLENGE_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(transform_context_from_query(
+            "transform_challenge_response".to_string(),
+            vec![],
+        )),
+        is_replicated: Some(true),
+    };
+    let response = management_canister::http_request(&args)
+        .await
+        .map_err(|e| format!("failed to fetch {}: {e:?}", args.url))?;
+
+    if response.status != 200u16 {
+        return Err(format!(
+            "fetching {} returned status {}",
+            args.url, response.status
+        ));
+    }
+    if String::from_utf8_lossy(&response.body).trim() != challenge.token {
+        return Err(format!(
+            "{} did not return the token of the ownership challenge",
+            args.url
+        ));
+    }
+
+    // a challenge proves ownership only once
+    CHALLENGES.with(|challenges| {
+        let mut challenges = challenges.borrow_mut();
+        if challenges.get(&key) == Some(&challenge) {
+            challenges.remove(&key);
+        }
+    });
+    Ok(())
+}
+
+/// Strips the headers of challenge responses, which differ between replicas
+/// and would prevent consensus on the response.
+#[ic_cdk::query(hidden = true)]
+fn transform_challenge_response(raw: TransformArgs) -> HttpRequestResult {
+    HttpRequestResult {
+        headers: vec![],
+        ..raw.response
+    }
+}
+
+fn reject_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        return Err("anonymous callers cannot prove ownership of a subject".to_string());
+    }
+    Ok(())
+}
+
+fn common_name(subject: &Name) -> Result<String, String> {
+    super::common_name(subject)
+        .ok_or_else(|| "the subject needs a common name to prove ownership".to_string())
+}
+
+fn normalize_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.to_ascii_lowercase();
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if domain.len() > MAX_DOMAIN_LENGTH || !domain.split('.').all(valid_label) {
+        return Err(format!("{domain} is not a valid domain name"));
+    }
+    Ok(domain)
+}
@@ -0,0 +1,349 @@
+//! Registry of the issued child certificates and the certificate revocation
+//! list (CRL) of the CA. Both are kept in stable memory, so that revocations
+//! survive canister upgrades.
+
+use crate::{
+    CertificateRevocationList, CertificateStatus, IssuedCertificate, Revocation, RevocationReason,
+};
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use der::{asn1::BitString, pem::LineEnding, referenced::OwnedToRef, Encode as DerEncode};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use signature::Keypair;
+use spki::{DynSignatureAlgorithmIdentifier, SubjectPublicKeyInfoOwned};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, time::Duration};
+use x509_cert::{
+    crl::{CertificateList, RevokedCert, TbsCertList},
+    ext::{
+        pkix::{crl::CrlNumber, AuthorityKeyIdentifier, CrlReason},
+        AsExtension,
+    },
+    name::Name,
+    serial_number::SerialNumber,
+    time::Time,
+    Version,
+};
+
+use super::signer::Sign;
+use super::storage::{memory, Memory, ISSUED_CERTIFICATES_MEMORY_ID, REVOCATION_LIST_MEMORY_ID};
+
+/// The time between the `thisUpdate` and `nextUpdate` fields of a CRL.
+const CRL_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long a CRL is served before it is regenerated, even if no certificate
+/// was revoked in the meantime.
+const CRL_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct RevocationListState {
+    /// Incremented with every revocation, so that a cached CRL can tell
+    /// whether it lists all revoked certificates.
+    revision: u64,
+    last_crl_number: u64,
+    /// The most recent CRL of each CA, keyed by the name of the intermediate
+    /// CA, or `None` for the root CA.
+    cached_crls: BTreeMap<Option<String>, CachedCrl>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct CachedCrl {
+    revision: u64,
+    crl_number: u64,
+    this_update: u64,
+    der: Vec<u8>,
+}
+
+impl Storable for IssuedCertificate {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, IssuedCertificate).expect("failed to deserialize IssuedCertificate")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self)
+            .expect("failed to serialize IssuedCertificate")
+            .into()
+    }
+}
+
+impl Storable for RevocationListState {
+    const BOUND: Bound = Bound::Unbounded;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, RevocationListState).expect("failed to deserialize RevocationListState")
+    }
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self)
+            .expect("failed to serialize RevocationListState")
+            .into()
+    }
+}
+
+thread_local! {
+    static ISSUED_CERTIFICATES: RefCell<StableBTreeMap<u32, IssuedCertificate, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ISSUED_CERTIFICATES_MEMORY_ID)));
+
+    static REVOCATION_LIST: RefCell<StableCell<RevocationListState, Memory>> =
+        RefCell::new(
+            StableCell::init(
+                memory(REVOCATION_LIST_MEMORY_ID),
+                RevocationListState::default(),
+            )
+            .expect("failed to initialize the revocation list state"),
+        );
+}
+
+pub fn register(certificate: IssuedCertificate) {
+    ISSUED_CERTIFICATES.with(|certificates| {
+        certificates
+            .borrow_mut()
+            .insert(certificate.serial_number, certificate)
+    });
+}
+
+pub fn issued_certificate(serial_number: u32) -> Option<IssuedCertificate> {
+    ISSUED_CERTIFICATES.with(|certificates| certificates.borrow().get(&serial_number))
+}
+
+pub fn last_serial_number() -> Option<u32> {
+    ISSUED_CERTIFICATES.with(|certificates| {
+        certificates
+            .borrow()
+            .last_key_value()
+            .map(|(serial_number, _)| serial_number)
+    })
+}
+
+pub fn status(serial_number: u32) -> CertificateStatus {
+    match issued_certificate(serial_number) {
+        None => CertificateStatus::Unknown,
+        Some(IssuedCertificate {
+            revocation: None, ..
+        }) => CertificateStatus::Good,
+        Some(IssuedCertificate {
+            revocation: Some(revocation),
+            ..
+        }) => CertificateStatus::Revoked {
+            revoked_at: revocation.revoked_at,
+            reason: revocation.reason,
+        },
+    }
+}
+
+/// Marks a certificate as revoked and returns it. Only the principal that
+/// requested the certificate or an admin may revoke it.
+pub fn revoke(
+    serial_number: u32,
+    reason: RevocationReason,
+    caller: Principal,
+    is_admin: bool,
+    now: u64,
+) -> Result<IssuedCertificate, String> {
+    let mut certificate = issued_certificate(serial_number)
+        .ok_or_else(|| format!("unknown certificate serial number {serial_number}"))?;
+    let is_requester = caller == certificate.requester && caller != Principal::anonymous();
+    if !is_requester && !is_admin {
+        return Err(format!(
+            "only the requester of certificate {serial_number} or a controller may revoke it"
+        ));
+    }
+    if certificate.revocation.is_some() {
+        return Err(format!("certificate {serial_number} is already revoked"));
+    }
+    certificate.revocation = Some(Revocation {
+        revoked_at: now,
+        revoked_by: caller,
+        reason,
+    });
+    register(certificate.clone());
+    update_state(|state| state.revision += 1);
+    Ok(certificate)
+}
+
+/// Returns the cached CRL of the given CA if it lists all revoked
+/// certificates and is not due for a refresh.
+pub fn cached_crl(intermediate_ca: &Option<String>, now: u64) -> Option<CertificateRevocationList> {
+    REVOCATION_LIST.with(|cell| {
+        let state = cell.borrow();
+        let state = state.get();
+        state
+            .cached_crls
+            .get(intermediate_ca)
+            .filter(|crl| {
+                crl.revision == state.revision
+                    && now
+                        < crl
+                            .this_update
+                            .saturating_add(CRL_REFRESH_INTERVAL.as_nanos() as u64)
+            })
+            .map(|crl| certificate_revocation_list(crl.crl_number, crl.der.clone()))
+    })
+}
+
+/// The certificates revoked by a CA at a point in time, together with a
+/// freshly allocated CRL number.
+pub struct CrlSnapshot {
+    intermediate_ca: Option<String>,
+    revision: u64,
+    crl_number: u64,
+    revoked: Vec<(u32, Revocation)>,
+}
+
+pub fn snapshot(intermediate_ca: Option<String>) -> CrlSnapshot {
+    let revoked = ISSUED_CERTIFICATES.with(|certificates| {
+        certificates
+            .borrow()
+            .iter()
+            .filter(|(_, certificate)| certificate.intermediate_ca == intermediate_ca)
+            .filter_map(|(serial_number, certificate)| {
+                certificate
+                    .revocation
+                    .map(|revocation| (serial_number, revocation))
+            })
+            .collect()
+    });
+    let (revision, crl_number) = update_state(|state| {
+        state.last_crl_number += 1;
+        (state.revision, state.last_crl_number)
+    });
+    CrlSnapshot {
+        intermediate_ca,
+        revision,
+        crl_number,
+        revoked,
+    }
+}
+
+/// Builds a CRL for `snapshot`, signs it with `signer`, and caches it unless a
+/// newer CRL was cached while signing.
+pub async fn signed_crl<Signer>(
+    snapshot: CrlSnapshot,
+    issuer: Name,
+    issuer_public_key_info: SubjectPublicKeyInfoOwned,
+    now: u64,
+    signer: Signer,
+) -> Result<CertificateRevocationList, String>
+where
+    Signer: Sign,
+    Signer: Keypair + DynSignatureAlgorithmIdentifier,
+{
+    let signature_algorithm = signer
+        .signature_algorithm_identifier()
+        .map_err(|e| format!("failed to get signature algorithm: {e:?}"))?;
+
+    let revoked_certificates = snapshot
+        .revoked
+        .iter()
+        .map(|(serial_number, revocation)| revoked_cert(*serial_number, revocation, &issuer))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let crl_extensions = vec![
+        AuthorityKeyIdentifier::try_from(issuer_public_key_info.owned_to_ref())
+            .and_then(|aki| aki.to_extension(&issuer, &[]))
+            .map_err(|e| format!("failed to encode authority key identifier: {e:?}"))?,
+        CrlNumber(
+            der::asn1::Uint::new(&snapshot.crl_number.to_be_bytes())
+                .map_err(|e| format!("failed to encode CRL number: {e:?}"))?,
+        )
+        .to_extension(&issuer, &[])
+        .map_err(|e| format!("failed to encode CRL number: {e:?}"))?,
+    ];
+
+    let tbs_cert_list = TbsCertList {
+        version: Version::V2,
+        signature: signature_algorithm.clone(),
+        issuer,
+        this_update: time_from_nanos(now)?,
+        next_update: Some(time_from_nanos(
+            now.saturating_add(CRL_VALIDITY.as_nanos() as u64),
+        )?),
+        revoked_certificates: (!revoked_certificates.is_empty()).then_some(revoked_certificates),
+        crl_extensions: Some(crl_extensions),
+    };
+
+    let blob = tbs_cert_list
+        .to_der()
+        .map_err(|e| format!("failed to encode CRL: {e:?}"))?;
+    let signature = BitString::from_bytes(&signer.sign(&blob).await?)
+        .map_err(|e| format!("wrong signature length: {e:?}"))?;
+
+    let der = CertificateList {
+        tbs_cert_list,
+        signature_algorithm,
+        signature,
+    }
+    .to_der()
+    .map_err(|e| format!("failed to encode CRL: {e:?}"))?;
+
+    update_state(|state| {
+        let is_newer = state
+            .cached_crls
+            .get(&snapshot.intermediate_ca)
+            .is_none_or(|cached| cached.crl_number < snapshot.crl_number);
+        if is_newer {
+            state.cached_crls.insert(
+                snapshot.intermediate_ca.clone(),
+                CachedCrl {
+                    revision: snapshot.revision,
+                    crl_number: snapshot.crl_number,
+                    this_update: now,
+                    der: der.clone(),
+                },
+            );
+        }
+    });
+
+    Ok(certificate_revocation_list(snapshot.crl_number, der))
+}
+
+fn revoked_cert(
+    serial_number: u32,
+    revocation: &Revocation,
+    issuer: &Name,
+) -> Result<RevokedCert, String> {
+    // RFC 5280 recommends omitting the reason code rather than using `unspecified`.
+    let reason = match revocation.reason {
+        RevocationReason::Unspecified => None,
+        RevocationReason::KeyCompromise => Some(CrlReason::KeyCompromise),
+        RevocationReason::CaCompromise => Some(CrlReason::CaCompromise),
+        RevocationReason::AffiliationChanged => Some(CrlReason::AffiliationChanged),
+        RevocationReason::Superseded => Some(CrlReason::Superseded),
+        RevocationReason::CessationOfOperation => Some(CrlReason::CessationOfOperation),
+        RevocationReason::PrivilegeWithdrawn => Some(CrlReason::PrivilegeWithdrawn),
+    };
+    let crl_entry_extensions = reason
+        .map(|reason| {
+            reason
+                .to_extension(issuer, &[])
+                .map(|extension| vec![extension])
+                .map_err(|e| format!("failed to encode revocation reason: {e:?}"))
+        })
+        .transpose()?;
+    Ok(RevokedCert {
+        serial_number: SerialNumber::from(serial_number),
+        revocation_date: time_from_nanos(revocation.revoked_at)?,
+        crl_entry_extensions,
+    })
+}
+
+fn certificate_revocation_list(crl_number: u64, der: Vec<u8>) -> CertificateRevocationList {
+    CertificateRevocationList {
+        pem_crl: der::pem::encode_string("X509 CRL", LineEnding::LF, &der)
+            .expect("failed to PEM-encode CRL"),
+        der_crl: der,
+        crl_number,
+    }
+}
+
+fn time_from_nanos(nanos: u64) -> Result<Time, String> {
+    Time::try_from(std::time::UNIX_EPOCH + Duration::from_nanos(nanos))
+        .map_err(|e| format!("failed to convert time: {e:?}"))
+}
+
+fn update_state<R>(f: impl FnOnce(&mut RevocationListState) -> R) -> R {
+    REVOCATION_LIST.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        let result = f(&mut state);
+        cell.set(state)
+            .expect("failed to store the revocation list state");
+        result
+    })
+}
@@ -1,4 +1,4 @@
-use std::{convert::TryFrom, ops::Deref};
+use std::convert::TryFrom;
 
 use elliptic_curve::sec1::ToEncodedPoint;
 use ic_cdk_management_canister::{
@@ -7,7 +7,7 @@ use ic_cdk_management_canister::{
 use sha2::Digest;
 use spki::{AlgorithmIdentifierOwned, DynSignatureAlgorithmIdentifier};
 
-use super::{derivation_path, root_ca_public_key_bytes, CA_KEY_INFORMATION};
+use super::{config, public_key_bytes};
 
 
 pub trait Sign {
@@ -16,17 +16,19 @@ pub trait Sign {
 
 pub struct Ed25519Signer {
     key_id: SchnorrKeyId,
+    derivation_path: Vec<Vec<u8>>,
     public_key: ed25519::pkcs8::PublicKeyBytes,
 }
 
 impl Ed25519Signer {
-    pub async fn new() -> Result<Self, String> {
-        let public_key_raw = <[u8; 32]>::try_from(root_ca_public_key_bytes().await?.as_slice())
-            .map_err(|e| format!("public key has wrong length: {e:?}"))?;
+    pub async fn new(derivation_path: Vec<Vec<u8>>) -> Result<Self, String> {
+        let public_key_raw =
+            <[u8; 32]>::try_from(public_key_bytes(&derivation_path).await?.as_slice())
+                .map_err(|e| format!("public key has wrong length: {e:?}"))?;
         let public_key = ed25519::pkcs8::PublicKeyBytes(public_key_raw);
         Ok(Self {
-            key_id: CA_KEY_INFORMATION
-                .with(|value| SchnorrKeyId::try_from(value.borrow().deref()))?,
+            key_id: SchnorrKeyId::try_from(&config::ca_key_information())?,
+            derivation_path,
             public_key,
         })
     }
@@ -36,7 +38,7 @@ impl Sign for Ed25519Signer {
     async fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, String> {
         let args = SignWithSchnorrArgs {
             message: msg.to_vec(),
-            derivation_path: derivation_path(), // empty because there is only one root certificate for everyone in this example
+            derivation_path: self.derivation_path.clone(),
             key_id: self.key_id.clone(),
             aux: None,
         };
@@ -68,20 +70,23 @@ impl DynSignatureAlgorithmIdentifier for Ed25519Signer {
 
 pub struct EcdsaSecp256k1Signer {
     key_id: EcdsaKeyId,
+    derivation_path: Vec<Vec<u8>>,
     public_key: k256::ecdsa::VerifyingKey,
 }
 
 impl EcdsaSecp256k1Signer {
-    pub async fn new() -> Result<Self, String> {
+    pub async fn new(derivation_path: Vec<Vec<u8>>) -> Result<Self, String> {
         let public_key = k256::ecdsa::VerifyingKey::from_encoded_point(
-            &k256::PublicKey::from_sec1_bytes(&root_ca_public_key_bytes().await?.as_slice())
-                .map_err(|e| format!("malformed public key: {e:?}"))?
-                .to_encoded_point(false),
+            &k256::PublicKey::from_sec1_bytes(
+                &public_key_bytes(&derivation_path).await?.as_slice(),
+            )
+            .map_err(|e| format!("malformed public key: {e:?}"))?
+            .to_encoded_point(false),
         )
         .unwrap();
         Ok(Self {
-            key_id: CA_KEY_INFORMATION
-                .with(|value| EcdsaKeyId::try_from(value.borrow().deref()))?,
+            key_id: EcdsaKeyId::try_from(&config::ca_key_information())?,
+            derivation_path,
             public_key,
         })
     }
@@ -94,7 +99,7 @@ impl Sign for EcdsaSecp256k1Signer {
 
         let args = SignWithEcdsaArgs {
             message_hash: hasher.finalize().to_vec(),
-            derivation_path: derivation_path(),
+            derivation_path: self.derivation_path.clone(),
             key_id: self.key_id.clone(),
         };
 
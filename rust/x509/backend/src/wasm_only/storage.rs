@@ -0,0 +1,26 @@
+//! Layout of the canister's stable memory.
+
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl,
+};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const ISSUED_CERTIFICATES_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const REVOCATION_LIST_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const INTERMEDIATE_CAS_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const ALLOWED_SUBJECTS_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const CA_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const SERIAL_NUMBER_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const ROOT_CA_CERTIFICATE_MEMORY_ID: MemoryId = MemoryId::new(6);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
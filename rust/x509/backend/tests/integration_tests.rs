@@ -4,10 +4,23 @@ use openssl::{
     hash::MessageDigest,
     nid::Nid,
     pkey::{PKey, PKeyRef, Private},
-    x509::{X509Name, X509Req, X509},
+    stack::Stack,
+    x509::{
+        extension::{ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        store::X509StoreBuilder,
+        CrlStatus, X509Crl, X509Extension, X509Name, X509Req, X509StoreContext, X509v3Context,
+        X509,
+    },
+};
+use pocket_ic::{
+    common::rest::{CanisterHttpReply, CanisterHttpResponse, MockCanisterHttpResponse},
+    PocketIcBuilder, RejectCode,
+};
+use backend::{
+    principal_uri, CaKeyInformation, CertificateRevocationList, CertificateStatus, Http01ChallengeConfig,
+    IntermediateCa, IntermediateCaConstraints, OwnershipChallenge, OwnershipPolicy,
+    PemCertificateRequest, RevocationReason, X509CertificateString,
 };
-use pocket_ic::PocketIcBuilder;
-use backend::{CaKeyInformation, PemCertificateRequest, X509CertificateString};
 
 fn load_wasm() -> Vec<u8> {
     let wasm_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
@@ -19,6 +32,19 @@ fn load_wasm() -> Vec<u8> {
 }
 
 fn pic_and_canister_id(ca_key_information: CaKeyInformation) -> (pocket_ic::PocketIc, Principal) {
+    pic_and_canister_id_with_ownership_policy(ca_key_information, OwnershipPolicy::AcceptAll)
+}
+
+fn pic_and_canister_id_with_ownership_policy(
+    ca_key_information: CaKeyInformation,
+    ownership_policy: OwnershipPolicy,
+) -> (pocket_ic::PocketIc, Principal) {
+    pic_and_canister_id_with_init_arg(
+        candid::encode_args((ca_key_information, ownership_policy)).unwrap(),
+    )
+}
+
+fn pic_and_canister_id_with_init_arg(init_arg: Vec<u8>) -> (pocket_ic::PocketIc, Principal) {
     // with_test_threshold_keys_subnet provides test_key_1 and dfx_test_key for all algorithms.
     let pic = PocketIcBuilder::new()
         .with_application_subnet()
@@ -31,7 +57,7 @@ fn pic_and_canister_id(ca_key_information: CaKeyInformation) -> (pocket_ic::Pock
     pic.install_canister(
         canister_id,
         load_wasm(),
-        encode_one(ca_key_information).unwrap(),
+        init_arg,
         None,
     );
 
@@ -61,32 +87,172 @@ fn generate_child_certificate(
     pic: &pocket_ic::PocketIc,
     canister_id: Principal,
 ) -> X509 {
+    generate_child_certificate_as(Principal::anonymous(), pem_req, pic, canister_id)
+}
+
+fn generate_child_certificate_as(
+    sender: Principal,
+    pem_req: Vec<u8>,
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+) -> X509 {
+    let pem = request_child_certificate(sender, pem_req, None, pic, canister_id)
+        .expect("failed to compute child certificate");
+
+    X509::from_pem(pem.x509_certificate_string.as_bytes())
+        .expect("failed to decode child certificate PEM")
+}
+
+fn request_child_certificate(
+    sender: Principal,
+    pem_req: Vec<u8>,
+    intermediate_ca: Option<String>,
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+) -> Result<X509CertificateString, String> {
     let pem_certificate_request =
         String::from_utf8(pem_req).expect("invalid request encoding");
 
+    request_child_certificate_with(
+        sender,
+        PemCertificateRequest {
+            pem_certificate_request,
+            intermediate_ca,
+            validity_seconds: None,
+        },
+        pic,
+        canister_id,
+    )
+}
+
+fn request_child_certificate_with(
+    sender: Principal,
+    request: PemCertificateRequest,
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+) -> Result<X509CertificateString, String> {
     let reply = pic
         .update_call(
             canister_id,
-            Principal::anonymous(),
+            sender,
             "child_certificate",
-            encode_one(PemCertificateRequest { pem_certificate_request }).unwrap(),
+            encode_one(request).unwrap(),
         )
         .expect("Failed to call child_certificate");
 
-    let result: Result<X509CertificateString, String> =
+    decode_one(&reply).expect("failed to decode reply")
+}
+
+fn create_intermediate_ca(
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+    name: &str,
+    constraints: IntermediateCaConstraints,
+) -> Result<IntermediateCa, String> {
+    let reply = pic
+        .update_call(
+            canister_id,
+            sender,
+            "create_intermediate_ca",
+            candid::encode_args((name, constraints)).unwrap(),
+        )
+        .expect("Failed to call create_intermediate_ca");
+    decode_one(&reply).expect("failed to decode reply")
+}
+
+fn serial_number(certificate: &X509) -> u32 {
+    certificate
+        .serial_number()
+        .to_bn()
+        .expect("failed to convert serial number")
+        .to_dec_str()
+        .expect("failed to format serial number")
+        .parse()
+        .expect("serial number does not fit into u32")
+}
+
+fn revoke(
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+    serial_number: u32,
+    reason: RevocationReason,
+) -> Result<(), String> {
+    let reply = pic
+        .update_call(
+            canister_id,
+            sender,
+            "revoke",
+            candid::encode_args((serial_number, reason)).unwrap(),
+        )
+        .expect("Failed to call revoke");
+    decode_one(&reply).expect("failed to decode reply")
+}
+
+fn certificate_status(
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+    serial_number: u32,
+) -> CertificateStatus {
+    let reply = pic
+        .query_call(
+            canister_id,
+            Principal::anonymous(),
+            "certificate_status",
+            encode_one(serial_number).unwrap(),
+        )
+        .expect("Failed to call certificate_status");
+    decode_one(&reply).expect("failed to decode reply")
+}
+
+fn fetch_certificate_revocation_list(
+    pic: &pocket_ic::PocketIc,
+    canister_id: Principal,
+    intermediate_ca: Option<String>,
+) -> (X509Crl, u64) {
+    let reply = pic
+        .update_call(
+            canister_id,
+            Principal::anonymous(),
+            "certificate_revocation_list",
+            encode_one(intermediate_ca).unwrap(),
+        )
+        .expect("Failed to call certificate_revocation_list");
+
+    let result: Result<CertificateRevocationList, String> =
         decode_one(&reply).expect("failed to decode reply");
-    let pem = result.expect("failed to compute child certificate");
+    let crl = result.expect("failed to compute certificate revocation list");
 
-    X509::from_pem(pem.x509_certificate_string.as_bytes())
-        .expect("failed to decode child certificate PEM")
+    let from_der = X509Crl::from_der(&crl.der_crl).expect("failed to decode CRL DER");
+    let from_pem = X509Crl::from_pem(crl.pem_crl.as_bytes()).expect("failed to decode CRL PEM");
+    assert_eq!(from_der.to_der().unwrap(), from_pem.to_der().unwrap());
+    (from_der, crl.crl_number)
 }
 
 fn generate_child_certificate_request(
     key: &PKeyRef<Private>,
     digest_type: MessageDigest,
+) -> X509Req {
+    generate_child_certificate_request_for(key, digest_type, "Test Corporation")
+}
+
+fn generate_child_certificate_request_for(
+    key: &PKeyRef<Private>,
+    digest_type: MessageDigest,
+    common_name: &str,
+) -> X509Req {
+    generate_child_certificate_request_with_extensions(key, digest_type, common_name, |_| vec![])
+}
+
+fn generate_child_certificate_request_with_extensions(
+    key: &PKeyRef<Private>,
+    digest_type: MessageDigest,
+    common_name: &str,
+    extensions: impl FnOnce(&X509v3Context) -> Vec<X509Extension>,
 ) -> X509Req {
     let mut builder = X509Name::builder().expect("failed to create X509NameBuilder");
-    builder.append_entry_by_text("CN", "Test Corporation").unwrap();
+    builder.append_entry_by_text("CN", common_name).unwrap();
     builder.append_entry_by_text("O", "Test Inc").unwrap();
     builder.append_entry_by_text("C", "US").unwrap();
     let subject_name = builder.build();
@@ -95,6 +261,14 @@ fn generate_child_certificate_request(
     req_builder.set_version(0).unwrap();
     req_builder.set_subject_name(&subject_name).unwrap();
     req_builder.set_pubkey(key).unwrap();
+    let extensions = extensions(&req_builder.x509v3_context(None));
+    if !extensions.is_empty() {
+        let mut stack = Stack::new().unwrap();
+        for extension in extensions {
+            stack.push(extension).unwrap();
+        }
+        req_builder.add_extensions(&stack).unwrap();
+    }
     req_builder.sign(key, digest_type).unwrap();
     req_builder.build()
 }
@@ -218,3 +392,1258 @@ mod ecdsa_secp256k1 {
         }
     }
 }
+
+mod revocation {
+    use super::*;
+
+    fn is_revoked(crl: &X509Crl, certificate: &X509) -> bool {
+        matches!(
+            crl.get_by_serial(certificate.serial_number()),
+            CrlStatus::Revoked(_)
+        )
+    }
+
+    #[test]
+    fn issued_certificates_should_be_revocable_by_requester_or_controller() {
+        for ca_key_information in [
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()),
+        ] {
+            let (pic, canister_id) = pic_and_canister_id(ca_key_information);
+            let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+            let root_public_key = root_certificate.public_key().unwrap();
+
+            let requester = Principal::from_slice(&[1]);
+            let other = Principal::from_slice(&[2]);
+
+            let (key, digest_type) = generate_child_keys().remove(0);
+            let pem_req = generate_child_certificate_request(&key, digest_type)
+                .to_pem()
+                .unwrap();
+            let first =
+                generate_child_certificate_as(requester, pem_req.clone(), &pic, canister_id);
+            let second = generate_child_certificate_as(requester, pem_req, &pic, canister_id);
+            assert_ne!(serial_number(&first), serial_number(&second));
+
+            assert_eq!(
+                certificate_status(&pic, canister_id, serial_number(&first)),
+                CertificateStatus::Good
+            );
+            assert_eq!(
+                certificate_status(&pic, canister_id, 1_000),
+                CertificateStatus::Unknown
+            );
+
+            let (crl, initial_crl_number) =
+                fetch_certificate_revocation_list(&pic, canister_id, None);
+            assert!(
+                crl.verify(&root_public_key).unwrap(),
+                "failed to verify CRL"
+            );
+            assert!(!is_revoked(&crl, &first));
+
+            // only the requester or a controller (the anonymous principal
+            // that installed the canister) may revoke
+            assert!(revoke(
+                &pic,
+                canister_id,
+                other,
+                serial_number(&first),
+                RevocationReason::KeyCompromise
+            )
+            .is_err());
+            revoke(
+                &pic,
+                canister_id,
+                requester,
+                serial_number(&first),
+                RevocationReason::KeyCompromise,
+            )
+            .expect("requester failed to revoke");
+            assert!(revoke(
+                &pic,
+                canister_id,
+                requester,
+                serial_number(&first),
+                RevocationReason::Superseded
+            )
+            .is_err());
+            revoke(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                serial_number(&second),
+                RevocationReason::Unspecified,
+            )
+            .expect("controller failed to revoke");
+
+            assert!(matches!(
+                certificate_status(&pic, canister_id, serial_number(&first)),
+                CertificateStatus::Revoked {
+                    reason: RevocationReason::KeyCompromise,
+                    ..
+                }
+            ));
+
+            let (crl, crl_number) = fetch_certificate_revocation_list(&pic, canister_id, None);
+            assert!(crl_number > initial_crl_number);
+            assert!(
+                crl.verify(&root_public_key).unwrap(),
+                "failed to verify CRL"
+            );
+            assert!(is_revoked(&crl, &first));
+            assert!(is_revoked(&crl, &second));
+        }
+    }
+}
+
+mod intermediate_ca {
+    use super::*;
+
+    fn verify_chain(root: &X509, intermediate: &X509, child: &X509) -> bool {
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(root.clone()).unwrap();
+        let store = store.build();
+        let mut chain = Stack::new().unwrap();
+        chain.push(intermediate.clone()).unwrap();
+        let mut context = X509StoreContext::new().unwrap();
+        context
+            .init(&store, child, &chain, |context| context.verify_cert())
+            .unwrap()
+    }
+
+    #[test]
+    fn intermediate_ca_should_sign_child_certificates_within_its_constraints() {
+        for ca_key_information in [
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()),
+        ] {
+            let (pic, canister_id) = pic_and_canister_id(ca_key_information);
+            let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+            let constraints = IntermediateCaConstraints {
+                path_length: Some(0),
+                permitted_dns_names: vec!["example.com".to_string()],
+                excluded_dns_names: vec![],
+            };
+
+            // only controllers (the anonymous principal that installed the
+            // canister) may create intermediate CAs
+            assert!(create_intermediate_ca(
+                &pic,
+                canister_id,
+                Principal::from_slice(&[1]),
+                "tenant-a",
+                constraints.clone()
+            )
+            .is_err());
+            let intermediate_ca = create_intermediate_ca(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                "tenant-a",
+                constraints.clone(),
+            )
+            .expect("failed to create intermediate CA");
+            assert!(create_intermediate_ca(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                "tenant-a",
+                constraints.clone()
+            )
+            .is_err());
+            let other_intermediate_ca = create_intermediate_ca(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                "tenant-b",
+                IntermediateCaConstraints::default(),
+            )
+            .expect("failed to create intermediate CA");
+
+            let intermediate_certificate =
+                X509::from_pem(intermediate_ca.x509_certificate_string.as_bytes()).unwrap();
+            let other_intermediate_certificate =
+                X509::from_pem(other_intermediate_ca.x509_certificate_string.as_bytes()).unwrap();
+            assert!(intermediate_certificate
+                .verify(&root_certificate.public_key().unwrap())
+                .unwrap());
+            let intermediate_public_key = intermediate_certificate.public_key().unwrap();
+            assert!(!intermediate_public_key.public_eq(&root_certificate.public_key().unwrap()));
+            assert!(!intermediate_public_key
+                .public_eq(&other_intermediate_certificate.public_key().unwrap()));
+
+            for (key, digest_type) in generate_child_keys() {
+                let permitted =
+                    generate_child_certificate_request_for(&key, digest_type, "www.example.com")
+                        .to_pem()
+                        .unwrap();
+                let child_certificate = request_child_certificate(
+                    Principal::anonymous(),
+                    permitted,
+                    Some("tenant-a".to_string()),
+                    &pic,
+                    canister_id,
+                )
+                .expect("failed to compute child certificate");
+                let child_certificate =
+                    X509::from_pem(child_certificate.x509_certificate_string.as_bytes()).unwrap();
+                assert!(verify_chain(
+                    &root_certificate,
+                    &intermediate_certificate,
+                    &child_certificate
+                ));
+                assert!(!verify_chain(
+                    &root_certificate,
+                    &other_intermediate_certificate,
+                    &child_certificate
+                ));
+
+                let not_permitted =
+                    generate_child_certificate_request_for(&key, digest_type, "www.example.org")
+                        .to_pem()
+                        .unwrap();
+                assert!(request_child_certificate(
+                    Principal::anonymous(),
+                    not_permitted,
+                    Some("tenant-a".to_string()),
+                    &pic,
+                    canister_id,
+                )
+                .is_err());
+            }
+
+            // child certificates of an intermediate CA are listed on its CRL
+            let (key, digest_type) = generate_child_keys().remove(0);
+            let pem_req = generate_child_certificate_request_for(&key, digest_type, "example.com")
+                .to_pem()
+                .unwrap();
+            let child_certificate = request_child_certificate(
+                Principal::anonymous(),
+                pem_req.clone(),
+                Some("tenant-a".to_string()),
+                &pic,
+                canister_id,
+            )
+            .unwrap();
+            let child_certificate =
+                X509::from_pem(child_certificate.x509_certificate_string.as_bytes()).unwrap();
+            revoke(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                serial_number(&child_certificate),
+                RevocationReason::Superseded,
+            )
+            .unwrap();
+            let (crl, _) =
+                fetch_certificate_revocation_list(&pic, canister_id, Some("tenant-a".to_string()));
+            assert!(crl.verify(&intermediate_public_key).unwrap());
+            assert!(matches!(
+                crl.get_by_serial(child_certificate.serial_number()),
+                CrlStatus::Revoked(_)
+            ));
+            let (root_crl, _) = fetch_certificate_revocation_list(&pic, canister_id, None);
+            assert!(matches!(
+                root_crl.get_by_serial(child_certificate.serial_number()),
+                CrlStatus::NotRevoked
+            ));
+
+            // a revoked intermediate CA is listed on the root CRL and no
+            // longer signs certificates
+            revoke(
+                &pic,
+                canister_id,
+                Principal::anonymous(),
+                serial_number(&intermediate_certificate),
+                RevocationReason::CaCompromise,
+            )
+            .unwrap();
+            let (root_crl, _) = fetch_certificate_revocation_list(&pic, canister_id, None);
+            assert!(matches!(
+                root_crl.get_by_serial(intermediate_certificate.serial_number()),
+                CrlStatus::Revoked(_)
+            ));
+            assert!(request_child_certificate(
+                Principal::anonymous(),
+                pem_req,
+                Some("tenant-a".to_string()),
+                &pic,
+                canister_id,
+            )
+            .is_err());
+        }
+    }
+}
+
+mod ownership {
+    use super::*;
+
+    const USER: Principal = Principal::from_slice(&[1]);
+    const OTHER_USER: Principal = Principal::from_slice(&[2]);
+
+    fn request_for(common_name: &str) -> Vec<u8> {
+        let (key, digest_type) = generate_child_keys().remove(0);
+        generate_child_certificate_request_for(&key, digest_type, common_name)
+            .to_pem()
+            .unwrap()
+    }
+
+    fn ownership_challenge(
+        pic: &pocket_ic::PocketIc,
+        canister_id: Principal,
+        sender: Principal,
+        domain: &str,
+    ) -> Result<OwnershipChallenge, String> {
+        let reply = pic
+            .update_call(
+                canister_id,
+                sender,
+                "ownership_challenge",
+                encode_one(domain).unwrap(),
+            )
+            .expect("Failed to call ownership_challenge");
+        decode_one(&reply).expect("failed to decode reply")
+    }
+
+    fn set_allowed_subjects(
+        pic: &pocket_ic::PocketIc,
+        canister_id: Principal,
+        sender: Principal,
+        principal: Principal,
+        subjects: Vec<&str>,
+    ) -> Result<(), String> {
+        let reply = pic
+            .update_call(
+                canister_id,
+                sender,
+                "set_allowed_subjects",
+                candid::encode_args((principal, subjects)).unwrap(),
+            )
+            .expect("Failed to call set_allowed_subjects");
+        decode_one(&reply).expect("failed to decode reply")
+    }
+
+    /// Requests a child certificate and answers the HTTPS outcall that checks
+    /// the challenge with `status` and `body`, standing in for the web server
+    /// of the domain.
+    fn request_child_certificate_serving(
+        pic: &pocket_ic::PocketIc,
+        canister_id: Principal,
+        sender: Principal,
+        pem_req: Vec<u8>,
+        expected_url: &str,
+        status: u16,
+        body: &str,
+    ) -> Result<X509CertificateString, String> {
+        let message_id = pic
+            .submit_call(
+                canister_id,
+                sender,
+                "child_certificate",
+                encode_one(PemCertificateRequest {
+                    pem_certificate_request: String::from_utf8(pem_req).unwrap(),
+                    intermediate_ca: None,
+                    validity_seconds: None,
+                })
+                .unwrap(),
+            )
+            .expect("Failed to submit child_certificate");
+
+        let mut requests = vec![];
+        for _ in 0..10 {
+            pic.tick();
+            requests = pic.get_canister_http();
+            if !requests.is_empty() {
+                break;
+            }
+        }
+        let [request] = requests.as_slice() else {
+            panic!("expected exactly one HTTPS outcall, got {requests:?}");
+        };
+        assert_eq!(request.url, expected_url);
+        pic.mock_canister_http_response(MockCanisterHttpResponse {
+            subnet_id: request.subnet_id,
+            request_id: request.request_id,
+            response: CanisterHttpResponse::CanisterHttpReply(CanisterHttpReply {
+                status,
+                headers: vec![],
+                body: body.as_bytes().to_vec(),
+            }),
+            additional_responses: vec![],
+        });
+
+        let reply = pic
+            .await_call(message_id)
+            .expect("Failed to call child_certificate");
+        decode_one(&reply).expect("failed to decode reply")
+    }
+
+    #[test]
+    fn common_name_should_be_the_callers_principal() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::CommonNameIsCaller,
+        );
+
+        let request = request_for(&USER.to_text());
+        assert!(
+            request_child_certificate(OTHER_USER, request.clone(), None, &pic, canister_id)
+                .is_err()
+        );
+        assert!(request_child_certificate(
+            Principal::anonymous(),
+            request_for(&Principal::anonymous().to_text()),
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+        let certificate = request_child_certificate(USER, request, None, &pic, canister_id)
+            .expect("failed to compute child certificate");
+        let certificate = X509::from_pem(certificate.x509_certificate_string.as_bytes()).unwrap();
+        let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+        assert!(certificate
+            .verify(&root_certificate.public_key().unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn allowlist_should_restrict_subjects_per_principal() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::SubjectAllowlist,
+        );
+        let request = request_for("www.example.com");
+        assert!(request_child_certificate(USER, request.clone(), None, &pic, canister_id).is_err());
+
+        // only controllers (the anonymous principal that installed the
+        // canister) may change the allowlist
+        // subjects are RFC 4514 strings, which list the attributes in reverse order
+        let subjects = vec!["C=US,O=Test Inc,CN=www.example.com"];
+        assert!(set_allowed_subjects(&pic, canister_id, USER, USER, subjects.clone()).is_err());
+        set_allowed_subjects(&pic, canister_id, Principal::anonymous(), USER, subjects).unwrap();
+
+        assert!(
+            request_child_certificate(OTHER_USER, request.clone(), None, &pic, canister_id)
+                .is_err()
+        );
+        assert!(request_child_certificate(
+            USER,
+            request_for("mail.example.com"),
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+        request_child_certificate(USER, request.clone(), None, &pic, canister_id)
+            .expect("failed to compute child certificate");
+
+        set_allowed_subjects(&pic, canister_id, Principal::anonymous(), USER, vec![]).unwrap();
+        assert!(request_child_certificate(USER, request, None, &pic, canister_id).is_err());
+    }
+
+    #[test]
+    fn http01_challenge_should_be_served_under_the_domain() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::Http01Challenge(Http01ChallengeConfig::default()),
+        );
+        // certificates are only signed after a challenge was requested
+        assert!(request_child_certificate(
+            USER,
+            request_for("www.example.com"),
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+        assert!(
+            ownership_challenge(&pic, canister_id, Principal::anonymous(), "example.com").is_err()
+        );
+        assert!(ownership_challenge(&pic, canister_id, USER, "not a domain").is_err());
+
+        let challenge = ownership_challenge(&pic, canister_id, USER, "WWW.example.com").unwrap();
+        assert_eq!(challenge.domain, "www.example.com");
+        assert_eq!(
+            challenge.url,
+            format!(
+                "https://www.example.com/.well-known/acme-challenge/{}",
+                challenge.token
+            )
+        );
+
+        // another principal cannot use the challenge
+        assert!(request_child_certificate(
+            OTHER_USER,
+            request_for("www.example.com"),
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+
+        assert!(request_child_certificate_serving(
+            &pic,
+            canister_id,
+            USER,
+            request_for("www.example.com"),
+            &challenge.url,
+            200,
+            "wrong token",
+        )
+        .is_err());
+        assert!(request_child_certificate_serving(
+            &pic,
+            canister_id,
+            USER,
+            request_for("www.example.com"),
+            &challenge.url,
+            404,
+            &challenge.token,
+        )
+        .is_err());
+        request_child_certificate_serving(
+            &pic,
+            canister_id,
+            USER,
+            request_for("www.example.com"),
+            &challenge.url,
+            200,
+            &format!("{}\n", challenge.token),
+        )
+        .expect("failed to compute child certificate");
+
+        // the challenge is consumed by the successful request
+        assert!(request_child_certificate(
+            USER,
+            request_for("www.example.com"),
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn http01_challenges_should_be_limited_per_caller_until_they_expire() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::Http01Challenge(Http01ChallengeConfig::default()),
+        );
+        for i in 0..10 {
+            ownership_challenge(&pic, canister_id, USER, &format!("www{i}.example.com")).unwrap();
+        }
+        assert!(ownership_challenge(&pic, canister_id, USER, "www10.example.com").is_err());
+        // a pending challenge can be replaced, and other callers have their own limit
+        ownership_challenge(&pic, canister_id, USER, "www0.example.com").unwrap();
+        ownership_challenge(&pic, canister_id, OTHER_USER, "www10.example.com").unwrap();
+
+        pic.advance_time(std::time::Duration::from_secs(60 * 60 + 1));
+        ownership_challenge(&pic, canister_id, USER, "www10.example.com").unwrap();
+    }
+
+    #[test]
+    fn http01_challenge_url_should_be_configurable() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()),
+            OwnershipPolicy::Http01Challenge(Http01ChallengeConfig {
+                url_template: Some("http://localhost:8000/{domain}/{token}".to_string()),
+            }),
+        );
+        let challenge = ownership_challenge(&pic, canister_id, USER, "example.com").unwrap();
+        assert_eq!(
+            challenge.url,
+            format!("http://localhost:8000/example.com/{}", challenge.token)
+        );
+        request_child_certificate_serving(
+            &pic,
+            canister_id,
+            USER,
+            request_for("example.com"),
+            &challenge.url,
+            200,
+            &challenge.token,
+        )
+        .expect("failed to compute child certificate");
+    }
+}
+
+mod extensions {
+    use super::*;
+
+    const USER: Principal = Principal::from_slice(&[1]);
+
+    fn request_with_extensions(
+        common_name: &str,
+        extensions: impl FnOnce(&X509v3Context) -> Vec<X509Extension>,
+    ) -> Vec<u8> {
+        let (key, digest_type) = generate_child_keys().remove(0);
+        generate_child_certificate_request_with_extensions(
+            &key,
+            digest_type,
+            common_name,
+            extensions,
+        )
+        .to_pem()
+        .unwrap()
+    }
+
+    fn request_with_subject_alt_name(common_name: &str, dns_name: &str, uri: &str) -> Vec<u8> {
+        request_with_extensions(common_name, |context| {
+            vec![SubjectAlternativeName::new()
+                .dns(dns_name)
+                .uri(uri)
+                .build(context)
+                .unwrap()]
+        })
+    }
+
+    fn certificate(result: Result<X509CertificateString, String>) -> X509 {
+        let pem = result.expect("failed to compute child certificate");
+        X509::from_pem(pem.x509_certificate_string.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn requested_validity_should_be_bounded_by_the_issuing_ca() {
+        let (pic, canister_id) =
+            pic_and_canister_id(CaKeyInformation::Ed25519("test_key_1".to_string()));
+        let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+        let (key, digest_type) = generate_child_keys().remove(0);
+        let pem_certificate_request = String::from_utf8(
+            generate_child_certificate_request(&key, digest_type)
+                .to_pem()
+                .unwrap(),
+        )
+        .unwrap();
+        let request = |validity_seconds| PemCertificateRequest {
+            pem_certificate_request: pem_certificate_request.clone(),
+            intermediate_ca: None,
+            validity_seconds,
+        };
+
+        let one_day = 24 * 60 * 60;
+        let child_certificate = certificate(request_child_certificate_with(
+            USER,
+            request(Some(one_day)),
+            &pic,
+            canister_id,
+        ));
+        let validity = child_certificate
+            .not_before()
+            .diff(child_certificate.not_after())
+            .unwrap();
+        assert_eq!((validity.days, validity.secs), (1, 0));
+        assert!(child_certificate.not_after() < root_certificate.not_after());
+
+        // without a requested validity, the certificate has the validity of the CA
+        let child_certificate = certificate(request_child_certificate_with(
+            USER,
+            request(None),
+            &pic,
+            canister_id,
+        ));
+        assert_eq!(child_certificate.not_after(), root_certificate.not_after());
+
+        let twenty_years = 20 * 365 * one_day;
+        for validity_seconds in [0, twenty_years, u64::MAX] {
+            assert!(request_child_certificate_with(
+                USER,
+                request(Some(validity_seconds)),
+                &pic,
+                canister_id
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn requested_extensions_should_be_carried_into_the_certificate() {
+        let (pic, canister_id) =
+            pic_and_canister_id(CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()));
+        let pem_req = request_with_extensions("www.example.com", |context| {
+            vec![
+                SubjectAlternativeName::new()
+                    .dns("www.example.com")
+                    .dns("example.com")
+                    .ip("192.0.2.1")
+                    .uri(&principal_uri(&USER))
+                    .build(context)
+                    .unwrap(),
+                ExtendedKeyUsage::new()
+                    .server_auth()
+                    .client_auth()
+                    .build()
+                    .unwrap(),
+                KeyUsage::new()
+                    .digital_signature()
+                    .key_agreement()
+                    .build()
+                    .unwrap(),
+            ]
+        });
+        let child_certificate = certificate(request_child_certificate(
+            USER,
+            pem_req,
+            None,
+            &pic,
+            canister_id,
+        ));
+
+        let names = child_certificate.subject_alt_names().unwrap();
+        let dns_names: Vec<_> = names.iter().filter_map(|name| name.dnsname()).collect();
+        assert_eq!(dns_names, vec!["www.example.com", "example.com"]);
+        let ip_addresses: Vec<_> = names.iter().filter_map(|name| name.ipaddress()).collect();
+        assert_eq!(ip_addresses, vec![&[192, 0, 2, 1][..]]);
+        let uris: Vec<_> = names.iter().filter_map(|name| name.uri()).collect();
+        assert_eq!(uris, vec![principal_uri(&USER)]);
+
+        let text = String::from_utf8(child_certificate.to_text().unwrap()).unwrap();
+        assert!(text.contains("TLS Web Server Authentication, TLS Web Client Authentication"));
+        assert!(text.contains("Digital Signature, Non Repudiation, Key Agreement"));
+        let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+        assert!(child_certificate
+            .verify(&root_certificate.public_key().unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn unsupported_extensions_should_be_rejected() {
+        let (pic, canister_id) =
+            pic_and_canister_id(CaKeyInformation::Ed25519("test_key_1".to_string()));
+        let unsupported = [
+            request_with_extensions("www.example.com", |_| {
+                vec![KeyUsage::new().key_cert_sign().build().unwrap()]
+            }),
+            request_with_extensions("www.example.com", |_| {
+                vec![ExtendedKeyUsage::new().email_protection().build().unwrap()]
+            }),
+            request_with_extensions("www.example.com", |context| {
+                vec![SubjectAlternativeName::new()
+                    .email("admin@example.com")
+                    .build(context)
+                    .unwrap()]
+            }),
+            request_with_extensions("www.example.com", |_| {
+                vec![openssl::x509::extension::BasicConstraints::new()
+                    .ca()
+                    .build()
+                    .unwrap()]
+            }),
+        ];
+        for pem_req in unsupported {
+            assert!(request_child_certificate(USER, pem_req, None, &pic, canister_id).is_err());
+        }
+    }
+
+    #[test]
+    fn subject_alt_names_should_be_checked_against_the_ownership_policy() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::CommonNameIsCaller,
+        );
+        let common_name = USER.to_text();
+        let own_uri = principal_uri(&USER);
+        let other_uri = principal_uri(&Principal::from_slice(&[2]));
+
+        let pem_req = request_with_extensions(&common_name, |context| {
+            vec![SubjectAlternativeName::new()
+                .uri(&own_uri)
+                .build(context)
+                .unwrap()]
+        });
+        let child_certificate = certificate(request_child_certificate(
+            USER,
+            pem_req,
+            None,
+            &pic,
+            canister_id,
+        ));
+        let names = child_certificate.subject_alt_names().unwrap();
+        assert_eq!(names.iter().next().unwrap().uri(), Some(own_uri.as_str()));
+
+        // the caller cannot prove that it owns other principals or DNS names
+        for pem_req in [
+            request_with_subject_alt_name(&common_name, "www.example.com", &own_uri),
+            request_with_extensions(&common_name, |context| {
+                vec![SubjectAlternativeName::new()
+                    .uri(&other_uri)
+                    .build(context)
+                    .unwrap()]
+            }),
+        ] {
+            assert!(request_child_certificate(USER, pem_req, None, &pic, canister_id).is_err());
+        }
+    }
+
+    #[test]
+    fn subject_alt_names_should_be_within_the_name_constraints() {
+        let (pic, canister_id) =
+            pic_and_canister_id(CaKeyInformation::Ed25519("test_key_1".to_string()));
+        create_intermediate_ca(
+            &pic,
+            canister_id,
+            Principal::anonymous(),
+            "tenant-a",
+            IntermediateCaConstraints {
+                path_length: Some(0),
+                permitted_dns_names: vec!["example.com".to_string()],
+                excluded_dns_names: vec![],
+            },
+        )
+        .unwrap();
+
+        let permitted = request_with_subject_alt_name(
+            "www.example.com",
+            "mail.example.com",
+            &principal_uri(&USER),
+        );
+        assert!(request_child_certificate(
+            USER,
+            permitted,
+            Some("tenant-a".to_string()),
+            &pic,
+            canister_id
+        )
+        .is_ok());
+        let not_permitted = request_with_subject_alt_name(
+            "www.example.com",
+            "www.example.org",
+            &principal_uri(&USER),
+        );
+        assert!(request_child_certificate(
+            USER,
+            not_permitted,
+            Some("tenant-a".to_string()),
+            &pic,
+            canister_id
+        )
+        .is_err());
+    }
+}
+
+mod upgrade {
+    use super::*;
+
+    const USER: Principal = Principal::from_slice(&[1]);
+
+    fn ownership_policy(pic: &pocket_ic::PocketIc, canister_id: Principal) -> OwnershipPolicy {
+        let reply = pic
+            .query_call(
+                canister_id,
+                Principal::anonymous(),
+                "ownership_policy",
+                encode_one(()).unwrap(),
+            )
+            .expect("Failed to call ownership_policy");
+        decode_one(&reply).expect("failed to decode reply")
+    }
+
+    fn upgrade(
+        pic: &pocket_ic::PocketIc,
+        canister_id: Principal,
+        arg: Vec<u8>,
+    ) -> Result<(), pocket_ic::RejectResponse> {
+        // stopping the canister lets calls that are in flight complete
+        // before the upgrade
+        pic.stop_canister(canister_id, None).unwrap();
+        let result = pic.upgrade_canister(canister_id, load_wasm(), arg, None);
+        pic.start_canister(canister_id, None).unwrap();
+        result
+    }
+
+    #[test]
+    fn upgrades_should_preserve_the_ca_configuration_and_serial_numbers() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()),
+            OwnershipPolicy::CommonNameIsCaller,
+        );
+        let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+        let (key, digest_type) = generate_child_keys().remove(1);
+        let pem_req = generate_child_certificate_request_for(&key, digest_type, &USER.to_text())
+            .to_pem()
+            .unwrap();
+
+        let mut certificates = vec![generate_child_certificate_as(
+            USER,
+            pem_req.clone(),
+            &pic,
+            canister_id,
+        )];
+        let in_flight: Vec<_> = (0..3)
+            .map(|_| {
+                pic.submit_call(
+                    canister_id,
+                    USER,
+                    "child_certificate",
+                    encode_one(PemCertificateRequest {
+                        pem_certificate_request: String::from_utf8(pem_req.clone()).unwrap(),
+                        intermediate_ca: None,
+                        validity_seconds: None,
+                    })
+                    .unwrap(),
+                )
+                .expect("Failed to submit child_certificate")
+            })
+            .collect();
+        upgrade(&pic, canister_id, candid::encode_args(()).unwrap())
+            .expect("failed to upgrade canister");
+        for message_id in in_flight {
+            let reply = pic
+                .await_call(message_id)
+                .expect("Failed to call child_certificate");
+            let result: Result<X509CertificateString, String> =
+                decode_one(&reply).expect("failed to decode reply");
+            let pem = result.expect("failed to compute child certificate");
+            certificates.push(X509::from_pem(pem.x509_certificate_string.as_bytes()).unwrap());
+        }
+        certificates.push(generate_child_certificate_as(
+            USER,
+            pem_req.clone(),
+            &pic,
+            canister_id,
+        ));
+
+        // the root CA certificate and key survive the upgrade
+        let root_certificate_after_upgrade = fetch_root_ca_certificate(&pic, canister_id);
+        assert_eq!(
+            root_certificate.to_pem().unwrap(),
+            root_certificate_after_upgrade.to_pem().unwrap()
+        );
+        let root_public_key = root_certificate.public_key().unwrap();
+        for certificate in &certificates {
+            assert!(certificate.verify(&root_public_key).unwrap());
+        }
+
+        // serial numbers are never reused
+        let serial_numbers: Vec<u32> = certificates.iter().map(serial_number).collect();
+        let mut unique_serial_numbers = serial_numbers.clone();
+        unique_serial_numbers.sort();
+        unique_serial_numbers.dedup();
+        assert_eq!(unique_serial_numbers.len(), serial_numbers.len());
+        assert!(
+            !serial_numbers.contains(&1),
+            "1 is the serial number of the root CA"
+        );
+        assert_eq!(
+            serial_numbers.last(),
+            unique_serial_numbers.last(),
+            "the certificate issued after the upgrade has the largest serial number"
+        );
+
+        // the ownership policy survives the upgrade
+        assert_eq!(
+            ownership_policy(&pic, canister_id),
+            OwnershipPolicy::CommonNameIsCaller
+        );
+        assert!(request_child_certificate(
+            Principal::from_slice(&[2]),
+            pem_req,
+            None,
+            &pic,
+            canister_id
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn upgrades_during_issuance_should_not_reuse_serial_numbers() {
+        let (pic, canister_id) = pic_and_canister_id_with_ownership_policy(
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            OwnershipPolicy::CommonNameIsCaller,
+        );
+        let root_public_key = fetch_root_ca_certificate(&pic, canister_id)
+            .public_key()
+            .unwrap();
+        let (key, digest_type) = generate_child_keys().remove(1);
+        let pem_req = generate_child_certificate_request_for(&key, digest_type, &USER.to_text())
+            .to_pem()
+            .unwrap();
+        // issuing a certificate first caches the public key of the CA, so that
+        // the next request reserves its serial number and waits for the
+        // threshold signature in its first round
+        let mut certificates = vec![generate_child_certificate_as(
+            USER,
+            pem_req.clone(),
+            &pic,
+            canister_id,
+        )];
+
+        let message_id = pic
+            .submit_call(
+                canister_id,
+                USER,
+                "child_certificate",
+                encode_one(PemCertificateRequest {
+                    pem_certificate_request: String::from_utf8(pem_req.clone()).unwrap(),
+                    intermediate_ca: None,
+                    validity_seconds: None,
+                })
+                .unwrap(),
+            )
+            .expect("Failed to submit child_certificate");
+        pic.tick();
+        // unlike `upgrade`, the canister is not stopped, so the call is still
+        // waiting for its signature
+        pic.upgrade_canister(
+            canister_id,
+            load_wasm(),
+            candid::encode_args(()).unwrap(),
+            None,
+        )
+        .expect("failed to upgrade canister");
+
+        // once the signature arrives, the callback runs in the upgraded code,
+        // which doesn't know the call's state anymore and traps, so the call
+        // is rejected rather than left hanging
+        let reject = pic
+            .await_call(message_id)
+            .expect_err("child_certificate should be rejected after the upgrade");
+        assert_eq!(reject.reject_code, RejectCode::CanisterError);
+        certificates.push(generate_child_certificate_as(
+            USER,
+            pem_req,
+            &pic,
+            canister_id,
+        ));
+
+        for certificate in &certificates {
+            assert!(certificate.verify(&root_public_key).unwrap());
+        }
+        let serial_numbers: Vec<u32> = certificates.iter().map(serial_number).collect();
+        let mut unique_serial_numbers = serial_numbers.clone();
+        unique_serial_numbers.sort();
+        unique_serial_numbers.dedup();
+        assert_eq!(unique_serial_numbers.len(), serial_numbers.len());
+        assert_eq!(
+            serial_numbers.last(),
+            unique_serial_numbers.last(),
+            "the certificate issued after the upgrade has the largest serial number"
+        );
+    }
+
+    #[test]
+    fn upgrades_should_not_change_the_ca_key() {
+        let ca_key_information = CaKeyInformation::Ed25519("test_key_1".to_string());
+        let (pic, canister_id) = pic_and_canister_id(ca_key_information.clone());
+        let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+
+        assert!(upgrade(
+            &pic,
+            canister_id,
+            encode_one(CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string())).unwrap(),
+        )
+        .is_err());
+
+        // the same argument as for the installation is accepted, and a given
+        // ownership policy replaces the persisted one
+        upgrade(
+            &pic,
+            canister_id,
+            encode_one(ca_key_information.clone()).unwrap(),
+        )
+        .expect("failed to upgrade canister");
+        assert_eq!(
+            ownership_policy(&pic, canister_id),
+            OwnershipPolicy::AcceptAll
+        );
+        upgrade(
+            &pic,
+            canister_id,
+            candid::encode_args((ca_key_information, Some(OwnershipPolicy::SubjectAllowlist)))
+                .unwrap(),
+        )
+        .expect("failed to upgrade canister");
+        assert_eq!(
+            ownership_policy(&pic, canister_id),
+            OwnershipPolicy::SubjectAllowlist
+        );
+
+        assert_eq!(
+            root_certificate.to_pem().unwrap(),
+            fetch_root_ca_certificate(&pic, canister_id)
+                .to_pem()
+                .unwrap()
+        );
+    }
+}
+
+mod csr_signatures {
+    //! CSRs generated with OpenSSL 3.5 for the subject
+    //! SUBJECT="/C=US/O=Test Inc/CN=Test Corporation".
+    use super::*;
+
+    // openssl req -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -sha256 -subj "$SUBJECT"
+    const P256_SHA256: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIH1MIGdAgEAMDsxCzAJBgNVBAYTAlVTMREwDwYDVQQKDAhUZXN0IEluYzEZMBcG
+A1UEAwwQVGVzdCBDb3Jwb3JhdGlvbjBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IA
+BGuYhIqmWQC5fSDVtU00xNV08Wmb8x9s9grXRJ3sTP07ugcNk+8nBcs0Qj+GZWLl
+QuiUgLoyYPg9eATee9HW7GagADAKBggqhkjOPQQDAgNHADBEAiB1ql8GmJxeaIGh
+VgOMaAU7NHSjtkTfw9hRZFRYjc5HCwIgVZkmsuHj8sVo0OpGILj+R/iMgEd3SWy/
+H5oVxAm3pQU=
+-----END CERTIFICATE REQUEST-----
+";
+
+    // openssl req -new -newkey ec -pkeyopt ec_paramgen_curve:P-384 -nodes -sha384 -subj "$SUBJECT"
+    const P384_SHA384: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIIBMjCBugIBADA7MQswCQYDVQQGEwJVUzERMA8GA1UECgwIVGVzdCBJbmMxGTAX
+BgNVBAMMEFRlc3QgQ29ycG9yYXRpb24wdjAQBgcqhkjOPQIBBgUrgQQAIgNiAAT5
+QMvdqwP31h/jekHXx2fVHaVngHjoxBBe+Orm20pJj/1cmqtAfDKg7kp+ukf2k51N
+EJ6Rh0FxSmFEcaiiH2mSOfdV8O6lwAULHolP/tuM95PMxWrjJea8mZbPN+dUcEig
+ADAKBggqhkjOPQQDAwNnADBkAjB/O9eCssRGmiq93kwLYq9JhulPnYfsYy55733n
+wci7LiB8YQ+lBV3oduqswamVKCcCMFLDlk5z6/rGxkAPwcjSOVIAVUuE8XBNOv0B
+iFabNeK8FsVt0His5lViAWXD4Z7kPA==
+-----END CERTIFICATE REQUEST-----
+";
+
+    // openssl req -new -newkey rsa:2048 -nodes -sha256 -subj "$SUBJECT"
+    const RSA_PKCS1V15_SHA256: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIICgDCCAWgCAQAwOzELMAkGA1UEBhMCVVMxETAPBgNVBAoMCFRlc3QgSW5jMRkw
+FwYDVQQDDBBUZXN0IENvcnBvcmF0aW9uMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A
+MIIBCgKCAQEAsJC9j8t5WEQ4MPJhn1GlDDChaW/qVnPGLDuaN1giliqw7Tfaca4y
+IFkqmZbm8YE0sB2+6KvFcOfOR9i8JT+fHJN35iP8JIRTMzfLRc8fI5l9ue/mVm2B
+LnkDAxcpG3WpcOCekHDFGFb69LIuu2jGppjWcMa1bRvqHg2avkfUYeETHHgkSUiq
+a/1cHUpf7GcnjP/rA88Gpg6cgTMAj1uSgZ7lmfxiqVEaWL9QiFibE7t+kjSItcm7
+USaFQfGjpkz/xOY2Hv0YjEIBCYCUwKoPMxSt73omLYEVl72ddVY5tXc1f4mQk2FQ
+r78ZuX4NTYy4DZ8RE0apspF+uph2dgzA7wIDAQABoAAwDQYJKoZIhvcNAQELBQAD
+ggEBAIaybxwMCnIeW6Qq4iN5rT2tqZHW9pFk2ZNI+JmltLFsD0+ZlBInWUgVKrNy
+biq1lKNdshCqZIQvCYCYTQCAY3zlf80QOm+R0BP49jx3WZHSERTQAXuWY5kfw6uE
+bsTXNIBk5NtV3tm4OAaVpGzFoDJHKEQYXg5l22qj1Q3edzcDJQWcKuXDIwhQ7WcC
+tocvlKKyQB2KTaCYMfW+3APHG40KPlRlXTEBrk4bMVIjRhAVvfITVD5Z9z+gJ0p/
+qozTF/ctEfSlQ26rTS8CWRG/P5W9WjY6ylxXqU4pTZ96/ASuoJKCL3IyODaDYp3D
+j3ra1tlpeh9pezfwNqv+AfFSAkk=
+-----END CERTIFICATE REQUEST-----
+";
+
+    // openssl req -new -key rsa.key -sha256 -sigopt rsa_padding_mode:pss -sigopt rsa_pss_saltlen:32 -subj "$SUBJECT"
+    const RSA_PSS_SHA256: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIICtDCCAWgCAQAwOzELMAkGA1UEBhMCVVMxETAPBgNVBAoMCFRlc3QgSW5jMRkw
+FwYDVQQDDBBUZXN0IENvcnBvcmF0aW9uMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A
+MIIBCgKCAQEAsJC9j8t5WEQ4MPJhn1GlDDChaW/qVnPGLDuaN1giliqw7Tfaca4y
+IFkqmZbm8YE0sB2+6KvFcOfOR9i8JT+fHJN35iP8JIRTMzfLRc8fI5l9ue/mVm2B
+LnkDAxcpG3WpcOCekHDFGFb69LIuu2jGppjWcMa1bRvqHg2avkfUYeETHHgkSUiq
+a/1cHUpf7GcnjP/rA88Gpg6cgTMAj1uSgZ7lmfxiqVEaWL9QiFibE7t+kjSItcm7
+USaFQfGjpkz/xOY2Hv0YjEIBCYCUwKoPMxSt73omLYEVl72ddVY5tXc1f4mQk2FQ
+r78ZuX4NTYy4DZ8RE0apspF+uph2dgzA7wIDAQABoAAwQQYJKoZIhvcNAQEKMDSg
+DzANBglghkgBZQMEAgEFAKEcMBoGCSqGSIb3DQEBCDANBglghkgBZQMEAgEFAKID
+AgEgA4IBAQBeTG0UhfgtTwlCj21p/WhJxjBsJfRCJ1yXuyy45vAkKSE9XPKg2RYD
+0GbAsTb0uOhTMoTcARZjtIwDd9oi/BHZkJluToV7mRoKZ9C6z1eBSSSdZB+Di3mu
+R3F9KqoEP3KWeUBssAEmkJebh0pHvqAOqSxf9xSTPW0oY3WfX5SG/E/8DbudeuC1
+mDkZw9+BtBLPyYyC/8jHDenYMLCgInu3ksMLsEL4tSAUSSX623TgOqN3KrA/mjBw
+fQY08JpgNtAdPQVIxhO/lFB9u2Zzw8jrOO3E8uWZ0dGTtnsjDkrFI1Beos/hxRgr
+Y8cvWadQVzHrFzV5YahROADJgL1N+Rla
+-----END CERTIFICATE REQUEST-----
+";
+
+    // openssl genpkey -algorithm RSA-PSS -pkeyopt rsa_keygen_bits:2048 -out pss.key
+    // openssl req -new -key pss.key -sha384 -sigopt rsa_pss_saltlen:48 -subj "$SUBJECT"
+    const RSA_PSS_KEY_SHA384: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIICsjCCAWYCAQAwOzELMAkGA1UEBhMCVVMxETAPBgNVBAoMCFRlc3QgSW5jMRkw
+FwYDVQQDDBBUZXN0IENvcnBvcmF0aW9uMIIBIDALBgkqhkiG9w0BAQoDggEPADCC
+AQoCggEBAL/u8eP1uMa3hN6CujDx41nRrvmTzCZfQSQOWjibIj6yC0224K2MZ8W4
+Wvf8e1OQbsWpMXkQ1U4oW+Qp+BIGPyMoGvbyytzuh+/BeeTe9158it3moFlnFAr1
+EorEa1/DtYPWITMj22znjLl/+AeDoUVwAALHBlJs1h1bhfrw0jpzO+7ML1g5wLZm
+E0TOATJZHR3Vklc3uStM2TxrpTdpu4+vPiz+8smqVYXrTDAugkHIgzvfg1zrb/s4
+J8DZazOQKROmSS1gFPhC427nL+XlGUGQ+QMj/78WhvFbZVAjcY0mT4VZ6zI42a6F
+vhk71/BEz00oFgmucaz+IR73VYRaZe0CAwEAAaAAMEEGCSqGSIb3DQEBCjA0oA8w
+DQYJYIZIAWUDBAICBQChHDAaBgkqhkiG9w0BAQgwDQYJYIZIAWUDBAICBQCiAwIB
+MAOCAQEAQSiFAhoGFEIL/HW17ldSIdTwRFCXMcNCqARP1ixF5B+EyK189KroSjMj
+4ZWYv6Uf8pLcZCeHDa9dr/7ZHAXQXP9zjDKK+HWZuraRzbv7/YOLd6z9arCmnl/f
+1dQX1zEIZSdEfca1sBN9l6Hci/+zUNvUtl9qw7JNQ8RzbWPdUN05hKWabF3XWBK1
+exMtlJUh5FZYVPM+cLt5/1C18vb/v/+P8vvsqVqFzx0wdH/v1S5zrwe7oj7oI8VJ
+AUyfFKvt0b8fnzu1bNFXKTpnNtXjuoxURXdJMW8DVOD8ocpDkQ3WffpYFepAD0uQ
+kKFUgZAvApToFRCoz8aiQ49AdB9eMA==
+-----END CERTIFICATE REQUEST-----
+";
+
+    // openssl req -new -newkey rsa:1024 -nodes -sha256 -subj "$SUBJECT"
+    const RSA_1024_SHA256: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIIBejCB5AIBADA7MQswCQYDVQQGEwJVUzERMA8GA1UECgwIVGVzdCBJbmMxGTAX
+BgNVBAMMEFRlc3QgQ29ycG9yYXRpb24wgZ8wDQYJKoZIhvcNAQEBBQADgY0AMIGJ
+AoGBAKjD6o4D1a6/5OgxpOcx3VKSJHlO6CAfBS0AUods/zi9QLTze14qYch5puQf
+yr2xOGeTod67+0RxI8Y5PSAWC79oWwsNo/pLair19L79VQYsZvsQ6uW0oeyo2G3n
+aIErUFSmD/v7nxL8gtdCGpaDFqDk4aKVQNFyHp8dic5tP2I7AgMBAAGgADANBgkq
+hkiG9w0BAQsFAAOBgQAaxnYJ+zUhVgxnK99qrXdkLi1aijHXTFvoLDZIhHO6cSrK
+s2+JeuJQCB8aHwRMnKWxBImx8nsFMqeK+h7kLObZlD+qBQ9LgZeCamBdBv5JxptR
+T14u2lH4aRx7cSiNX4hXkFexZ1U2pKN45NRKwgltJ4eHi3B+uLPxQhOg8A++9g==
+-----END CERTIFICATE REQUEST-----
+";
+
+    // P256_SHA256 with the common name changed to "Test Corporatioo"
+    const TAMPERED_P256_SHA256: &str = "\
+-----BEGIN CERTIFICATE REQUEST-----
+MIH1MIGdAgEAMDsxCzAJBgNVBAYTAlVTMREwDwYDVQQKDAhUZXN0IEluYzEZMBcG
+A1UEAwwQVGVzdCBDb3Jwb3JhdGlvbzBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IA
+BGuYhIqmWQC5fSDVtU00xNV08Wmb8x9s9grXRJ3sTP07ugcNk+8nBcs0Qj+GZWLl
+QuiUgLoyYPg9eATee9HW7GagADAKBggqhkjOPQQDAgNHADBEAiB1ql8GmJxeaIGh
+VgOMaAU7NHSjtkTfw9hRZFRYjc5HCwIgVZkmsuHj8sVo0OpGILj+R/iMgEd3SWy/
+H5oVxAm3pQU=
+-----END CERTIFICATE REQUEST-----
+";
+
+    #[test]
+    fn openssl_requests_should_be_accepted() {
+        for ca_key_information in [
+            CaKeyInformation::Ed25519("test_key_1".to_string()),
+            CaKeyInformation::EcdsaSecp256k1("test_key_1".to_string()),
+        ] {
+            let (pic, canister_id) = pic_and_canister_id(ca_key_information);
+            let root_certificate = fetch_root_ca_certificate(&pic, canister_id);
+
+            for pem_req in [
+                P256_SHA256,
+                P384_SHA384,
+                RSA_PKCS1V15_SHA256,
+                RSA_PSS_SHA256,
+                RSA_PSS_KEY_SHA384,
+            ] {
+                let req = X509Req::from_pem(pem_req.as_bytes()).expect("invalid test vector");
+                let child_certificate =
+                    generate_child_certificate(pem_req.as_bytes().to_vec(), &pic, canister_id);
+
+                assert!(
+                    child_certificate
+                        .verify(&root_certificate.public_key().unwrap())
+                        .expect("errors while verifying child certificate"),
+                    "failed to verify child certificate"
+                );
+                assert!(
+                    child_certificate
+                        .public_key()
+                        .unwrap()
+                        .public_eq(&req.public_key().unwrap()),
+                    "the child certificate should certify the key of the request"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_requests_should_be_rejected() {
+        let (pic, canister_id) =
+            pic_and_canister_id(CaKeyInformation::Ed25519("test_key_1".to_string()));
+
+        for (pem_req, expected_error) in [
+            (TAMPERED_P256_SHA256, "invalid signature"),
+            (RSA_1024_SHA256, "RSA keys must have at least 2048 bits"),
+        ] {
+            let result = request_child_certificate(
+                Principal::anonymous(),
+                pem_req.as_bytes().to_vec(),
+                None,
+                &pic,
+                canister_id,
+            );
+            assert!(
+                matches!(&result, Err(e) if e.contains(expected_error)),
+                "unexpected result: {result:?}"
+            );
+        }
+    }
+}
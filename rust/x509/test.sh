@@ -1,7 +1,7 @@
 #!/usr/bin/env bash
 set -e
 
-trap 'rm -f /tmp/x509_test_key.pem /tmp/x509_test_csr.pem /tmp/x509_root_ca.pem /tmp/x509_child_cert.pem' EXIT
+trap 'rm -f /tmp/x509_test_key.pem /tmp/x509_test_csr.pem /tmp/x509_root_ca.pem /tmp/x509_child_cert.pem /tmp/x509_crl.pem /tmp/x509_intermediate_ca.pem /tmp/x509_intermediate_csr.pem /tmp/x509_intermediate_child_cert.pem /tmp/x509_san_csr.pem /tmp/x509_san_cert.pem' EXIT
 
 echo "=== Test 1: Generate root CA certificate (Ed25519) ==="
 root_cert=$(icp canister call backend root_ca_certificate '()') && \
@@ -45,3 +45,67 @@ echo "$child_cert" \
 openssl verify -CAfile /tmp/x509_root_ca.pem /tmp/x509_child_cert.pem 2>&1 && \
   echo "PASS" || (echo "FAIL" && exit 1)
 
+echo "=== Test 5: Revoke the child certificate ==="
+# openssl prints the serial number in hex, e.g. "serial=02".
+serial=$((16#$(openssl x509 -noout -serial -in /tmp/x509_child_cert.pem | cut -d= -f2)))
+icp canister call backend certificate_status "(${serial} : nat32)" | grep -q 'Good' && \
+  icp canister call backend revoke "(${serial} : nat32, variant { KeyCompromise })" && \
+  icp canister call backend certificate_status "(${serial} : nat32)" | grep -q 'Revoked' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 6: Verify the CRL with OpenSSL ==="
+icp canister call backend certificate_revocation_list '()' \
+  | grep 'pem_crl' \
+  | sed 's/.*pem_crl = "//; s/"[;,]*$//; s/\\n/\n/g' \
+  > /tmp/x509_crl.pem
+openssl crl -in /tmp/x509_crl.pem -CAfile /tmp/x509_root_ca.pem -noout 2>&1
+# With the CRL, the revoked child certificate must no longer verify.
+if openssl verify -crl_check -CRLfile /tmp/x509_crl.pem -CAfile /tmp/x509_root_ca.pem \
+  /tmp/x509_child_cert.pem 2>&1; then
+  echo "FAIL: revoked certificate still verifies" && exit 1
+else
+  echo "PASS"
+fi
+
+echo "=== Test 7: Sign a child certificate with an intermediate CA ==="
+icp canister call backend create_intermediate_ca \
+  '("tenant-a", record { path_length = opt (0 : nat8); permitted_dns_names = vec { "example.com" }; excluded_dns_names = vec {} })' \
+  | grep 'x509_certificate_string' \
+  | sed 's/.*x509_certificate_string = "//; s/"[;,]*$//; s/\\n/\n/g' \
+  > /tmp/x509_intermediate_ca.pem
+openssl req -new -key /tmp/x509_test_key.pem -out /tmp/x509_intermediate_csr.pem \
+  -subj "/CN=www.example.com/O=Test Inc/C=US" 2>/dev/null
+CSR_ESCAPED=$(awk '{printf "%s\\n", $0}' /tmp/x509_intermediate_csr.pem)
+icp canister call backend child_certificate \
+  "(record { pem_certificate_request = \"${CSR_ESCAPED}\"; intermediate_ca = opt \"tenant-a\" })" \
+  | grep 'x509_certificate_string' \
+  | sed 's/.*x509_certificate_string = "//; s/"[;,]*$//; s/\\n/\n/g' \
+  > /tmp/x509_intermediate_child_cert.pem
+openssl verify -CAfile /tmp/x509_root_ca.pem -untrusted /tmp/x509_intermediate_ca.pem \
+  /tmp/x509_intermediate_child_cert.pem 2>&1 && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 8: The deployed ownership policy accepts all requests ==="
+# The canister is deployed with the AcceptAll policy (see icp.yaml), so HTTP-01
+# challenges are not available. See the README for deploying with a policy.
+icp canister call backend ownership_policy '()' | grep -q 'AcceptAll' && \
+  icp canister call backend ownership_challenge '("www.example.com")' | grep -q 'Err' && \
+  echo "PASS" || (echo "FAIL" && exit 1)
+
+echo "=== Test 9: Request a one-day certificate with a subject alternative name ==="
+openssl req -new -key /tmp/x509_test_key.pem -out /tmp/x509_san_csr.pem \
+  -subj "/CN=www.example.com/O=Test Inc/C=US" \
+  -addext "subjectAltName=DNS:www.example.com,DNS:example.com" \
+  -addext "extendedKeyUsage=serverAuth" 2>/dev/null
+CSR_ESCAPED=$(awk '{printf "%s\\n", $0}' /tmp/x509_san_csr.pem)
+icp canister call backend child_certificate \
+  "(record { pem_certificate_request = \"${CSR_ESCAPED}\"; validity_seconds = opt (86400 : nat64) })" \
+  | grep 'x509_certificate_string' \
+  | sed 's/.*x509_certificate_string = "//; s/"[;,]*$//; s/\\n/\n/g' \
+  > /tmp/x509_san_cert.pem
+openssl x509 -noout -ext subjectAltName,extendedKeyUsage -in /tmp/x509_san_cert.pem
+# The certificate must verify, contain both DNS names, and expire within two days.
+openssl verify -CAfile /tmp/x509_root_ca.pem /tmp/x509_san_cert.pem 2>&1 && \
+  openssl x509 -noout -ext subjectAltName -in /tmp/x509_san_cert.pem | grep -q 'DNS:example.com' && \
+  ! openssl x509 -noout -checkend 172800 -in /tmp/x509_san_cert.pem && \
+  echo "PASS" || (echo "FAIL" && exit 1)